@@ -0,0 +1,139 @@
+use anyhow::{bail, Context, Result};
+
+pub enum Command {
+    /// the default: listen for hotkeys and synchronize the clipboard
+    Run,
+    /// sweep config.dir_name once
+    Gc { dry_run: bool },
+    /// create the certificate for the tls of the tcp transport
+    GenCert { force: bool },
+    /// list the instances announcing themselves on the LAN
+    Discover,
+    /// record a discovered instance as remote in the config
+    Approve { name: String },
+    /// show which remotes are running
+    Peers,
+    /// show whether an instance is running here. json includes the metrics
+    Status { json: bool },
+    /// publish stdin. None is the default channel. A sensitive entry expires
+    Send {
+        channel: Option<String>,
+        sensitive: bool,
+    },
+    /// write the newest remote entry to stdout
+    Recv { channel: Option<String> },
+    /// list the entries waiting in the queue
+    Queue { channel: Option<String> },
+    /// empty the queue in both directions
+    QueueClear { channel: Option<String> },
+    /// list the registers
+    Registers,
+    /// write a register to stdout
+    RegisterGet { name: String },
+    /// write stdin to a register
+    RegisterSet { name: String },
+    /// list the pinned snippets
+    Snippets,
+    /// write a snippet to stdout
+    SnippetGet { id: String },
+    /// pin stdin as a new snippet
+    SnippetAdd { id: String },
+    /// replace a snippet with stdin. Fails if it changed since base
+    SnippetEdit { id: String, base: u64 },
+    /// unpin a snippet. Fails if it changed since base
+    SnippetDelete { id: String, base: u64 },
+    /// choose an entry in a terminal ui
+    Pick { channel: Option<String> },
+    /// show what the content filters do with a text. None reads stdin
+    FilterCheck { text: Option<String> },
+}
+
+const USAGE: &str =
+    "usage: clipboard [gc [--dry-run] | gen-cert [--force] | discover | approve <name> | peers | status [--json]
+                  | send [--channel <name>] [--sensitive] | recv [--channel <name>] | queue [clear] [--channel <name>]
+                  | register [list | get <name> | set <name>]
+                  | snippets [list | get <id or index> | add <id> | edit <id> <revision> | delete <id> <revision>]
+                  | pick [--channel <name>] | filter check [<text>]]";
+
+impl Command {
+    pub fn parse(args: &[String]) -> Result<Self> {
+        let args: Vec<_> = args.iter().map(|a| a.as_str()).collect();
+
+        match args.as_slice() {
+            [] => Ok(Command::Run),
+            ["gc"] => Ok(Command::Gc { dry_run: false }),
+            ["gc", "--dry-run"] => Ok(Command::Gc { dry_run: true }),
+            ["gen-cert"] => Ok(Command::GenCert { force: false }),
+            ["gen-cert", "--force"] => Ok(Command::GenCert { force: true }),
+            ["discover"] => Ok(Command::Discover),
+            ["approve", name] => Ok(Command::Approve {
+                name: name.to_string(),
+            }),
+            ["peers"] => Ok(Command::Peers),
+            ["status"] => Ok(Command::Status { json: false }),
+            ["status", "--json"] => Ok(Command::Status { json: true }),
+            ["send"] => Ok(Command::Send {
+                channel: None,
+                sensitive: false,
+            }),
+            ["send", "--sensitive"] => Ok(Command::Send {
+                channel: None,
+                sensitive: true,
+            }),
+            ["send", "--channel", channel] => Ok(Command::Send {
+                channel: Some(channel.to_string()),
+                sensitive: false,
+            }),
+            ["send", "--channel", channel, "--sensitive"]
+            | ["send", "--sensitive", "--channel", channel] => Ok(Command::Send {
+                channel: Some(channel.to_string()),
+                sensitive: true,
+            }),
+            ["recv"] => Ok(Command::Recv { channel: None }),
+            ["recv", "--channel", channel] => Ok(Command::Recv {
+                channel: Some(channel.to_string()),
+            }),
+            ["queue"] => Ok(Command::Queue { channel: None }),
+            ["queue", "--channel", channel] => Ok(Command::Queue {
+                channel: Some(channel.to_string()),
+            }),
+            ["queue", "clear"] => Ok(Command::QueueClear { channel: None }),
+            ["queue", "clear", "--channel", channel] => Ok(Command::QueueClear {
+                channel: Some(channel.to_string()),
+            }),
+            ["register"] | ["register", "list"] => Ok(Command::Registers),
+            ["register", "get", name] => Ok(Command::RegisterGet {
+                name: name.to_string(),
+            }),
+            ["register", "set", name] => Ok(Command::RegisterSet {
+                name: name.to_string(),
+            }),
+            ["snippets"] | ["snippets", "list"] => Ok(Command::Snippets),
+            ["snippets", "get", id] => Ok(Command::SnippetGet { id: id.to_string() }),
+            ["snippets", "add", id] => Ok(Command::SnippetAdd { id: id.to_string() }),
+            ["snippets", "edit", id, base] => Ok(Command::SnippetEdit {
+                id: id.to_string(),
+                base: parse_revision(base)?,
+            }),
+            ["snippets", "delete", id, base] => Ok(Command::SnippetDelete {
+                id: id.to_string(),
+                base: parse_revision(base)?,
+            }),
+            ["pick"] => Ok(Command::Pick { channel: None }),
+            ["pick", "--channel", channel] => Ok(Command::Pick {
+                channel: Some(channel.to_string()),
+            }),
+            ["filter", "check"] => Ok(Command::FilterCheck { text: None }),
+            ["filter", "check", text] => Ok(Command::FilterCheck {
+                text: Some(text.to_string()),
+            }),
+            _ => bail!("unknown arguments {:?}\n{}", args, USAGE),
+        }
+    }
+}
+
+fn parse_revision(value: &str) -> Result<u64> {
+    value
+        .parse()
+        .with_context(|| format!("revision has to be a number: {}\n{}", value, USAGE))
+}
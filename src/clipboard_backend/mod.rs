@@ -0,0 +1,179 @@
+// Access to the clipboard of the desktop. Which backend is used is decided at runtime.
+//
+// windows: the win-api clipboard
+// linux:   the wayland data-control protocol, or the X11 CLIPBOARD selection
+
+#[cfg(target_os = "linux")]
+mod wayland;
+#[cfg(windows)]
+mod windows;
+#[cfg(target_os = "linux")]
+mod x11;
+
+use anyhow::Result;
+#[cfg(test)]
+use std::collections::HashMap;
+#[cfg(test)]
+use std::sync::{Arc, Mutex};
+
+// formats password managers add to their content to keep it out of clipboard histories. On
+// windows these are registered clipboard formats, on linux mime types.
+const EXCLUDE_FORMAT: &str = "ExcludeClipboardContentFromMonitorProcessing";
+const HISTORY_FORMAT: &str = "CanIncludeInClipboardHistory";
+const CLOUD_FORMAT: &str = "CanUploadToCloudClipboard";
+const KDE_HINT_FORMAT: &str = "x-kde-passwordManagerHint";
+
+pub trait ClipboardBackend: Send {
+    /// the text currently on the clipboard. None if there is no text on it.
+    fn get_text(&mut self) -> Result<Option<String>>;
+
+    fn set_text(&mut self, text: &str) -> Result<()>;
+
+    /// the raw data of a format on the clipboard. None if the content isn't offered in it.
+    fn get_format(&mut self, format: &str) -> Result<Option<Vec<u8>>>;
+
+    /// true if the application which set the content asked to keep it out of clipboard
+    /// histories. Password managers do that, and such content must not be synchronized either.
+    fn is_excluded(&mut self) -> Result<bool> {
+        // its presence alone is the hint
+        if self.get_format(EXCLUDE_FORMAT)?.is_some() {
+            return Ok(true);
+        }
+
+        // these hold a DWORD, 0 means no. Anything else is not what they are documented as
+        for format in [HISTORY_FORMAT, CLOUD_FORMAT] {
+            if let Some(data) = self.get_format(format)? {
+                if <[u8; 4]>::try_from(data.as_slice())
+                    .map(u32::from_le_bytes)
+                    .ok()
+                    == Some(0)
+                {
+                    return Ok(true);
+                }
+            }
+        }
+
+        // KeePassXC and others set it to secret
+        match self.get_format(KDE_HINT_FORMAT)? {
+            Some(data) => Ok(data.trim_ascii() == b"secret"),
+            None => Ok(false),
+        }
+    }
+
+    /// whether the text set stays on the clipboard once this process exits. If not, the process
+    /// has to keep running until something else is copied.
+    fn outlives_process(&self) -> bool {
+        false
+    }
+}
+
+/// opens the clipboard of the current session
+#[cfg(windows)]
+pub fn open() -> Result<Box<dyn ClipboardBackend>> {
+    Ok(Box::new(self::windows::WindowsClipboard))
+}
+
+/// opens the clipboard of the current session. Wayland is preferred, but not every compositor
+/// supports the data-control protocol. Those usually run XWayland, so X11 is the fallback.
+#[cfg(target_os = "linux")]
+pub fn open() -> Result<Box<dyn ClipboardBackend>> {
+    use crate::logfile::log;
+    use anyhow::bail;
+
+    let has_wayland = std::env::var_os("WAYLAND_DISPLAY").is_some();
+    let has_x11 = std::env::var_os("DISPLAY").is_some();
+
+    if has_wayland {
+        match wayland::WaylandClipboard::connect() {
+            Ok(clipboard) => return Ok(Box::new(clipboard)),
+            Err(e) if has_x11 => log(&format!(
+                "wayland clipboard unavailable, using X11: {}\n",
+                e
+            )),
+            Err(e) => return Err(e),
+        }
+    }
+
+    if has_x11 {
+        return Ok(Box::new(x11::X11Clipboard::connect()?));
+    }
+
+    bail!("neither WAYLAND_DISPLAY nor DISPLAY is set. There is no clipboard to use");
+}
+
+#[cfg(not(any(windows, target_os = "linux")))]
+pub fn open() -> Result<Box<dyn ClipboardBackend>> {
+    anyhow::bail!("there is no clipboard backend for this platform");
+}
+
+/// a clipboard in memory for the tests. The text is shared, so a test sees what the code under
+/// test did with it
+#[cfg(test)]
+#[derive(Default)]
+pub struct FakeClipboard {
+    pub text: Arc<Mutex<Option<String>>>,
+    /// the other formats on the clipboard and their data
+    pub formats: HashMap<String, Vec<u8>>,
+}
+
+#[cfg(test)]
+impl ClipboardBackend for FakeClipboard {
+    fn get_text(&mut self) -> Result<Option<String>> {
+        Ok(self.text.lock().unwrap().clone())
+    }
+
+    fn set_text(&mut self, text: &str) -> Result<()> {
+        *self.text.lock().unwrap() = Some(text.to_string());
+        Ok(())
+    }
+
+    fn get_format(&mut self, format: &str) -> Result<Option<Vec<u8>>> {
+        Ok(self.formats.get(format).cloned())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn offering(formats: &[(&str, &[u8])]) -> FakeClipboard {
+        FakeClipboard {
+            formats: formats
+                .iter()
+                .map(|(format, data)| (format.to_string(), data.to_vec()))
+                .collect(),
+            ..Default::default()
+        }
+    }
+
+    fn excluded(formats: &[(&str, &[u8])]) -> bool {
+        offering(formats).is_excluded().unwrap()
+    }
+
+    #[test]
+    fn the_exclude_format_excludes_whatever_its_data() {
+        assert!(excluded(&[(EXCLUDE_FORMAT, b"")]));
+        assert!(excluded(&[(EXCLUDE_FORMAT, &[1, 0, 0, 0])]));
+        assert!(!excluded(&[]));
+    }
+
+    #[test]
+    fn history_and_cloud_exclude_only_with_a_zero_dword() {
+        for format in [HISTORY_FORMAT, CLOUD_FORMAT] {
+            assert!(excluded(&[(format, &[0, 0, 0, 0])]));
+            assert!(!excluded(&[(format, &[1, 0, 0, 0])]));
+            assert!(!excluded(&[(format, &[0, 0, 0, 1])]));
+            // not a DWORD at all
+            assert!(!excluded(&[(format, b"")]));
+            assert!(!excluded(&[(format, &[0, 0])]));
+            assert!(!excluded(&[(format, &[0; 8])]));
+        }
+    }
+
+    #[test]
+    fn the_kde_hint_excludes_secrets() {
+        assert!(excluded(&[(KDE_HINT_FORMAT, b"secret")]));
+        assert!(excluded(&[(KDE_HINT_FORMAT, b"secret\n")]));
+        assert!(!excluded(&[(KDE_HINT_FORMAT, b"public")]));
+    }
+}
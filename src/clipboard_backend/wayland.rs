@@ -0,0 +1,71 @@
+use super::ClipboardBackend;
+use anyhow::{Context, Result};
+use std::io::Read;
+use wl_clipboard_rs::{copy, paste};
+
+/// the regular clipboard of a wayland compositor, accessed through the data-control protocol.
+/// The content set is served by a background thread of wl_clipboard_rs.
+pub struct WaylandClipboard;
+
+impl WaylandClipboard {
+    /// checks that the compositor supports the data-control protocol
+    pub fn connect() -> Result<Self> {
+        let mut clipboard = Self;
+        clipboard.get_text()?;
+        Ok(clipboard)
+    }
+}
+
+impl ClipboardBackend for WaylandClipboard {
+    fn get_text(&mut self) -> Result<Option<String>> {
+        let result = paste::get_contents(
+            paste::ClipboardType::Regular,
+            paste::Seat::Unspecified,
+            paste::MimeType::Text,
+        );
+
+        let mut pipe = match result {
+            Ok((pipe, _mime_type)) => pipe,
+            Err(
+                paste::Error::NoSeats | paste::Error::ClipboardEmpty | paste::Error::NoMimeType,
+            ) => return Ok(None),
+            Err(e) => return Err(e).with_context(|| "could not get clipboard"),
+        };
+
+        let mut content = Vec::new();
+        pipe.read_to_end(&mut content)
+            .with_context(|| "could not read clipboard")?;
+
+        Ok(Some(String::from_utf8_lossy(&content).into_owned()))
+    }
+
+    fn get_format(&mut self, format: &str) -> Result<Option<Vec<u8>>> {
+        let result = paste::get_contents(
+            paste::ClipboardType::Regular,
+            paste::Seat::Unspecified,
+            paste::MimeType::Specific(format),
+        );
+
+        let mut pipe = match result {
+            Ok((pipe, _mime_type)) => pipe,
+            Err(
+                paste::Error::NoSeats | paste::Error::ClipboardEmpty | paste::Error::NoMimeType,
+            ) => return Ok(None),
+            Err(e) => return Err(e).with_context(|| format!("could not get {}", format)),
+        };
+
+        let mut data = Vec::new();
+        pipe.read_to_end(&mut data)
+            .with_context(|| format!("could not read {}", format))?;
+        Ok(Some(data))
+    }
+
+    fn set_text(&mut self, text: &str) -> Result<()> {
+        copy::Options::new()
+            .copy(
+                copy::Source::Bytes(text.as_bytes().into()),
+                copy::MimeType::Text,
+            )
+            .with_context(|| "could not set clipboard")
+    }
+}
@@ -0,0 +1,47 @@
+use super::ClipboardBackend;
+use anyhow::{anyhow, Result};
+
+// other applications hold the clipboard open for short moments
+const OPEN_ATTEMPTS: usize = 10;
+
+/// the clipboard of the win-api. It has no state, every call opens the clipboard anew.
+pub struct WindowsClipboard;
+
+impl ClipboardBackend for WindowsClipboard {
+    fn get_text(&mut self) -> Result<Option<String>> {
+        if !clipboard_win::is_format_avail(clipboard_win::formats::CF_UNICODETEXT) {
+            return Ok(None);
+        }
+
+        let content: String = clipboard_win::get_clipboard(clipboard_win::formats::Unicode)
+            .map_err(|e| anyhow!("could not get clipboard: {}", e))?;
+        Ok(Some(content))
+    }
+
+    fn set_text(&mut self, text: &str) -> Result<()> {
+        clipboard_win::set_clipboard(clipboard_win::formats::Unicode, text)
+            .map_err(|e| anyhow!("could not set clipboard: {}", e))
+    }
+
+    fn get_format(&mut self, format: &str) -> Result<Option<Vec<u8>>> {
+        // registering is how the id of a named format is looked up
+        let Some(id) = clipboard_win::register_format(format) else {
+            return Err(anyhow!("could not register clipboard format {}", format));
+        };
+        if !clipboard_win::is_format_avail(id.get()) {
+            return Ok(None);
+        }
+
+        let _clipboard = clipboard_win::Clipboard::new_attempts(OPEN_ATTEMPTS)
+            .map_err(|e| anyhow!("could not open clipboard: {}", e))?;
+        let mut data = Vec::new();
+        clipboard_win::raw::get_vec(id.get(), &mut data)
+            .map_err(|e| anyhow!("could not get clipboard format {}: {}", format, e))?;
+        Ok(Some(data))
+    }
+
+    fn outlives_process(&self) -> bool {
+        // the content is copied into memory owned by windows
+        true
+    }
+}
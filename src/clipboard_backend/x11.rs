@@ -0,0 +1,91 @@
+use super::ClipboardBackend;
+use anyhow::{Context, Result};
+use std::time::Duration;
+use x11_clipboard::Clipboard;
+use x11rb::protocol::xproto::ConnectionExt;
+
+// the owner of the selection has this long to hand over its content
+const LOAD_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// the CLIPBOARD selection of an X11 server. The setter of x11_clipboard keeps serving the stored
+/// content to other clients as long as this lives.
+pub struct X11Clipboard {
+    clipboard: Clipboard,
+}
+
+impl X11Clipboard {
+    pub fn connect() -> Result<Self> {
+        let clipboard = Clipboard::new().with_context(|| "could not connect to the X11 server")?;
+        Ok(Self { clipboard })
+    }
+}
+
+impl ClipboardBackend for X11Clipboard {
+    fn get_text(&mut self) -> Result<Option<String>> {
+        let atoms = &self.clipboard.getter.atoms;
+
+        let content = match self.clipboard.load(
+            atoms.clipboard,
+            atoms.utf8_string,
+            atoms.property,
+            LOAD_TIMEOUT,
+        ) {
+            Ok(content) => content,
+            // nobody owns the selection
+            Err(x11_clipboard::error::Error::Timeout) => return Ok(None),
+            Err(e) => return Err(e).with_context(|| "could not get clipboard"),
+        };
+
+        if content.is_empty() {
+            return Ok(None);
+        }
+
+        Ok(Some(String::from_utf8_lossy(&content).into_owned()))
+    }
+
+    fn get_format(&mut self, format: &str) -> Result<Option<Vec<u8>>> {
+        let getter = &self.clipboard.getter;
+        let atoms = &getter.atoms;
+
+        let target = getter
+            .connection
+            .intern_atom(false, format.as_bytes())
+            .with_context(|| format!("could not look up {}", format))?
+            .reply()
+            .with_context(|| format!("could not look up {}", format))?
+            .atom;
+
+        // asking for a target the owner doesn't offer only fails after the timeout. So the list
+        // of targets is checked first
+        let targets =
+            match self
+                .clipboard
+                .load(atoms.clipboard, atoms.targets, atoms.property, LOAD_TIMEOUT)
+            {
+                Ok(targets) => targets,
+                Err(x11_clipboard::error::Error::Timeout) => return Ok(None),
+                Err(e) => return Err(e).with_context(|| "could not get clipboard targets"),
+            };
+
+        let offered = targets
+            .chunks_exact(4)
+            .any(|atom| u32::from_ne_bytes([atom[0], atom[1], atom[2], atom[3]]) == target);
+        if !offered {
+            return Ok(None);
+        }
+
+        let data = self
+            .clipboard
+            .load(atoms.clipboard, target, atoms.property, LOAD_TIMEOUT)
+            .with_context(|| format!("could not get {}", format))?;
+        Ok(Some(data))
+    }
+
+    fn set_text(&mut self, text: &str) -> Result<()> {
+        let atoms = &self.clipboard.setter.atoms;
+
+        self.clipboard
+            .store(atoms.clipboard, atoms.utf8_string, text)
+            .with_context(|| "could not set clipboard")
+    }
+}
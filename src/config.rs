@@ -1,26 +1,254 @@
+use crate::entry::is_valid_name;
+use crate::filters::{Detector, FilterAction, FilterRule, Matcher, DETECTORS};
+use crate::hooks::{Hook, HookEvent, DEFAULT_HOOK_TIMEOUT_SECS};
+use crate::script::Script;
+use crate::text_format::TextFormatMode;
+use crate::transforms::Transform;
 use anyhow::{anyhow, bail, Context, Result};
+use std::collections::HashMap;
+use std::time::Duration;
 
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum WatchMode {
+    /// filesystem notifications of the os
+    Events,
+    /// periodic rescans. Needed for network shares which don't emit events
+    Poll,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum TransportKind {
+    /// entries are files in config.dir_name
+    Directory,
+    /// entries are pushed to config.peers directly
+    Tcp,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum PasteMode {
+    /// a paste inserts the newest remote entry
+    Latest,
+    /// copies are queued up and every paste inserts the next one in order
+    Queue,
+}
+
+/// the modifiers held down together with c or v
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HotkeyModifiers {
+    /// ctrl+c and ctrl+v. Always used by the default channel
+    Ctrl,
+    CtrlShift,
+    CtrlAlt,
+}
+
+/// an independent sync group sharing config.dir_name with the others
+#[derive(Clone)]
+pub struct Channel {
+    pub name: String,
+    pub remote_names: Vec<String>,
+    /// None if the channel is only used with send and recv
+    pub hotkey: Option<HotkeyModifiers>,
+}
+
+// channel.<name>.remote_names and channel.<name>.hotkey as they are read
+#[derive(Default)]
+struct ChannelLines {
+    remote_names: Option<Vec<String>>,
+    hotkey: Option<HotkeyModifiers>,
+}
+
+// filter.<name>.pattern and filter.<name>.action as they are read
+#[derive(Default)]
+struct FilterLines {
+    pattern: Option<String>,
+    action: Option<FilterAction>,
+}
+
+// hook.<name>.command, hook.<name>.on, hook.<name>.timeout and hook.<name>.on_failure as they are
+// read
+#[derive(Default)]
+struct HookLines {
+    command: Option<String>,
+    event: Option<HookEvent>,
+    timeout: Option<u64>,
+    accept_on_failure: Option<bool>,
+}
+
+/// a remote reachable over the network
+#[derive(Clone)]
+pub struct Peer {
+    pub name: String,
+    /// host:port
+    pub address: String,
+    /// SHA-256 of the certificate of the peer. Needed with tls
+    pub fingerprint: Option<String>,
+}
+
+#[derive(Clone)]
 pub struct Config {
     pub local_name: String,
+    /// the remotes of config.channel
     pub remote_names: Vec<String>,
+    /// the channel this config is narrowed to, see Config::for_channel. None is the default
+    /// channel made of the top level remote_names
+    pub channel: Option<String>,
+    /// the named channels this instance takes part in besides the default one
+    pub channels: Vec<Channel>,
     pub dir_name: String,
+    /// entries older than this are removed by the garbage collector
+    pub entry_ttl_secs: u64,
+    /// how often the garbage collector sweeps config.dir_name
+    pub gc_interval_secs: u64,
+    /// how changes in config.dir_name are detected
+    pub watch_mode: WatchMode,
+    /// interval of the rescans if config.watch_mode is WatchMode::Poll
+    pub poll_interval_secs: u64,
+    pub transport: TransportKind,
+    pub paste_mode: PasteMode,
+    /// ctrl+shift+1 to 9 put the pinned snippet with that index on the clipboard
+    pub snippet_hotkeys: bool,
+    /// address the tcp transport listens on
+    pub listen: String,
+    /// where the tcp transport delivers entries to
+    pub peers: Vec<Peer>,
+    /// encrypt the tcp transport. Needs a certificate and pinned fingerprints for all peers
+    pub tls: bool,
+    pub tls_cert: String,
+    pub tls_key: String,
+    /// announce this instance on the LAN and look for others
+    pub discovery: bool,
+    /// only instances of the same group discover each other
+    pub group: String,
+    /// multicast group or broadcast address used for discovery
+    pub discovery_address: String,
+    /// checked against every entry before it is published, see filters.rs
+    pub filters: Vec<FilterRule>,
+    /// how long a sensitive entry lives before it is removed everywhere
+    pub sensitive_ttl_secs: u64,
+    /// applied in order to every entry before it is published, see transforms.rs
+    pub send_transforms: Vec<Transform>,
+    /// applied in order to every remote entry before it is pasted
+    pub receive_transforms: Vec<Transform>,
+    /// applied to the entries of one remote only, after config.receive_transforms
+    pub peer_receive_transforms: HashMap<String, Vec<Transform>>,
+    /// ctrl+shift+alt+1 to 9 apply the transform with that index to the local clipboard
+    pub transform_hotkeys: Vec<Transform>,
+    /// the line endings and encoding received entries are converted to, see text_format.rs
+    pub text_format: TextFormatMode,
+    /// external commands run on the entries, see hooks.rs
+    pub hooks: Vec<Hook>,
+    /// the rhai script deciding about every entry, see script.rs
+    pub script: Option<Script>,
+    /// serve the control socket other tools and the cli talk to, see control/mod.rs
+    pub control: bool,
+    /// the unix socket, or the named pipe on windows
+    pub control_path: String,
+    /// where the metrics are served over http, see metrics.rs. None turns the endpoint off
+    pub metrics_listen: Option<String>,
+    /// remove the entry of this instance from dir_name when it shuts down, see shutdown.rs
+    pub remove_entry_on_exit: bool,
 }
 
 const CONFIG_FILE_NAME: &str = "config.ini";
 
+const DEFAULT_ENTRY_TTL_SECS: u64 = 24 * 60 * 60;
+const DEFAULT_GC_INTERVAL_SECS: u64 = 10 * 60;
+const DEFAULT_POLL_INTERVAL_SECS: u64 = 2;
+const DEFAULT_SENSITIVE_TTL_SECS: u64 = 30;
+const DEFAULT_LISTEN: &str = "0.0.0.0:4717";
+const DEFAULT_GROUP: &str = "default";
+const DEFAULT_DISCOVERY_ADDRESS: &str = "239.255.47.17:4718";
+#[cfg(not(windows))]
+const DEFAULT_CONTROL_PATH: &str = "clipboard.sock";
+
 // example config.ini:
 // local_name=ubuntu
 // remote_names=win,ubuntu
 // dir_name=./
+// entry_ttl=86400
+// gc_interval=600
+// watch=poll
+// poll_interval=2
+// transport=tcp
+// paste_mode=queue
+// snippet_hotkeys=on
+// listen=0.0.0.0:4717
+// peers=win@192.168.0.2:4717#<fingerprint of win>
+// tls=on
+// tls_cert=ubuntu.cert.pem
+// tls_key=ubuntu.key.pem
+// discovery=on
+// group=office
+// discovery_address=239.255.47.17:4718
+// channels=team-a,personal
+// channel.team-a.remote_names=alice,bob
+// channel.team-a.hotkey=ctrl+shift
+// channel.personal.remote_names=win
+// filters=pem-key,aws-key,github-token,credit-card,jwt
+// filter_action=block
+// filter.internal-host.pattern=\bdb\d+\.corp\.example\.com\b
+// filter.internal-host.action=redact
+// sensitive_ttl=30
+// send_transforms=strip-ansi,trim-trailing
+// receive_transforms=nfc
+// receive_transforms.win=crlf-to-lf,unix-paths
+// transform_hotkeys=strip-ansi,unix-paths
+// text_format=native
+// hook.journal.command=cat >> ~/team-journal.txt
+// hook.journal.on=receive
+// hook.journal.timeout=5
+// hook.journal.on_failure=accept
+// script=rules.rhai
+// control=on
+// control_path=clipboard.sock
+// metrics_listen=127.0.0.1:9717
+// remove_entry_on_exit=off
 
 impl Config {
     pub fn load() -> Result<Self> {
         let content = std::fs::read_to_string(CONFIG_FILE_NAME)
             .with_context(|| format!("Looking for config-file at: {}", CONFIG_FILE_NAME))?;
 
+        Self::parse(&content)
+    }
+
+    /// parses the content of a config.ini
+    pub fn parse(content: &str) -> Result<Self> {
         let mut conf_local_name = None;
-        let mut conf_remote_names = None;
+        let mut conf_remote_names: Option<Vec<String>> = None;
         let mut conf_dir_name = None;
+        let mut conf_entry_ttl = None;
+        let mut conf_gc_interval = None;
+        let mut conf_watch_mode = None;
+        let mut conf_poll_interval = None;
+        let mut conf_transport = None;
+        let mut conf_paste_mode = None;
+        let mut conf_snippet_hotkeys = None;
+        let mut conf_listen = None;
+        let mut conf_peers = None;
+        let mut conf_tls = None;
+        let mut conf_tls_cert = None;
+        let mut conf_tls_key = None;
+        let mut conf_discovery = None;
+        let mut conf_group = None;
+        let mut conf_discovery_address = None;
+        let mut conf_channels: Option<Vec<String>> = None;
+        let mut conf_channel_lines: HashMap<String, ChannelLines> = HashMap::new();
+        let mut conf_filters: Option<Vec<Detector>> = None;
+        let mut conf_filter_action = None;
+        let mut conf_filter_lines: HashMap<String, FilterLines> = HashMap::new();
+        let mut conf_sensitive_ttl = None;
+        let mut conf_send_transforms = None;
+        let mut conf_receive_transforms = None;
+        let mut conf_peer_receive_transforms: HashMap<String, Vec<Transform>> = HashMap::new();
+        let mut conf_transform_hotkeys = None;
+        let mut conf_text_format = None;
+        let mut conf_hook_lines: HashMap<String, HookLines> = HashMap::new();
+        let mut conf_script = None;
+        let mut conf_control = None;
+        let mut conf_control_path = None;
+        let mut conf_metrics_listen = None;
+        let mut conf_remove_entry_on_exit = None;
 
         for (i, line) in content.lines().enumerate() {
             // skip empty lines or comments
@@ -28,22 +256,14 @@ impl Config {
                 continue;
             }
 
-            let mut parts = line.split("=");
-            let key = parts
-                .next()
-                .with_context(|| {
-                    format!("no key provided on line {} in config file:\n{}", i, line)
-                })?
-                .trim();
-
-            let value = parts
-                .next()
-                .with_context(|| {
-                    format!("no value provided on line {} in config file:\n{}", i, line)
-                })?
-                .trim();
-
-            if parts.next().is_some() {
+            let (key, value) = line.split_once("=").with_context(|| {
+                format!("no value provided on line {} in config file:\n{}", i, line)
+            })?;
+            let key = key.trim();
+            let value = value.trim();
+
+            // patterns and commands are the only values which may contain = themselves
+            if value.contains("=") && !key.starts_with("filter.") && !key.starts_with("hook.") {
                 bail!("too many parts on line {} in config file:\n{}", i, line);
             }
 
@@ -76,6 +296,336 @@ impl Config {
                         format!("Could not read specified directory: {}", value)
                     })?;
                 }
+                "entry_ttl" => {
+                    if conf_entry_ttl.is_some() {
+                        bail!("entry_ttl is a duplicate");
+                    }
+                    conf_entry_ttl = Some(parse_secs(key, value)?);
+                }
+                "gc_interval" => {
+                    if conf_gc_interval.is_some() {
+                        bail!("gc_interval is a duplicate");
+                    }
+                    conf_gc_interval = Some(parse_secs(key, value)?);
+                }
+                "watch" => {
+                    if conf_watch_mode.is_some() {
+                        bail!("watch is a duplicate");
+                    }
+                    conf_watch_mode = Some(match value {
+                        "events" => WatchMode::Events,
+                        "poll" => WatchMode::Poll,
+                        _ => bail!("watch has to be either events or poll: {}", value),
+                    });
+                }
+                "poll_interval" => {
+                    if conf_poll_interval.is_some() {
+                        bail!("poll_interval is a duplicate");
+                    }
+                    conf_poll_interval = Some(parse_secs(key, value)?);
+                }
+                "transport" => {
+                    if conf_transport.is_some() {
+                        bail!("transport is a duplicate");
+                    }
+                    conf_transport = Some(match value {
+                        "directory" => TransportKind::Directory,
+                        "tcp" => TransportKind::Tcp,
+                        _ => bail!("transport has to be either directory or tcp: {}", value),
+                    });
+                }
+                "paste_mode" => {
+                    if conf_paste_mode.is_some() {
+                        bail!("paste_mode is a duplicate");
+                    }
+                    conf_paste_mode = Some(match value {
+                        "latest" => PasteMode::Latest,
+                        "queue" => PasteMode::Queue,
+                        _ => bail!("paste_mode has to be either latest or queue: {}", value),
+                    });
+                }
+                "snippet_hotkeys" => {
+                    if conf_snippet_hotkeys.is_some() {
+                        bail!("snippet_hotkeys is a duplicate");
+                    }
+                    conf_snippet_hotkeys = Some(match value {
+                        "on" => true,
+                        "off" => false,
+                        _ => bail!("snippet_hotkeys has to be either on or off: {}", value),
+                    });
+                }
+                "listen" => {
+                    if conf_listen.is_some() {
+                        bail!("listen is a duplicate");
+                    }
+                    conf_listen = Some(value.to_string());
+                }
+                "peers" => {
+                    if conf_peers.is_some() {
+                        bail!("peers is a duplicate");
+                    }
+                    conf_peers = Some(parse_peers(value)?);
+                }
+                "tls" => {
+                    if conf_tls.is_some() {
+                        bail!("tls is a duplicate");
+                    }
+                    conf_tls = Some(match value {
+                        "on" => true,
+                        "off" => false,
+                        _ => bail!("tls has to be either on or off: {}", value),
+                    });
+                }
+                "tls_cert" => {
+                    if conf_tls_cert.is_some() {
+                        bail!("tls_cert is a duplicate");
+                    }
+                    conf_tls_cert = Some(value.to_string());
+                }
+                "tls_key" => {
+                    if conf_tls_key.is_some() {
+                        bail!("tls_key is a duplicate");
+                    }
+                    conf_tls_key = Some(value.to_string());
+                }
+                "discovery" => {
+                    if conf_discovery.is_some() {
+                        bail!("discovery is a duplicate");
+                    }
+                    conf_discovery = Some(match value {
+                        "on" => true,
+                        "off" => false,
+                        _ => bail!("discovery has to be either on or off: {}", value),
+                    });
+                }
+                "group" => {
+                    if conf_group.is_some() {
+                        bail!("group is a duplicate");
+                    }
+                    if value.contains(char::is_whitespace) {
+                        bail!("group must not contain whitespace: {}", value);
+                    }
+                    conf_group = Some(value.to_string());
+                }
+                "discovery_address" => {
+                    if conf_discovery_address.is_some() {
+                        bail!("discovery_address is a duplicate");
+                    }
+                    conf_discovery_address = Some(value.to_string());
+                }
+                "channels" => {
+                    if conf_channels.is_some() {
+                        bail!("channels is a duplicate");
+                    }
+                    conf_channels = Some(value.split(",").map(|s| s.trim().to_string()).collect());
+                }
+                _ if key.starts_with("channel.") => {
+                    let (name, field) =
+                        key["channel.".len()..].split_once(".").with_context(|| {
+                            format!("channel keys look like channel.<name>.<key>: {}", key)
+                        })?;
+                    let lines = conf_channel_lines.entry(name.to_string()).or_default();
+
+                    match field {
+                        "remote_names" => {
+                            if lines.remote_names.is_some() {
+                                bail!("{} is a duplicate", key);
+                            }
+                            lines.remote_names =
+                                Some(value.split(",").map(|s| s.trim().to_string()).collect());
+                        }
+                        "hotkey" => {
+                            if lines.hotkey.is_some() {
+                                bail!("{} is a duplicate", key);
+                            }
+                            lines.hotkey = Some(match value {
+                                "ctrl+shift" => HotkeyModifiers::CtrlShift,
+                                "ctrl+alt" => HotkeyModifiers::CtrlAlt,
+                                _ => bail!(
+                                    "{} has to be either ctrl+shift or ctrl+alt: {}",
+                                    key,
+                                    value
+                                ),
+                            });
+                        }
+                        _ => bail!(
+                            "unknown key {} on line {} in config file:\n{}",
+                            key,
+                            i,
+                            line
+                        ),
+                    }
+                }
+                "sensitive_ttl" => {
+                    if conf_sensitive_ttl.is_some() {
+                        bail!("sensitive_ttl is a duplicate");
+                    }
+                    conf_sensitive_ttl = Some(parse_secs(key, value)?);
+                }
+                "send_transforms" => {
+                    if conf_send_transforms.is_some() {
+                        bail!("send_transforms is a duplicate");
+                    }
+                    conf_send_transforms = Some(Transform::parse_list(value)?);
+                }
+                "receive_transforms" => {
+                    if conf_receive_transforms.is_some() {
+                        bail!("receive_transforms is a duplicate");
+                    }
+                    conf_receive_transforms = Some(Transform::parse_list(value)?);
+                }
+                _ if key.starts_with("receive_transforms.") => {
+                    let peer = &key["receive_transforms.".len()..];
+                    if conf_peer_receive_transforms.contains_key(peer) {
+                        bail!("{} is a duplicate", key);
+                    }
+                    conf_peer_receive_transforms
+                        .insert(peer.to_string(), Transform::parse_list(value)?);
+                }
+                "transform_hotkeys" => {
+                    if conf_transform_hotkeys.is_some() {
+                        bail!("transform_hotkeys is a duplicate");
+                    }
+                    let transforms = Transform::parse_list(value)?;
+                    if transforms.len() > 9 {
+                        bail!("transform_hotkeys takes at most 9 transforms, one per number key");
+                    }
+                    conf_transform_hotkeys = Some(transforms);
+                }
+                "text_format" => {
+                    if conf_text_format.is_some() {
+                        bail!("text_format is a duplicate");
+                    }
+                    conf_text_format = Some(TextFormatMode::parse(value)?);
+                }
+                "script" => {
+                    if conf_script.is_some() {
+                        bail!("script is a duplicate");
+                    }
+                    conf_script = Some(Script::load(value)?);
+                }
+                "control" => {
+                    if conf_control.is_some() {
+                        bail!("control is a duplicate");
+                    }
+                    conf_control = Some(match value {
+                        "on" => true,
+                        "off" => false,
+                        _ => bail!("control has to be either on or off: {}", value),
+                    });
+                }
+                "control_path" => {
+                    if conf_control_path.is_some() {
+                        bail!("control_path is a duplicate");
+                    }
+                    conf_control_path = Some(value.to_string());
+                }
+                "metrics_listen" => {
+                    if conf_metrics_listen.is_some() {
+                        bail!("metrics_listen is a duplicate");
+                    }
+                    conf_metrics_listen = Some(value.to_string());
+                }
+                "remove_entry_on_exit" => {
+                    if conf_remove_entry_on_exit.is_some() {
+                        bail!("remove_entry_on_exit is a duplicate");
+                    }
+                    conf_remove_entry_on_exit = Some(match value {
+                        "on" => true,
+                        "off" => false,
+                        _ => bail!("remove_entry_on_exit has to be either on or off: {}", value),
+                    });
+                }
+                _ if key.starts_with("hook.") => {
+                    let (name, field) =
+                        key["hook.".len()..].split_once(".").with_context(|| {
+                            format!("hook keys look like hook.<name>.<key>: {}", key)
+                        })?;
+                    let lines = conf_hook_lines.entry(name.to_string()).or_default();
+
+                    match field {
+                        "command" => {
+                            if lines.command.is_some() {
+                                bail!("{} is a duplicate", key);
+                            }
+                            lines.command = Some(value.to_string());
+                        }
+                        "on" => {
+                            if lines.event.is_some() {
+                                bail!("{} is a duplicate", key);
+                            }
+                            lines.event = Some(HookEvent::parse(value)?);
+                        }
+                        "timeout" => {
+                            if lines.timeout.is_some() {
+                                bail!("{} is a duplicate", key);
+                            }
+                            lines.timeout = Some(parse_secs(key, value)?);
+                        }
+                        "on_failure" => {
+                            if lines.accept_on_failure.is_some() {
+                                bail!("{} is a duplicate", key);
+                            }
+                            lines.accept_on_failure = Some(match value {
+                                "accept" => true,
+                                "reject" => false,
+                                _ => bail!("{} is either accept or reject: {}", key, value),
+                            });
+                        }
+                        _ => bail!(
+                            "unknown key {} on line {} in config file:\n{}",
+                            key,
+                            i,
+                            line
+                        ),
+                    }
+                }
+                "filters" => {
+                    if conf_filters.is_some() {
+                        bail!("filters is a duplicate");
+                    }
+                    conf_filters = Some(match value {
+                        "all" => DETECTORS.to_vec(),
+                        _ => value
+                            .split(",")
+                            .map(|d| Detector::parse(d.trim()))
+                            .collect::<Result<_>>()?,
+                    });
+                }
+                "filter_action" => {
+                    if conf_filter_action.is_some() {
+                        bail!("filter_action is a duplicate");
+                    }
+                    conf_filter_action = Some(FilterAction::parse(value)?);
+                }
+                _ if key.starts_with("filter.") => {
+                    let (name, field) =
+                        key["filter.".len()..].split_once(".").with_context(|| {
+                            format!("filter keys look like filter.<name>.<key>: {}", key)
+                        })?;
+                    let lines = conf_filter_lines.entry(name.to_string()).or_default();
+
+                    match field {
+                        "pattern" => {
+                            if lines.pattern.is_some() {
+                                bail!("{} is a duplicate", key);
+                            }
+                            lines.pattern = Some(value.to_string());
+                        }
+                        "action" => {
+                            if lines.action.is_some() {
+                                bail!("{} is a duplicate", key);
+                            }
+                            lines.action = Some(FilterAction::parse(value)?);
+                        }
+                        _ => bail!(
+                            "unknown key {} on line {} in config file:\n{}",
+                            key,
+                            i,
+                            line
+                        ),
+                    }
+                }
                 _ => {
                     bail!(
                         "unknown key {} on line {} in config file:\n{}",
@@ -90,18 +640,445 @@ impl Config {
         let local_name = conf_local_name.ok_or_else(|| anyhow!("local_name not provided"))?;
         let remote_names = conf_remote_names.ok_or_else(|| anyhow!("remote_names not provided"))?;
 
+        check_peer_name(&local_name).with_context(|| "invalid local_name")?;
         for r_name in &remote_names {
+            check_peer_name(r_name).with_context(|| "invalid remote_names")?;
             if r_name == &local_name {
                 bail!("remote_names contains local_name which is invalid");
             }
         }
 
+        let transport = conf_transport.unwrap_or(TransportKind::Directory);
+        let peers = conf_peers.unwrap_or_default();
+
+        for peer in &peers {
+            if !remote_names.contains(&peer.name) {
+                bail!("peer {} is not listed in remote_names", peer.name);
+            }
+        }
+
+        if transport == TransportKind::Tcp && peers.is_empty() {
+            bail!("transport tcp needs at least one entry in peers");
+        }
+
+        // the clipboard must never cross the network in clear unless asked to with tls=off. The
+        // fingerprints are checked once the transport starts, gen-cert has to work without them
+        let tls = conf_tls.unwrap_or(transport == TransportKind::Tcp);
+
+        let channels = parse_channels(
+            conf_channels.unwrap_or_default(),
+            conf_channel_lines,
+            &local_name,
+        )?;
+
+        if !channels.is_empty() && transport != TransportKind::Directory {
+            bail!("channels need transport=directory");
+        }
+
+        let snippet_hotkeys = conf_snippet_hotkeys.unwrap_or(false);
+        if snippet_hotkeys && transport != TransportKind::Directory {
+            bail!("snippet_hotkeys need transport=directory");
+        }
+
+        let filters = parse_filters(
+            conf_filters.unwrap_or_default(),
+            conf_filter_action.unwrap_or(FilterAction::Block),
+            conf_filter_lines,
+        )?;
+
+        let hooks = parse_hooks(conf_hook_lines)?;
+
+        let tls_cert = conf_tls_cert.unwrap_or_else(|| format!("{}.cert.pem", local_name));
+        let tls_key = conf_tls_key.unwrap_or_else(|| format!("{}.key.pem", local_name));
+        let control_path = conf_control_path.unwrap_or_else(|| default_control_path(&local_name));
+
         let config = Self {
             local_name,
             remote_names,
+            channel: None,
+            channels,
             dir_name: conf_dir_name.ok_or_else(|| anyhow!("dir_name not provided"))?,
+            entry_ttl_secs: conf_entry_ttl.unwrap_or(DEFAULT_ENTRY_TTL_SECS),
+            gc_interval_secs: conf_gc_interval.unwrap_or(DEFAULT_GC_INTERVAL_SECS),
+            watch_mode: conf_watch_mode.unwrap_or(WatchMode::Events),
+            poll_interval_secs: conf_poll_interval.unwrap_or(DEFAULT_POLL_INTERVAL_SECS),
+            transport,
+            paste_mode: conf_paste_mode.unwrap_or(PasteMode::Latest),
+            snippet_hotkeys,
+            listen: conf_listen.unwrap_or_else(|| DEFAULT_LISTEN.to_string()),
+            peers,
+            tls,
+            tls_cert,
+            tls_key,
+            discovery: conf_discovery.unwrap_or(false),
+            group: conf_group.unwrap_or_else(|| DEFAULT_GROUP.to_string()),
+            discovery_address: conf_discovery_address
+                .unwrap_or_else(|| DEFAULT_DISCOVERY_ADDRESS.to_string()),
+            filters,
+            sensitive_ttl_secs: conf_sensitive_ttl.unwrap_or(DEFAULT_SENSITIVE_TTL_SECS),
+            send_transforms: conf_send_transforms.unwrap_or_default(),
+            receive_transforms: conf_receive_transforms.unwrap_or_default(),
+            peer_receive_transforms: conf_peer_receive_transforms,
+            transform_hotkeys: conf_transform_hotkeys.unwrap_or_default(),
+            text_format: conf_text_format.unwrap_or(TextFormatMode::Native),
+            hooks,
+            script: conf_script,
+            control: conf_control.unwrap_or(true),
+            control_path,
+            metrics_listen: conf_metrics_listen,
+            remove_entry_on_exit: conf_remove_entry_on_exit.unwrap_or(false),
         };
 
+        for peer in config.peer_receive_transforms.keys() {
+            if !config.knows(peer) {
+                bail!(
+                    "receive_transforms.{} is set, but {} is no remote of any channel",
+                    peer,
+                    peer
+                );
+            }
+        }
+
         Ok(config)
     }
+
+    /// the same config narrowed to the named channel
+    pub fn for_channel(&self, name: &str) -> Result<Config> {
+        let channel = self
+            .channels
+            .iter()
+            .find(|c| c.name == name)
+            .with_context(|| format!("{} is not listed in channels", name))?;
+
+        Ok(Config {
+            remote_names: channel.remote_names.clone(),
+            channel: Some(channel.name.clone()),
+            ..self.clone()
+        })
+    }
+
+    /// the remotes of all channels, each one once
+    pub fn all_remote_names(&self) -> Vec<String> {
+        let mut names = self.remote_names.clone();
+        for name in self.channels.iter().flat_map(|c| &c.remote_names) {
+            if !names.contains(name) {
+                names.push(name.clone());
+            }
+        }
+        names
+    }
+
+    /// true if name is a remote of any channel
+    pub fn knows(&self, name: &str) -> bool {
+        self.all_remote_names().iter().any(|r| r == name)
+    }
+}
+
+/// checks the channel lines against the list of channels. Every listed channel needs its
+/// remote_names, and no two channels may share a hotkey.
+fn parse_channels(
+    names: Vec<String>,
+    mut lines: HashMap<String, ChannelLines>,
+    local_name: &str,
+) -> Result<Vec<Channel>> {
+    let mut channels: Vec<Channel> = Vec::new();
+
+    for name in names {
+        if !is_valid_name(&name) {
+            bail!(
+                "channel names may only contain letters, digits, - and _: {}",
+                name
+            );
+        }
+        if channels.iter().any(|c| c.name == name) {
+            bail!("channel {} is listed twice", name);
+        }
+
+        let ChannelLines {
+            remote_names,
+            hotkey,
+        } = lines.remove(&name).unwrap_or_default();
+
+        let remote_names =
+            remote_names.ok_or_else(|| anyhow!("channel.{}.remote_names not provided", name))?;
+        for remote_name in &remote_names {
+            check_peer_name(remote_name)
+                .with_context(|| format!("invalid channel.{}.remote_names", name))?;
+        }
+        if remote_names.iter().any(|r| r == local_name) {
+            bail!(
+                "channel.{}.remote_names contains local_name which is invalid",
+                name
+            );
+        }
+
+        if let Some(other) = channels
+            .iter()
+            .find(|c| hotkey.is_some() && c.hotkey == hotkey)
+        {
+            bail!("channels {} and {} use the same hotkey", other.name, name);
+        }
+
+        channels.push(Channel {
+            name,
+            remote_names,
+            hotkey,
+        });
+    }
+
+    if let Some(name) = lines.keys().next() {
+        bail!("channel.{} is configured but not listed in channels", name);
+    }
+
+    Ok(channels)
+}
+
+/// turns the enabled detectors and the filter lines into rules. The detectors use
+/// filter_action, the patterns their own action or filter_action if they have none.
+fn parse_filters(
+    detectors: Vec<Detector>,
+    action: FilterAction,
+    lines: HashMap<String, FilterLines>,
+) -> Result<Vec<FilterRule>> {
+    let mut rules: Vec<FilterRule> = Vec::new();
+
+    for detector in detectors {
+        if rules.iter().any(|r| r.name == detector.name()) {
+            bail!("filter {} is listed twice", detector.name());
+        }
+        rules.push(FilterRule {
+            name: detector.name().to_string(),
+            matcher: Matcher::Detector(detector),
+            action,
+        });
+    }
+
+    // sorted, so the rules are always checked in the same order
+    let mut lines: Vec<_> = lines.into_iter().collect();
+    lines.sort_by(|a, b| a.0.cmp(&b.0));
+
+    for (
+        name,
+        FilterLines {
+            pattern,
+            action: rule_action,
+        },
+    ) in lines
+    {
+        // the name shows up in the placeholder of redacted content
+        if !is_valid_name(&name) {
+            bail!(
+                "filter names may only contain letters, digits, - and _: {}",
+                name
+            );
+        }
+        if DETECTORS.iter().any(|d| d.name() == name) {
+            bail!("filter.{} has the name of a built-in detector", name);
+        }
+
+        let pattern = pattern.ok_or_else(|| anyhow!("filter.{}.pattern not provided", name))?;
+        let regex = regex::bytes::Regex::new(&pattern)
+            .with_context(|| format!("filter.{}.pattern is not a valid regex", name))?;
+
+        rules.push(FilterRule {
+            name,
+            matcher: Matcher::Pattern(regex),
+            action: rule_action.unwrap_or(action),
+        });
+    }
+
+    Ok(rules)
+}
+
+/// checks the hook lines. Every hook needs a command and the event it runs on
+fn parse_hooks(lines: HashMap<String, HookLines>) -> Result<Vec<Hook>> {
+    // sorted, so the hooks always run in the same order
+    let mut lines: Vec<_> = lines.into_iter().collect();
+    lines.sort_by(|a, b| a.0.cmp(&b.0));
+
+    let mut hooks = Vec::new();
+    for (
+        name,
+        HookLines {
+            command,
+            event,
+            timeout,
+            accept_on_failure,
+        },
+    ) in lines
+    {
+        if !is_valid_name(&name) {
+            bail!(
+                "hook names may only contain letters, digits, - and _: {}",
+                name
+            );
+        }
+
+        hooks.push(Hook {
+            command: command.ok_or_else(|| anyhow!("hook.{}.command not provided", name))?,
+            event: event.ok_or_else(|| anyhow!("hook.{}.on not provided", name))?,
+            timeout: Duration::from_secs(timeout.unwrap_or(DEFAULT_HOOK_TIMEOUT_SECS)),
+            accept_on_failure: accept_on_failure.unwrap_or(true),
+            name,
+        });
+    }
+
+    Ok(hooks)
+}
+
+/// records a new remote in config.ini. The name is added to remote_names and the peer to peers.
+/// All other lines are kept as they are.
+pub fn add_peer(peer: &Peer) -> Result<()> {
+    let content = std::fs::read_to_string(CONFIG_FILE_NAME)
+        .with_context(|| format!("Looking for config-file at: {}", CONFIG_FILE_NAME))?;
+
+    let mut entry = format!("{}@{}", peer.name, peer.address);
+    if let Some(fingerprint) = &peer.fingerprint {
+        entry = format!("{}#{}", entry, fingerprint);
+    }
+
+    let mut has_peers = false;
+    let mut lines: Vec<String> = content
+        .lines()
+        .map(|line| match line.split_once("=") {
+            Some((key, value)) if key.trim() == "remote_names" => {
+                format!("{}={},{}", key, value.trim_end(), peer.name)
+            }
+            Some((key, value)) if key.trim() == "peers" => {
+                has_peers = true;
+                format!("{}={},{}", key, value.trim_end(), entry)
+            }
+            _ => line.to_string(),
+        })
+        .collect();
+
+    if !has_peers {
+        lines.push(format!("peers={}", entry));
+    }
+
+    // a crash half way must not leave a truncated config behind
+    let temp_name = format!("{}.tmp", CONFIG_FILE_NAME);
+    std::fs::write(&temp_name, lines.join("\n") + "\n")
+        .with_context(|| format!("could not write {}", temp_name))?;
+    std::fs::rename(&temp_name, CONFIG_FILE_NAME)
+        .with_context(|| format!("could not replace {}", CONFIG_FILE_NAME))
+}
+
+/// parses a list of peers in the form name@host:port#fingerprint,name@host:port. The fingerprint
+/// is optional and may be written with colons between the bytes.
+fn parse_peers(value: &str) -> Result<Vec<Peer>> {
+    value
+        .split(",")
+        .map(|peer| {
+            let (name, rest) = peer
+                .trim()
+                .split_once("@")
+                .with_context(|| format!("peer has to look like name@host:port: {}", peer))?;
+
+            let (address, fingerprint) = match rest.split_once("#") {
+                Some((address, fingerprint)) => (address, Some(parse_fingerprint(fingerprint)?)),
+                None => (rest, None),
+            };
+
+            if !address.contains(":") {
+                bail!("peer has to look like name@host:port: {}", peer);
+            }
+            check_peer_name(name)?;
+
+            Ok(Peer {
+                name: name.to_string(),
+                address: address.to_string(),
+                fingerprint,
+            })
+        })
+        .collect()
+}
+
+/// a peer name ends up in config.ini and in the names of entry files, so characters which would
+/// break either are rejected. The parts of a file name are separated by -
+pub fn check_peer_name(name: &str) -> Result<()> {
+    if name.is_empty()
+        || name
+            .chars()
+            .any(|c| c.is_whitespace() || c.is_control() || ",@#=/\\-".contains(c))
+    {
+        bail!(
+            "peer names may not be empty or contain whitespace or any of , @ # = / \\ -: {}",
+            name
+        );
+    }
+
+    Ok(())
+}
+
+/// normalizes a SHA-256 fingerprint to lowercase hex without separators
+pub fn parse_fingerprint(value: &str) -> Result<String> {
+    let fingerprint: String = value
+        .chars()
+        .filter(|c| *c != ':')
+        .map(|c| c.to_ascii_lowercase())
+        .collect();
+
+    if fingerprint.len() != 64 || !fingerprint.chars().all(|c| c.is_ascii_hexdigit()) {
+        bail!("fingerprint has to be a SHA-256 in hex: {}", value);
+    }
+
+    Ok(fingerprint)
+}
+
+/// named pipes live in their own namespace, which all users of the machine share
+#[cfg(windows)]
+fn default_control_path(local_name: &str) -> String {
+    format!(r"\\.\pipe\clipboard-{}", local_name)
+}
+
+/// next to the config.ini, like the log
+#[cfg(not(windows))]
+fn default_control_path(_local_name: &str) -> String {
+    DEFAULT_CONTROL_PATH.to_string()
+}
+
+/// parses a duration given in seconds. Zero is rejected since it would make no sense for any of
+/// the intervals in the config.
+fn parse_secs(key: &str, value: &str) -> Result<u64> {
+    let secs = value
+        .parse::<u64>()
+        .with_context(|| format!("{} has to be a number of seconds: {}", key, value))?;
+
+    if secs == 0 {
+        bail!("{} has to be greater than 0", key);
+    }
+
+    Ok(secs)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn names_which_would_break_file_names_are_rejected() {
+        let parse = |local: &str, remotes: &str| {
+            Config::parse(&format!(
+                "local_name={}\nremote_names={}\ndir_name=.\n",
+                local, remotes
+            ))
+        };
+
+        assert!(parse("pc1", "pc2,pc3").is_ok());
+        assert!(parse("pc-1", "pc2").is_err());
+        assert!(parse("pc1", "pc2,pc-3").is_err());
+        assert!(parse("pc 1", "pc2").is_err());
+        assert!(parse("pc1", "pc2,").is_err());
+
+        let channel = Config::parse(
+            "local_name=a\nremote_names=b\ndir_name=.\nchannels=team\n\
+             channel.team.remote_names=c-d\n",
+        );
+        let Err(error) = channel else {
+            panic!("a channel remote with a - was taken");
+        };
+        assert!(format!("{:#}", error).contains("channel.team.remote_names"));
+        assert!(parse_peers("pc-2@127.0.0.1:4717").is_err());
+    }
 }
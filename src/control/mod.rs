@@ -0,0 +1,720 @@
+// The control socket: a local endpoint other tools drive the running instance with. It is a unix
+// domain socket at config.control_path (a named pipe on windows) speaking json lines. Every
+// request is one line and gets one line back:
+//
+// {"cmd":"push","text":"hello","channel":"team-a","sensitive":false}   publishes the text. "data"
+//                                                                      takes base64 instead
+// {"cmd":"pull","channel":"team-a"}                                    takes the next remote entry
+// {"cmd":"history","channel":"team-a","limit":10}                      the entries in dir_name
+// {"cmd":"peers"}                                                      the presence of the remotes
+// {"cmd":"status"}                                                     about this instance
+// {"cmd":"subscribe","events":["entry-received"]}                      streams the events
+//
+// channel, sensitive, limit and events are optional. Without channel it is the default one,
+// without events all of them are streamed.
+//
+// {"ok":true,"result":...}
+// {"ok":false,"error":"..."}
+//
+// After subscribe the connection carries nothing but the events of events.rs, one per line.
+//
+// send, recv and status are clients of it whenever an instance is running.
+
+#[cfg(unix)]
+mod unix;
+#[cfg(windows)]
+mod windows;
+
+#[cfg(unix)]
+use self::unix as platform;
+#[cfg(windows)]
+use self::windows as platform;
+
+use crate::config::{Config, PasteMode, TransportKind};
+use crate::events::{self, Event, EVENT_NAMES};
+use crate::file_handlers::ClipboardAction;
+use crate::logfile::log;
+use crate::metrics;
+use crate::pick;
+use crate::presence::{self, Presence};
+use crate::shutdown;
+use crate::text_format::{self, TextFormatMode};
+use crate::transport::Entry;
+use crate::utils::get_timestamp;
+use anyhow::{anyhow, bail, Context, Result};
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::HashMap;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::sync::mpsc::{self, Sender};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+// a failing accept is usually not going away at once
+const ACCEPT_RETRY_INTERVAL: Duration = Duration::from_secs(1);
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "cmd", rename_all = "kebab-case")]
+pub enum Request {
+    Push {
+        #[serde(skip_serializing_if = "Option::is_none")]
+        text: Option<String>,
+        /// base64, for content which isn't utf-8
+        #[serde(skip_serializing_if = "Option::is_none")]
+        data: Option<String>,
+        channel: Option<String>,
+        #[serde(default)]
+        sensitive: bool,
+    },
+    Pull {
+        channel: Option<String>,
+    },
+    History {
+        channel: Option<String>,
+        limit: Option<usize>,
+    },
+    Peers,
+    Status,
+    Subscribe {
+        /// the names of the events to stream. All of them if None
+        events: Option<Vec<String>>,
+    },
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Response {
+    ok: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    result: Option<Value>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+/// a remote entry taken with pull
+#[derive(Debug, Serialize, Deserialize)]
+pub struct PulledEntry {
+    pub origin: String,
+    pub timestamp: u64,
+    pub expires: Option<u64>,
+    /// what the content originally was
+    pub encoding: String,
+    pub line_ending: String,
+    /// the content, lossy if it isn't utf-8
+    pub text: String,
+    /// base64 of the content in the encoding config.text_format asks for, as recv writes it
+    pub data: String,
+}
+
+impl PulledEntry {
+    fn new(entry: Entry, mode: TextFormatMode) -> Self {
+        let data = text_format::encode(entry.content.clone(), entry.format, mode);
+        Self {
+            origin: entry.origin,
+            timestamp: entry.timestamp,
+            expires: entry.expires,
+            encoding: entry.format.encoding.name().to_string(),
+            line_ending: entry.format.line_ending.name().to_string(),
+            text: String::from_utf8_lossy(&entry.content).into_owned(),
+            data: BASE64.encode(data),
+        }
+    }
+
+    pub fn data(&self) -> Result<Vec<u8>> {
+        BASE64
+            .decode(&self.data)
+            .with_context(|| "the data of the entry is no base64")
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct HistoryEntry {
+    pub origin: String,
+    pub timestamp: u64,
+    pub expires: Option<u64>,
+    pub size: usize,
+    pub text: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct PeerInfo {
+    pub name: String,
+    /// online, stale or never-seen
+    pub state: String,
+    pub version: Option<String>,
+    pub last_seen: Option<u64>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Peers {
+    pub remotes: Vec<PeerInfo>,
+    /// instances sending heartbeats which are no remote of any channel
+    pub unknown: Vec<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Status {
+    pub local_name: String,
+    pub version: String,
+    pub pid: u32,
+    pub uptime_secs: u64,
+    pub transport: String,
+    pub paste_mode: String,
+    /// the named channels, the default one is always there
+    pub channels: Vec<String>,
+    pub metrics: metrics::Snapshot,
+}
+
+/// the file handlers of the channels, None is the default channel
+pub type ChannelSenders = HashMap<Option<String>, Sender<ClipboardAction>>;
+
+struct Server {
+    config: Config,
+    channels: ChannelSenders,
+    started: u64,
+}
+
+/// serves the control socket at config.control_path. Fails if another instance is serving it
+pub fn spawn_server(config: &Config, channels: ChannelSenders) -> Result<()> {
+    let mut listener = platform::Listener::bind(&config.control_path)?;
+    let server = Arc::new(Server {
+        config: config.clone(),
+        channels,
+        started: get_timestamp(),
+    });
+
+    thread::spawn(move || loop {
+        match listener.accept() {
+            Ok(stream) => {
+                let server = server.clone();
+                thread::spawn(move || server.serve(stream));
+            }
+            Err(e) => {
+                log(&format!("could not accept a control client: {:#}\n", e));
+                thread::sleep(ACCEPT_RETRY_INTERVAL);
+            }
+        }
+    });
+
+    Ok(())
+}
+
+/// removes the socket so no new client connects. Clients already connected get errors from now on
+pub fn close(config: &Config) -> Result<()> {
+    platform::remove(&config.control_path)
+}
+
+impl Server {
+    /// answers the requests of one client until it disconnects or subscribes
+    fn serve<S: Read + Write>(&self, stream: S) {
+        let mut reader = BufReader::new(stream);
+        let mut line = String::new();
+
+        loop {
+            line.clear();
+            match reader.read_line(&mut line) {
+                Ok(0) => return,
+                Ok(_) => {}
+                Err(e) => {
+                    log(&format!("could not read a control request: {}\n", e));
+                    return;
+                }
+            }
+            if line.trim().is_empty() {
+                continue;
+            }
+
+            let request = serde_json::from_str::<Request>(&line)
+                .with_context(|| "invalid request")
+                .and_then(check_events);
+            if let Ok(Request::Subscribe { events }) = request {
+                self.stream_events(reader.get_mut(), events);
+                return;
+            }
+
+            let response = match request.and_then(|request| self.handle(request)) {
+                Ok(result) => Response {
+                    ok: true,
+                    result: Some(result),
+                    error: None,
+                },
+                Err(e) => Response {
+                    ok: false,
+                    result: None,
+                    error: Some(format!("{:#}", e)),
+                },
+            };
+            if write_line(reader.get_mut(), &response).is_err() {
+                return;
+            }
+        }
+    }
+
+    /// writes the events to the client until it disconnects
+    fn stream_events<S: Write>(&self, stream: &mut S, names: Option<Vec<String>>) {
+        let subscription = events::subscribe();
+        let confirmation = Response {
+            ok: true,
+            result: Some(Value::String("subscribed".to_string())),
+            error: None,
+        };
+        if write_line(stream, &confirmation).is_err() {
+            return;
+        }
+
+        let wanted = |event: &Event| {
+            names
+                .as_ref()
+                .is_none_or(|names| names.iter().any(|name| name == event.name()))
+        };
+        for event in subscription.iter().filter(wanted) {
+            if write_line(stream, &event).is_err() {
+                return;
+            }
+        }
+    }
+
+    fn handle(&self, request: Request) -> Result<Value> {
+        if shutdown::is_stopping() {
+            bail!("the instance is shutting down");
+        }
+
+        let result = match request {
+            Request::Push {
+                text,
+                data,
+                channel,
+                sensitive,
+            } => {
+                let content = match (text, data) {
+                    (Some(text), None) => text.into_bytes(),
+                    (None, Some(data)) => BASE64
+                        .decode(data)
+                        .with_context(|| "data has to be base64")?,
+                    _ => bail!("push takes either text or data"),
+                };
+                if content.is_empty() {
+                    bail!("nothing to push, the content is empty");
+                }
+
+                let (reply, published) = mpsc::channel();
+                self.channel(channel.as_deref())?
+                    .send(ClipboardAction::Push {
+                        content,
+                        sensitive,
+                        reply,
+                    })
+                    .map_err(|_| anyhow!("the file handler stopped"))?;
+                let published = published
+                    .recv()
+                    .map_err(|_| anyhow!("the file handler stopped"))??;
+
+                serde_json::json!({ "published": published })
+            }
+            Request::Pull { channel } => {
+                let (reply, entry) = mpsc::channel();
+                self.channel(channel.as_deref())?
+                    .send(ClipboardAction::Pull { reply })
+                    .map_err(|_| anyhow!("the file handler stopped"))?;
+                let entry = entry
+                    .recv()
+                    .map_err(|_| anyhow!("the file handler stopped"))??;
+
+                serde_json::to_value(
+                    entry.map(|entry| PulledEntry::new(entry, self.config.text_format)),
+                )?
+            }
+            Request::History { channel, limit } => {
+                let entries: Vec<_> = pick::load_entries(&self.config, channel.as_deref(), limit)?
+                    .into_iter()
+                    .map(|entry| HistoryEntry {
+                        origin: entry.file.origin,
+                        timestamp: entry.file.timestamp,
+                        expires: entry.file.expires,
+                        size: entry.content.len(),
+                        text: String::from_utf8_lossy(&entry.content).into_owned(),
+                    })
+                    .collect();
+
+                serde_json::to_value(entries)?
+            }
+            Request::Peers => serde_json::to_value(self.peers()?)?,
+            Request::Status => serde_json::to_value(self.status())?,
+            Request::Subscribe { .. } => unreachable!("subscriptions are handled by serve"),
+        };
+
+        Ok(result)
+    }
+
+    fn channel(&self, channel: Option<&str>) -> Result<&Sender<ClipboardAction>> {
+        self.channels
+            .get(&channel.map(|c| c.to_string()))
+            .with_context(|| format!("{} is not listed in channels", channel.unwrap_or_default()))
+    }
+
+    fn peers(&self) -> Result<Peers> {
+        let report = presence::peer_status(&self.config)?;
+
+        let remotes = report
+            .remotes
+            .into_iter()
+            .map(|peer| {
+                let (state, heartbeat) = match peer.presence {
+                    Presence::Online(h) => ("online", Some(h)),
+                    Presence::Stale(h) => ("stale", Some(h)),
+                    Presence::NeverSeen => ("never-seen", None),
+                };
+                PeerInfo {
+                    name: peer.name,
+                    state: state.to_string(),
+                    version: heartbeat.as_ref().map(|h| h.version.clone()),
+                    last_seen: heartbeat.map(|h| h.last_seen),
+                }
+            })
+            .collect();
+
+        Ok(Peers {
+            remotes,
+            unknown: report.unknown.into_iter().map(|h| h.name).collect(),
+        })
+    }
+
+    fn status(&self) -> Status {
+        Status {
+            local_name: self.config.local_name.clone(),
+            version: env!("CARGO_PKG_VERSION").to_string(),
+            pid: std::process::id(),
+            uptime_secs: get_timestamp().saturating_sub(self.started),
+            transport: match self.config.transport {
+                TransportKind::Directory => "directory",
+                TransportKind::Tcp => "tcp",
+            }
+            .to_string(),
+            paste_mode: match self.config.paste_mode {
+                PasteMode::Latest => "latest",
+                PasteMode::Queue => "queue",
+            }
+            .to_string(),
+            channels: self
+                .config
+                .channels
+                .iter()
+                .map(|c| c.name.clone())
+                .collect(),
+            metrics: metrics::snapshot(),
+        }
+    }
+}
+
+/// rejects subscriptions to events which don't exist, those would stay silent forever
+fn check_events(request: Request) -> Result<Request> {
+    if let Request::Subscribe {
+        events: Some(names),
+    } = &request
+    {
+        if let Some(unknown) = names
+            .iter()
+            .find(|name| !EVENT_NAMES.contains(&name.as_str()))
+        {
+            bail!(
+                "unknown event {}, expected one of {}",
+                unknown,
+                EVENT_NAMES.join(", ")
+            );
+        }
+    }
+    Ok(request)
+}
+
+fn write_line<S: Write, T: Serialize>(stream: &mut S, value: &T) -> Result<()> {
+    let mut line = serde_json::to_vec(value)?;
+    line.push(b'\n');
+    stream.write_all(&line)?;
+    stream.flush()?;
+    Ok(())
+}
+
+/// a connection to the control socket of the running instance
+pub struct Client {
+    reader: BufReader<platform::Stream>,
+}
+
+/// connects to the running instance. None if there is none
+pub fn connect(config: &Config) -> Result<Option<Client>> {
+    if !config.control {
+        return Ok(None);
+    }
+
+    Ok(
+        platform::connect(&config.control_path)?.map(|stream| Client {
+            reader: BufReader::new(stream),
+        }),
+    )
+}
+
+impl Client {
+    /// sends the request and returns the result of the response
+    pub fn request(&mut self, request: &Request) -> Result<Value> {
+        write_line(self.reader.get_mut(), request)
+            .with_context(|| "could not send the request to the running instance")?;
+
+        let mut line = String::new();
+        if self.reader.read_line(&mut line)? == 0 {
+            bail!("the running instance closed the connection");
+        }
+        let response: Response = serde_json::from_str(&line)
+            .with_context(|| "invalid response from the running instance")?;
+
+        if !response.ok {
+            bail!("{}", response.error.unwrap_or_default());
+        }
+        Ok(response.result.unwrap_or_default())
+    }
+
+    /// publishes the content. Returns false if it wasn't published
+    pub fn push(&mut self, content: &[u8], channel: Option<&str>, sensitive: bool) -> Result<bool> {
+        let result = self.request(&Request::Push {
+            text: None,
+            data: Some(BASE64.encode(content)),
+            channel: channel.map(|c| c.to_string()),
+            sensitive,
+        })?;
+
+        result["published"]
+            .as_bool()
+            .with_context(|| "invalid response from the running instance")
+    }
+
+    pub fn pull(&mut self, channel: Option<&str>) -> Result<Option<PulledEntry>> {
+        let result = self.request(&Request::Pull {
+            channel: channel.map(|c| c.to_string()),
+        })?;
+        Ok(serde_json::from_value(result)?)
+    }
+
+    pub fn status(&mut self) -> Result<Status> {
+        let result = self.request(&Request::Status)?;
+        Ok(serde_json::from_value(result)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::text_format::{Encoding, LineEnding, TextFormat};
+    use crate::utils::test_dir;
+    use std::sync::mpsc::Receiver;
+
+    /// a running instance of a with only the default channel, pulled entries keep their format
+    fn config(name: &str) -> Config {
+        let dir = test_dir(&format!("control-{}", name));
+        Config::parse(&format!(
+            "local_name=a\nremote_names=b\ndir_name={dir}\ncontrol_path={dir}/control.sock\n\
+             text_format=original\n",
+            dir = dir
+        ))
+        .unwrap()
+    }
+
+    /// stands in for the file handler of a channel. Pushes are reported on the returned
+    /// receiver, pulls are answered with the given entries until they run out
+    fn handler(mut pulled: Vec<Entry>) -> (Sender<ClipboardAction>, Receiver<(Vec<u8>, bool)>) {
+        let (sender, actions) = mpsc::channel();
+        let (pushed, pushes) = mpsc::channel();
+
+        thread::spawn(move || {
+            for action in actions {
+                match action {
+                    ClipboardAction::Push {
+                        content,
+                        sensitive,
+                        reply,
+                    } => {
+                        let _ = reply.send(Ok(true));
+                        let _ = pushed.send((content, sensitive));
+                    }
+                    ClipboardAction::Pull { reply } => {
+                        let entry = (!pulled.is_empty()).then(|| pulled.remove(0));
+                        let _ = reply.send(Ok(entry));
+                    }
+                    _ => {}
+                }
+            }
+        });
+
+        (sender, pushes)
+    }
+
+    /// serves the control socket of config and connects to it
+    fn serve(config: &Config, pulled: Vec<Entry>) -> (Client, Receiver<(Vec<u8>, bool)>) {
+        let (sender, pushes) = handler(pulled);
+        spawn_server(config, HashMap::from([(None, sender)])).unwrap();
+        (connect(config).unwrap().unwrap(), pushes)
+    }
+
+    fn error(result: Result<Value>) -> String {
+        match result {
+            Ok(value) => panic!("the request succeeded with {}", value),
+            Err(e) => e.to_string(),
+        }
+    }
+
+    #[test]
+    fn there_is_nothing_to_connect_to_without_an_instance() {
+        let config = config("none");
+        assert!(connect(&config).unwrap().is_none());
+
+        let config = Config {
+            control: false,
+            ..config
+        };
+        assert!(connect(&config).unwrap().is_none());
+    }
+
+    #[test]
+    fn pushed_content_reaches_the_file_handler() {
+        let (mut client, pushes) = serve(&config("push"), Vec::new());
+
+        assert!(client.push(b"\xff binary", None, true).unwrap());
+        assert_eq!(pushes.recv().unwrap(), (b"\xff binary".to_vec(), true));
+
+        let result = client.request(&Request::Push {
+            text: Some("text".to_string()),
+            data: None,
+            channel: None,
+            sensitive: false,
+        });
+        assert_eq!(result.unwrap()["published"], true);
+        assert_eq!(pushes.recv().unwrap(), (b"text".to_vec(), false));
+    }
+
+    #[test]
+    fn invalid_pushes_are_refused() {
+        let (mut client, pushes) = serve(&config("invalid-push"), Vec::new());
+        let push = |text: Option<&str>, data: Option<&str>, channel: Option<&str>| Request::Push {
+            text: text.map(str::to_string),
+            data: data.map(str::to_string),
+            channel: channel.map(str::to_string),
+            sensitive: false,
+        };
+
+        assert!(error(client.request(&push(None, None, None))).contains("either text or data"));
+        assert!(error(client.request(&push(Some("a"), Some("YQ=="), None)))
+            .contains("either text or data"));
+        assert!(error(client.request(&push(None, Some("not base64!"), None))).contains("base64"));
+        assert!(error(client.request(&push(Some(""), None, None))).contains("empty"));
+        assert!(error(client.request(&push(Some("a"), None, Some("team"))))
+            .contains("not listed in channels"));
+
+        // the connection is still usable and nothing was published
+        client.status().unwrap();
+        assert!(pushes.try_recv().is_err());
+    }
+
+    #[test]
+    fn pulled_entries_come_in_the_configured_text_format() {
+        let entry = Entry {
+            origin: "b".to_string(),
+            timestamp: 100,
+            expires: None,
+            content: b"one\ntwo".to_vec(),
+            format: TextFormat {
+                encoding: Encoding::Utf16Be,
+                line_ending: LineEnding::Lf,
+            },
+        };
+        let (mut client, _) = serve(&config("pull"), vec![entry]);
+
+        let pulled = client.pull(None).unwrap().unwrap();
+        assert_eq!(pulled.origin, "b");
+        assert_eq!(pulled.timestamp, 100);
+        assert_eq!(pulled.text, "one\ntwo");
+        assert_eq!(pulled.encoding, Encoding::Utf16Be.name());
+        assert_eq!(pulled.line_ending, "lf");
+        assert_eq!(pulled.data().unwrap(), b"\xfe\xff\0o\0n\0e\0\n\0t\0w\0o");
+
+        // taken entries are gone
+        assert!(client.pull(None).unwrap().is_none());
+    }
+
+    #[test]
+    fn status_describes_the_instance() {
+        let (mut client, _) = serve(&config("status"), Vec::new());
+
+        let status = client.status().unwrap();
+        assert_eq!(status.local_name, "a");
+        assert_eq!(status.pid, std::process::id());
+        assert_eq!(status.transport, "directory");
+        assert_eq!(status.paste_mode, "latest");
+    }
+
+    #[test]
+    fn history_of_an_empty_directory_is_empty() {
+        let (mut client, _) = serve(&config("history"), Vec::new());
+
+        let history = client
+            .request(&Request::History {
+                channel: None,
+                limit: Some(5),
+            })
+            .unwrap();
+        assert_eq!(history, serde_json::json!([]));
+    }
+
+    #[test]
+    fn invalid_lines_get_an_error_and_the_connection_stays() {
+        let (mut client, _) = serve(&config("invalid-line"), Vec::new());
+
+        write!(client.reader.get_mut(), "not json\n\n").unwrap();
+        let mut line = String::new();
+        client.reader.read_line(&mut line).unwrap();
+        let response: Response = serde_json::from_str(&line).unwrap();
+        assert!(!response.ok);
+        assert!(response.error.unwrap().contains("invalid request"));
+
+        client.status().unwrap();
+    }
+
+    #[test]
+    fn subscribers_get_the_events_they_asked_for() {
+        let (mut client, _) = serve(&config("subscribe"), Vec::new());
+
+        let unknown = client.request(&Request::Subscribe {
+            events: Some(vec!["entry-deleted".to_string()]),
+        });
+        assert!(error(unknown).contains("unknown event entry-deleted"));
+
+        let subscribed = client
+            .request(&Request::Subscribe {
+                events: Some(vec!["peer-offline".to_string()]),
+            })
+            .unwrap();
+        assert_eq!(subscribed, "subscribed");
+
+        events::publish(Event::PeerOnline {
+            name: "control-test".to_string(),
+            version: None,
+        });
+        events::publish(Event::PeerOffline {
+            name: "control-test".to_string(),
+        });
+
+        // other tests publish events as well
+        let mut line = String::new();
+        loop {
+            line.clear();
+            client.reader.read_line(&mut line).unwrap();
+            let event: Event = serde_json::from_str(&line).unwrap();
+            assert_eq!(event.name(), "peer-offline");
+            if event
+                == (Event::PeerOffline {
+                    name: "control-test".to_string(),
+                })
+            {
+                break;
+            }
+        }
+    }
+}
@@ -0,0 +1,147 @@
+use anyhow::{bail, Context, Result};
+use std::fs::DirBuilder;
+use std::io::ErrorKind;
+use std::os::unix::fs::{DirBuilderExt, PermissionsExt};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::Path;
+
+pub type Stream = UnixStream;
+
+pub struct Listener {
+    listener: UnixListener,
+}
+
+impl Listener {
+    pub fn bind(path: &str) -> Result<Self> {
+        if Path::new(path).exists() {
+            if UnixStream::connect(path).is_ok() {
+                bail!("another instance is already listening on {}", path);
+            }
+
+            // left behind by an instance which didn't shut down cleanly
+            std::fs::remove_file(path)
+                .with_context(|| format!("could not remove the stale socket {}", path))?;
+        }
+
+        // whoever can connect drives the clipboard, so that is this user only. The socket is
+        // bound in a directory nobody else can enter and only moved into place once its
+        // permissions are restricted
+        let staging = format!("{}.{}.tmp", path, std::process::id());
+        DirBuilder::new()
+            .mode(0o700)
+            .create(&staging)
+            .with_context(|| format!("could not create {}", staging))?;
+
+        let result = bind_restricted(&format!("{}/socket", staging), path);
+        let _ = std::fs::remove_dir(&staging);
+
+        Ok(Self { listener: result? })
+    }
+
+    pub fn accept(&mut self) -> Result<Stream> {
+        Ok(self.listener.accept()?.0)
+    }
+}
+
+/// binds the socket at staged, restricts it to this user and moves it to path
+fn bind_restricted(staged: &str, path: &str) -> Result<UnixListener> {
+    let listener =
+        UnixListener::bind(staged).with_context(|| format!("could not listen on {}", path))?;
+
+    std::fs::set_permissions(staged, std::fs::Permissions::from_mode(0o600))
+        .and_then(|()| std::fs::rename(staged, path))
+        .inspect_err(|_| {
+            let _ = std::fs::remove_file(staged);
+        })
+        .with_context(|| {
+            format!(
+                "could not move the socket with restricted permissions to {}",
+                path
+            )
+        })?;
+
+    Ok(listener)
+}
+
+/// removes the socket file
+pub fn remove(path: &str) -> Result<()> {
+    match std::fs::remove_file(path) {
+        Ok(()) => Ok(()),
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(()),
+        Err(e) => Err(e).with_context(|| format!("could not remove {}", path)),
+    }
+}
+
+/// None if no instance is listening
+pub fn connect(path: &str) -> Result<Option<Stream>> {
+    match UnixStream::connect(path) {
+        Ok(stream) => Ok(Some(stream)),
+        // a socket file without an instance behind it refuses the connection
+        Err(e) if matches!(e.kind(), ErrorKind::NotFound | ErrorKind::ConnectionRefused) => {
+            Ok(None)
+        }
+        Err(e) => Err(e).with_context(|| format!("could not connect to {}", path)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::test_dir;
+
+    fn mode(path: &str) -> u32 {
+        std::fs::metadata(path).unwrap().permissions().mode() & 0o777
+    }
+
+    #[test]
+    fn only_this_user_can_connect() {
+        let path = format!("{}/control.sock", test_dir("control-mode"));
+        let _listener = Listener::bind(&path).unwrap();
+
+        assert_eq!(mode(&path), 0o600);
+        // the staging directory is gone
+        let staging = format!("{}.{}.tmp", path, std::process::id());
+        assert!(!Path::new(&staging).exists());
+        assert!(connect(&path).unwrap().is_some());
+    }
+
+    #[test]
+    fn a_running_instance_keeps_its_socket() {
+        let path = format!("{}/control.sock", test_dir("control-running"));
+        let _listener = Listener::bind(&path).unwrap();
+
+        let error = Listener::bind(&path).err().unwrap().to_string();
+        assert!(error.contains("already listening"), "{}", error);
+        assert!(connect(&path).unwrap().is_some());
+    }
+
+    #[test]
+    fn a_stale_socket_is_replaced() {
+        let path = format!("{}/control.sock", test_dir("control-stale"));
+        drop(UnixListener::bind(&path).unwrap());
+        assert!(connect(&path).unwrap().is_none());
+
+        let mut listener = Listener::bind(&path).unwrap();
+        let client = thread_connect(&path);
+        listener.accept().unwrap();
+        client.join().unwrap();
+        assert_eq!(mode(&path), 0o600);
+    }
+
+    #[test]
+    fn removing_a_missing_socket_is_fine() {
+        let path = format!("{}/control.sock", test_dir("control-remove"));
+        let _listener = Listener::bind(&path).unwrap();
+
+        remove(&path).unwrap();
+        assert!(connect(&path).unwrap().is_none());
+        remove(&path).unwrap();
+    }
+
+    fn thread_connect(path: &str) -> std::thread::JoinHandle<()> {
+        let path = path.to_string();
+        std::thread::spawn(move || {
+            connect(&path).unwrap().unwrap();
+        })
+    }
+}
@@ -0,0 +1,110 @@
+use anyhow::{Context, Result};
+use std::fs::File;
+use std::io::ErrorKind;
+use std::os::windows::io::{AsRawHandle, FromRawHandle};
+use std::thread;
+use std::time::Duration;
+use windows::core::HSTRING;
+use windows::Win32::Foundation::{ERROR_PIPE_BUSY, ERROR_PIPE_CONNECTED, HANDLE};
+use windows::Win32::Storage::FileSystem::{FILE_FLAG_FIRST_PIPE_INSTANCE, PIPE_ACCESS_DUPLEX};
+use windows::Win32::System::Pipes::{
+    ConnectNamedPipe, CreateNamedPipeW, PIPE_READMODE_BYTE, PIPE_REJECT_REMOTE_CLIENTS,
+    PIPE_TYPE_BYTE, PIPE_UNLIMITED_INSTANCES, PIPE_WAIT,
+};
+
+const BUFFER_SIZE: u32 = 64 * 1024;
+// a new instance of the pipe is created right after a client connected to the previous one
+const CONNECT_ATTEMPTS: u32 = 20;
+const CONNECT_RETRY_INTERVAL: Duration = Duration::from_millis(50);
+
+/// the server end of a pipe instance, or the client end. Both close the handle once dropped
+pub type Stream = File;
+
+pub struct Listener {
+    path: HSTRING,
+    /// the instance waiting for the next client
+    pending: Option<File>,
+}
+
+impl Listener {
+    pub fn bind(path: &str) -> Result<Self> {
+        let first = create_instance(&HSTRING::from(path), true).with_context(|| {
+            format!("could not create {}, is another instance listening?", path)
+        })?;
+
+        Ok(Self {
+            path: HSTRING::from(path),
+            pending: Some(first),
+        })
+    }
+
+    pub fn accept(&mut self) -> Result<Stream> {
+        let pipe = match self.pending.take() {
+            Some(pipe) => pipe,
+            None => create_instance(&self.path, false)?,
+        };
+
+        match unsafe { ConnectNamedPipe(HANDLE(pipe.as_raw_handle()), None) } {
+            Ok(()) => Ok(pipe),
+            // the client was faster than ConnectNamedPipe
+            Err(e) if e.code() == ERROR_PIPE_CONNECTED.to_hresult() => Ok(pipe),
+            Err(e) => Err(e).with_context(|| "could not wait for a client"),
+        }
+    }
+}
+
+/// one instance of the pipe, every client gets its own. The first one makes sure no other
+/// process owns the name already
+fn create_instance(path: &HSTRING, first: bool) -> Result<File> {
+    let mut open_mode = PIPE_ACCESS_DUPLEX;
+    if first {
+        open_mode |= FILE_FLAG_FIRST_PIPE_INSTANCE;
+    }
+
+    let handle = unsafe {
+        CreateNamedPipeW(
+            path,
+            open_mode,
+            PIPE_TYPE_BYTE | PIPE_READMODE_BYTE | PIPE_WAIT | PIPE_REJECT_REMOTE_CLIENTS,
+            PIPE_UNLIMITED_INSTANCES,
+            BUFFER_SIZE,
+            BUFFER_SIZE,
+            0,
+            None,
+        )
+    };
+    if handle.is_invalid() {
+        return Err(windows::core::Error::from_win32())
+            .with_context(|| format!("could not create pipe {}", path));
+    }
+
+    Ok(unsafe { File::from_raw_handle(handle.0) })
+}
+
+/// a named pipe is gone once its last handle is closed, that is when the process exits
+pub fn remove(_path: &str) -> Result<()> {
+    Ok(())
+}
+
+/// None if no instance is listening
+pub fn connect(path: &str) -> Result<Option<Stream>> {
+    let mut attempt = 1;
+    loop {
+        match std::fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(path)
+        {
+            Ok(pipe) => return Ok(Some(pipe)),
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+            Err(e)
+                if e.raw_os_error() == Some(ERROR_PIPE_BUSY.0 as i32)
+                    && attempt < CONNECT_ATTEMPTS =>
+            {
+                attempt += 1;
+                thread::sleep(CONNECT_RETRY_INTERVAL);
+            }
+            Err(e) => return Err(e).with_context(|| format!("could not connect to {}", path)),
+        }
+    }
+}
@@ -0,0 +1,462 @@
+// Discovery of other instances on the LAN.
+//
+// Every instance with discovery=on periodically sends a single line announcement to a multicast
+// group (or a broadcast address):
+//
+// clipboard-announce 1 <group> <local_name> <tcp port> <fingerprint or ->
+//
+// Nothing is synchronized with a discovered instance until it was approved, which records it in
+// the config.ini of this machine.
+
+use crate::config::{self, Config, Peer};
+use crate::logfile::log;
+use crate::transport::own_fingerprint;
+use anyhow::{bail, Context, Result};
+use socket2::{Domain, Protocol, Socket, Type};
+use std::collections::HashSet;
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4, UdpSocket};
+use std::thread;
+use std::time::{Duration, Instant};
+
+const ANNOUNCE_PREFIX: &str = "clipboard-announce";
+const ANNOUNCE_VERSION: &str = "1";
+const ANNOUNCE_INTERVAL: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Announcement {
+    pub group: String,
+    pub name: String,
+    pub port: u16,
+    pub fingerprint: Option<String>,
+}
+
+impl Announcement {
+    fn encode(&self) -> String {
+        format!(
+            "{} {} {} {} {} {}",
+            ANNOUNCE_PREFIX,
+            ANNOUNCE_VERSION,
+            self.group,
+            self.name,
+            self.port,
+            self.fingerprint.as_deref().unwrap_or("-")
+        )
+    }
+
+    fn decode(message: &str) -> Option<Self> {
+        let parts: Vec<_> = message.split_whitespace().collect();
+
+        if parts.len() != 6 || parts[0] != ANNOUNCE_PREFIX || parts[1] != ANNOUNCE_VERSION {
+            return None;
+        }
+
+        // anyone on the LAN can send this, it is held to the rules of config.ini
+        config::check_peer_name(parts[3]).ok()?;
+
+        Some(Self {
+            group: parts[2].to_string(),
+            name: parts[3].to_string(),
+            port: parts[4].parse().ok()?,
+            fingerprint: match parts[5] {
+                "-" => None,
+                fingerprint => Some(config::parse_fingerprint(fingerprint).ok()?),
+            },
+        })
+    }
+}
+
+/// an instance found on the LAN
+#[derive(Debug, Clone)]
+pub struct Discovered {
+    pub announcement: Announcement,
+    /// where the tcp transport of the instance can be reached
+    pub address: SocketAddr,
+}
+
+impl Discovered {
+    pub fn to_peer(&self) -> Peer {
+        Peer {
+            name: self.announcement.name.clone(),
+            address: self.address.to_string(),
+            fingerprint: self.announcement.fingerprint.clone(),
+        }
+    }
+}
+
+pub struct Discovery {
+    socket: UdpSocket,
+    target: SocketAddrV4,
+    own: Announcement,
+}
+
+impl Discovery {
+    /// joins config.discovery_address. Several instances on one machine can do this at once.
+    pub fn bind(config: &Config) -> Result<Self> {
+        let target: SocketAddrV4 = config.discovery_address.parse().with_context(|| {
+            format!(
+                "discovery_address has to be ipv4:port: {}",
+                config.discovery_address
+            )
+        })?;
+
+        let socket = Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP))?;
+        socket.set_reuse_address(true)?;
+        socket
+            .bind(&SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, target.port()).into())
+            .with_context(|| format!("could not bind discovery port {}", target.port()))?;
+
+        let socket: UdpSocket = socket.into();
+        if target.ip().is_multicast() {
+            socket.join_multicast_v4(target.ip(), &Ipv4Addr::UNSPECIFIED)?;
+            // instances on the same machine have to hear each other as well
+            socket.set_multicast_loop_v4(true)?;
+        } else {
+            socket.set_broadcast(true)?;
+        }
+
+        // a missing certificate only means the others can't pin it. Discovery still works
+        let fingerprint = if config.tls {
+            Some(own_fingerprint(config)?)
+        } else {
+            own_fingerprint(config).ok()
+        };
+
+        let own = Announcement {
+            group: config.group.clone(),
+            name: config.local_name.clone(),
+            port: listen_port(&config.listen)?,
+            fingerprint,
+        };
+
+        Ok(Self {
+            socket,
+            target,
+            own,
+        })
+    }
+
+    pub fn announce(&self) -> Result<()> {
+        self.socket
+            .send_to(self.own.encode().as_bytes(), self.target)
+            .with_context(|| format!("could not announce to {}", self.target))?;
+        Ok(())
+    }
+
+    /// waits up to timeout for an announcement of another instance in the same group
+    pub fn receive(&self, timeout: Duration) -> Result<Option<Discovered>> {
+        let deadline = Instant::now() + timeout;
+        let mut buf = [0u8; 512];
+
+        loop {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining.is_zero() {
+                return Ok(None);
+            }
+            self.socket.set_read_timeout(Some(remaining))?;
+
+            let (len, source) = match self.socket.recv_from(&mut buf) {
+                Ok(received) => received,
+                Err(e)
+                    if e.kind() == std::io::ErrorKind::WouldBlock
+                        || e.kind() == std::io::ErrorKind::TimedOut =>
+                {
+                    return Ok(None)
+                }
+                Err(e) => return Err(e.into()),
+            };
+
+            let Some(announcement) = std::str::from_utf8(&buf[..len])
+                .ok()
+                .and_then(Announcement::decode)
+            else {
+                continue;
+            };
+
+            if announcement.group != self.own.group || announcement.name == self.own.name {
+                continue;
+            }
+
+            let address = SocketAddr::new(source.ip(), announcement.port);
+            return Ok(Some(Discovered {
+                announcement,
+                address,
+            }));
+        }
+    }
+}
+
+/// listens for duration and returns every instance that announced itself, one entry per name
+pub fn discover(config: &Config, duration: Duration) -> Result<Vec<Discovered>> {
+    let discovery = Discovery::bind(config)?;
+    discovery.announce()?;
+
+    let deadline = Instant::now() + duration;
+    let mut found: Vec<Discovered> = Vec::new();
+
+    while let Some(discovered) =
+        discovery.receive(deadline.saturating_duration_since(Instant::now()))?
+    {
+        if !found
+            .iter()
+            .any(|f| f.announcement.name == discovered.announcement.name)
+        {
+            // answer so that instances which just started find us as well
+            discovery.announce()?;
+            found.push(discovered);
+        }
+    }
+
+    Ok(found)
+}
+
+/// waits for the announcement of name and records it as remote in config.ini
+pub fn approve(config: &Config, name: &str, duration: Duration) -> Result<Peer> {
+    config::check_peer_name(name)?;
+    if config.remote_names.iter().any(|r| r == name) {
+        bail!("{} is already in remote_names", name);
+    }
+
+    let Some(discovered) = discover(config, duration)?
+        .into_iter()
+        .find(|d| d.announcement.name == name)
+    else {
+        bail!(
+            "{} did not announce itself in group {} within {}s",
+            name,
+            config.group,
+            duration.as_secs()
+        );
+    };
+
+    let peer = discovered.to_peer();
+    if config.tls && peer.fingerprint.is_none() {
+        bail!(
+            "tls is on but {} did not announce a certificate fingerprint",
+            name
+        );
+    }
+
+    config::add_peer(&peer)?;
+    Ok(peer)
+}
+
+/// keeps announcing this instance and hints at instances which are not configured yet
+pub fn spawn_announcer(config: Config) -> Result<()> {
+    let discovery = Discovery::bind(&config)?;
+
+    thread::spawn(move || {
+        let mut hinted = HashSet::new();
+        let mut next_announcement = Instant::now();
+
+        loop {
+            if Instant::now() >= next_announcement {
+                if let Err(e) = discovery.announce() {
+                    log(&format!("{}\n", e));
+                }
+                next_announcement = Instant::now() + ANNOUNCE_INTERVAL;
+            }
+
+            match discovery.receive(next_announcement.saturating_duration_since(Instant::now())) {
+                Ok(Some(discovered)) => {
+                    let name = &discovered.announcement.name;
+                    if !config.knows(name) && hinted.insert(name.clone()) {
+                        log(&format!(
+                            "discovered {} at {}. Run `clipboard approve {}` to synchronize with it\n",
+                            name, discovered.address, name
+                        ));
+                    }
+                }
+                Ok(None) => {}
+                Err(e) => {
+                    log(&format!("discovery failed: {}\n", e));
+                    thread::sleep(ANNOUNCE_INTERVAL);
+                }
+            }
+        }
+    });
+
+    Ok(())
+}
+
+fn listen_port(listen: &str) -> Result<u16> {
+    listen
+        .rsplit_once(":")
+        .and_then(|(_, port)| port.parse().ok())
+        .with_context(|| format!("listen has to end with a port: {}", listen))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transport::generate_certificate;
+    use crate::utils::test_dir;
+
+    const FINGERPRINT: &str = "00112233445566778899aabbccddeeff00112233445566778899aabbccddeeff";
+
+    fn announcement(name: &str, fingerprint: Option<&str>) -> Announcement {
+        Announcement {
+            group: "default".to_string(),
+            name: name.to_string(),
+            port: 4717,
+            fingerprint: fingerprint.map(str::to_string),
+        }
+    }
+
+    fn free_udp_port() -> u16 {
+        UdpSocket::bind("0.0.0.0:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port()
+    }
+
+    /// an instance announcing on loopback multicast. tls is off unless extra turns it on
+    fn config(name: &str, group: &str, port: u16, extra: &str) -> Config {
+        let dir = test_dir(&format!("discovery-{}-{}-{}", name, group, port));
+        Config::parse(&format!(
+            "local_name={}\nremote_names=known\ndir_name={}\nlisten=127.0.0.1:4717\n\
+             discovery_address=239.255.47.17:{}\ngroup={}\ntls=off\n\
+             tls_cert={}/cert.pem\ntls_key={}/key.pem\n{}",
+            name, dir, port, group, dir, dir, extra
+        ))
+        .unwrap()
+    }
+
+    fn names(found: &[Discovered]) -> Vec<&str> {
+        let mut names: Vec<_> = found.iter().map(|d| d.announcement.name.as_str()).collect();
+        names.sort();
+        names
+    }
+
+    #[test]
+    fn announcements_survive_encoding() {
+        for sent in [
+            announcement("a", Some(FINGERPRINT)),
+            announcement("a", None),
+        ] {
+            assert_eq!(Announcement::decode(&sent.encode()), Some(sent));
+        }
+    }
+
+    #[test]
+    fn fingerprints_are_normalized() {
+        let upper = FINGERPRINT.to_uppercase();
+        let message = format!("clipboard-announce 1 default a 4717 {}", upper);
+        assert_eq!(
+            Announcement::decode(&message),
+            Some(announcement("a", Some(FINGERPRINT)))
+        );
+    }
+
+    #[test]
+    fn malformed_announcements_are_ignored() {
+        for message in [
+            "",
+            "clipboard-announce 1 default a 4717",
+            "clipboard-announce 1 default a 4717 - extra",
+            "clipboard-announce 2 default a 4717 -",
+            "other-announce 1 default a 4717 -",
+            "clipboard-announce 1 default a port -",
+            "clipboard-announce 1 default a 70000 -",
+            "clipboard-announce 1 default a 4717 notahash",
+            "clipboard-announce 1 default a,b 4717 -",
+            "clipboard-announce 1 default a=b 4717 -",
+            "clipboard-announce 1 default ../a 4717 -",
+            "clipboard-announce 1 default a#b 4717 -",
+        ] {
+            assert_eq!(Announcement::decode(message), None, "{}", message);
+        }
+    }
+
+    #[test]
+    fn the_port_is_taken_from_listen() {
+        assert_eq!(listen_port("0.0.0.0:4717").unwrap(), 4717);
+        assert_eq!(listen_port("[::1]:80").unwrap(), 80);
+        assert!(listen_port("localhost").is_err());
+    }
+
+    #[test]
+    fn instances_in_the_same_group_find_each_other() {
+        let port = free_udp_port();
+        let duration = Duration::from_secs(2);
+
+        let found = thread::scope(|scope| {
+            let finders: Vec<_> = [("a", "default"), ("b", "default"), ("c", "other")]
+                .into_iter()
+                .map(|(name, group)| {
+                    let config = config(name, group, port, "");
+                    scope.spawn(move || discover(&config, duration).unwrap())
+                })
+                .collect();
+            finders
+                .into_iter()
+                .map(|finder| finder.join().unwrap())
+                .collect::<Vec<_>>()
+        });
+
+        assert_eq!(names(&found[0]), ["b"]);
+        assert_eq!(names(&found[1]), ["a"]);
+        assert_eq!(names(&found[2]), Vec::<&str>::new());
+
+        let peer = found[0][0].to_peer();
+        assert_eq!(peer.name, "b");
+        assert!(peer.address.ends_with(":4717"));
+        assert_eq!(peer.fingerprint, None);
+    }
+
+    #[test]
+    fn the_fingerprint_of_the_certificate_is_announced() {
+        let port = free_udp_port();
+        let duration = Duration::from_secs(2);
+        let a = config("a", "default", port, "");
+        let b = config("b", "default", port, "");
+        let fingerprint = generate_certificate(&b, false).unwrap();
+
+        let found = thread::scope(|scope| {
+            let finder = scope.spawn(|| discover(&a, duration).unwrap());
+            discover(&b, duration).unwrap();
+            finder.join().unwrap()
+        });
+
+        assert_eq!(names(&found), ["b"]);
+        assert_eq!(found[0].announcement.fingerprint, Some(fingerprint));
+    }
+
+    #[test]
+    fn approve_refuses_known_and_invalid_names() {
+        let a = config("a", "default", free_udp_port(), "");
+        let duration = Duration::from_millis(100);
+
+        assert!(approve(&a, "known", duration).is_err());
+        assert!(approve(&a, "a,b", duration).is_err());
+        // nobody announces
+        assert!(approve(&a, "b", duration).is_err());
+    }
+
+    #[test]
+    fn approve_with_tls_needs_a_fingerprint() {
+        let port = free_udp_port();
+        let a = config("a", "default", port, "");
+        let a = Config { tls: true, ..a };
+        generate_certificate(&a, false).unwrap();
+        // b has no certificate, it announces without a fingerprint
+        let b = config("b", "default", port, "");
+
+        let approved = thread::scope(|scope| {
+            let announcer = scope.spawn(|| discover(&b, Duration::from_secs(2)).unwrap());
+            let approved = approve(&a, "b", Duration::from_secs(2));
+            announcer.join().unwrap();
+            approved
+        });
+
+        let Err(error) = approved else {
+            panic!("b was approved without a fingerprint");
+        };
+        let error = error.to_string();
+        assert!(
+            error.contains("did not announce a certificate fingerprint"),
+            "{}",
+            error
+        );
+    }
+}
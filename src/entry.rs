@@ -0,0 +1,387 @@
+// Naming grammar of the files this tool writes into config.dir_name.
+//
+// clipboard-<origin>-<timestamp>.tmp       a published clipboard entry
+// clipboard-<origin>-<timestamp>.tmp.part  an entry that is still being written
+//
+// Entries of a named channel carry it in the prefix: clipboard.<channel>-<origin>-<timestamp>.tmp
+// Channel names may contain dashes, origins may not. So the name is split from the right.
+//
+// A sensitive entry carries the time it expires at: clipboard2-<origin>-<timestamp>_<expires>.tmp
+// Versions before sensitive entries parse every clipboard-<origin>-<stamp>.tmp as a plain number
+// and give up on anything else, so entries with more in their stamp get the prefix clipboard2.
+// Those versions skip it as a file that isn't theirs.
+//
+// Text which isn't utf-8 with unix line endings carries its original format at the end of the
+// stamp, e.g. clipboard2-<origin>-<timestamp>+crlf.tmp, see text_format.rs
+//
+// register-<name>-<origin>-<timestamp>.tmp  a version of a named register, see registers.rs
+//
+// The pinned snippets live in the subdirectory snippets, which the garbage collector never
+// enters:
+//
+// snippet-<id>-<origin>-<revision>.tmp     a revision of a snippet, see snippets.rs
+//
+// Everything that does not match this grammar is not ours and must never be touched. The only
+// other files this tool writes are the heartbeats, see presence.rs.
+
+use crate::text_format::TextFormat;
+
+const ENTRY_PREFIX: &str = "clipboard";
+// entries whose stamp is more than the timestamp, see above
+const EXTENDED_ENTRY_PREFIX: &str = "clipboard2";
+const CHANNEL_SEPARATOR: &str = ".";
+const REGISTER_PREFIX: &str = "register-";
+const SNIPPET_PREFIX: &str = "snippet-";
+const EXPIRY_SEPARATOR: &str = "_";
+const ENTRY_EXTENSION: &str = ".tmp";
+const STAGING_EXTENSION: &str = ".part";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EntryKind {
+    /// a complete entry which can be loaded by the remotes
+    Published,
+    /// a partially written entry. It is renamed to a published one once it is complete
+    Staging,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EntryFile {
+    /// None for the default channel
+    pub channel: Option<String>,
+    pub origin: String,
+    pub timestamp: u64,
+    /// when a sensitive entry has to be gone. None for all others
+    pub expires: Option<u64>,
+    /// what the content originally was
+    pub format: TextFormat,
+    pub kind: EntryKind,
+}
+
+impl EntryFile {
+    pub fn new(channel: Option<&str>, origin: &str, timestamp: u64, kind: EntryKind) -> Self {
+        Self {
+            channel: channel.map(|c| c.to_string()),
+            origin: origin.to_string(),
+            timestamp,
+            expires: None,
+            format: TextFormat::default(),
+            kind,
+        }
+    }
+
+    pub fn with_expiry(self, expires: Option<u64>) -> Self {
+        Self { expires, ..self }
+    }
+
+    pub fn with_format(self, format: TextFormat) -> Self {
+        Self { format, ..self }
+    }
+
+    /// true if this is a sensitive entry whose time is up
+    pub fn is_expired(&self, now: u64) -> bool {
+        is_expired(self.expires, now)
+    }
+
+    /// parses a filename. Returns None if the file does not belong to this tool.
+    pub fn parse(file_name: &str) -> Option<Self> {
+        let (prefix, origin, stamp, kind) = split_file_name(file_name)?;
+
+        let (stamp, format) = TextFormat::split_suffix(stamp)?;
+        let (timestamp, expires) = match stamp.split_once(EXPIRY_SEPARATOR) {
+            Some((timestamp, expires)) => (parse_number(timestamp)?, Some(parse_number(expires)?)),
+            None => (parse_number(stamp)?, None),
+        };
+
+        let (rest, extended) = match prefix.strip_prefix(EXTENDED_ENTRY_PREFIX) {
+            Some(rest) => (rest, true),
+            None => (prefix.strip_prefix(ENTRY_PREFIX)?, false),
+        };
+        // an extended stamp without the prefix would break older versions, it isn't ours
+        if extended != has_extended_stamp(expires, format) {
+            return None;
+        }
+
+        let channel = match rest {
+            "" => None,
+            rest => {
+                let channel = rest.strip_prefix(CHANNEL_SEPARATOR)?;
+                if !is_valid_name(channel) {
+                    return None;
+                }
+                Some(channel)
+            }
+        };
+
+        Some(
+            Self::new(channel, origin, timestamp, kind)
+                .with_expiry(expires)
+                .with_format(format),
+        )
+    }
+
+    pub fn file_name(&self) -> String {
+        let prefix = if has_extended_stamp(self.expires, self.format) {
+            EXTENDED_ENTRY_PREFIX
+        } else {
+            ENTRY_PREFIX
+        };
+        let prefix = match &self.channel {
+            Some(channel) => format!("{}{}{}", prefix, CHANNEL_SEPARATOR, channel),
+            None => prefix.to_string(),
+        };
+        let stamp = match self.expires {
+            Some(expires) => format!("{}{}{}", self.timestamp, EXPIRY_SEPARATOR, expires),
+            None => self.timestamp.to_string(),
+        };
+        let stamp = stamp + self.format.suffix().as_str();
+        join_file_name(&prefix, &self.origin, &stamp, self.kind)
+    }
+
+    /// the name this entry will have once it is published
+    pub fn published(&self) -> Self {
+        Self {
+            kind: EntryKind::Published,
+            ..self.clone()
+        }
+    }
+}
+
+/// a version of a named register. Registers are never consumed and don't expire, a version is
+/// only replaced by a newer one.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RegisterFile {
+    pub name: String,
+    pub origin: String,
+    pub timestamp: u64,
+    pub kind: EntryKind,
+}
+
+impl RegisterFile {
+    pub fn new(name: &str, origin: &str, timestamp: u64, kind: EntryKind) -> Self {
+        Self {
+            name: name.to_string(),
+            origin: origin.to_string(),
+            timestamp,
+            kind,
+        }
+    }
+
+    /// parses a filename. Returns None if it is not a register.
+    pub fn parse(file_name: &str) -> Option<Self> {
+        let (prefix, origin, stamp, kind) = split_file_name(file_name)?;
+
+        let name = prefix.strip_prefix(REGISTER_PREFIX)?;
+        if !is_valid_name(name) {
+            return None;
+        }
+
+        Some(Self::new(name, origin, parse_number(stamp)?, kind))
+    }
+
+    pub fn file_name(&self) -> String {
+        let prefix = format!("{}{}", REGISTER_PREFIX, self.name);
+        join_file_name(
+            &prefix,
+            &self.origin,
+            &self.timestamp.to_string(),
+            self.kind,
+        )
+    }
+
+    /// the name this version will have once it is published
+    pub fn published(&self) -> Self {
+        Self {
+            kind: EntryKind::Published,
+            ..self.clone()
+        }
+    }
+}
+
+/// a revision of a pinned snippet. Two files with the same id and revision mean two machines
+/// changed the snippet at the same time.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SnippetFile {
+    pub id: String,
+    pub origin: String,
+    pub revision: u64,
+    pub kind: EntryKind,
+}
+
+impl SnippetFile {
+    pub fn new(id: &str, origin: &str, revision: u64, kind: EntryKind) -> Self {
+        Self {
+            id: id.to_string(),
+            origin: origin.to_string(),
+            revision,
+            kind,
+        }
+    }
+
+    /// parses a filename. Returns None if it is not a snippet.
+    pub fn parse(file_name: &str) -> Option<Self> {
+        let (prefix, origin, stamp, kind) = split_file_name(file_name)?;
+
+        let id = prefix.strip_prefix(SNIPPET_PREFIX)?;
+        if !is_valid_name(id) {
+            return None;
+        }
+
+        Some(Self::new(id, origin, parse_number(stamp)?, kind))
+    }
+
+    pub fn file_name(&self) -> String {
+        let prefix = format!("{}{}", SNIPPET_PREFIX, self.id);
+        join_file_name(&prefix, &self.origin, &self.revision.to_string(), self.kind)
+    }
+
+    /// the name this revision will have once it is published
+    pub fn published(&self) -> Self {
+        Self {
+            kind: EntryKind::Published,
+            ..self.clone()
+        }
+    }
+}
+
+/// true if the stamp of an entry is more than its timestamp
+fn has_extended_stamp(expires: Option<u64>, format: TextFormat) -> bool {
+    expires.is_some() || format != TextFormat::default()
+}
+
+/// splits <prefix>-<origin>-<stamp>.tmp(.part) into its parts. The stamp is the timestamp or
+/// revision, and is parsed by the caller
+fn split_file_name(file_name: &str) -> Option<(&str, &str, &str, EntryKind)> {
+    let (rest, kind) = match file_name.strip_suffix(STAGING_EXTENSION) {
+        Some(rest) => (rest, EntryKind::Staging),
+        None => (file_name, EntryKind::Published),
+    };
+
+    let rest = rest.strip_suffix(ENTRY_EXTENSION)?;
+
+    // stamp, origin, prefix
+    let parts: Vec<_> = rest.rsplitn(3, "-").collect();
+    if parts.len() != 3 || parts[1].is_empty() {
+        return None;
+    }
+
+    Some((parts[2], parts[1], parts[0], kind))
+}
+
+/// only plain digits, no sign or whitespace
+fn parse_number(value: &str) -> Option<u64> {
+    if value.is_empty() || !value.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    value.parse().ok()
+}
+
+fn join_file_name(prefix: &str, origin: &str, stamp: &str, kind: EntryKind) -> String {
+    let file_name = format!("{}-{}-{}{}", prefix, origin, stamp, ENTRY_EXTENSION);
+
+    match kind {
+        EntryKind::Published => file_name,
+        EntryKind::Staging => file_name + STAGING_EXTENSION,
+    }
+}
+
+/// true if a sensitive entry expiring at expires has to be gone by now
+pub fn is_expired(expires: Option<u64>, now: u64) -> bool {
+    expires.is_some_and(|expires| expires <= now)
+}
+
+/// channel, register and snippet names end up in file names, so only a safe set of characters is allowed
+pub fn is_valid_name(name: &str) -> bool {
+    !name.is_empty()
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// how versions before this grammar read dir_name: every name with three parts, the first
+    /// being clipboard and the last ending with .tmp, must have a number as stamp. Anything else
+    /// fails, which makes them crash. Returns the timestamp if they take the file
+    fn parse_like_old_versions(file_name: &str) -> Result<Option<u64>, String> {
+        let parts: Vec<_> = file_name.split("-").collect();
+        if parts.len() != 3 || parts[0] != "clipboard" || !parts[2].ends_with(".tmp") {
+            return Ok(None);
+        }
+
+        parts[2][..parts[2].len() - ".tmp".len()]
+            .parse::<u64>()
+            .map(Some)
+            .map_err(|_| format!("could not parse timestamp of {}", file_name))
+    }
+
+    use crate::text_format::{Encoding, LineEnding};
+
+    fn entry(expires: Option<u64>) -> EntryFile {
+        EntryFile::new(None, "a", 100, EntryKind::Published).with_expiry(expires)
+    }
+
+    #[test]
+    fn entries_survive_their_file_name() {
+        for file in [
+            entry(None),
+            entry(Some(130)),
+            EntryFile::new(Some("team-a"), "a", 100, EntryKind::Staging).with_expiry(Some(130)),
+            entry(Some(130)).with_format(TextFormat {
+                encoding: Encoding::Utf16Le,
+                line_ending: LineEnding::Crlf,
+            }),
+            entry(None).with_format(TextFormat::BINARY),
+        ] {
+            assert_eq!(EntryFile::parse(&file.file_name()), Some(file));
+        }
+    }
+
+    #[test]
+    fn sensitive_entries_are_skipped_by_old_versions() {
+        let file_name = entry(Some(130)).file_name();
+        assert_eq!(file_name, "clipboard2-a-100_130.tmp");
+        assert_eq!(parse_like_old_versions(&file_name), Ok(None));
+
+        // plain entries stay readable for them
+        assert_eq!(
+            parse_like_old_versions(&entry(None).file_name()),
+            Ok(Some(100))
+        );
+    }
+
+    #[test]
+    fn entries_in_another_format_are_skipped_by_old_versions() {
+        let crlf = TextFormat {
+            encoding: Encoding::Utf8,
+            line_ending: LineEnding::Crlf,
+        };
+        for (file, expected) in [
+            (entry(None).with_format(crlf), "clipboard2-a-100+crlf.tmp"),
+            (
+                entry(Some(130)).with_format(TextFormat::BINARY),
+                "clipboard2-a-100_130+binary.tmp",
+            ),
+            (
+                EntryFile::new(Some("team"), "a", 100, EntryKind::Published).with_format(crlf),
+                "clipboard2.team-a-100+crlf.tmp",
+            ),
+        ] {
+            let file_name = file.file_name();
+            assert_eq!(file_name, expected);
+            assert_eq!(parse_like_old_versions(&file_name), Ok(None));
+            assert_eq!(
+                parse_like_old_versions(&file.published().file_name()),
+                Ok(None)
+            );
+        }
+    }
+
+    #[test]
+    fn names_old_versions_would_choke_on_are_not_ours() {
+        assert_eq!(EntryFile::parse("clipboard-a-100+crlf.tmp"), None);
+        assert_eq!(EntryFile::parse("clipboard-a-100_130.tmp"), None);
+        assert_eq!(EntryFile::parse("clipboard2-a-100.tmp"), None);
+    }
+}
@@ -0,0 +1,150 @@
+// Events of the running instance, for integrations which want to react to them instead of
+// polling. The file handlers, the transports, the presence and the hotkeys publish them,
+// subscribers get every event published after they subscribed. The control socket streams them
+// as json lines:
+//
+// {"event":"entry-published","origin":"pc1","timestamp":1700000000,"channel":null,"size":12,"sensitive":false}
+// {"event":"entry-received","origin":"pc2","timestamp":1700000005,"channel":"team-a","size":3,"sensitive":false}
+// {"event":"entry-pasted","origin":"pc2","timestamp":1700000005,"channel":"team-a"}
+// {"event":"peer-online","name":"pc2","version":"0.3.2"}
+// {"event":"peer-offline","name":"pc2"}
+// {"event":"error","kind":"hook","message":"hook journal failed: timed out"}
+//
+// A subscriber which falls behind by more than MAX_PENDING events misses the ones after that.
+
+use crate::logfile::log;
+use crate::metrics;
+use serde::{Deserialize, Serialize};
+use std::sync::mpsc::{self, Receiver, SyncSender, TrySendError};
+use std::sync::Mutex;
+
+const MAX_PENDING: usize = 1024;
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "event", rename_all = "kebab-case")]
+pub enum Event {
+    /// an entry of this instance was published
+    EntryPublished {
+        origin: String,
+        timestamp: u64,
+        /// None for the default channel
+        channel: Option<String>,
+        size: usize,
+        sensitive: bool,
+    },
+    /// a remote entry arrived. It is taken later, by a paste or a pull
+    EntryReceived {
+        origin: String,
+        timestamp: u64,
+        channel: Option<String>,
+        size: usize,
+        sensitive: bool,
+    },
+    /// a remote entry was put on the clipboard by a paste
+    EntryPasted {
+        origin: String,
+        timestamp: u64,
+        channel: Option<String>,
+    },
+    PeerOnline {
+        name: String,
+        /// unknown with the tcp transport
+        version: Option<String>,
+    },
+    PeerOffline {
+        name: String,
+    },
+    /// something went wrong, but the instance keeps running
+    Error {
+        kind: ErrorKind,
+        message: String,
+    },
+}
+
+/// the part of the instance which failed
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ErrorKind {
+    Publish,
+    Load,
+    Expiry,
+    Route,
+    Hook,
+    Script,
+    Heartbeat,
+    Watcher,
+    Transport,
+    Gc,
+    Clipboard,
+    Snippets,
+}
+
+/// the names of the events as they are serialized
+pub const EVENT_NAMES: [&str; 6] = [
+    "entry-published",
+    "entry-received",
+    "entry-pasted",
+    "peer-online",
+    "peer-offline",
+    "error",
+];
+
+impl ErrorKind {
+    pub fn name(&self) -> &'static str {
+        match self {
+            ErrorKind::Publish => "publish",
+            ErrorKind::Load => "load",
+            ErrorKind::Expiry => "expiry",
+            ErrorKind::Route => "route",
+            ErrorKind::Hook => "hook",
+            ErrorKind::Script => "script",
+            ErrorKind::Heartbeat => "heartbeat",
+            ErrorKind::Watcher => "watcher",
+            ErrorKind::Transport => "transport",
+            ErrorKind::Gc => "gc",
+            ErrorKind::Clipboard => "clipboard",
+            ErrorKind::Snippets => "snippets",
+        }
+    }
+}
+
+impl Event {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Event::EntryPublished { .. } => "entry-published",
+            Event::EntryReceived { .. } => "entry-received",
+            Event::EntryPasted { .. } => "entry-pasted",
+            Event::PeerOnline { .. } => "peer-online",
+            Event::PeerOffline { .. } => "peer-offline",
+            Event::Error { .. } => "error",
+        }
+    }
+}
+
+static SUBSCRIBERS: Mutex<Vec<SyncSender<Event>>> = Mutex::new(Vec::new());
+
+/// the events published from now on. Dropping the receiver unsubscribes
+pub fn subscribe() -> Receiver<Event> {
+    let (sender, receiver) = mpsc::sync_channel(MAX_PENDING);
+    SUBSCRIBERS.lock().unwrap().push(sender);
+    receiver
+}
+
+/// counts the event and hands it to every subscriber. Subscribers which are gone are removed
+pub fn publish(event: Event) {
+    metrics::record(&event);
+    SUBSCRIBERS
+        .lock()
+        .unwrap()
+        .retain(|subscriber| match subscriber.try_send(event.clone()) {
+            // publishing must never wait for a slow subscriber
+            Ok(()) | Err(TrySendError::Full(_)) => true,
+            Err(TrySendError::Disconnected(_)) => false,
+        });
+}
+
+/// logs the error and publishes it
+pub fn report_error(kind: ErrorKind, message: String) {
+    log(&format!("{}\n", message));
+    publish(Event::Error { kind, message });
+}
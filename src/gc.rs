@@ -0,0 +1,252 @@
+use crate::config::Config;
+use crate::entry::{EntryFile, EntryKind, RegisterFile};
+use crate::events;
+use crate::logfile::log;
+use crate::metrics::{self, Counter};
+use crate::utils::{get_timestamp, read_file_names};
+use anyhow::Result;
+use std::collections::HashMap;
+use std::io::ErrorKind;
+use std::thread;
+use std::time::Duration;
+
+/// a staging file is only around for the duration of a single write. If one of our own is older
+/// than this, the write was interrupted and the file will never be published.
+const STAGING_GRACE_SECS: u64 = 60;
+
+pub struct Garbage {
+    pub file_name: String,
+    pub reason: &'static str,
+}
+
+/// decides which files in config.dir_name can be removed. Only files matching the naming grammar
+/// of this tool are considered, and only those of this machine and its remotes. Everything else
+/// is left alone, other groups may share the directory.
+pub fn find_garbage(config: &Config, file_names: &[String], now: u64) -> Vec<Garbage> {
+    let mut garbage = find_register_garbage(config, file_names, now);
+
+    for file_name in file_names {
+        let Some(entry) = EntryFile::parse(file_name) else {
+            continue;
+        };
+        if entry.origin != config.local_name && !config.knows(&entry.origin) {
+            continue;
+        }
+
+        let age = now.saturating_sub(entry.timestamp);
+        let own = entry.origin == config.local_name;
+
+        let reason = match entry.kind {
+            EntryKind::Staging if own && age > STAGING_GRACE_SECS => "interrupted write",
+            EntryKind::Staging if age > config.entry_ttl_secs => "stale staging file",
+            EntryKind::Published if age > config.entry_ttl_secs => "expired entry",
+            EntryKind::Published if entry.is_expired(now) => "expired sensitive entry",
+            _ => continue,
+        };
+
+        garbage.push(Garbage {
+            file_name: file_name.clone(),
+            reason,
+        });
+    }
+
+    garbage
+}
+
+/// registers don't expire. Only versions replaced by a newer one are removed, and only those of
+/// this machine and its remotes. Other groups may use the same names in a shared directory.
+fn find_register_garbage(config: &Config, file_names: &[String], now: u64) -> Vec<Garbage> {
+    let registers: Vec<_> = file_names
+        .iter()
+        .filter_map(|file_name| Some((file_name, RegisterFile::parse(file_name)?)))
+        .filter(|(_, r)| r.origin == config.local_name || config.knows(&r.origin))
+        .collect();
+
+    let mut newest: HashMap<&str, u64> = HashMap::new();
+    for (_, register) in &registers {
+        if register.kind == EntryKind::Published {
+            let timestamp = newest.entry(&register.name).or_default();
+            *timestamp = register.timestamp.max(*timestamp);
+        }
+    }
+
+    let mut garbage = Vec::new();
+    for (file_name, register) in &registers {
+        let age = now.saturating_sub(register.timestamp);
+        let own = register.origin == config.local_name;
+
+        let reason = match register.kind {
+            EntryKind::Staging if own && age > STAGING_GRACE_SECS => "interrupted write",
+            EntryKind::Staging if age > config.entry_ttl_secs => "stale staging file",
+            EntryKind::Published if newest[register.name.as_str()] > register.timestamp => {
+                "superseded register"
+            }
+            _ => continue,
+        };
+
+        garbage.push(Garbage {
+            file_name: file_name.to_string(),
+            reason,
+        });
+    }
+
+    garbage
+}
+
+/// sweeps config.dir_name once and returns what was removed. With dry_run set nothing is
+/// deleted, only reported. A file which can't be deleted doesn't keep the others from going.
+pub fn collect_garbage(config: &Config, dry_run: bool) -> Result<Vec<Garbage>> {
+    let file_names = read_file_names(&config.dir_name)?;
+    let garbage = find_garbage(config, &file_names, get_timestamp());
+
+    if dry_run {
+        return Ok(garbage);
+    }
+
+    let mut removed = Vec::new();
+    for g in garbage {
+        let file_path = format!("{}/{}", config.dir_name, g.file_name);
+        match std::fs::remove_file(&file_path) {
+            Ok(()) => removed.push(g),
+            // somebody else was faster. That is fine
+            Err(e) if e.kind() == ErrorKind::NotFound => {}
+            Err(e) => {
+                events::report_error(
+                    events::ErrorKind::Gc,
+                    format!("could not delete {}: {}", file_path, e),
+                );
+            }
+        }
+    }
+
+    Ok(removed)
+}
+
+/// starts a thread which periodically removes stale files from config.dir_name
+pub fn spawn_sweeper(config: Config) {
+    thread::spawn(move || loop {
+        match collect_garbage(&config, false) {
+            Ok(garbage) => {
+                metrics::add(Counter::GcDeleted, "", garbage.len() as u64);
+                for g in garbage {
+                    log(&format!("gc removed {} ({})\n", g.file_name, g.reason));
+                }
+            }
+            Err(e) => {
+                // not fatal. Maybe the share is just unavailable at the moment
+                events::report_error(events::ErrorKind::Gc, format!("gc failed: {}", e));
+            }
+        }
+
+        thread::sleep(Duration::from_secs(config.gc_interval_secs));
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const NOW: u64 = 1_000_000;
+
+    fn config() -> Config {
+        Config::parse("local_name=a\nremote_names=b\ndir_name=.\nentry_ttl=600\n").unwrap()
+    }
+
+    fn entry(origin: &str, age: u64, kind: EntryKind) -> String {
+        EntryFile::new(None, origin, NOW - age, kind).file_name()
+    }
+
+    fn found(file_names: &[String]) -> Vec<(&str, &'static str)> {
+        let garbage = find_garbage(&config(), file_names, NOW);
+        file_names
+            .iter()
+            .filter_map(|file_name| {
+                let g = garbage.iter().find(|g| &g.file_name == file_name)?;
+                Some((file_name.as_str(), g.reason))
+            })
+            .collect()
+    }
+
+    #[test]
+    fn foreign_and_unknown_files_are_left_alone() {
+        let file_names = vec![
+            "notes.txt".to_string(),
+            "clipboard-a.tmp".to_string(),
+            "clipboard-a-notanumber.tmp".to_string(),
+            "clipboard-a-1-2.tmp".to_string(),
+            "log-clipboard-current.tmp".to_string(),
+            // an old entry of a machine which is no remote of this one
+            entry("z", 10_000, EntryKind::Published),
+            entry("z", 10_000, EntryKind::Staging),
+        ];
+        assert!(find_garbage(&config(), &file_names, NOW).is_empty());
+    }
+
+    #[test]
+    fn entries_expire_after_entry_ttl() {
+        let expired = entry("b", 601, EntryKind::Published);
+        let fresh = entry("b", 599, EntryKind::Published);
+        let own_expired = entry("a", 10_000, EntryKind::Published);
+        // written by a clock running a bit ahead
+        let future = EntryFile::new(None, "b", NOW + 30, EntryKind::Published).file_name();
+
+        let file_names = vec![expired.clone(), fresh, own_expired.clone(), future];
+        assert_eq!(
+            found(&file_names),
+            vec![
+                (expired.as_str(), "expired entry"),
+                (own_expired.as_str(), "expired entry")
+            ]
+        );
+    }
+
+    #[test]
+    fn sensitive_entries_expire_on_their_own() {
+        let over = EntryFile::new(None, "b", NOW - 60, EntryKind::Published)
+            .with_expiry(Some(NOW - 1))
+            .file_name();
+        let running = EntryFile::new(None, "b", NOW - 60, EntryKind::Published)
+            .with_expiry(Some(NOW + 1))
+            .file_name();
+
+        let file_names = vec![over.clone(), running];
+        assert_eq!(
+            found(&file_names),
+            vec![(over.as_str(), "expired sensitive entry")]
+        );
+    }
+
+    #[test]
+    fn only_own_staging_files_are_interrupted_writes() {
+        let own = entry("a", STAGING_GRACE_SECS + 1, EntryKind::Staging);
+        let own_running = entry("a", STAGING_GRACE_SECS - 1, EntryKind::Staging);
+        // the remote may still be writing over a slow share
+        let remote = entry("b", STAGING_GRACE_SECS + 1, EntryKind::Staging);
+        let remote_stale = entry("b", 601, EntryKind::Staging);
+
+        let file_names = vec![own.clone(), own_running, remote, remote_stale.clone()];
+        assert_eq!(
+            found(&file_names),
+            vec![
+                (own.as_str(), "interrupted write"),
+                (remote_stale.as_str(), "stale staging file")
+            ]
+        );
+    }
+
+    #[test]
+    fn only_superseded_registers_go() {
+        let register = |origin: &str, age: u64| {
+            RegisterFile::new("r", origin, NOW - age, EntryKind::Published).file_name()
+        };
+        let old = register("a", 10_000);
+        let newest = register("b", 1);
+        let foreign = register("z", 30_000);
+
+        let file_names = vec![old.clone(), newest, foreign];
+        assert_eq!(
+            found(&file_names),
+            vec![(old.as_str(), "superseded register")]
+        );
+    }
+}
@@ -0,0 +1,126 @@
+use super::keyboard::{KeyEvent, KeyboardKey};
+use super::{handle_key_event, HotkeyBackend, Stopper};
+use crate::logfile::log;
+use anyhow::{bail, Result};
+use evdev::{Device, EventType, KeyCode};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::thread;
+
+// value of a key event
+const RELEASED: i32 = 0;
+
+/// reads the key events of every keyboard in /dev/input. The events are only observed, they
+/// still reach the applications. Works with X11, wayland and on the console alike.
+pub struct EvdevHotkeys {
+    keyboards: Vec<Device>,
+    notify: Sender<Notification>,
+    notifications: Receiver<Notification>,
+}
+
+enum Notification {
+    KeyboardGone,
+    Stop,
+}
+
+impl EvdevHotkeys {
+    pub fn open() -> Result<Self> {
+        let keyboards: Vec<_> = evdev::enumerate()
+            .map(|(_, device)| device)
+            .filter(|device| {
+                device.supported_keys().is_some_and(|keys| {
+                    keys.contains(KeyCode::KEY_LEFTCTRL) && keys.contains(KeyCode::KEY_V)
+                })
+            })
+            .collect();
+
+        if keyboards.is_empty() {
+            bail!("no readable keyboard in /dev/input. Is this user in the input group?");
+        }
+
+        let (notify, notifications) = mpsc::channel();
+        Ok(Self {
+            keyboards,
+            notify,
+            notifications,
+        })
+    }
+}
+
+impl HotkeyBackend for EvdevHotkeys {
+    fn listen(&mut self) -> Result<()> {
+        let mut remaining = self.keyboards.len();
+
+        for mut keyboard in self.keyboards.drain(..) {
+            let notify = self.notify.clone();
+            thread::spawn(move || {
+                let name = keyboard.name().unwrap_or("unknown").to_string();
+                loop {
+                    let events = match keyboard.fetch_events() {
+                        Ok(events) => events,
+                        Err(e) => {
+                            // most likely unplugged
+                            log(&format!("stopped reading keyboard {}: {}\n", name, e));
+                            let _ = notify.send(Notification::KeyboardGone);
+                            return;
+                        }
+                    };
+
+                    for event in events.filter(|e| e.event_type() == EventType::KEY) {
+                        // auto repeat counts as a press, just like on windows
+                        handle_key_event(KeyEvent {
+                            key: KeyboardKey::from_evdev(event.code()),
+                            pressed: event.value() != RELEASED,
+                        });
+                    }
+                }
+            });
+        }
+
+        // the keyboards are only read, not grabbed. Their threads may as well keep blocking
+        while remaining > 0 {
+            match self.notifications.recv() {
+                Ok(Notification::KeyboardGone) => remaining -= 1,
+                Ok(Notification::Stop) | Err(_) => return Ok(()),
+            }
+        }
+
+        bail!("all keyboards are gone");
+    }
+
+    fn stopper(&self) -> Stopper {
+        let notify = self.notify.clone();
+        Box::new(move || {
+            let _ = notify.send(Notification::Stop);
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn without_keyboards() -> EvdevHotkeys {
+        let (notify, notifications) = mpsc::channel();
+        EvdevHotkeys {
+            keyboards: Vec::new(),
+            notify,
+            notifications,
+        }
+    }
+
+    #[test]
+    fn listening_fails_once_every_keyboard_is_gone() {
+        let mut hotkeys = without_keyboards();
+        let error = hotkeys.listen().err().unwrap().to_string();
+        assert_eq!(error, "all keyboards are gone");
+    }
+
+    #[test]
+    fn the_stopper_outlives_the_backend() {
+        let hotkeys = without_keyboards();
+        let stopper = hotkeys.stopper();
+        drop(hotkeys);
+        // the instance may stop after the listener ended by itself
+        stopper();
+    }
+}
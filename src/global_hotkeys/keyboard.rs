@@ -1,9 +1,19 @@
+use crate::config::HotkeyModifiers;
+
+// the names follow the ones of the Inputbot crate
+#[allow(clippy::enum_variant_names)]
 #[derive(Debug, Eq, PartialEq, Hash, Copy, Clone)]
 pub enum KeyboardKey {
     CKey,
     VKey,
     LControlKey,
     RControlKey,
+    LShiftKey,
+    RShiftKey,
+    LAltKey,
+    RAltKey,
+    /// Numrow1Key to Numrow9Key of Inputbot in one
+    NumrowKey(u8),
     OtherKey(u64),
 }
 
@@ -15,6 +25,11 @@ impl From<KeyboardKey> for u64 {
             KeyboardKey::VKey => 0x56,
             KeyboardKey::LControlKey => 0xA2,
             KeyboardKey::RControlKey => 0xA3,
+            KeyboardKey::LShiftKey => 0xA0,
+            KeyboardKey::RShiftKey => 0xA1,
+            KeyboardKey::LAltKey => 0xA4,
+            KeyboardKey::RAltKey => 0xA5,
+            KeyboardKey::NumrowKey(n) => 0x30 + u64::from(n),
             KeyboardKey::OtherKey(code) => code,
         }
     }
@@ -28,7 +43,359 @@ impl From<u64> for KeyboardKey {
             0x56 => KeyboardKey::VKey,
             0xA2 => KeyboardKey::LControlKey,
             0xA3 => KeyboardKey::RControlKey,
+            0xA0 => KeyboardKey::LShiftKey,
+            0xA1 => KeyboardKey::RShiftKey,
+            0xA4 => KeyboardKey::LAltKey,
+            0xA5 => KeyboardKey::RAltKey,
+            0x31..=0x39 => KeyboardKey::NumrowKey((code - 0x30) as u8),
             _ => KeyboardKey::OtherKey(code),
         }
     }
 }
+
+impl KeyboardKey {
+    // https://github.com/torvalds/linux/blob/master/include/uapi/linux/input-event-codes.h
+    pub fn from_evdev(code: u16) -> KeyboardKey {
+        match code {
+            46 => KeyboardKey::CKey,
+            47 => KeyboardKey::VKey,
+            29 => KeyboardKey::LControlKey,
+            97 => KeyboardKey::RControlKey,
+            42 => KeyboardKey::LShiftKey,
+            54 => KeyboardKey::RShiftKey,
+            56 => KeyboardKey::LAltKey,
+            100 => KeyboardKey::RAltKey,
+            2..=10 => KeyboardKey::NumrowKey((code - 1) as u8),
+            _ => KeyboardKey::OtherKey(u64::from(code)),
+        }
+    }
+
+    // https://gitlab.freedesktop.org/xorg/proto/xorgproto/-/blob/master/include/X11/keysymdef.h
+    pub fn from_x11_keysym(keysym: u32) -> KeyboardKey {
+        match keysym {
+            0x63 | 0x43 => KeyboardKey::CKey,
+            0x76 | 0x56 => KeyboardKey::VKey,
+            0xffe3 => KeyboardKey::LControlKey,
+            0xffe4 => KeyboardKey::RControlKey,
+            0xffe1 => KeyboardKey::LShiftKey,
+            0xffe2 => KeyboardKey::RShiftKey,
+            0xffe9 => KeyboardKey::LAltKey,
+            0xffea => KeyboardKey::RAltKey,
+            0x31..=0x39 => KeyboardKey::NumrowKey((keysym - 0x30) as u8),
+            _ => KeyboardKey::OtherKey(u64::from(keysym)),
+        }
+    }
+}
+
+#[derive(Debug, Eq, PartialEq, Copy, Clone)]
+pub struct KeyEvent {
+    pub key: KeyboardKey,
+    /// false if the key was released
+    pub pressed: bool,
+}
+
+/// the actions the hotkeys trigger
+#[derive(Debug, Eq, PartialEq, Copy, Clone)]
+pub enum Hotkey {
+    /// a control key went down. A paste might follow
+    ControlPressed,
+    Copy(HotkeyModifiers),
+    /// ctrl+shift+alt+c. Copies to the default channel, but the entry expires
+    SensitiveCopy,
+    Paste(HotkeyModifiers),
+    /// ctrl+shift and a number
+    Snippet(u8),
+    /// ctrl+shift+alt and a number
+    Transform(u8),
+}
+
+/// turns the key events of any backend into hotkeys. It keeps track of the state of the modifier
+/// keys, so it has to see every press and release of them.
+#[derive(Debug, Default)]
+pub struct ChordMatcher {
+    l_control_pressed: bool,
+    r_control_pressed: bool,
+    l_shift_pressed: bool,
+    r_shift_pressed: bool,
+    l_alt_pressed: bool,
+    r_alt_pressed: bool,
+}
+
+impl ChordMatcher {
+    pub const fn new() -> Self {
+        Self {
+            l_control_pressed: false,
+            r_control_pressed: false,
+            l_shift_pressed: false,
+            r_shift_pressed: false,
+            l_alt_pressed: false,
+            r_alt_pressed: false,
+        }
+    }
+
+    pub fn feed(&mut self, event: KeyEvent) -> Option<Hotkey> {
+        match (event.key, event.pressed) {
+            (KeyboardKey::LControlKey, pressed) => {
+                self.l_control_pressed = pressed;
+                pressed.then_some(Hotkey::ControlPressed)
+            }
+            (KeyboardKey::RControlKey, pressed) => {
+                self.r_control_pressed = pressed;
+                pressed.then_some(Hotkey::ControlPressed)
+            }
+            (KeyboardKey::LShiftKey, pressed) => {
+                self.l_shift_pressed = pressed;
+                None
+            }
+            (KeyboardKey::RShiftKey, pressed) => {
+                self.r_shift_pressed = pressed;
+                None
+            }
+            (KeyboardKey::LAltKey, pressed) => {
+                self.l_alt_pressed = pressed;
+                None
+            }
+            (KeyboardKey::RAltKey, pressed) => {
+                self.r_alt_pressed = pressed;
+                None
+            }
+            (KeyboardKey::CKey, true) if self.all_modifiers_pressed() => {
+                Some(Hotkey::SensitiveCopy)
+            }
+            (KeyboardKey::CKey, true) => self.modifiers().map(Hotkey::Copy),
+            (KeyboardKey::VKey, true) => self.modifiers().map(Hotkey::Paste),
+            (KeyboardKey::NumrowKey(n), true) if self.all_modifiers_pressed() => {
+                Some(Hotkey::Transform(n))
+            }
+            (KeyboardKey::NumrowKey(n), true)
+                if self.modifiers() == Some(HotkeyModifiers::CtrlShift) =>
+            {
+                Some(Hotkey::Snippet(n))
+            }
+            _ => None,
+        }
+    }
+
+    /// for backends which don't see the modifier keys themselves but know the modifier state of
+    /// every event
+    pub fn sync_modifiers(&mut self, control: bool, shift: bool, alt: bool) {
+        if control != self.control_pressed() {
+            self.l_control_pressed = control;
+            self.r_control_pressed = false;
+        }
+        self.l_shift_pressed = shift;
+        self.r_shift_pressed = false;
+        self.l_alt_pressed = alt;
+        self.r_alt_pressed = false;
+    }
+
+    /// the modifiers currently held down, if they form one of the hotkeys
+    fn modifiers(&self) -> Option<HotkeyModifiers> {
+        // check if atleast one crtl is currently pressed
+        if !self.control_pressed() {
+            return None;
+        }
+
+        let shift = self.l_shift_pressed || self.r_shift_pressed;
+        let alt = self.l_alt_pressed || self.r_alt_pressed;
+
+        match (shift, alt) {
+            (false, false) => Some(HotkeyModifiers::Ctrl),
+            (true, false) => Some(HotkeyModifiers::CtrlShift),
+            (false, true) => Some(HotkeyModifiers::CtrlAlt),
+            (true, true) => None,
+        }
+    }
+
+    fn control_pressed(&self) -> bool {
+        self.l_control_pressed || self.r_control_pressed
+    }
+
+    /// ctrl, shift and alt at once. That's no channel hotkey, it's the sensitive copy or a
+    /// transform
+    fn all_modifiers_pressed(&self) -> bool {
+        self.control_pressed()
+            && (self.l_shift_pressed || self.r_shift_pressed)
+            && (self.l_alt_pressed || self.r_alt_pressed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn press(key: KeyboardKey) -> KeyEvent {
+        KeyEvent { key, pressed: true }
+    }
+
+    fn release(key: KeyboardKey) -> KeyEvent {
+        KeyEvent {
+            key,
+            pressed: false,
+        }
+    }
+
+    /// the hotkeys the events trigger, in order
+    fn feed_all(matcher: &mut ChordMatcher, events: &[KeyEvent]) -> Vec<Hotkey> {
+        events.iter().filter_map(|e| matcher.feed(*e)).collect()
+    }
+
+    #[test]
+    fn ctrl_c_and_ctrl_v() {
+        let mut matcher = ChordMatcher::new();
+        let hotkeys = feed_all(
+            &mut matcher,
+            &[
+                press(KeyboardKey::LControlKey),
+                press(KeyboardKey::CKey),
+                release(KeyboardKey::CKey),
+                press(KeyboardKey::VKey),
+                release(KeyboardKey::LControlKey),
+            ],
+        );
+
+        assert_eq!(
+            hotkeys,
+            vec![
+                Hotkey::ControlPressed,
+                Hotkey::Copy(HotkeyModifiers::Ctrl),
+                Hotkey::Paste(HotkeyModifiers::Ctrl),
+            ]
+        );
+    }
+
+    #[test]
+    fn no_hotkey_without_control() {
+        let mut matcher = ChordMatcher::new();
+        let hotkeys = feed_all(
+            &mut matcher,
+            &[
+                press(KeyboardKey::LShiftKey),
+                press(KeyboardKey::CKey),
+                press(KeyboardKey::VKey),
+                press(KeyboardKey::NumrowKey(1)),
+            ],
+        );
+
+        assert!(hotkeys.is_empty());
+    }
+
+    #[test]
+    fn released_control_ends_the_chord() {
+        let mut matcher = ChordMatcher::new();
+        feed_all(
+            &mut matcher,
+            &[
+                press(KeyboardKey::RControlKey),
+                release(KeyboardKey::RControlKey),
+            ],
+        );
+
+        assert_eq!(matcher.feed(press(KeyboardKey::CKey)), None);
+    }
+
+    #[test]
+    fn either_side_counts() {
+        let mut matcher = ChordMatcher::new();
+        feed_all(
+            &mut matcher,
+            &[press(KeyboardKey::RControlKey), press(KeyboardKey::LAltKey)],
+        );
+        assert_eq!(
+            matcher.feed(press(KeyboardKey::VKey)),
+            Some(Hotkey::Paste(HotkeyModifiers::CtrlAlt))
+        );
+
+        // the right control is still down
+        matcher.feed(release(KeyboardKey::LAltKey));
+        matcher.feed(press(KeyboardKey::LControlKey));
+        matcher.feed(release(KeyboardKey::LControlKey));
+        assert_eq!(
+            matcher.feed(press(KeyboardKey::CKey)),
+            Some(Hotkey::Copy(HotkeyModifiers::Ctrl))
+        );
+    }
+
+    #[test]
+    fn ctrl_shift_is_a_channel_and_snippets() {
+        let mut matcher = ChordMatcher::new();
+        feed_all(
+            &mut matcher,
+            &[
+                press(KeyboardKey::LControlKey),
+                press(KeyboardKey::RShiftKey),
+            ],
+        );
+
+        assert_eq!(
+            matcher.feed(press(KeyboardKey::CKey)),
+            Some(Hotkey::Copy(HotkeyModifiers::CtrlShift))
+        );
+        assert_eq!(
+            matcher.feed(press(KeyboardKey::NumrowKey(3))),
+            Some(Hotkey::Snippet(3))
+        );
+    }
+
+    #[test]
+    fn all_modifiers_are_the_sensitive_copy_and_transforms() {
+        let mut matcher = ChordMatcher::new();
+        feed_all(
+            &mut matcher,
+            &[
+                press(KeyboardKey::LControlKey),
+                press(KeyboardKey::LShiftKey),
+                press(KeyboardKey::RAltKey),
+            ],
+        );
+
+        assert_eq!(
+            matcher.feed(press(KeyboardKey::CKey)),
+            Some(Hotkey::SensitiveCopy)
+        );
+        assert_eq!(
+            matcher.feed(press(KeyboardKey::NumrowKey(9))),
+            Some(Hotkey::Transform(9))
+        );
+        // there is no channel with all three
+        assert_eq!(matcher.feed(press(KeyboardKey::VKey)), None);
+    }
+
+    #[test]
+    fn numbers_need_shift() {
+        let mut matcher = ChordMatcher::new();
+        feed_all(
+            &mut matcher,
+            &[press(KeyboardKey::LControlKey), press(KeyboardKey::LAltKey)],
+        );
+
+        assert_eq!(matcher.feed(press(KeyboardKey::NumrowKey(1))), None);
+    }
+
+    #[test]
+    fn synced_modifiers() {
+        let mut matcher = ChordMatcher::new();
+        matcher.sync_modifiers(true, true, false);
+        assert_eq!(
+            matcher.feed(press(KeyboardKey::VKey)),
+            Some(Hotkey::Paste(HotkeyModifiers::CtrlShift))
+        );
+
+        matcher.sync_modifiers(false, false, false);
+        assert_eq!(matcher.feed(press(KeyboardKey::VKey)), None);
+    }
+
+    #[test]
+    fn key_codes_of_the_backends() {
+        assert_eq!(KeyboardKey::from(0x43), KeyboardKey::CKey);
+        assert_eq!(KeyboardKey::from(0x35), KeyboardKey::NumrowKey(5));
+        assert_eq!(u64::from(KeyboardKey::NumrowKey(5)), 0x35);
+        assert_eq!(KeyboardKey::from_evdev(2), KeyboardKey::NumrowKey(1));
+        assert_eq!(KeyboardKey::from_evdev(97), KeyboardKey::RControlKey);
+        assert_eq!(KeyboardKey::from_x11_keysym(0x63), KeyboardKey::CKey);
+        assert_eq!(
+            KeyboardKey::from_x11_keysym(0x30),
+            KeyboardKey::OtherKey(0x30)
+        );
+    }
+}
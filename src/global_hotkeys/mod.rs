@@ -3,195 +3,404 @@
 //
 // Since the original crate gives way more functionality than we need, we will only take what we
 // need and tailor it to our needs.
-// Additionally the original crate is cross platform, but we will only focus on Windows.
+//
+// Every platform has its own way to get the key events (see the backends below). They all feed
+// them into handle_key_event, which matches the chords and triggers the clipboard actions. The
+// backend listens on a thread of its own until the instance shuts down.
 
+#[cfg(target_os = "linux")]
+mod evdev;
 mod keyboard;
+#[cfg(windows)]
+mod windows;
+#[cfg(target_os = "linux")]
+mod x11;
 
-use crate::file_handlers::ClipboardAction;
+use crate::clipboard_backend::ClipboardBackend;
+use crate::config::{Config, HotkeyModifiers};
+use crate::events::{self, ErrorKind, Event};
+use crate::file_handlers::{ClipboardAction, LoadedClipboard};
 use crate::logfile::{log, log_and_panic};
+use crate::shutdown::{self, Reason};
+use crate::snippets;
+use crate::transforms::Transform;
+use crate::utils::join_until;
 use anyhow::{anyhow, bail, Context, Result};
-use keyboard::KeyboardKey;
-use std::ffi::{c_int, c_ulong};
-use std::sync::atomic::{AtomicBool, AtomicPtr, Ordering};
+use keyboard::{ChordMatcher, Hotkey, KeyEvent};
 use std::sync::{mpsc, Mutex};
-use windows::Win32::Foundation::{LPARAM, LRESULT, WPARAM};
-use windows::Win32::UI::WindowsAndMessaging::{
-    CallNextHookEx, GetMessageW, KillTimer, SetTimer, SetWindowsHookExW, UnhookWindowsHookEx,
-    HHOOK, KBDLLHOOKSTRUCT, MSG, WH_KEYBOARD_LL, WINDOWS_HOOK_ID, WM_KEYDOWN, WM_KEYUP,
-    WM_SYSKEYDOWN, WM_SYSKEYUP,
-};
-
-// these keep track of the state of the control keys
-static L_CONTROL_PRESSED: AtomicBool = AtomicBool::new(false);
-static R_CONTROL_PRESSED: AtomicBool = AtomicBool::new(false);
-pub static LOADED_CLIPBOARD: Mutex<Option<String>> = Mutex::new(None);
-pub static CLIPBOARD_ACTION_SENDER: Mutex<Option<mpsc::Sender<ClipboardAction>>> = Mutex::new(None);
-
-pub struct KeyboardListener {
-    // stores the hook for our event so it can be unset later
-    keyboard_hhock: AtomicPtr<HHOOK>,
-    timer_id: Option<c_ulong>,
-}
-
-impl KeyboardListener {
-    pub fn new() -> Self {
-        Self {
-            keyboard_hhock: AtomicPtr::default(),
-            timer_id: None,
-        }
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+/// makes listen return. Called from another thread than the listening one
+pub type Stopper = Box<dyn FnOnce() + Send>;
+
+pub trait HotkeyBackend: Send {
+    /// blocks and feeds every key event into handle_key_event until it is stopped. The keyboard
+    /// is released before it returns
+    fn listen(&mut self) -> Result<()>;
+
+    fn stopper(&self) -> Stopper;
+}
+
+/// the backend listening on its thread
+pub struct HotkeyListener {
+    stopper: Stopper,
+    handle: JoinHandle<()>,
+}
+
+impl HotkeyListener {
+    /// stops listening and releases the keyboard. Returns false if that didn't happen in time
+    pub fn stop(self, timeout: Duration) -> bool {
+        (self.stopper)();
+        join_until(self.handle, Instant::now() + timeout)
     }
-    pub fn handle_input_events(&mut self) {
-        Self::set_hook(WH_KEYBOARD_LL, &self.keyboard_hhock, keybd_proc);
+}
 
-        let timer_id = unsafe { SetTimer(None, 0, 100, None) };
-        self.timer_id = Some(timer_id as c_ulong);
+/// starts listening on a thread of its own. If the backend stops by itself, the instance shuts
+/// down
+pub fn spawn_listener(mut backend: Box<dyn HotkeyBackend>) -> HotkeyListener {
+    let stopper = backend.stopper();
 
-        loop {
-            let mut msg: MSG = unsafe { std::mem::MaybeUninit::zeroed().assume_init() };
-            unsafe {
-                let _ = GetMessageW(&mut msg, None, 0, 0);
-            };
+    let handle = thread::spawn(move || {
+        let result = backend.listen();
+        if shutdown::is_stopping() {
+            return;
         }
-    }
 
-    /// register the hook into the win-api
-    fn set_hook(
-        hook_id: WINDOWS_HOOK_ID,
-        hook_ptr: &AtomicPtr<HHOOK>,
-        hook_proc: unsafe extern "system" fn(c_int, WPARAM, LPARAM) -> LRESULT,
-    ) {
-        hook_ptr.store(
-            unsafe { &mut SetWindowsHookExW(hook_id, Some(hook_proc), None, 0).unwrap() },
-            Ordering::Relaxed,
-        );
-    }
+        shutdown::request(match result {
+            // only windows stops without being asked to, on WM_QUIT
+            Ok(()) => Reason::Quit,
+            Err(e) => Reason::HotkeysFailed(format!("{:#}", e)),
+        });
+    });
+
+    HotkeyListener { stopper, handle }
+}
+
+/// opens the hotkey backend of this platform
+#[cfg(windows)]
+pub fn open() -> Result<Box<dyn HotkeyBackend>> {
+    Ok(Box::new(self::windows::KeyboardListener::new()))
+}
 
-    /// unregisters the hook from the win-api
-    fn unset_hook(hook_ptr: &AtomicPtr<HHOOK>) {
-        if !hook_ptr.load(Ordering::Relaxed).is_null() {
-            unsafe { UnhookWindowsHookEx(*hook_ptr.load(Ordering::Relaxed)).unwrap() };
-            hook_ptr.store(std::ptr::null_mut(), Ordering::Relaxed);
+/// opens the hotkey backend of this platform. Key grabs only work for X11 clients, so with
+/// wayland (or if the grabs fail) the input devices are read directly. That needs read access to
+/// /dev/input, usually by being in the input group.
+#[cfg(target_os = "linux")]
+pub fn open() -> Result<Box<dyn HotkeyBackend>> {
+    let x11_only =
+        std::env::var_os("DISPLAY").is_some() && std::env::var_os("WAYLAND_DISPLAY").is_none();
+
+    if x11_only {
+        match x11::X11Hotkeys::grab() {
+            Ok(hotkeys) => return Ok(Box::new(hotkeys)),
+            Err(e) => log(&format!(
+                "could not grab keys on X11, reading input devices: {}\n",
+                e
+            )),
         }
     }
+
+    Ok(Box::new(evdev::EvdevHotkeys::open()?))
 }
 
-impl Drop for KeyboardListener {
-    fn drop(&mut self) {
-        if let Some(timer_id) = self.timer_id {
-            let _ = unsafe { KillTimer(None, timer_id as usize) };
-        }
+#[cfg(not(any(windows, target_os = "linux")))]
+pub fn open() -> Result<Box<dyn HotkeyBackend>> {
+    bail!("there are no global hotkeys for this platform");
+}
 
-        // expected that KEYBD_HHOOK is alreadt set. Dont know what happens if we unset the default
-        // ptr. Probably a unwrap panic... in unsets unsafe block
-        Self::unset_hook(&self.keyboard_hhock);
-    }
+// keeps track of the state of the control keys
+static CHORD_MATCHER: Mutex<ChordMatcher> = Mutex::new(ChordMatcher::new());
+
+/// the file handler of one channel and the hotkey it is reached with
+struct ChannelActions {
+    /// None for the default channel
+    channel: Option<String>,
+    hotkey: HotkeyModifiers,
+    sender: mpsc::Sender<ClipboardAction>,
+    loaded: LoadedClipboard,
 }
 
-/// sets the sender for the clipboard actions
-/// this sender will send the actions activated by the hotkeys
-pub fn set_action_sender(sender: mpsc::Sender<ClipboardAction>) -> Result<()> {
-    CLIPBOARD_ACTION_SENDER
+static CHANNELS: Mutex<Vec<ChannelActions>> = Mutex::new(Vec::new());
+pub static CLIPBOARD_BACKEND: Mutex<Option<Box<dyn ClipboardBackend>>> = Mutex::new(None);
+// set if the snippet hotkeys are on. The snippets are read from config.dir_name on every use
+static SNIPPET_CONFIG: Mutex<Option<Config>> = Mutex::new(None);
+// config.transform_hotkeys. Empty if the transform hotkeys are off
+static TRANSFORM_HOTKEYS: Mutex<Vec<Transform>> = Mutex::new(Vec::new());
+
+/// adds a channel. The sender will send the actions activated by its hotkey
+pub fn add_channel(
+    channel: Option<String>,
+    hotkey: HotkeyModifiers,
+    sender: mpsc::Sender<ClipboardAction>,
+    loaded: LoadedClipboard,
+) -> Result<()> {
+    CHANNELS
         .lock()
         .map_err(|e| anyhow!(e.to_string()))?
-        .replace(sender);
+        .push(ChannelActions {
+            channel,
+            hotkey,
+            sender,
+            loaded,
+        });
 
     Ok(())
 }
 
-/// sends the action to the file_handler via the established channel
-fn send_action(action: ClipboardAction) -> Result<()> {
-    match CLIPBOARD_ACTION_SENDER.lock() {
-        Ok(sender) => match sender.as_ref() {
-            None => {
-                bail!("tried to use action-sender, but it was not set");
-            }
-            Some(sender) => sender
-                .send(action)
-                .with_context(|| "could not send action to the file-handler"),
-        },
+/// turns on the snippet hotkeys
+pub fn enable_snippets(config: Config) -> Result<()> {
+    SNIPPET_CONFIG
+        .lock()
+        .map_err(|e| anyhow!(e.to_string()))?
+        .replace(config);
+
+    Ok(())
+}
+
+/// turns on the transform hotkeys. ctrl+shift+alt+1 applies the first transform and so on
+pub fn enable_transforms(transforms: Vec<Transform>) -> Result<()> {
+    *TRANSFORM_HOTKEYS
+        .lock()
+        .map_err(|e| anyhow!(e.to_string()))? = transforms;
+
+    Ok(())
+}
+
+/// sets the clipboard the hotkeys read from and write to
+pub fn set_clipboard_backend(backend: Box<dyn ClipboardBackend>) -> Result<()> {
+    CLIPBOARD_BACKEND
+        .lock()
+        .map_err(|e| anyhow!(e.to_string()))?
+        .replace(backend);
+
+    Ok(())
+}
+
+/// runs f with the channel of the hotkey. Modifiers no channel claims fall back to the default
+/// channel, so ctrl+shift+c in a terminal keeps working without any channels configured.
+fn with_channel<T>(
+    hotkey: HotkeyModifiers,
+    f: impl FnOnce(&ChannelActions) -> Result<T>,
+) -> Result<T> {
+    let channels = match CHANNELS.lock() {
+        Ok(channels) => channels,
         Err(e) => {
             bail!("could not aquire lock. It seems like the channel broke: {e}",);
         }
+    };
+
+    let channel = channels
+        .iter()
+        .find(|c| c.hotkey == hotkey)
+        .or_else(|| channels.iter().find(|c| c.hotkey == HotkeyModifiers::Ctrl));
+
+    match channel {
+        Some(channel) => f(channel),
+        None => bail!("tried to use action-sender, but it was not set"),
     }
 }
 
-/// handler for the win-api
-unsafe extern "system" fn keybd_proc(code: c_int, w_param: WPARAM, l_param: LPARAM) -> LRESULT {
-    let event_type = w_param.0 as u32;
-    let key_code = u64::from((*(l_param.0 as *const KBDLLHOOKSTRUCT)).vkCode);
-    let key = KeyboardKey::from(key_code);
-
-    #[allow(non_snake_case)]
-    if event_type == WM_KEYDOWN || event_type == WM_SYSKEYDOWN {
-        match key {
-            KeyboardKey::LControlKey => {
-                L_CONTROL_PRESSED.store(true, Ordering::Relaxed);
-                let _ = send_action(ClipboardAction::TryLoad).map_err(|e| {
-                    log_and_panic(&format!("could not send action: {}", e));
-                });
-            }
-            KeyboardKey::RControlKey => {
-                R_CONTROL_PRESSED.store(true, Ordering::Relaxed);
-                let _ = send_action(ClipboardAction::TryLoad).map_err(|e| {
-                    log_and_panic(&format!("could not send action: {}", e));
-                });
-            }
+/// sends the action to the file_handler of the channel
+fn send_action(channel: &ChannelActions, action: ClipboardAction) -> Result<()> {
+    channel
+        .sender
+        .send(action)
+        .with_context(|| "could not send action to the file-handler")
+}
 
-            KeyboardKey::CKey => {
-                if L_CONTROL_PRESSED.load(Ordering::Relaxed)
-                    || R_CONTROL_PRESSED.load(Ordering::Relaxed)
-                {
-                    // check if atleast one crtl is currently pressed
-                    std::thread::spawn(move || {
-                        std::thread::sleep(std::time::Duration::from_secs(1));
-                        match clipboard_win::get_clipboard(clipboard_win::formats::Unicode) {
-                            Ok(content) => {
-                                let content: String = content;
-                                let _ = send_action(ClipboardAction::Store(content.clone()))
-                                    .unwrap_or_else(|e| {
-                                        log_and_panic(&format!("could not send action: {}", e));
-                                    });
-                                log(&format!("Stored <{}>\n", content));
-                            }
-                            Err(e) => {
-                                log(&format!("could not get clipboard: {}", e));
-                            }
-                        }
-                    });
-                }
-            }
-            KeyboardKey::VKey => {
-                if L_CONTROL_PRESSED.load(Ordering::Relaxed)
-                    || R_CONTROL_PRESSED.load(Ordering::Relaxed)
-                {
-                    // check if atleast one crtl is currently pressed
-                    let content = LOADED_CLIPBOARD.lock().unwrap_or_else(|e| {
-                        let _ = &format!("Could not aquire lock for the loaded clipboard value. This means the loading of a value failed or is still locking it... {}", e);
-                        unreachable!();
-                    }).clone();
-
-                    if let Some(content) = content {
-                        clipboard_win::set_clipboard(clipboard_win::formats::Unicode, &content)
-                            .unwrap_or_else(|e| {
-                                log_and_panic(&format!("could not set clipboard: {}", e))
-                            });
-                        log(&format!("Inserted <{}>\n", &content));
-                    }
-                }
-            }
-            _ => {}
+fn with_backend<T>(f: impl FnOnce(&mut dyn ClipboardBackend) -> Result<T>) -> Result<T> {
+    let mut backend = CLIPBOARD_BACKEND
+        .lock()
+        .map_err(|e| anyhow!("could not aquire lock for the clipboard backend: {}", e))?;
+
+    match backend.as_mut() {
+        Some(backend) => f(backend.as_mut()),
+        None => bail!("tried to use the clipboard backend, but it was not set"),
+    }
+}
+
+/// matches the event against the hotkeys and triggers the clipboard action if one matched
+fn handle_key_event(event: KeyEvent) {
+    let hotkey = CHORD_MATCHER
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .feed(event);
+
+    dispatch(hotkey);
+}
+
+fn dispatch(hotkey: Option<Hotkey>) {
+    // no new actions once the instance shuts down
+    if shutdown::is_stopping() {
+        return;
+    }
+
+    match hotkey {
+        Some(Hotkey::ControlPressed) => on_control_pressed(),
+        Some(Hotkey::Copy(modifiers)) => on_copy(modifiers, false),
+        Some(Hotkey::SensitiveCopy) => on_copy(HotkeyModifiers::Ctrl, true),
+        Some(Hotkey::Paste(modifiers)) => on_paste(modifiers),
+        Some(Hotkey::Snippet(index)) => on_snippet(index),
+        Some(Hotkey::Transform(index)) => on_transform(index),
+        None => {}
+    }
+}
+
+/// a control key went down. A paste might follow, so the newest remote entry of every channel is
+/// loaded
+fn on_control_pressed() {
+    let channels = CHANNELS.lock().unwrap_or_else(|e| e.into_inner());
+    for channel in channels.iter() {
+        let _ = send_action(channel, ClipboardAction::TryLoad).map_err(|e| {
+            log_and_panic(&format!("could not send action: {}", e));
+        });
+    }
+}
+
+/// ctrl+c was pressed. The application needs a moment to actually put its content on the
+/// clipboard, so the file handler is told a bit later. It reads the clipboard on its own thread,
+/// the key events must not wait for that.
+fn on_copy(hotkey: HotkeyModifiers, sensitive: bool) {
+    std::thread::spawn(move || {
+        std::thread::sleep(std::time::Duration::from_secs(1));
+        // the file handlers don't take any actions anymore
+        if shutdown::is_stopping() {
+            return;
         }
-    } else if event_type == WM_KEYUP || event_type == WM_SYSKEYUP {
-        match key {
-            KeyboardKey::LControlKey => {
-                L_CONTROL_PRESSED.store(false, Ordering::Relaxed);
+
+        with_channel(hotkey, |channel| {
+            send_action(channel, ClipboardAction::Copied { sensitive })
+        })
+        .unwrap_or_else(|e| {
+            log_and_panic(&format!("could not send action: {}", e));
+        });
+    });
+}
+
+/// ctrl+v was pressed. If a remote entry was loaded, it is put on the clipboard before the
+/// application reads it.
+fn on_paste(hotkey: HotkeyModifiers) {
+    let content = with_channel(hotkey, |channel| {
+        let loaded = channel.loaded.lock().unwrap_or_else(|e| {
+            let _ = &format!("Could not aquire lock for the loaded clipboard value. This means the loading of a value failed or is still locking it... {}", e);
+            unreachable!();
+        }).clone();
+        Ok(loaded.map(|entry| (entry, channel.channel.clone())))
+    })
+    .unwrap_or_else(|e| {
+        log_and_panic(&format!("could not read the loaded clipboard: {}", e));
+        None
+    });
+
+    if let Some((entry, channel)) = content {
+        // the key events must not wait. Only a snippet or a transform may be busy with the
+        // clipboard, then the paste goes through untouched
+        let Ok(mut backend) = CLIPBOARD_BACKEND.try_lock() else {
+            events::report_error(
+                ErrorKind::Clipboard,
+                "the clipboard is busy, the paste was not replaced".to_string(),
+            );
+            return;
+        };
+        match backend.as_mut() {
+            Some(backend) => backend
+                .set_text(&entry.text)
+                .unwrap_or_else(|e| log_and_panic(&format!("could not set clipboard: {}", e))),
+            None => log_and_panic("tried to use the clipboard backend, but it was not set"),
+        }
+        drop(backend);
+
+        events::publish(Event::EntryPasted {
+            origin: entry.origin.clone(),
+            timestamp: entry.timestamp,
+            channel,
+        });
+
+        // never the content, the log must not become a clipboard history
+        log(&format!(
+            "Inserted {} bytes from {} at {}{}\n",
+            entry.text.len(),
+            entry.origin,
+            entry.timestamp,
+            if entry.expires.is_some() {
+                ", sensitive"
+            } else {
+                ""
             }
-            KeyboardKey::RControlKey => {
-                R_CONTROL_PRESSED.store(false, Ordering::Relaxed);
+        ));
+        // in queue mode this moves on to the next entry. A sensitive one is cleared from the
+        // clipboard again once it expired
+        let expiring = entry.expires.map(|expires| (entry.text, expires));
+        with_channel(hotkey, |channel| {
+            send_action(channel, ClipboardAction::Pasted { expiring })
+        })
+        .unwrap_or_else(|e| log_and_panic(&format!("could not send action: {}", e)));
+    }
+}
+
+/// ctrl+shift and a number was pressed. The pinned snippet with that index is put on the
+/// clipboard, ready to be pasted.
+fn on_snippet(index: u8) {
+    let Some(config) = SNIPPET_CONFIG
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .clone()
+    else {
+        return;
+    };
+
+    // reading the share may take a moment. Don't block the key events meanwhile
+    std::thread::spawn(move || match snippets::at(&config, index.into()) {
+        Ok(Some(snippet)) => {
+            let content = String::from_utf8_lossy(&snippet.content).into_owned();
+            match with_backend(|backend| backend.set_text(&content)) {
+                Ok(()) => log(&format!(
+                    "Snippet {}, {} bytes\n",
+                    snippet.id,
+                    snippet.content.len()
+                )),
+                Err(e) => events::report_error(
+                    ErrorKind::Clipboard,
+                    format!("could not set clipboard: {}", e),
+                ),
             }
-            _ => {}
         }
-    }
+        Ok(None) => log(&format!("there is no snippet {}\n", index)),
+        Err(e) => events::report_error(
+            ErrorKind::Snippets,
+            format!("could not read snippets: {}", e),
+        ),
+    });
+}
 
-    return CallNextHookEx(None, code, w_param, l_param);
+/// ctrl+shift+alt and a number was pressed. The transform with that index is applied to the text
+/// on the local clipboard. Nothing is published, the next copy does that.
+fn on_transform(index: u8) {
+    let Some(transform) = TRANSFORM_HOTKEYS
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .get(usize::from(index) - 1)
+        .copied()
+    else {
+        return;
+    };
+
+    // the key events must not wait for the clipboard
+    std::thread::spawn(move || {
+        let result = with_backend(|backend| {
+            let Some(text) = backend.get_text()? else {
+                return Ok(());
+            };
+            backend.set_text(&transform.apply(&text))
+        });
+
+        match result {
+            Ok(()) => log(&format!("Applied {} to the clipboard\n", transform.name())),
+            Err(e) => events::report_error(
+                ErrorKind::Clipboard,
+                format!("could not transform the clipboard: {}", e),
+            ),
+        }
+    });
 }
@@ -0,0 +1,123 @@
+use super::keyboard::{KeyEvent, KeyboardKey};
+use super::{handle_key_event, HotkeyBackend, Stopper};
+use crate::logfile::log;
+use anyhow::{Context, Result};
+use std::ffi::{c_int, c_ulong, c_void};
+use std::sync::atomic::{AtomicBool, AtomicPtr, Ordering};
+use std::sync::Arc;
+use windows::Win32::Foundation::{LPARAM, LRESULT, WPARAM};
+use windows::Win32::UI::WindowsAndMessaging::{
+    CallNextHookEx, GetMessageW, KillTimer, SetTimer, SetWindowsHookExW, UnhookWindowsHookEx,
+    HHOOK, KBDLLHOOKSTRUCT, MSG, WH_KEYBOARD_LL, WINDOWS_HOOK_ID, WM_KEYDOWN, WM_KEYUP,
+    WM_SYSKEYDOWN, WM_SYSKEYUP,
+};
+
+pub struct KeyboardListener {
+    // stores the hook for our event so it can be unset later. Null while there is none
+    keyboard_hook: AtomicPtr<c_void>,
+    timer_id: Option<c_ulong>,
+    // set by the stopper. The timer wakes up the message loop to notice it
+    stop: Arc<AtomicBool>,
+}
+
+impl KeyboardListener {
+    pub fn new() -> Self {
+        Self {
+            keyboard_hook: AtomicPtr::default(),
+            timer_id: None,
+            stop: Arc::default(),
+        }
+    }
+
+    /// runs the message loop the hook needs until it is stopped or WM_QUIT arrives
+    pub fn handle_input_events(&mut self) -> Result<()> {
+        Self::set_hook(WH_KEYBOARD_LL, &self.keyboard_hook, keybd_proc)?;
+
+        let timer_id = unsafe { SetTimer(None, 0, 100, None) };
+        self.timer_id = Some(timer_id as c_ulong);
+
+        while !self.stop.load(Ordering::Relaxed) {
+            let mut msg = MSG::default();
+            match unsafe { GetMessageW(&mut msg, None, 0, 0) }.0 {
+                // WM_QUIT
+                0 => break,
+                -1 => {
+                    return Err(windows::core::Error::from_win32())
+                        .with_context(|| "could not get a message")
+                }
+                _ => {}
+            }
+        }
+
+        Ok(())
+    }
+
+    /// register the hook into the win-api
+    fn set_hook(
+        hook_id: WINDOWS_HOOK_ID,
+        hook_ptr: &AtomicPtr<c_void>,
+        hook_proc: unsafe extern "system" fn(c_int, WPARAM, LPARAM) -> LRESULT,
+    ) -> Result<()> {
+        let hook = unsafe { SetWindowsHookExW(hook_id, Some(hook_proc), None, 0) }
+            .with_context(|| "could not hook the keyboard")?;
+        hook_ptr.store(hook.0, Ordering::Relaxed);
+        Ok(())
+    }
+
+    /// unregisters the hook from the win-api, if it is set
+    fn unset_hook(hook_ptr: &AtomicPtr<c_void>) {
+        let hook = hook_ptr.swap(std::ptr::null_mut(), Ordering::Relaxed);
+        if !hook.is_null() {
+            if let Err(e) = unsafe { UnhookWindowsHookEx(HHOOK(hook)) } {
+                log(&format!("could not unhook the keyboard: {}\n", e));
+            }
+        }
+    }
+
+    /// unhooks the keyboard and stops the timer. Runs as soon as listen returns, not only once
+    /// the listener is dropped
+    fn release(&mut self) {
+        if let Some(timer_id) = self.timer_id.take() {
+            let _ = unsafe { KillTimer(None, timer_id as usize) };
+        }
+
+        Self::unset_hook(&self.keyboard_hook);
+    }
+}
+
+impl HotkeyBackend for KeyboardListener {
+    fn listen(&mut self) -> Result<()> {
+        let result = self.handle_input_events();
+        self.release();
+        result
+    }
+
+    fn stopper(&self) -> Stopper {
+        let stop = self.stop.clone();
+        Box::new(move || stop.store(true, Ordering::Relaxed))
+    }
+}
+
+impl Drop for KeyboardListener {
+    fn drop(&mut self) {
+        self.release();
+    }
+}
+
+/// handler for the win-api
+unsafe extern "system" fn keybd_proc(code: c_int, w_param: WPARAM, l_param: LPARAM) -> LRESULT {
+    let event_type = w_param.0 as u32;
+    let key_code = u64::from((*(l_param.0 as *const KBDLLHOOKSTRUCT)).vkCode);
+    let key = KeyboardKey::from(key_code);
+
+    if event_type == WM_KEYDOWN || event_type == WM_SYSKEYDOWN {
+        handle_key_event(KeyEvent { key, pressed: true });
+    } else if event_type == WM_KEYUP || event_type == WM_SYSKEYUP {
+        handle_key_event(KeyEvent {
+            key,
+            pressed: false,
+        });
+    }
+
+    return CallNextHookEx(None, code, w_param, l_param);
+}
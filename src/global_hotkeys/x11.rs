@@ -0,0 +1,206 @@
+use super::keyboard::{KeyEvent, KeyboardKey};
+use super::{dispatch, handle_key_event, HotkeyBackend, Stopper, CHORD_MATCHER};
+use anyhow::{Context, Result};
+use std::sync::Arc;
+use x11rb::connection::Connection;
+use x11rb::protocol::xproto::{
+    Allow, AtomEnum, ClientMessageEvent, ConnectionExt, CreateWindowAux, EventMask, Grab, GrabMode,
+    KeyButMask, Keycode, ModMask, Window, WindowClass,
+};
+use x11rb::protocol::Event;
+use x11rb::rust_connection::RustConnection;
+
+// keysyms of the keys we are interested in, see KeyboardKey::from_x11_keysym
+const GRABBED_KEYSYMS: [u32; 13] = [
+    0x63, 0x76, 0xffe3, 0xffe4, 0x31, 0x32, 0x33, 0x34, 0x35, 0x36, 0x37, 0x38, 0x39,
+];
+
+/// grabs the hotkeys on the root window of an X11 server.
+///
+/// A grab takes the key away from the focused application. The grabs are synchronous, so the
+/// keyboard freezes until the event is handled and then the event is replayed to the application.
+/// That way the clipboard is already set when the application sees ctrl+v.
+pub struct X11Hotkeys {
+    // shared with the stopper, a connection can be used by several threads at once
+    connection: Arc<RustConnection>,
+    root: Window,
+    // an invisible window. A message to it stops listening
+    stop_window: Window,
+    // keycode -> key, only for the grabbed keys
+    keys: Vec<(Keycode, KeyboardKey)>,
+}
+
+impl X11Hotkeys {
+    pub fn grab() -> Result<Self> {
+        let (connection, screen_num) =
+            x11rb::connect(None).with_context(|| "could not connect to the X11 server")?;
+        let root = connection.setup().roots[screen_num].root;
+
+        let keys = Self::keycodes(&connection)?;
+
+        for (keycode, key) in &keys {
+            let modifiers = match key {
+                // the control keys themselves are grabbed regardless of any modifier
+                KeyboardKey::LControlKey | KeyboardKey::RControlKey => vec![ModMask::ANY],
+                // caps lock and num lock are modifiers as well. Grab every combination of them
+                // with each of the hotkey modifiers (ctrl, ctrl+shift, ctrl+alt and
+                // ctrl+shift+alt for the sensitive copy)
+                _ => [
+                    ModMask::from(0u16),
+                    ModMask::SHIFT,
+                    ModMask::M1,
+                    ModMask::SHIFT | ModMask::M1,
+                ]
+                .into_iter()
+                .flat_map(|hotkey| {
+                    [
+                        ModMask::from(0u16),
+                        ModMask::LOCK,
+                        ModMask::M2,
+                        ModMask::LOCK | ModMask::M2,
+                    ]
+                    .into_iter()
+                    .map(move |locks| ModMask::CONTROL | hotkey | locks)
+                })
+                .collect(),
+            };
+
+            for modifiers in modifiers {
+                Self::grab_key(&connection, root, modifiers, *keycode)?;
+            }
+        }
+
+        let stop_window = connection.generate_id()?;
+        connection
+            .create_window(
+                0,
+                stop_window,
+                root,
+                0,
+                0,
+                1,
+                1,
+                0,
+                WindowClass::INPUT_ONLY,
+                x11rb::COPY_FROM_PARENT,
+                &CreateWindowAux::new(),
+            )?
+            .check()
+            .with_context(|| "could not create a window")?;
+
+        connection.flush()?;
+        Ok(Self {
+            connection: Arc::new(connection),
+            root,
+            stop_window,
+            keys,
+        })
+    }
+
+    /// looks up which keycodes produce the keysyms we are interested in
+    fn keycodes(connection: &RustConnection) -> Result<Vec<(Keycode, KeyboardKey)>> {
+        let setup = connection.setup();
+        let count = setup.max_keycode - setup.min_keycode + 1;
+        let mapping = connection
+            .get_keyboard_mapping(setup.min_keycode, count)?
+            .reply()?;
+
+        let per_keycode = usize::from(mapping.keysyms_per_keycode);
+        let mut keys = Vec::new();
+
+        for (i, keysyms) in mapping.keysyms.chunks(per_keycode.max(1)).enumerate() {
+            // the first keysym is the one without shift
+            if let Some(keysym) = keysyms.first().filter(|k| GRABBED_KEYSYMS.contains(k)) {
+                let keycode = setup.min_keycode + i as u8;
+                keys.push((keycode, KeyboardKey::from_x11_keysym(*keysym)));
+            }
+        }
+
+        Ok(keys)
+    }
+
+    fn grab_key(
+        connection: &RustConnection,
+        root: Window,
+        modifiers: ModMask,
+        keycode: Keycode,
+    ) -> Result<()> {
+        connection
+            .grab_key(
+                true,
+                root,
+                modifiers,
+                keycode,
+                GrabMode::ASYNC,
+                GrabMode::SYNC,
+            )?
+            .check()
+            .with_context(|| format!("could not grab keycode {}", keycode))
+    }
+
+    fn key(&self, keycode: Keycode) -> KeyboardKey {
+        self.keys
+            .iter()
+            .find(|(code, _)| *code == keycode)
+            .map(|(_, key)| *key)
+            .unwrap_or(KeyboardKey::OtherKey(u64::from(keycode)))
+    }
+}
+
+impl HotkeyBackend for X11Hotkeys {
+    fn listen(&mut self) -> Result<()> {
+        loop {
+            let event = match self.connection.wait_for_event()? {
+                Event::KeyPress(event) => event,
+                Event::ClientMessage(message) if message.window == self.stop_window => break,
+                _ => continue,
+            };
+
+            let key = self.key(event.detail);
+            match key {
+                KeyboardKey::LControlKey | KeyboardKey::RControlKey => {
+                    handle_key_event(KeyEvent { key, pressed: true });
+                }
+                _ => {
+                    // the releases are never seen here, so the control state comes from the
+                    // modifiers of the event itself
+                    let hotkey = {
+                        let mut matcher = CHORD_MATCHER.lock().unwrap_or_else(|e| e.into_inner());
+                        matcher.sync_modifiers(
+                            event.state.contains(KeyButMask::CONTROL),
+                            event.state.contains(KeyButMask::SHIFT),
+                            event.state.contains(KeyButMask::MOD1),
+                        );
+                        matcher.feed(KeyEvent { key, pressed: true })
+                    };
+                    dispatch(hotkey);
+                }
+            }
+
+            // hand the key over to the focused application
+            self.connection
+                .allow_events(Allow::REPLAY_KEYBOARD, event.time)?;
+            self.connection.flush()?;
+        }
+
+        self.connection
+            .ungrab_key(Grab::ANY, self.root, ModMask::ANY)?
+            .check()
+            .with_context(|| "could not release the keys")?;
+        self.connection.destroy_window(self.stop_window)?;
+        self.connection.flush()?;
+        Ok(())
+    }
+
+    fn stopper(&self) -> Stopper {
+        let connection = self.connection.clone();
+        let window = self.stop_window;
+
+        Box::new(move || {
+            let message = ClientMessageEvent::new(32, window, AtomEnum::NONE, [0u32; 5]);
+            // without an event mask it goes to the client which created the window, that is us
+            let _ = connection.send_event(false, window, EventMask::NO_EVENT, message);
+            let _ = connection.flush();
+        })
+    }
+}
@@ -0,0 +1,440 @@
+// Hooks: external commands which see every entry before it is sent or once it is received.
+//
+// hook.<name>.command=<command line>   run with sh -c (cmd /C on windows)
+// hook.<name>.on=send|receive          send hooks run after the filters, receive hooks when this
+//                                      instance takes a remote entry to paste it
+// hook.<name>.timeout=5                the hook is killed after this many seconds
+// hook.<name>.on_failure=accept        what happens if the hook fails, times out or can't be
+//                                      started: accept or reject
+//
+// The content is written to stdin of the hook, the rest is passed as environment variables:
+//
+// CLIPBOARD_EVENT        send or receive
+// CLIPBOARD_ORIGIN       the machine which sent the entry
+// CLIPBOARD_TIMESTAMP    when it was sent, seconds since the epoch
+// CLIPBOARD_CHANNEL      the named channel, empty for the default one
+// CLIPBOARD_SENSITIVE    1 for sensitive entries, 0 otherwise
+// CLIPBOARD_ENCODING     the encoding the content originally had, stdin is always utf-8 text
+//                        unless this is binary
+// CLIPBOARD_LINE_ENDING  lf or crlf
+//
+// The exit code decides what happens with the entry:
+//
+// 0   accept it as it is
+// 1   reject it. A rejected send is not published, a rejected receive is dropped
+// 10  accept it with the output of the hook as the new content
+//
+// Hooks run one after another in the order of their names, each one sees the content the
+// previous one left. They run on a thread of their own, and all hooks of an entry together get
+// at most 10 seconds. A hook still running then is killed like on its own timeout. A hook never
+// takes the file handler down with it, every failure ends up in the log.
+
+use crate::events::{self, ErrorKind};
+use crate::text_format::TextFormat;
+use anyhow::{bail, Context, Result};
+use std::io::{Read, Write};
+use std::process::{Child, Command, ExitStatus, Stdio};
+use std::sync::mpsc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+pub const DEFAULT_HOOK_TIMEOUT_SECS: u64 = 5;
+
+const EXIT_ACCEPT: i32 = 0;
+const EXIT_REJECT: i32 = 1;
+const EXIT_MODIFY: i32 = 10;
+
+// how often a running hook is checked for its exit
+const WAIT_INTERVAL: Duration = Duration::from_millis(10);
+// how long the file handler waits for all hooks of an entry together
+const HOOKS_TIMEOUT: Duration = Duration::from_secs(10);
+// what a hook killed at the deadline gets to be cleaned up
+const KILL_GRACE: Duration = Duration::from_secs(1);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HookEvent {
+    Send,
+    Receive,
+}
+
+impl HookEvent {
+    pub fn parse(value: &str) -> Result<Self> {
+        match value {
+            "send" => Ok(HookEvent::Send),
+            "receive" => Ok(HookEvent::Receive),
+            _ => bail!("hooks run on send or receive: {}", value),
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            HookEvent::Send => "send",
+            HookEvent::Receive => "receive",
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Hook {
+    pub name: String,
+    pub command: String,
+    pub event: HookEvent,
+    pub timeout: Duration,
+    /// accept the entry if the hook fails. Otherwise it is rejected
+    pub accept_on_failure: bool,
+}
+
+/// what the hooks and the script get to know about the entry besides its content
+pub struct HookContext<'a> {
+    pub event: HookEvent,
+    pub origin: &'a str,
+    pub timestamp: u64,
+    pub channel: Option<&'a str>,
+    pub sensitive: bool,
+    pub format: TextFormat,
+}
+
+pub enum HookOutcome {
+    /// the content, modified by the hooks or not
+    Accept(Vec<u8>),
+    /// the name of the hook which rejected the entry
+    Reject(String),
+}
+
+/// runs the hooks of context.event on the content
+pub fn run(hooks: &[Hook], context: &HookContext, content: Vec<u8>) -> HookOutcome {
+    let hooks: Vec<_> = hooks
+        .iter()
+        .filter(|h| h.event == context.event)
+        .cloned()
+        .collect();
+    if hooks.is_empty() {
+        return HookOutcome::Accept(content);
+    }
+
+    let deadline = Instant::now() + HOOKS_TIMEOUT;
+    let env = environment(context);
+    // whatever a hook does, the file handler gets its answer by the deadline
+    let (outcome_sender, outcome) = mpsc::channel();
+    let running = hooks.clone();
+    let input = content.clone();
+    thread::spawn(move || {
+        let _ = outcome_sender.send(run_all(&running, &env, input, deadline));
+    });
+
+    match outcome.recv_timeout(deadline.saturating_duration_since(Instant::now()) + KILL_GRACE) {
+        Ok(outcome) => outcome,
+        // the hooks are killed at the deadline. This only covers a thread which can't even get
+        // that far
+        Err(_) => {
+            events::report_error(ErrorKind::Hook, "the hooks timed out".to_string());
+            match hooks.iter().find(|h| !h.accept_on_failure) {
+                Some(hook) => HookOutcome::Reject(hook.name.clone()),
+                None => HookOutcome::Accept(content),
+            }
+        }
+    }
+}
+
+/// the environment variables describing the entry
+fn environment(context: &HookContext) -> Vec<(&'static str, String)> {
+    vec![
+        ("CLIPBOARD_EVENT", context.event.name().to_string()),
+        ("CLIPBOARD_ORIGIN", context.origin.to_string()),
+        ("CLIPBOARD_TIMESTAMP", context.timestamp.to_string()),
+        (
+            "CLIPBOARD_CHANNEL",
+            context.channel.unwrap_or_default().to_string(),
+        ),
+        (
+            "CLIPBOARD_SENSITIVE",
+            if context.sensitive { "1" } else { "0" }.to_string(),
+        ),
+        (
+            "CLIPBOARD_ENCODING",
+            context.format.encoding.name().to_string(),
+        ),
+        (
+            "CLIPBOARD_LINE_ENDING",
+            context.format.line_ending.name().to_string(),
+        ),
+    ]
+}
+
+fn run_all(
+    hooks: &[Hook],
+    env: &[(&str, String)],
+    mut content: Vec<u8>,
+    deadline: Instant,
+) -> HookOutcome {
+    for hook in hooks {
+        match run_hook(
+            hook,
+            env,
+            &content,
+            deadline.min(Instant::now() + hook.timeout),
+        ) {
+            Ok(HookResult::Accept) => {}
+            Ok(HookResult::Modify(modified)) => content = modified,
+            Ok(HookResult::Reject) => return HookOutcome::Reject(hook.name.clone()),
+            Err(e) => {
+                events::report_error(
+                    ErrorKind::Hook,
+                    format!("hook {} failed: {:#}", hook.name, e),
+                );
+                if !hook.accept_on_failure {
+                    return HookOutcome::Reject(hook.name.clone());
+                }
+            }
+        }
+    }
+
+    HookOutcome::Accept(content)
+}
+
+enum HookResult {
+    Accept,
+    Modify(Vec<u8>),
+    Reject,
+}
+
+/// runs a single hook. It is killed at the deadline
+fn run_hook(
+    hook: &Hook,
+    env: &[(&str, String)],
+    content: &[u8],
+    deadline: Instant,
+) -> Result<HookResult> {
+    let mut child = shell(&hook.command)
+        .envs(env.iter().map(|(key, value)| (key, value)))
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .with_context(|| format!("could not start {}", hook.command))?;
+
+    // stdin and stdout get their own threads, a hook which doesn't read its input or writes a lot
+    // must not block the other side
+    let mut stdin = child.stdin.take().unwrap();
+    let input = content.to_vec();
+    let (written_sender, written) = mpsc::channel();
+    thread::spawn(move || {
+        let _ = written_sender.send(stdin.write_all(&input));
+    });
+
+    let mut stdout = child.stdout.take().unwrap();
+    let (output_sender, output) = mpsc::channel();
+    thread::spawn(move || {
+        let mut buf = Vec::new();
+        let _ = output_sender.send(stdout.read_to_end(&mut buf).map(|_| buf));
+    });
+
+    let status = wait_until(&mut child, deadline)?;
+
+    // a process the hook started in the background may still hold stdin open
+    match written.recv_timeout(deadline.saturating_duration_since(Instant::now())) {
+        Ok(Ok(())) => {}
+        // a hook doesn't have to read its input
+        Ok(Err(e)) if e.kind() == std::io::ErrorKind::BrokenPipe => {}
+        Ok(Err(e)) => return Err(e).with_context(|| "could not write the input"),
+        Err(_) => bail!("timed out writing the input"),
+    }
+
+    match status.code() {
+        Some(EXIT_ACCEPT) => Ok(HookResult::Accept),
+        Some(EXIT_REJECT) => Ok(HookResult::Reject),
+        Some(EXIT_MODIFY) => {
+            // a process the hook started in the background may still hold stdout open
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            let output = output
+                .recv_timeout(remaining)
+                .with_context(|| "timed out reading the output")?
+                .with_context(|| "could not read the output")?;
+            Ok(HookResult::Modify(output))
+        }
+        Some(code) => bail!("unknown exit code {}", code),
+        None => bail!("terminated by a signal"),
+    }
+}
+
+/// waits for the child to exit. It is killed once the deadline passed
+fn wait_until(child: &mut Child, deadline: Instant) -> Result<ExitStatus> {
+    loop {
+        if let Some(status) = child.try_wait()? {
+            return Ok(status);
+        }
+
+        if Instant::now() >= deadline {
+            let _ = child.kill();
+            let _ = child.wait();
+            bail!("timed out");
+        }
+
+        thread::sleep(WAIT_INTERVAL);
+    }
+}
+
+#[cfg(windows)]
+fn shell(command: &str) -> Command {
+    let mut shell = Command::new("cmd");
+    shell.arg("/C").arg(command);
+    shell
+}
+
+#[cfg(not(windows))]
+fn shell(command: &str) -> Command {
+    let mut shell = Command::new("sh");
+    shell.arg("-c").arg(command);
+    shell
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+
+    fn hook(name: &str, command: &str) -> Hook {
+        Hook {
+            name: name.to_string(),
+            command: command.to_string(),
+            event: HookEvent::Send,
+            timeout: Duration::from_secs(DEFAULT_HOOK_TIMEOUT_SECS),
+            accept_on_failure: true,
+        }
+    }
+
+    fn context(event: HookEvent) -> HookContext<'static> {
+        HookContext {
+            event,
+            origin: "pc1",
+            timestamp: 1700000000,
+            channel: Some("team"),
+            sensitive: true,
+            format: TextFormat::default(),
+        }
+    }
+
+    fn accepted(outcome: HookOutcome) -> String {
+        match outcome {
+            HookOutcome::Accept(content) => String::from_utf8(content).unwrap(),
+            HookOutcome::Reject(hook) => panic!("rejected by {}", hook),
+        }
+    }
+
+    fn rejected_by(outcome: HookOutcome) -> String {
+        match outcome {
+            HookOutcome::Accept(_) => panic!("accepted"),
+            HookOutcome::Reject(hook) => hook,
+        }
+    }
+
+    fn send(hooks: &[Hook], content: &str) -> HookOutcome {
+        run(
+            hooks,
+            &context(HookEvent::Send),
+            content.as_bytes().to_vec(),
+        )
+    }
+
+    #[test]
+    fn exit_codes_decide() {
+        assert_eq!(
+            accepted(send(&[hook("ok", "cat > /dev/null")], "text")),
+            "text"
+        );
+        assert_eq!(rejected_by(send(&[hook("no", "exit 1")], "text")), "no");
+        assert_eq!(
+            accepted(send(&[hook("upper", "tr a-z A-Z; exit 10")], "text")),
+            "TEXT"
+        );
+    }
+
+    #[test]
+    fn hooks_see_what_the_previous_one_left() {
+        let hooks = [
+            hook("a", "sed s/one/two/; exit 10"),
+            hook("b", "sed s/two/three/; exit 10"),
+        ];
+        assert_eq!(accepted(send(&hooks, "one")), "three");
+
+        let hooks = [hook("a", "tr a-z A-Z; exit 10"), hook("b", "exit 1")];
+        assert_eq!(rejected_by(send(&hooks, "one")), "b");
+    }
+
+    #[test]
+    fn the_entry_is_described_in_the_environment() {
+        let describe = hook(
+            "env",
+            r#"printf '%s %s %s %s %s %s %s' "$CLIPBOARD_EVENT" "$CLIPBOARD_ORIGIN" "$CLIPBOARD_TIMESTAMP" "$CLIPBOARD_CHANNEL" "$CLIPBOARD_SENSITIVE" "$CLIPBOARD_ENCODING" "$CLIPBOARD_LINE_ENDING"; exit 10"#,
+        );
+        assert_eq!(
+            accepted(send(&[describe], "")),
+            "send pc1 1700000000 team 1 utf-8 lf"
+        );
+    }
+
+    #[test]
+    fn only_the_hooks_of_the_event_run() {
+        let receive = Hook {
+            event: HookEvent::Receive,
+            ..hook("receive", "exit 1")
+        };
+        let hooks = [receive];
+        assert_eq!(accepted(send(&hooks, "text")), "text");
+
+        let outcome = run(&hooks, &context(HookEvent::Receive), b"text".to_vec());
+        assert_eq!(rejected_by(outcome), "receive");
+    }
+
+    #[test]
+    fn failures_follow_on_failure() {
+        assert_eq!(accepted(send(&[hook("unknown", "exit 3")], "text")), "text");
+
+        let strict = Hook {
+            accept_on_failure: false,
+            ..hook("unknown", "exit 3")
+        };
+        assert_eq!(rejected_by(send(&[strict], "text")), "unknown");
+
+        let missing = Hook {
+            accept_on_failure: false,
+            ..hook("missing", "exec /nonexistent/hook")
+        };
+        assert_eq!(rejected_by(send(&[missing], "text")), "missing");
+    }
+
+    #[test]
+    fn a_slow_hook_is_killed() {
+        let slow = Hook {
+            timeout: Duration::from_millis(200),
+            accept_on_failure: false,
+            ..hook("slow", "sleep 5")
+        };
+
+        let start = Instant::now();
+        assert_eq!(rejected_by(send(&[slow], "text")), "slow");
+        assert!(start.elapsed() < Duration::from_secs(2));
+    }
+
+    #[test]
+    fn a_hook_may_ignore_its_input() {
+        let content = "x".repeat(1024 * 1024);
+        assert_eq!(
+            accepted(send(&[hook("ignore", "exit 0")], &content)).len(),
+            content.len()
+        );
+    }
+
+    #[test]
+    fn a_background_process_holding_stdin_doesnt_block() {
+        // the hook exits, but what it started keeps stdin open without reading it
+        let holder = Hook {
+            timeout: Duration::from_millis(500),
+            accept_on_failure: false,
+            ..hook("holder", "exec 3<&0; sleep 3 <&3 & exit 0")
+        };
+
+        let start = Instant::now();
+        let content = "x".repeat(1024 * 1024);
+        assert_eq!(rejected_by(send(&[holder], &content)), "holder");
+        assert!(start.elapsed() < Duration::from_secs(2));
+    }
+}
@@ -0,0 +1,37 @@
+// The clipboard synchronization as a library. The binary in main.rs is one user of it, other
+// programs can run the parts they need in-process. Their events are the way to react to what
+// the instance does:
+//
+// let events = clipboard::subscribe();
+// for event in events.iter() {
+//     if let clipboard::Event::EntryReceived { origin, .. } = event { ... }
+// }
+
+pub mod cli;
+pub mod clipboard_backend;
+pub mod config;
+pub mod control;
+pub mod discovery;
+pub mod entry;
+pub mod events;
+pub mod file_handlers;
+pub mod filters;
+pub mod gc;
+pub mod global_hotkeys;
+pub mod hooks;
+pub mod logfile;
+pub mod metrics;
+pub mod pick;
+pub mod pipe;
+pub mod presence;
+pub mod queue;
+pub mod registers;
+pub mod script;
+pub mod shutdown;
+pub mod snippets;
+pub mod text_format;
+pub mod transforms;
+pub mod transport;
+pub mod utils;
+
+pub use events::{subscribe, ErrorKind, Event};
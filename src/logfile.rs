@@ -1,25 +1,69 @@
 use crate::utils::get_timestamp;
 use std::fs;
 use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::OnceLock;
 
 static LOGFILE: &str = "log-clipboard-current.tmp";
+// the working directory unless set_log_dir was called
+static LOG_DIR: OnceLock<PathBuf> = OnceLock::new();
+// pipe mode owns stdout. Everything logged goes to stderr instead
+static LOG_TO_STDERR: AtomicBool = AtomicBool::new(false);
+
+pub fn log_to_stderr() {
+    LOG_TO_STDERR.store(true, Ordering::Relaxed);
+}
+
+/// writes the logfile and crash logs to dir from now on. Only the first call counts
+pub fn set_log_dir(dir: &Path) {
+    let _ = LOG_DIR.set(dir.to_path_buf());
+}
+
+fn log_path(file_name: &str) -> PathBuf {
+    match LOG_DIR.get() {
+        Some(dir) => dir.join(file_name),
+        None => PathBuf::from(file_name),
+    }
+}
 
 pub fn log(content: &str) {
     let mut file = fs::OpenOptions::new()
         .append(true)
         .create(true)
-        .open(LOGFILE)
+        .open(log_path(LOGFILE))
         .expect("could not open logfile");
 
-    file.write(content.as_bytes())
+    file.write_all(content.as_bytes())
         .expect("could not write to logfile");
 
-    println!("{}", content);
+    if LOG_TO_STDERR.load(Ordering::Relaxed) {
+        eprintln!("{}", content);
+    } else {
+        println!("{}", content);
+    }
 }
 
 pub fn log_and_panic(error: &str) {
     log(error);
     let file_name = format!("crash-clipboard-{}.log", get_timestamp());
-    fs::rename(LOGFILE, &file_name).expect("could not rename logfile");
-    panic!("See logfile {}", file_name);
+    let crash_log = log_path(&file_name);
+    fs::rename(log_path(LOGFILE), &crash_log).expect("could not rename logfile");
+    panic!("See logfile {}", crash_log.display());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::test_dir;
+
+    #[test]
+    fn the_logfile_lands_in_the_log_dir() {
+        let dir = test_dir("logfile");
+        log("logfile test\n");
+        let root = Path::new(&dir).parent().unwrap();
+        let logged = fs::read_to_string(root.join(LOGFILE)).unwrap();
+        assert!(logged.contains("logfile test\n"));
+        assert!(!Path::new(LOGFILE).exists());
+    }
 }
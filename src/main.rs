@@ -1,25 +1,620 @@
-mod config;
-mod file_handlers;
-mod global_hotkeys;
-mod logfile;
-mod utils;
-
+use anyhow::Context;
+use cli::Command;
+use clipboard::{
+    cli, clipboard_backend, config, control, discovery, file_handlers, filters, gc, global_hotkeys,
+    logfile, metrics, pick, pipe, presence, queue, registers, shutdown, snippets, text_format,
+    transport, utils,
+};
+use config::{HotkeyModifiers, TransportKind};
 use logfile::log_and_panic;
+use presence::Presence;
+use std::collections::HashMap;
+use std::io::{IsTerminal, Read, Write};
+use std::time::Duration;
 
 fn main() {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let command = Command::parse(&args).unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        std::process::exit(2);
+    });
+
+    if matches!(
+        command,
+        Command::Send { .. }
+            | Command::Recv { .. }
+            | Command::RegisterGet { .. }
+            | Command::RegisterSet { .. }
+            | Command::SnippetGet { .. }
+            | Command::SnippetAdd { .. }
+            | Command::SnippetEdit { .. }
+            | Command::SnippetDelete { .. }
+            | Command::Pick { .. }
+    ) {
+        logfile::log_to_stderr();
+    }
+
     let conf = config::Config::load().unwrap_or_else(|e| {
         log_and_panic(&format!("Could not load config file {}", &e));
         unreachable!();
     });
 
-    let file_handler = file_handlers::FileHandler::new(conf);
-    let action_sender = file_handlers::provide_file_handler(file_handler);
+    match command {
+        Command::Run => run(conf),
+        Command::Gc { dry_run } => gc(conf, dry_run),
+        Command::GenCert { force } => gen_cert(conf, force),
+        Command::Discover => discover(conf),
+        Command::Approve { name } => approve(conf, &name),
+        Command::Peers => peers(conf),
+        Command::Status { json } => status(conf, json),
+        Command::Send { channel, sensitive } => send(conf, channel, sensitive),
+        Command::Recv { channel } => recv(conf, channel),
+        Command::Queue { channel } => show_queue(conf, channel),
+        Command::QueueClear { channel } => clear_queue(conf, channel),
+        Command::Registers => list_registers(conf),
+        Command::RegisterGet { name } => get_register(conf, &name),
+        Command::RegisterSet { name } => set_register(conf, &name),
+        Command::Snippets => list_snippets(conf),
+        Command::SnippetGet { id } => get_snippet(conf, &id),
+        Command::SnippetAdd { id } => add_snippet(conf, &id),
+        Command::SnippetEdit { id, base } => edit_snippet(conf, &id, base),
+        Command::SnippetDelete { id, base } => delete_snippet(conf, &id, base),
+        Command::Pick { channel } => pick(conf, channel),
+        Command::FilterCheck { text } => check_filters(conf, text),
+    }
+}
+
+fn run(conf: config::Config) {
+    shutdown::handle_signals().unwrap_or_else(|e| {
+        log_and_panic(&format!("Could not set up the shutdown {:#}", &e));
+    });
+
+    if conf.transport == TransportKind::Directory {
+        gc::spawn_sweeper(conf.clone());
+    }
+
+    let heartbeat = presence::spawn_heartbeat(conf.clone());
+
+    if conf.discovery {
+        discovery::spawn_announcer(conf.clone()).unwrap_or_else(|e| {
+            log_and_panic(&format!("Could not start discovery {}", &e));
+        });
+    }
+
+    let mut channels = HashMap::new();
+    channels.insert(None, add_channel(&conf, Some(HotkeyModifiers::Ctrl)));
+
+    for channel in &conf.channels {
+        let channel_conf = conf.for_channel(&channel.name).unwrap_or_else(|e| {
+            log_and_panic(&format!("Could not open channel {}", &e));
+            unreachable!();
+        });
+        // channels without a hotkey are only used through the control socket
+        let action_sender = add_channel(&channel_conf, channel.hotkey);
+        channels.insert(Some(channel.name.clone()), action_sender);
+    }
+
+    let file_handlers = channels.values().cloned().collect();
+
+    if let Some(address) = &conf.metrics_listen {
+        metrics::spawn_endpoint(address).unwrap_or_else(|e| {
+            log_and_panic(&format!("Could not serve the metrics {:#}", &e));
+        });
+    }
+
+    if conf.control {
+        control::spawn_server(&conf, channels).unwrap_or_else(|e| {
+            log_and_panic(&format!("Could not start the control socket {:#}", &e));
+        });
+    }
+
+    if conf.snippet_hotkeys {
+        global_hotkeys::enable_snippets(conf.clone()).unwrap_or_else(|e| {
+            log_and_panic(&format!("Could not enable snippet hotkeys {}", &e));
+        });
+    }
+
+    if !conf.transform_hotkeys.is_empty() {
+        global_hotkeys::enable_transforms(conf.transform_hotkeys.clone()).unwrap_or_else(|e| {
+            log_and_panic(&format!("Could not enable transform hotkeys {}", &e));
+        });
+    }
+
+    let clipboard = clipboard_backend::open().unwrap_or_else(|e| {
+        log_and_panic(&format!("Could not open clipboard {}", &e));
+        unreachable!();
+    });
+
+    global_hotkeys::set_clipboard_backend(clipboard).unwrap_or_else(|e| {
+        log_and_panic(&format!("Could not set clipboard backend {}", &e));
+        unreachable!();
+    });
+
+    let hotkeys = global_hotkeys::open().unwrap_or_else(|e| {
+        log_and_panic(&format!("Could not set up global hotkeys {}", &e));
+        unreachable!();
+    });
+    let hotkeys = global_hotkeys::spawn_listener(hotkeys);
+
+    let reason = shutdown::wait();
+    let instance = shutdown::Instance {
+        config: conf,
+        channels: file_handlers,
+        heartbeat,
+        hotkeys,
+    };
+    std::process::exit(shutdown::shut_down(instance, reason));
+}
 
-    global_hotkeys::set_action_sender(action_sender).unwrap_or_else(|e| {
-        log_and_panic(&format!("Could not set action-sender {}", &e));
+/// starts the file handler of the channel conf is narrowed to and connects the hotkey to it, if
+/// the channel has one. Returns the sender of its actions
+fn add_channel(
+    conf: &config::Config,
+    hotkey: Option<HotkeyModifiers>,
+) -> std::sync::mpsc::Sender<file_handlers::ClipboardAction> {
+    let transport = transport::open(conf).unwrap_or_else(|e| {
+        log_and_panic(&format!("Could not open transport {}", &e));
         unreachable!();
     });
 
-    let mut listener = global_hotkeys::KeyboardListener::new();
-    listener.handle_input_events();
+    let file_handler = file_handlers::FileHandler::new(conf, transport);
+    // reads what was copied with the hotkey, apart from the clipboard the pastes use
+    let clipboard = hotkey.map(|_| {
+        clipboard_backend::open().unwrap_or_else(|e| {
+            log_and_panic(&format!("Could not open clipboard {}", &e));
+            unreachable!();
+        })
+    });
+    let (action_sender, loaded_clipboard) =
+        file_handlers::provide_file_handler(file_handler, clipboard);
+
+    if let Some(hotkey) = hotkey {
+        global_hotkeys::add_channel(
+            conf.channel.clone(),
+            hotkey,
+            action_sender.clone(),
+            loaded_clipboard,
+        )
+        .unwrap_or_else(|e| {
+            log_and_panic(&format!("Could not set action-sender {}", &e));
+            unreachable!();
+        });
+    }
+
+    action_sender
+}
+
+fn gc(conf: config::Config, dry_run: bool) {
+    match gc::collect_garbage(&conf, dry_run) {
+        Ok(garbage) => {
+            let verb = if dry_run { "would remove" } else { "removed" };
+            for g in &garbage {
+                println!("{} {} ({})", verb, g.file_name, g.reason);
+            }
+            println!("{} {} file(s)", verb, garbage.len());
+        }
+        Err(e) => {
+            eprintln!("gc failed: {}", e);
+            std::process::exit(1);
+        }
+    }
+}
+
+fn gen_cert(conf: config::Config, force: bool) {
+    match transport::generate_certificate(&conf, force) {
+        Ok(fingerprint) => {
+            println!("wrote {} and {}", conf.tls_cert, conf.tls_key);
+            println!("fingerprint: {}", fingerprint);
+            println!(
+                "pin it on every remote: peers={}@<host:port>#{}",
+                conf.local_name, fingerprint
+            );
+        }
+        Err(e) => {
+            eprintln!("gen-cert failed: {:#}", e);
+            std::process::exit(1);
+        }
+    }
+}
+
+// how long discover and approve listen for announcements
+const DISCOVERY_DURATION: Duration = Duration::from_secs(5);
+
+fn discover(conf: config::Config) {
+    match discovery::discover(&conf, DISCOVERY_DURATION) {
+        Ok(found) => {
+            for d in &found {
+                let known = if conf.knows(&d.announcement.name) {
+                    "configured"
+                } else {
+                    "new"
+                };
+                println!(
+                    "{} at {} fingerprint {} ({})",
+                    d.announcement.name,
+                    d.address,
+                    d.announcement.fingerprint.as_deref().unwrap_or("-"),
+                    known
+                );
+            }
+            println!("found {} instance(s) in group {}", found.len(), conf.group);
+        }
+        Err(e) => {
+            eprintln!("discover failed: {:#}", e);
+            std::process::exit(1);
+        }
+    }
+}
+
+fn approve(conf: config::Config, name: &str) {
+    match discovery::approve(&conf, name, DISCOVERY_DURATION) {
+        Ok(peer) => {
+            println!(
+                "added {} at {} fingerprint {} to config.ini",
+                peer.name,
+                peer.address,
+                peer.fingerprint.as_deref().unwrap_or("-"),
+            );
+            println!(
+                "make sure this is the fingerprint gen-cert printed on {}",
+                peer.name
+            );
+            println!("restart clipboard to synchronize with it");
+        }
+        Err(e) => {
+            eprintln!("approve failed: {:#}", e);
+            std::process::exit(1);
+        }
+    }
+}
+
+fn peers(conf: config::Config) {
+    let report = presence::peer_status(&conf).unwrap_or_else(|e| {
+        eprintln!("peers failed: {:#}", e);
+        std::process::exit(1);
+    });
+    let now = utils::get_timestamp();
+
+    for peer in &report.remotes {
+        match &peer.presence {
+            Presence::Online(h) => println!(
+                "{}: online (version {}, pid {}, {})",
+                peer.name,
+                h.version,
+                h.pid,
+                h.capabilities.join(",")
+            ),
+            Presence::Stale(h) => println!(
+                "{}: stale, last seen {}s ago",
+                peer.name,
+                now.saturating_sub(h.last_seen)
+            ),
+            Presence::NeverSeen => println!("{}: never seen", peer.name),
+        }
+    }
+
+    for h in &report.unknown {
+        println!(
+            "hint: {} sends heartbeats but is not a remote of any channel. A typo in one of the configs?",
+            h.name
+        );
+    }
+}
+
+// lets scripts tell "not running" apart from a failure, and from recv finding nothing
+const NOT_RUNNING_EXIT_CODE: i32 = 4;
+
+fn status(conf: config::Config, json: bool) {
+    let status = control::connect(&conf)
+        .and_then(|client| client.map(|mut client| client.status()).transpose())
+        .unwrap_or_else(|e| {
+            eprintln!("status failed: {:#}", e);
+            std::process::exit(1);
+        });
+
+    let Some(status) = status else {
+        if json {
+            println!("{{\"running\":false}}");
+        } else {
+            println!("not running");
+        }
+        std::process::exit(NOT_RUNNING_EXIT_CODE);
+    };
+
+    if json {
+        match serde_json::to_string_pretty(&status) {
+            Ok(status) => println!("{}", status),
+            Err(e) => {
+                eprintln!("status failed: {}", e);
+                std::process::exit(1);
+            }
+        }
+        return;
+    }
+
+    println!(
+        "{}: running (version {}, pid {}, up {}s)",
+        status.local_name, status.version, status.pid, status.uptime_secs
+    );
+    println!(
+        "transport {}, paste_mode {}",
+        status.transport, status.paste_mode
+    );
+    if !status.channels.is_empty() {
+        println!("channels: {}", status.channels.join(","));
+    }
+}
+
+fn send(conf: config::Config, channel: Option<String>, sensitive: bool) {
+    if let Err(e) = pipe::send(&conf, channel.as_deref(), sensitive) {
+        eprintln!("send failed: {:#}", e);
+        std::process::exit(1);
+    }
+}
+
+// lets scripts tell "nothing there" apart from a failure
+const NOTHING_RECEIVED_EXIT_CODE: i32 = 3;
+
+fn recv(conf: config::Config, channel: Option<String>) {
+    match pipe::recv(&conf, channel.as_deref()) {
+        Ok(true) => {}
+        Ok(false) => {
+            eprintln!("no remote entry");
+            std::process::exit(NOTHING_RECEIVED_EXIT_CODE);
+        }
+        Err(e) => {
+            eprintln!("recv failed: {:#}", e);
+            std::process::exit(1);
+        }
+    }
+}
+
+fn show_queue(conf: config::Config, channel: Option<String>) {
+    let report = queue::inspect(&conf, channel.as_deref()).unwrap_or_else(|e| {
+        eprintln!("queue failed: {:#}", e);
+        std::process::exit(1);
+    });
+    let now = utils::get_timestamp();
+
+    for (title, entries) in [
+        ("incoming", &report.incoming),
+        ("outgoing", &report.outgoing),
+    ] {
+        println!("{} ({}):", title, entries.len());
+        for (i, e) in entries.iter().enumerate() {
+            println!(
+                "  {}. from {} {}s ago, {} bytes: {}",
+                i + 1,
+                e.file.origin,
+                now.saturating_sub(e.file.timestamp),
+                e.size,
+                e.preview
+            );
+        }
+    }
+}
+
+fn clear_queue(conf: config::Config, channel: Option<String>) {
+    match queue::clear(&conf, channel.as_deref()) {
+        Ok(removed) => println!("removed {} queued entries", removed),
+        Err(e) => {
+            eprintln!("queue failed: {:#}", e);
+            std::process::exit(1);
+        }
+    }
+}
+
+fn list_registers(conf: config::Config) {
+    let registers = registers::list(&conf).unwrap_or_else(|e| {
+        eprintln!("register failed: {:#}", e);
+        std::process::exit(1);
+    });
+    let now = utils::get_timestamp();
+
+    for r in &registers {
+        println!(
+            "{}: set by {} {}s ago",
+            r.name,
+            r.origin,
+            now.saturating_sub(r.timestamp)
+        );
+    }
+    println!("{} register(s)", registers.len());
+}
+
+fn get_register(conf: config::Config, name: &str) {
+    match registers::get(&conf, name) {
+        Ok(Some(content)) => {
+            let mut stdout = std::io::stdout().lock();
+            if let Err(e) = stdout.write_all(&content).and_then(|()| stdout.flush()) {
+                eprintln!("register failed: could not write stdout: {}", e);
+                std::process::exit(1);
+            }
+        }
+        Ok(None) => {
+            eprintln!("register {} is empty", name);
+            std::process::exit(NOTHING_RECEIVED_EXIT_CODE);
+        }
+        Err(e) => {
+            eprintln!("register failed: {:#}", e);
+            std::process::exit(1);
+        }
+    }
+}
+
+fn set_register(conf: config::Config, name: &str) {
+    let mut content = Vec::new();
+    let result = std::io::stdin()
+        .read_to_end(&mut content)
+        .map_err(|e| e.into())
+        .and_then(|_| registers::set(&conf, name, &content));
+
+    if let Err(e) = result {
+        eprintln!("register failed: {:#}", e);
+        std::process::exit(1);
+    }
+}
+
+fn list_snippets(conf: config::Config) {
+    let snippets = snippets::list(&conf).unwrap_or_else(|e| {
+        eprintln!("snippets failed: {:#}", e);
+        std::process::exit(1);
+    });
+
+    for (i, s) in snippets.iter().enumerate() {
+        let content = String::from_utf8_lossy(&s.content);
+        println!(
+            "{}. {} (revision {} by {}): {}",
+            i + 1,
+            s.id,
+            s.revision,
+            s.origin,
+            content.lines().next().unwrap_or_default()
+        );
+        if !s.conflicts.is_empty() {
+            println!(
+                "   conflict: changed by {} at the same time. Edit or delete revision {} to resolve it",
+                s.conflicts.join(", "),
+                s.revision
+            );
+        }
+    }
+    println!("{} snippet(s)", snippets.len());
+}
+
+fn get_snippet(conf: config::Config, id: &str) {
+    match snippets::get(&conf, id) {
+        Ok(Some(snippet)) => {
+            let mut stdout = std::io::stdout().lock();
+            if let Err(e) = stdout
+                .write_all(&snippet.content)
+                .and_then(|()| stdout.flush())
+            {
+                eprintln!("snippets failed: could not write stdout: {}", e);
+                std::process::exit(1);
+            }
+        }
+        Ok(None) => {
+            eprintln!("there is no snippet {}", id);
+            std::process::exit(NOTHING_RECEIVED_EXIT_CODE);
+        }
+        Err(e) => {
+            eprintln!("snippets failed: {:#}", e);
+            std::process::exit(1);
+        }
+    }
+}
+
+fn read_stdin() -> Vec<u8> {
+    let mut content = Vec::new();
+    if let Err(e) = std::io::stdin().read_to_end(&mut content) {
+        eprintln!("could not read stdin: {}", e);
+        std::process::exit(1);
+    }
+    content
+}
+
+fn add_snippet(conf: config::Config, id: &str) {
+    let content = read_stdin();
+    if let Err(e) = snippets::add(&conf, id, &content) {
+        eprintln!("snippets failed: {:#}", e);
+        std::process::exit(1);
+    }
+}
+
+fn edit_snippet(conf: config::Config, id: &str, base: u64) {
+    let content = read_stdin();
+    match snippets::edit(&conf, id, base, &content) {
+        Ok(revision) => eprintln!("{} is now at revision {}", id, revision),
+        Err(e) => {
+            eprintln!("snippets failed: {:#}", e);
+            std::process::exit(1);
+        }
+    }
+}
+
+fn delete_snippet(conf: config::Config, id: &str, base: u64) {
+    if let Err(e) = snippets::delete(&conf, id, base) {
+        eprintln!("snippets failed: {:#}", e);
+        std::process::exit(1);
+    }
+}
+
+fn pick(conf: config::Config, channel: Option<String>) {
+    let entry = match pick::pick(&conf, channel.as_deref()) {
+        Ok(Some(entry)) => entry,
+        Ok(None) => std::process::exit(NOTHING_RECEIVED_EXIT_CODE),
+        Err(e) => {
+            eprintln!("pick failed: {:#}", e);
+            std::process::exit(1);
+        }
+    };
+
+    // redirected stdout means the selection is meant for a pipe or a file
+    let result = if std::io::stdout().is_terminal() {
+        pick::place_on_clipboard(&entry.content)
+    } else {
+        // like recv, in the encoding text_format asks for
+        let content = text_format::encode(entry.content, entry.file.format, conf.text_format);
+        let mut stdout = std::io::stdout().lock();
+        stdout
+            .write_all(&content)
+            .and_then(|()| stdout.flush())
+            .with_context(|| "could not write stdout")
+    };
+
+    if let Err(e) = result {
+        eprintln!("pick failed: {:#}", e);
+        std::process::exit(1);
+    }
+}
+
+fn check_filters(conf: config::Config, text: Option<String>) {
+    let content = match text {
+        Some(text) => text.into_bytes(),
+        None => read_stdin(),
+    };
+
+    if conf.filters.is_empty() {
+        println!("no filters are configured");
+    }
+
+    // the same step publish starts with
+    let check = filters::check(&conf.filters, &content);
+    for f in &check.findings {
+        println!(
+            "{} ({}) matches bytes {} to {}",
+            f.rule,
+            f.action.name(),
+            f.range.start,
+            f.range.end
+        );
+    }
+
+    match check.verdict {
+        filters::Verdict::Block(rules) => println!("blocked by {}", rules.join(", ")),
+        filters::Verdict::Publish {
+            content: published,
+            sensitive,
+        } => {
+            if sensitive {
+                println!(
+                    "published as sensitive entry, removed after {}s",
+                    conf.sensitive_ttl_secs
+                );
+            }
+            if published == check.content {
+                println!("published unchanged");
+                return;
+            }
+
+            println!("published as:");
+            let mut stdout = std::io::stdout().lock();
+            if let Err(e) = stdout.write_all(&published).and_then(|()| stdout.flush()) {
+                eprintln!("filter failed: could not write stdout: {}", e);
+                std::process::exit(1);
+            }
+            println!();
+        }
+    }
 }
@@ -0,0 +1,469 @@
+// Metrics of the running instance, to tell how healthy the synchronization is. They are served
+// in the prometheus text format when metrics_listen is set:
+//
+// metrics_listen=127.0.0.1:9717    curl http://127.0.0.1:9717/metrics
+//
+// and are part of `clipboard status --json`. The published, received and error counters follow
+// the events, see events.rs. Everything starts at zero with every start of the instance.
+//
+// What was sent is only counted per peer with the tcp transport, which delivers every entry to
+// each peer itself. The directory transport writes a single file for all remotes, and whichever
+// remote takes it doesn't tell. There the published entries are counted per channel, and the
+// receiving side counts them per origin.
+
+use crate::events::Event;
+use crate::logfile::log;
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::Write;
+use std::sync::Mutex;
+use std::thread;
+use std::time::Duration;
+
+/// upper bounds in seconds. Loads and scans of a slow share take seconds, a local one a few ms
+const BUCKETS: [f64; 10] = [0.001, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 5.0];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Counter {
+    EntriesPublished,
+    PublishedBytes,
+    EntriesReceived,
+    ReceivedBytes,
+    /// entries the tcp transport handed to a peer
+    EntriesDelivered,
+    DeliveredBytes,
+    Errors,
+    GcDeleted,
+}
+
+const COUNTERS: [Counter; 8] = [
+    Counter::EntriesPublished,
+    Counter::PublishedBytes,
+    Counter::EntriesReceived,
+    Counter::ReceivedBytes,
+    Counter::EntriesDelivered,
+    Counter::DeliveredBytes,
+    Counter::Errors,
+    Counter::GcDeleted,
+];
+
+impl Counter {
+    fn name(&self) -> &'static str {
+        match self {
+            Counter::EntriesPublished => "clipboard_entries_published_total",
+            Counter::PublishedBytes => "clipboard_published_bytes_total",
+            Counter::EntriesReceived => "clipboard_entries_received_total",
+            Counter::ReceivedBytes => "clipboard_received_bytes_total",
+            Counter::EntriesDelivered => "clipboard_entries_delivered_total",
+            Counter::DeliveredBytes => "clipboard_delivered_bytes_total",
+            Counter::Errors => "clipboard_errors_total",
+            Counter::GcDeleted => "clipboard_gc_deleted_total",
+        }
+    }
+
+    fn help(&self) -> &'static str {
+        match self {
+            Counter::EntriesPublished => "Entries published by this instance",
+            Counter::PublishedBytes => "Bytes published by this instance",
+            Counter::EntriesReceived => "Remote entries which arrived",
+            Counter::ReceivedBytes => "Bytes of the remote entries which arrived",
+            Counter::EntriesDelivered => "Entries the tcp transport delivered to a peer",
+            Counter::DeliveredBytes => "Bytes of the entries the tcp transport delivered to a peer",
+            Counter::Errors => "Errors the instance survived",
+            Counter::GcDeleted => "Files the garbage collector deleted",
+        }
+    }
+
+    /// every counter has at most one label
+    fn label(&self) -> Option<&'static str> {
+        match self {
+            Counter::EntriesPublished | Counter::PublishedBytes => Some("channel"),
+            Counter::EntriesReceived | Counter::ReceivedBytes => Some("origin"),
+            Counter::EntriesDelivered | Counter::DeliveredBytes => Some("peer"),
+            Counter::Errors => Some("kind"),
+            Counter::GcDeleted => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Histogram {
+    /// loading the next remote entry, which a paste waits for
+    LoadSeconds,
+    /// reading all file names of dir_name
+    ScanSeconds,
+}
+
+const HISTOGRAMS: [Histogram; 2] = [Histogram::LoadSeconds, Histogram::ScanSeconds];
+
+impl Histogram {
+    fn name(&self) -> &'static str {
+        match self {
+            Histogram::LoadSeconds => "clipboard_load_seconds",
+            Histogram::ScanSeconds => "clipboard_directory_scan_seconds",
+        }
+    }
+
+    fn help(&self) -> &'static str {
+        match self {
+            Histogram::LoadSeconds => "Time to load the next remote entry",
+            Histogram::ScanSeconds => "Time to scan dir_name",
+        }
+    }
+}
+
+#[derive(Default)]
+struct Observations {
+    /// per bucket of BUCKETS, not cumulative
+    buckets: [u64; BUCKETS.len()],
+    count: u64,
+    sum: f64,
+}
+
+impl Observations {
+    fn observe(&mut self, secs: f64) {
+        if let Some(i) = BUCKETS.iter().position(|&bound| secs <= bound) {
+            self.buckets[i] += 1;
+        }
+        self.count += 1;
+        self.sum += secs;
+    }
+
+    fn values(&self) -> HistogramValues {
+        let mut total = 0;
+        let buckets = BUCKETS
+            .iter()
+            .zip(self.buckets)
+            .map(|(&le, count)| {
+                total += count;
+                Bucket { le, count: total }
+            })
+            .collect();
+
+        HistogramValues {
+            buckets,
+            count: self.count,
+            sum: self.sum,
+        }
+    }
+}
+
+/// the observations up to le, like the buckets of prometheus
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Bucket {
+    pub le: f64,
+    pub count: u64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct HistogramValues {
+    pub buckets: Vec<Bucket>,
+    pub count: u64,
+    pub sum: f64,
+}
+
+#[derive(Default)]
+struct Metrics {
+    // (counter, label value) -> value. The label value is empty for counters without a label
+    counters: HashMap<(Counter, String), u64>,
+    histograms: HashMap<Histogram, Observations>,
+}
+
+static METRICS: Mutex<Option<Metrics>> = Mutex::new(None);
+
+fn with_metrics<T>(f: impl FnOnce(&mut Metrics) -> T) -> T {
+    let mut metrics = METRICS.lock().unwrap_or_else(|e| e.into_inner());
+    f(metrics.get_or_insert_with(Metrics::default))
+}
+
+/// adds to the counter. label is ignored for counters without one
+pub fn add(counter: Counter, label: &str, value: u64) {
+    let label = match counter.label() {
+        Some(_) => label.to_string(),
+        None => String::new(),
+    };
+    with_metrics(|m| *m.counters.entry((counter, label)).or_default() += value);
+}
+
+pub fn observe(histogram: Histogram, duration: Duration) {
+    with_metrics(|m| {
+        m.histograms
+            .entry(histogram)
+            .or_default()
+            .observe(duration.as_secs_f64())
+    });
+}
+
+/// counts what the event tells about
+pub fn record(event: &Event) {
+    match event {
+        Event::EntryPublished { channel, size, .. } => {
+            let channel = channel_label(channel.as_deref());
+            add(Counter::EntriesPublished, channel, 1);
+            add(Counter::PublishedBytes, channel, *size as u64);
+        }
+        Event::EntryReceived { origin, size, .. } => {
+            add(Counter::EntriesReceived, origin, 1);
+            add(Counter::ReceivedBytes, origin, *size as u64);
+        }
+        Event::Error { kind, .. } => add(Counter::Errors, kind.name(), 1),
+        Event::EntryPasted { .. } | Event::PeerOnline { .. } | Event::PeerOffline { .. } => {}
+    }
+}
+
+fn channel_label(channel: Option<&str>) -> &str {
+    channel.unwrap_or("default")
+}
+
+/// all metrics, for status --json
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Snapshot {
+    /// name -> label value -> value. The label value is empty for counters without a label
+    pub counters: BTreeMap<String, BTreeMap<String, u64>>,
+    pub histograms: BTreeMap<String, HistogramValues>,
+}
+
+pub fn snapshot() -> Snapshot {
+    with_metrics(|m| {
+        let mut snapshot = Snapshot::default();
+        for ((counter, label), value) in &m.counters {
+            snapshot
+                .counters
+                .entry(counter.name().to_string())
+                .or_default()
+                .insert(label.clone(), *value);
+        }
+        for (histogram, observations) in &m.histograms {
+            snapshot
+                .histograms
+                .insert(histogram.name().to_string(), observations.values());
+        }
+        snapshot
+    })
+}
+
+/// all metrics in the prometheus text format
+pub fn render() -> String {
+    let snapshot = snapshot();
+    let mut text = String::new();
+
+    for counter in COUNTERS {
+        let _ = writeln!(text, "# HELP {} {}", counter.name(), counter.help());
+        let _ = writeln!(text, "# TYPE {} counter", counter.name());
+
+        let values = snapshot.counters.get(counter.name());
+        match counter.label() {
+            Some(label) => {
+                for (value_label, value) in values.into_iter().flatten() {
+                    let _ = writeln!(
+                        text,
+                        "{}{{{}=\"{}\"}} {}",
+                        counter.name(),
+                        label,
+                        escape(value_label),
+                        value
+                    );
+                }
+            }
+            None => {
+                let value = values.and_then(|v| v.get("")).copied().unwrap_or_default();
+                let _ = writeln!(text, "{} {}", counter.name(), value);
+            }
+        }
+    }
+
+    for histogram in HISTOGRAMS {
+        let name = histogram.name();
+        let values = snapshot
+            .histograms
+            .get(name)
+            .cloned()
+            .unwrap_or_else(|| Observations::default().values());
+
+        let _ = writeln!(text, "# HELP {} {}", name, histogram.help());
+        let _ = writeln!(text, "# TYPE {} histogram", name);
+        for bucket in &values.buckets {
+            let _ = writeln!(
+                text,
+                "{}_bucket{{le=\"{}\"}} {}",
+                name, bucket.le, bucket.count
+            );
+        }
+        let _ = writeln!(text, "{}_bucket{{le=\"+Inf\"}} {}", name, values.count);
+        let _ = writeln!(text, "{}_sum {}", name, values.sum);
+        let _ = writeln!(text, "{}_count {}", name, values.count);
+    }
+
+    text
+}
+
+/// label values are names from the config, but those may contain anything
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+/// serves the metrics at http://<address>/metrics
+pub fn spawn_endpoint(address: &str) -> Result<()> {
+    let server = tiny_http::Server::http(address)
+        .map_err(|e| anyhow!("could not listen on {}: {}", address, e))?;
+    log(&format!("serving metrics on http://{}/metrics\n", address));
+
+    thread::spawn(move || {
+        for request in server.incoming_requests() {
+            let response = if request.url() == "/metrics" {
+                let content_type = tiny_http::Header::from_bytes(
+                    &b"Content-Type"[..],
+                    &b"text/plain; version=0.0.4"[..],
+                )
+                .unwrap();
+                tiny_http::Response::from_string(render()).with_header(content_type)
+            } else {
+                tiny_http::Response::from_string("not found\n").with_status_code(404)
+            };
+
+            if let Err(e) = request.respond(response) {
+                log(&format!("could not answer a metrics request: {}\n", e));
+            }
+        }
+    });
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::events::ErrorKind;
+    use std::io::{Read, Write as _};
+    use std::net::{TcpListener, TcpStream};
+
+    // the metrics are shared by all tests, so every test counts under its own labels
+
+    fn counter(name: &str, label: &str) -> Option<u64> {
+        snapshot().counters.get(name)?.get(label).copied()
+    }
+
+    /// a plain http/1.0 request. Returns the whole response
+    fn get(address: &str, path: &str) -> String {
+        let mut stream = TcpStream::connect(address).unwrap();
+        write!(stream, "GET {} HTTP/1.0\r\nHost: {}\r\n\r\n", path, address).unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        response
+    }
+
+    #[test]
+    fn events_are_counted_per_label() {
+        for size in [10, 5] {
+            record(&Event::EntryReceived {
+                origin: "record-origin".to_string(),
+                timestamp: 100,
+                channel: None,
+                size,
+                sensitive: false,
+            });
+        }
+        record(&Event::EntryPublished {
+            origin: "a".to_string(),
+            timestamp: 100,
+            channel: Some("record-channel".to_string()),
+            size: 7,
+            sensitive: false,
+        });
+
+        assert_eq!(
+            counter("clipboard_entries_received_total", "record-origin"),
+            Some(2)
+        );
+        assert_eq!(
+            counter("clipboard_received_bytes_total", "record-origin"),
+            Some(15)
+        );
+        assert_eq!(
+            counter("clipboard_entries_published_total", "record-channel"),
+            Some(1)
+        );
+        assert_eq!(
+            counter("clipboard_published_bytes_total", "record-channel"),
+            Some(7)
+        );
+    }
+
+    #[test]
+    fn counters_without_a_label_ignore_it() {
+        add(Counter::GcDeleted, "label-test", 1);
+
+        let labels: Vec<_> = snapshot().counters["clipboard_gc_deleted_total"]
+            .keys()
+            .cloned()
+            .collect();
+        assert_eq!(labels, [""]);
+    }
+
+    #[test]
+    fn buckets_are_cumulative() {
+        let mut observations = Observations::default();
+        for secs in [0.0005, 0.003, 0.003, 0.7, 60.0] {
+            observations.observe(secs);
+        }
+
+        let values = observations.values();
+        let counts: Vec<_> = values.buckets.iter().map(|b| b.count).collect();
+        assert_eq!(counts, [1, 3, 3, 3, 3, 3, 3, 3, 4, 4]);
+        // the slowest one only counts for +Inf
+        assert_eq!(values.count, 5);
+        assert!((values.sum - 60.7065).abs() < 1e-9);
+    }
+
+    #[test]
+    fn label_values_are_escaped() {
+        add(Counter::EntriesDelivered, "escape\"peer\\\n", 3);
+
+        assert!(render()
+            .contains("clipboard_entries_delivered_total{peer=\"escape\\\"peer\\\\\\n\"} 3\n"));
+    }
+
+    #[test]
+    fn every_metric_is_rendered_even_at_zero() {
+        let text = render();
+
+        for counter in COUNTERS {
+            assert!(text.contains(&format!("# TYPE {} counter\n", counter.name())));
+        }
+        for histogram in HISTOGRAMS {
+            assert!(text.contains(&format!("# TYPE {} histogram\n", histogram.name())));
+            assert!(text.contains(&format!("{}_bucket{{le=\"+Inf\"}}", histogram.name())));
+        }
+        assert!(text.contains("\nclipboard_gc_deleted_total "));
+    }
+
+    #[test]
+    fn the_endpoint_serves_the_metrics() {
+        let address = TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .to_string();
+        spawn_endpoint(&address).unwrap();
+
+        crate::events::publish(Event::Error {
+            kind: ErrorKind::Snippets,
+            message: "endpoint test".to_string(),
+        });
+        add(Counter::DeliveredBytes, "endpoint-peer", 42);
+
+        let response = get(&address, "/metrics");
+        assert!(response.starts_with("HTTP/1.0 200"), "{}", response);
+        assert!(response.contains("text/plain; version=0.0.4"));
+        assert!(response.contains("clipboard_delivered_bytes_total{peer=\"endpoint-peer\"} 42\n"));
+        assert!(response.contains("clipboard_errors_total{kind=\"snippets\"}"));
+
+        assert!(get(&address, "/").starts_with("HTTP/1.0 404"));
+        // the address is taken now
+        assert!(spawn_endpoint(&address).is_err());
+    }
+}
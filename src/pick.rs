@@ -0,0 +1,621 @@
+// Interactive picker for the entries in config.dir_name.
+//
+// clipboard pick                lists the entries of this machine and its remotes, newest first
+// clipboard pick > file         writes the selected entry to stdout instead of the clipboard
+//
+// Typing filters the list with a fuzzy search, up and down move the selection, enter takes it and
+// esc quits. The picker is drawn on stderr, so stdout stays free for the selection.
+//
+// The state and the drawing are kept apart from the terminal, so the picker can be driven with
+// the TestBackend of ratatui as well.
+
+use crate::clipboard_backend;
+use crate::config::{Config, TransportKind};
+use crate::entry::{EntryFile, EntryKind};
+use crate::file_handlers::ReceivePath;
+use crate::text_format;
+use crate::transport::Entry;
+use crate::utils::{get_timestamp, read_file_names};
+use anyhow::{bail, Context, Result};
+use ratatui::backend::{Backend, CrosstermBackend};
+use ratatui::crossterm::event::{self, Event, KeyCode, KeyEvent, KeyEventKind, KeyModifiers};
+use ratatui::crossterm::execute;
+use ratatui::crossterm::terminal::{self, EnterAlternateScreen, LeaveAlternateScreen};
+use ratatui::layout::{Constraint, Layout};
+use ratatui::style::{Modifier, Style};
+use ratatui::text::{Line, Span};
+use ratatui::widgets::{Block, Borders, List, ListItem, ListState, Paragraph, Wrap};
+use ratatui::{Frame, Terminal};
+use std::io::ErrorKind;
+use std::time::Duration;
+
+// how often the clipboard is checked while the selection is held on it
+const HOLD_INTERVAL: Duration = Duration::from_millis(500);
+
+#[derive(Clone)]
+pub struct PickEntry {
+    pub file: EntryFile,
+    /// converted like a paste would insert it. The script and the receive hooks only run once
+    /// the entry is taken, see take
+    pub content: Vec<u8>,
+}
+
+impl PickEntry {
+    fn text(&self) -> std::borrow::Cow<'_, str> {
+        String::from_utf8_lossy(&self.content)
+    }
+}
+
+pub enum PickResult {
+    /// the key was handled, keep going
+    Continue,
+    Selected(PickEntry),
+    Cancelled,
+}
+
+pub struct Picker {
+    entries: Vec<PickEntry>,
+    query: String,
+    /// indices into entries which match the query, best match first
+    matches: Vec<usize>,
+    /// position in matches
+    selected: usize,
+}
+
+impl Picker {
+    /// entries are expected newest first
+    pub fn new(entries: Vec<PickEntry>) -> Self {
+        let mut picker = Self {
+            entries,
+            query: String::new(),
+            matches: Vec::new(),
+            selected: 0,
+        };
+        picker.filter();
+        picker
+    }
+
+    pub fn handle_key(&mut self, key: KeyEvent) -> PickResult {
+        let ctrl = key.modifiers.contains(KeyModifiers::CONTROL);
+
+        match key.code {
+            KeyCode::Esc => return PickResult::Cancelled,
+            KeyCode::Char('c') if ctrl => return PickResult::Cancelled,
+            KeyCode::Enter => {
+                return match self.matches.get(self.selected) {
+                    Some(&i) => PickResult::Selected(self.entries[i].clone()),
+                    None => PickResult::Continue,
+                };
+            }
+            KeyCode::Up => self.selected = self.selected.saturating_sub(1),
+            KeyCode::Char('p') if ctrl => self.selected = self.selected.saturating_sub(1),
+            KeyCode::Down => self.select_next(),
+            KeyCode::Char('n') if ctrl => self.select_next(),
+            KeyCode::Backspace => {
+                self.query.pop();
+                self.filter();
+            }
+            KeyCode::Char(c) if !ctrl => {
+                self.query.push(c);
+                self.filter();
+            }
+            _ => {}
+        }
+
+        PickResult::Continue
+    }
+
+    fn select_next(&mut self) {
+        if self.selected + 1 < self.matches.len() {
+            self.selected += 1;
+        }
+    }
+
+    fn filter(&mut self) {
+        let mut scored: Vec<_> = self
+            .entries
+            .iter()
+            .enumerate()
+            .filter_map(|(i, e)| fuzzy_score(&self.query, &e.text()).map(|score| (score, i)))
+            .collect();
+
+        // the sort is stable, so equally good matches stay newest first
+        scored.sort_by_key(|&(score, _)| score);
+        self.matches = scored.into_iter().map(|(_, i)| i).collect();
+        self.selected = 0;
+    }
+
+    pub fn draw(&self, frame: &mut Frame, now: u64) {
+        let [search_area, list_area, preview_area] = Layout::vertical([
+            Constraint::Length(1),
+            Constraint::Min(3),
+            Constraint::Length(8),
+        ])
+        .areas(frame.area());
+
+        frame.render_widget(
+            Paragraph::new(Line::from(vec![
+                Span::styled("> ", Style::default().add_modifier(Modifier::BOLD)),
+                Span::raw(self.query.as_str()),
+            ])),
+            search_area,
+        );
+
+        let items: Vec<_> = self
+            .matches
+            .iter()
+            .map(|&i| {
+                let entry = &self.entries[i];
+                let text = entry.text();
+                ListItem::new(format!(
+                    "{:<12} {:>4}  {}",
+                    entry.file.origin,
+                    format_age(now.saturating_sub(entry.file.timestamp)),
+                    text.lines().next().unwrap_or_default()
+                ))
+            })
+            .collect();
+
+        let title = format!(
+            " {}/{} entries  enter: take  esc: quit ",
+            self.matches.len(),
+            self.entries.len()
+        );
+        let list = List::new(items)
+            .block(Block::default().borders(Borders::TOP).title(title))
+            .highlight_style(Style::default().add_modifier(Modifier::REVERSED));
+
+        let mut state = ListState::default();
+        if !self.matches.is_empty() {
+            state.select(Some(self.selected));
+        }
+        frame.render_stateful_widget(list, list_area, &mut state);
+
+        let preview = match self.matches.get(self.selected) {
+            Some(&i) => self.entries[i].text().into_owned(),
+            None => String::new(),
+        };
+        frame.render_widget(
+            Paragraph::new(preview)
+                .block(Block::default().borders(Borders::TOP).title(" preview "))
+                .wrap(Wrap { trim: false }),
+            preview_area,
+        );
+    }
+}
+
+/// None if the characters of the query don't appear in text in this order. Otherwise lower is
+/// better: the length of the shortest stretch of text the query was found in, counted from the
+/// first possible start.
+fn fuzzy_score(query: &str, text: &str) -> Option<usize> {
+    if query.is_empty() {
+        return Some(0);
+    }
+
+    let query: Vec<_> = query.to_lowercase().chars().collect();
+    let text: Vec<_> = text.to_lowercase().chars().collect();
+
+    let mut best = None;
+    for start in 0..text.len() {
+        if text[start] != query[0] {
+            continue;
+        }
+
+        let mut matched = 1;
+        let mut end = start;
+        for (i, &c) in text.iter().enumerate().skip(start + 1) {
+            if matched == query.len() {
+                break;
+            }
+            if c == query[matched] {
+                matched += 1;
+                end = i;
+            }
+        }
+
+        if matched == query.len() {
+            let span = end - start + 1;
+            if best.is_none_or(|b| span < b) {
+                best = Some(span);
+            }
+        }
+    }
+
+    best
+}
+
+fn format_age(seconds: u64) -> String {
+    match seconds {
+        0..60 => format!("{}s", seconds),
+        60..3600 => format!("{}m", seconds / 60),
+        3600..86400 => format!("{}h", seconds / 3600),
+        _ => format!("{}d", seconds / 86400),
+    }
+}
+
+/// the published entries of the channel written by this machine or one of its remotes, newest
+/// first. The line endings of all entries are converted and the receive transforms applied to
+/// those of the remotes. The script and the hooks don't run, listing must not set them off
+pub fn load_entries(
+    config: &Config,
+    channel: Option<&str>,
+    limit: Option<usize>,
+) -> Result<Vec<PickEntry>> {
+    // the entries of the tcp transport only exist inside the running instance
+    if config.transport != TransportKind::Directory {
+        bail!("pick needs transport=directory");
+    }
+
+    let config = match channel {
+        Some(channel) => config.for_channel(channel)?,
+        None => config.clone(),
+    };
+
+    let now = get_timestamp();
+    let mut files: Vec<_> = read_file_names(&config.dir_name)?
+        .iter()
+        .filter_map(|file_name| EntryFile::parse(file_name))
+        .filter(|entry| entry.kind == EntryKind::Published && entry.channel == config.channel)
+        .filter(|entry| {
+            entry.origin == config.local_name || config.remote_names.contains(&entry.origin)
+        })
+        .filter(|entry| !entry.is_expired(now))
+        .collect();
+    files.sort_by_key(|entry| std::cmp::Reverse(entry.timestamp));
+
+    let receiving = ReceivePath::new(&config);
+    let mut entries = Vec::new();
+    for file in files {
+        if limit.is_some_and(|limit| entries.len() >= limit) {
+            break;
+        }
+
+        let file_path = format!("{}/{}", config.dir_name, file.file_name());
+        match std::fs::read(&file_path) {
+            // the own entries were never received, only the line endings are adjusted
+            Ok(content) if file.origin == config.local_name => {
+                let content =
+                    text_format::convert_line_endings(content, file.format, config.text_format);
+                entries.push(PickEntry { file, content });
+            }
+            Ok(content) => {
+                let entry = receiving.convert(Entry {
+                    origin: file.origin.clone(),
+                    timestamp: file.timestamp,
+                    expires: file.expires,
+                    content,
+                    format: file.format,
+                });
+                entries.push(PickEntry {
+                    file,
+                    content: entry.content,
+                });
+            }
+            // pasted or collected in the meantime
+            Err(e) if e.kind() == ErrorKind::NotFound => {}
+            Err(e) => return Err(e).with_context(|| format!("could not read {}", file_path)),
+        }
+    }
+
+    Ok(entries)
+}
+
+/// shows the picker until something is selected or it is cancelled
+pub fn run<B: Backend>(
+    terminal: &mut Terminal<B>,
+    mut picker: Picker,
+) -> Result<Option<PickEntry>> {
+    loop {
+        let now = get_timestamp();
+        terminal
+            .draw(|frame| picker.draw(frame, now))
+            .with_context(|| "could not draw the picker")?;
+
+        let Event::Key(key) = event::read().with_context(|| "could not read the terminal")? else {
+            continue;
+        };
+        // windows reports releases as well
+        if key.kind != KeyEventKind::Press {
+            continue;
+        }
+
+        match picker.handle_key(key) {
+            PickResult::Continue => {}
+            PickResult::Selected(entry) => return Ok(Some(entry)),
+            PickResult::Cancelled => return Ok(None),
+        }
+    }
+}
+
+/// takes an entry selected from load_entries. An entry of a remote goes through the script and
+/// the receive hooks now, just like a paste. None if one of them dropped it
+pub fn take(config: &Config, channel: Option<&str>, entry: PickEntry) -> Result<Option<PickEntry>> {
+    if entry.file.origin == config.local_name {
+        return Ok(Some(entry));
+    }
+
+    let config = match channel {
+        Some(channel) => config.for_channel(channel)?,
+        None => config.clone(),
+    };
+    let received = ReceivePath::new(&config).judge(Entry {
+        origin: entry.file.origin.clone(),
+        timestamp: entry.file.timestamp,
+        expires: entry.file.expires,
+        content: entry.content,
+        format: entry.file.format,
+    });
+
+    Ok(received.map(|received| PickEntry {
+        file: entry.file,
+        content: received.content,
+    }))
+}
+
+/// opens the picker on stderr. Returns the selected entry once it was taken, None if it was
+/// cancelled or the script or a hook dropped it
+pub fn pick(config: &Config, channel: Option<&str>) -> Result<Option<PickEntry>> {
+    let picker = Picker::new(load_entries(config, channel, None)?);
+
+    terminal::enable_raw_mode().with_context(|| "could not set up the terminal")?;
+    let mut stderr = std::io::stderr();
+    if let Err(e) = execute!(stderr, EnterAlternateScreen) {
+        let _ = terminal::disable_raw_mode();
+        return Err(e).with_context(|| "could not set up the terminal");
+    }
+
+    let result = Terminal::new(CrosstermBackend::new(std::io::stderr()))
+        .with_context(|| "could not set up the terminal")
+        .and_then(|mut terminal| run(&mut terminal, picker));
+
+    // always hand the terminal back in the state it was found in
+    let _ = execute!(stderr, LeaveAlternateScreen);
+    let _ = terminal::disable_raw_mode();
+
+    match result? {
+        Some(entry) => take(config, channel, entry),
+        None => Ok(None),
+    }
+}
+
+/// puts the content on the clipboard. If the clipboard forgets it once this process exits, it is
+/// held until something else is copied.
+pub fn place_on_clipboard(content: &[u8]) -> Result<()> {
+    let Ok(text) = std::str::from_utf8(content) else {
+        bail!("the entry is not text. Redirect stdout to write it to a file instead");
+    };
+
+    let mut clipboard = clipboard_backend::open()?;
+    clipboard.set_text(text)?;
+
+    if clipboard.outlives_process() {
+        return Ok(());
+    }
+
+    eprintln!("holding the selection on the clipboard until something else is copied");
+    loop {
+        std::thread::sleep(HOLD_INTERVAL);
+        if clipboard.get_text()?.as_deref() != Some(text) {
+            return Ok(());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::test_dir;
+    use ratatui::backend::TestBackend;
+
+    fn entry(origin: &str, timestamp: u64, text: &str) -> PickEntry {
+        PickEntry {
+            file: EntryFile::new(None, origin, timestamp, EntryKind::Published),
+            content: text.as_bytes().to_vec(),
+        }
+    }
+
+    fn key(code: KeyCode) -> KeyEvent {
+        KeyEvent::new(code, KeyModifiers::NONE)
+    }
+
+    fn typed(picker: &mut Picker, query: &str) {
+        for c in query.chars() {
+            picker.handle_key(key(KeyCode::Char(c)));
+        }
+    }
+
+    fn selected(result: PickResult) -> String {
+        match result {
+            PickResult::Selected(entry) => String::from_utf8(entry.content).unwrap(),
+            _ => panic!("nothing was selected"),
+        }
+    }
+
+    fn entries() -> Vec<PickEntry> {
+        vec![
+            entry("b", 300, "cargo build --release"),
+            entry("a", 200, "git commit"),
+            entry("b", 100, "cat Cargo.toml"),
+        ]
+    }
+
+    #[test]
+    fn enter_takes_the_newest_entry() {
+        let mut picker = Picker::new(entries());
+        assert_eq!(
+            selected(picker.handle_key(key(KeyCode::Enter))),
+            "cargo build --release"
+        );
+    }
+
+    #[test]
+    fn query_filters_with_the_best_match_first() {
+        let mut picker = Picker::new(entries());
+        typed(&mut picker, "ca");
+        // both start with "ca". Equal scores keep the newest first
+        assert_eq!(picker.matches, vec![0, 2]);
+
+        typed(&mut picker, "t");
+        assert_eq!(
+            selected(picker.handle_key(key(KeyCode::Enter))),
+            "cat Cargo.toml"
+        );
+    }
+
+    #[test]
+    fn no_match_selects_nothing() {
+        let mut picker = Picker::new(entries());
+        typed(&mut picker, "xyz");
+        assert!(matches!(
+            picker.handle_key(key(KeyCode::Enter)),
+            PickResult::Continue
+        ));
+
+        picker.handle_key(key(KeyCode::Backspace));
+        picker.handle_key(key(KeyCode::Backspace));
+        picker.handle_key(key(KeyCode::Backspace));
+        assert_eq!(picker.matches.len(), 3);
+    }
+
+    #[test]
+    fn arrows_move_within_the_matches() {
+        let mut picker = Picker::new(entries());
+        picker.handle_key(key(KeyCode::Up));
+        picker.handle_key(key(KeyCode::Down));
+        picker.handle_key(key(KeyCode::Down));
+        picker.handle_key(key(KeyCode::Down));
+        assert_eq!(
+            selected(picker.handle_key(key(KeyCode::Enter))),
+            "cat Cargo.toml"
+        );
+
+        picker.handle_key(KeyEvent::new(KeyCode::Char('p'), KeyModifiers::CONTROL));
+        assert_eq!(
+            selected(picker.handle_key(key(KeyCode::Enter))),
+            "git commit"
+        );
+    }
+
+    #[test]
+    fn esc_and_ctrl_c_cancel() {
+        let mut picker = Picker::new(entries());
+        assert!(matches!(
+            picker.handle_key(key(KeyCode::Esc)),
+            PickResult::Cancelled
+        ));
+        assert!(matches!(
+            picker.handle_key(KeyEvent::new(KeyCode::Char('c'), KeyModifiers::CONTROL)),
+            PickResult::Cancelled
+        ));
+    }
+
+    #[test]
+    fn draws_the_list_and_the_preview() {
+        let mut picker = Picker::new(entries());
+        typed(&mut picker, "git");
+
+        let mut terminal = Terminal::new(TestBackend::new(60, 14)).unwrap();
+        terminal.draw(|frame| picker.draw(frame, 260)).unwrap();
+
+        let lines: Vec<String> = terminal
+            .backend()
+            .buffer()
+            .content()
+            .chunks(60)
+            .map(|line| line.iter().map(|cell| cell.symbol()).collect())
+            .collect();
+
+        assert_eq!(lines[0].trim_end(), "> git");
+        assert!(lines[1].contains(" 1/3 entries "));
+        assert_eq!(lines[2].trim_end(), "a              1m  git commit");
+        assert!(lines.iter().any(|line| line.contains(" preview ")));
+        assert_eq!(lines[7].trim_end(), "git commit");
+    }
+
+    #[test]
+    fn age_in_the_largest_unit() {
+        assert_eq!(format_age(59), "59s");
+        assert_eq!(format_age(60), "1m");
+        assert_eq!(format_age(7200), "2h");
+        assert_eq!(format_age(86400 * 3), "3d");
+    }
+
+    #[test]
+    fn loaded_entries_went_through_the_receive_path() {
+        let dir = test_dir("pick-receive");
+        let config = Config::parse(&format!(
+            "local_name=a\nremote_names=b\ndir_name={}\nreceive_transforms=strip-ansi\n",
+            dir
+        ))
+        .unwrap();
+
+        let now = get_timestamp();
+        let files = [
+            ("a", now - 3, "\x1b[1mown\x1b[0m"),
+            ("b", now - 2, "\x1b[1mremote\x1b[0m"),
+            ("c", now - 1, "unknown origin"),
+        ];
+        for (origin, timestamp, text) in files {
+            let file = EntryFile::new(None, origin, timestamp, EntryKind::Published);
+            std::fs::write(format!("{}/{}", dir, file.file_name()), text).unwrap();
+        }
+
+        let loaded = load_entries(&config, None, None).unwrap();
+        let texts: Vec<_> = loaded.iter().map(|e| e.text().into_owned()).collect();
+        assert_eq!(texts, vec!["remote", "\x1b[1mown\x1b[0m"]);
+
+        assert_eq!(load_entries(&config, None, Some(1)).unwrap().len(), 1);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn the_hooks_only_run_for_the_taken_entry() {
+        let dir = test_dir("pick-hooks");
+        let config = Config::parse(&format!(
+            "local_name=a\nremote_names=b\ndir_name={dir}\n\
+             hook.journal.command=echo taken >> {dir}/journal\nhook.journal.on=receive\n",
+            dir = dir
+        ))
+        .unwrap();
+
+        let now = get_timestamp();
+        for timestamp in [now - 2, now - 1] {
+            let file = EntryFile::new(None, "b", timestamp, EntryKind::Published);
+            std::fs::write(format!("{}/{}", dir, file.file_name()), "remote").unwrap();
+        }
+
+        let mut loaded = load_entries(&config, None, None).unwrap();
+        assert_eq!(loaded.len(), 2);
+        let journal = format!("{}/journal", dir);
+        assert!(!std::path::Path::new(&journal).exists());
+
+        let taken = take(&config, None, loaded.remove(0)).unwrap().unwrap();
+        assert_eq!(taken.text(), "remote");
+        assert_eq!(std::fs::read_to_string(&journal).unwrap(), "taken\n");
+    }
+
+    #[test]
+    fn a_hook_may_still_reject_the_taken_entry() {
+        let dir = test_dir("pick-reject");
+        let config = Config::parse(&format!(
+            "local_name=a\nremote_names=b\ndir_name={}\nhook.deny.command=exit 1\nhook.deny.on=receive\n",
+            dir
+        ))
+        .unwrap();
+
+        let now = get_timestamp();
+        for (origin, timestamp) in [("a", now - 1), ("b", now)] {
+            let file = EntryFile::new(None, origin, timestamp, EntryKind::Published);
+            std::fs::write(format!("{}/{}", dir, file.file_name()), "text").unwrap();
+        }
+
+        let loaded = load_entries(&config, None, None).unwrap();
+        assert_eq!(loaded.len(), 2);
+        let mut taken = loaded
+            .into_iter()
+            .map(|entry| take(&config, None, entry).unwrap().is_some());
+        // the remote one is rejected, the own one never was received
+        assert_eq!(taken.next(), Some(false));
+        assert_eq!(taken.next(), Some(true));
+    }
+}
@@ -0,0 +1,304 @@
+// Presence of the instances sharing config.dir_name.
+//
+// Every running instance keeps a heartbeat-<local_name>.tmp file in config.dir_name up to date:
+//
+// name=pc1
+// version=0.3.2
+// pid=1234
+// last_seen=1729288000
+// capabilities=directory,discovery
+
+use crate::config::{Config, TransportKind};
+use crate::events::{self, ErrorKind, Event};
+use crate::logfile::log;
+use crate::utils::{get_timestamp, read_file_names};
+use anyhow::{anyhow, Context, Result};
+use std::collections::HashSet;
+use std::io::ErrorKind as IoErrorKind;
+use std::sync::mpsc::{self, RecvTimeoutError, Sender};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+const HEARTBEAT_PREFIX: &str = "heartbeat-";
+const HEARTBEAT_EXTENSION: &str = ".tmp";
+const HEARTBEAT_INTERVAL_SECS: u64 = 30;
+// a few missed heartbeats are tolerated before a peer counts as stale
+const ONLINE_THRESHOLD_SECS: u64 = 3 * HEARTBEAT_INTERVAL_SECS;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Heartbeat {
+    pub name: String,
+    pub version: String,
+    pub pid: u32,
+    pub last_seen: u64,
+    pub capabilities: Vec<String>,
+}
+
+impl Heartbeat {
+    /// the heartbeat of this instance right now
+    pub fn own(config: &Config) -> Self {
+        let mut capabilities = vec![match config.transport {
+            TransportKind::Directory => "directory".to_string(),
+            TransportKind::Tcp => "tcp".to_string(),
+        }];
+        if config.tls {
+            capabilities.push("tls".to_string());
+        }
+        if config.discovery {
+            capabilities.push("discovery".to_string());
+        }
+
+        Self {
+            name: config.local_name.clone(),
+            version: env!("CARGO_PKG_VERSION").to_string(),
+            pid: std::process::id(),
+            last_seen: get_timestamp(),
+            capabilities,
+        }
+    }
+
+    fn encode(&self) -> String {
+        format!(
+            "name={}\nversion={}\npid={}\nlast_seen={}\ncapabilities={}\n",
+            self.name,
+            self.version,
+            self.pid,
+            self.last_seen,
+            self.capabilities.join(",")
+        )
+    }
+
+    fn decode(content: &str) -> Result<Self> {
+        let mut name = None;
+        let mut version = None;
+        let mut pid = None;
+        let mut last_seen = None;
+        let mut capabilities = Vec::new();
+
+        for line in content.lines() {
+            let Some((key, value)) = line.split_once("=") else {
+                continue;
+            };
+
+            match key {
+                "name" => name = Some(value.to_string()),
+                "version" => version = Some(value.to_string()),
+                "pid" => pid = Some(value.parse().with_context(|| "invalid pid")?),
+                "last_seen" => {
+                    last_seen = Some(value.parse().with_context(|| "invalid last_seen")?)
+                }
+                "capabilities" => {
+                    capabilities = value
+                        .split(",")
+                        .filter(|c| !c.is_empty())
+                        .map(|c| c.to_string())
+                        .collect()
+                }
+                // newer versions may add keys
+                _ => {}
+            }
+        }
+
+        Ok(Self {
+            name: name.ok_or_else(|| anyhow!("name missing"))?,
+            version: version.unwrap_or_default(),
+            pid: pid.unwrap_or_default(),
+            last_seen: last_seen.ok_or_else(|| anyhow!("last_seen missing"))?,
+            capabilities,
+        })
+    }
+}
+
+pub enum Presence {
+    Online(Heartbeat),
+    /// the instance stopped sending heartbeats
+    Stale(Heartbeat),
+    NeverSeen,
+}
+
+pub struct PeerStatus {
+    pub name: String,
+    pub presence: Presence,
+}
+
+pub struct PresenceReport {
+    /// one status per configured remote of any channel
+    pub remotes: Vec<PeerStatus>,
+    /// heartbeats of instances which are not a remote of any channel. Often a typo in one of the
+    /// configs
+    pub unknown: Vec<Heartbeat>,
+}
+
+fn heartbeat_file_name(name: &str) -> String {
+    format!("{}{}{}", HEARTBEAT_PREFIX, name, HEARTBEAT_EXTENSION)
+}
+
+fn parse_heartbeat_file_name(file_name: &str) -> Option<&str> {
+    file_name
+        .strip_prefix(HEARTBEAT_PREFIX)?
+        .strip_suffix(HEARTBEAT_EXTENSION)
+        .filter(|name| !name.is_empty())
+}
+
+/// writes the heartbeat of this instance
+pub fn write_heartbeat(config: &Config) -> Result<()> {
+    let file_name = heartbeat_file_name(&config.local_name);
+    let file_path = format!("{}/{}", config.dir_name, file_name);
+    // same as the entries: never let anyone read a half written file
+    let staging_path = format!("{}.part", file_path);
+
+    std::fs::write(&staging_path, Heartbeat::own(config).encode())
+        .with_context(|| format!("could not write to file {}", staging_path))?;
+    std::fs::rename(&staging_path, &file_path)
+        .with_context(|| format!("could not publish file {}", file_path))
+}
+
+/// removes the heartbeat of this instance, the remotes see it gone right away
+pub fn remove_heartbeat(config: &Config) -> Result<()> {
+    let file_path = format!(
+        "{}/{}",
+        config.dir_name,
+        heartbeat_file_name(&config.local_name)
+    );
+
+    match std::fs::remove_file(&file_path) {
+        Ok(()) => Ok(()),
+        Err(e) if e.kind() == IoErrorKind::NotFound => Ok(()),
+        Err(e) => Err(e).with_context(|| format!("could not delete file {}", file_path)),
+    }
+}
+
+/// the thread keeping the heartbeat fresh
+pub struct HeartbeatThread {
+    // dropping it stops the thread
+    stop: Sender<()>,
+    handle: JoinHandle<Result<()>>,
+}
+
+impl HeartbeatThread {
+    /// stops the thread and removes the heartbeat. No heartbeat is written after that
+    pub fn stop(self) -> Result<()> {
+        drop(self.stop);
+        self.handle
+            .join()
+            .map_err(|_| anyhow!("the heartbeat thread panicked"))?
+    }
+}
+
+/// starts a thread which keeps the heartbeat of this instance fresh. With the directory
+/// transport it publishes when a remote comes online or goes offline as well, the tcp transport
+/// does that itself
+pub fn spawn_heartbeat(config: Config) -> HeartbeatThread {
+    let (stop, stopped) = mpsc::channel();
+
+    let handle = thread::spawn(move || {
+        let mut online = HashSet::new();
+
+        loop {
+            if let Err(e) = write_heartbeat(&config) {
+                // not fatal. Maybe the share is just unavailable at the moment
+                events::report_error(
+                    ErrorKind::Heartbeat,
+                    format!("could not write heartbeat: {}", e),
+                );
+            }
+
+            if config.transport == TransportKind::Directory {
+                watch_peers(&config, &mut online);
+            }
+
+            match stopped.recv_timeout(Duration::from_secs(HEARTBEAT_INTERVAL_SECS)) {
+                Err(RecvTimeoutError::Timeout) => {}
+                Ok(()) | Err(RecvTimeoutError::Disconnected) => break,
+            }
+        }
+
+        remove_heartbeat(&config)
+    });
+
+    HeartbeatThread { stop, handle }
+}
+
+/// publishes the remotes which came online or went offline since the last call. online holds the
+/// names of the remotes which were online then
+fn watch_peers(config: &Config, online: &mut HashSet<String>) {
+    let report = match peer_status(config) {
+        Ok(report) => report,
+        Err(e) => {
+            log(&format!("could not read heartbeats: {}\n", e));
+            return;
+        }
+    };
+
+    for peer in report.remotes {
+        match peer.presence {
+            Presence::Online(h) => {
+                if online.insert(peer.name.clone()) {
+                    events::publish(Event::PeerOnline {
+                        name: peer.name,
+                        version: Some(h.version),
+                    });
+                }
+            }
+            Presence::Stale(_) | Presence::NeverSeen => {
+                if online.remove(&peer.name) {
+                    events::publish(Event::PeerOffline { name: peer.name });
+                }
+            }
+        }
+    }
+}
+
+/// reads all heartbeats in config.dir_name. Unreadable ones are skipped.
+pub fn read_heartbeats(config: &Config) -> Result<Vec<Heartbeat>> {
+    let mut heartbeats = Vec::new();
+
+    for file_name in read_file_names(&config.dir_name)? {
+        if parse_heartbeat_file_name(&file_name).is_none() {
+            continue;
+        }
+
+        let file_path = format!("{}/{}", config.dir_name, file_name);
+        match std::fs::read_to_string(&file_path)
+            .map_err(|e| e.into())
+            .and_then(|content| Heartbeat::decode(&content))
+        {
+            Ok(heartbeat) => heartbeats.push(heartbeat),
+            Err(e) => log(&format!("skipping heartbeat {}: {}\n", file_path, e)),
+        }
+    }
+
+    Ok(heartbeats)
+}
+
+/// the presence of every configured remote and all unknown instances
+pub fn peer_status(config: &Config) -> Result<PresenceReport> {
+    let heartbeats = read_heartbeats(config)?;
+    let now = get_timestamp();
+
+    let remotes = config
+        .all_remote_names()
+        .iter()
+        .map(|name| {
+            let presence = match heartbeats.iter().find(|h| &h.name == name) {
+                None => Presence::NeverSeen,
+                Some(h) if now.saturating_sub(h.last_seen) <= ONLINE_THRESHOLD_SECS => {
+                    Presence::Online(h.clone())
+                }
+                Some(h) => Presence::Stale(h.clone()),
+            };
+
+            PeerStatus {
+                name: name.clone(),
+                presence,
+            }
+        })
+        .collect();
+
+    let unknown = heartbeats
+        .into_iter()
+        .filter(|h| h.name != config.local_name && !config.knows(&h.name))
+        .collect();
+
+    Ok(PresenceReport { remotes, unknown })
+}
@@ -0,0 +1,111 @@
+// Inspection of the queue mode (paste_mode=queue).
+//
+// The queue is made of the entries in config.dir_name themselves. Incoming are the ones of the
+// remotes which were not pasted yet, outgoing the ones this instance copied and no remote pasted
+// yet. Both are ordered oldest first, which is the order they are pasted in.
+
+use crate::config::{Config, TransportKind};
+use crate::entry::{EntryFile, EntryKind};
+use crate::utils::read_file_names;
+use anyhow::{bail, Context, Result};
+use std::io::ErrorKind;
+
+// how much of an entry is shown when listing the queue
+const PREVIEW_CHARS: usize = 40;
+
+pub struct QueuedEntry {
+    pub file: EntryFile,
+    pub size: usize,
+    /// the first line of the content, shortened
+    pub preview: String,
+}
+
+pub struct QueueReport {
+    pub incoming: Vec<QueuedEntry>,
+    pub outgoing: Vec<QueuedEntry>,
+}
+
+/// narrows the config to the channel. The queue only exists in the shared directory
+fn channel_config(config: &Config, channel: Option<&str>) -> Result<Config> {
+    if config.transport != TransportKind::Directory {
+        bail!("the queue can only be inspected with transport=directory");
+    }
+
+    match channel {
+        Some(channel) => config.for_channel(channel),
+        None => Ok(config.clone()),
+    }
+}
+
+/// the published entries of the channel, oldest first
+fn queued_files(config: &Config) -> Result<Vec<EntryFile>> {
+    let mut files: Vec<_> = read_file_names(&config.dir_name)?
+        .iter()
+        .filter_map(|file_name| EntryFile::parse(file_name))
+        .filter(|entry| entry.kind == EntryKind::Published && entry.channel == config.channel)
+        .collect();
+
+    files.sort_by_key(|entry| entry.timestamp);
+    Ok(files)
+}
+
+pub fn inspect(config: &Config, channel: Option<&str>) -> Result<QueueReport> {
+    let config = channel_config(config, channel)?;
+
+    let mut report = QueueReport {
+        incoming: Vec::new(),
+        outgoing: Vec::new(),
+    };
+
+    for file in queued_files(&config)? {
+        let file_path = format!("{}/{}", config.dir_name, file.file_name());
+        let content = match std::fs::read(&file_path) {
+            Ok(content) => content,
+            // pasted in the meantime
+            Err(e) if e.kind() == ErrorKind::NotFound => continue,
+            Err(e) => return Err(e).with_context(|| format!("could not read {}", file_path)),
+        };
+
+        let text = String::from_utf8_lossy(&content);
+        let queued = QueuedEntry {
+            size: content.len(),
+            preview: text
+                .lines()
+                .next()
+                .unwrap_or_default()
+                .chars()
+                .take(PREVIEW_CHARS)
+                .collect(),
+            file,
+        };
+
+        if queued.file.origin == config.local_name {
+            report.outgoing.push(queued);
+        } else if config.remote_names.contains(&queued.file.origin) {
+            report.incoming.push(queued);
+        }
+    }
+
+    Ok(report)
+}
+
+/// removes all queued entries in both directions. Returns how many there were
+pub fn clear(config: &Config, channel: Option<&str>) -> Result<usize> {
+    let config = channel_config(config, channel)?;
+    let mut removed = 0;
+
+    for file in queued_files(&config)? {
+        if file.origin != config.local_name && !config.remote_names.contains(&file.origin) {
+            continue;
+        }
+
+        let file_path = format!("{}/{}", config.dir_name, file.file_name());
+        match std::fs::remove_file(&file_path) {
+            Ok(()) => removed += 1,
+            Err(e) if e.kind() == ErrorKind::NotFound => {}
+            Err(e) => return Err(e).with_context(|| format!("could not delete {}", file_path)),
+        }
+    }
+
+    Ok(removed)
+}
@@ -0,0 +1,171 @@
+// Named registers, like the ones of vim.
+//
+// Every register holds one value which is shared by all machines using config.dir_name. Unlike
+// the clipboard entries a register is not consumed when it is read and does not expire. Writing
+// a register adds a newer version, the older ones are removed by the garbage collector.
+//
+// Only versions written by this machine or one of its remotes are ever read.
+
+use crate::config::{Config, TransportKind};
+use crate::entry::{is_valid_name, EntryKind, RegisterFile};
+use crate::utils::{get_timestamp, read_file_names};
+use anyhow::{bail, Context, Result};
+use std::collections::HashMap;
+use std::io::ErrorKind;
+
+// a version may be replaced between listing and reading it. After this many tries in a row
+// something else is wrong
+const MAX_READ_ATTEMPTS: usize = 5;
+
+fn check_config(config: &Config) -> Result<()> {
+    // registers live in the shared directory, which tcp doesn't have
+    if config.transport != TransportKind::Directory {
+        bail!("registers need transport=directory");
+    }
+    Ok(())
+}
+
+/// the newest published version of every register, sorted by name
+pub fn list(config: &Config) -> Result<Vec<RegisterFile>> {
+    check_config(config)?;
+
+    let mut newest: HashMap<String, RegisterFile> = HashMap::new();
+    for file_name in read_file_names(&config.dir_name)? {
+        let Some(register) = RegisterFile::parse(&file_name) else {
+            continue;
+        };
+
+        if register.kind != EntryKind::Published
+            || (register.origin != config.local_name && !config.knows(&register.origin))
+        {
+            continue;
+        }
+
+        match newest.get(&register.name) {
+            Some(current) if current.timestamp >= register.timestamp => {}
+            _ => {
+                newest.insert(register.name.clone(), register);
+            }
+        }
+    }
+
+    let mut registers: Vec<_> = newest.into_values().collect();
+    registers.sort_by(|a, b| a.name.cmp(&b.name));
+    Ok(registers)
+}
+
+/// the value of the register. None if it was never written
+pub fn get(config: &Config, name: &str) -> Result<Option<Vec<u8>>> {
+    for _ in 0..MAX_READ_ATTEMPTS {
+        let Some(register) = list(config)?.into_iter().find(|r| r.name == name) else {
+            return Ok(None);
+        };
+
+        let file_path = format!("{}/{}", config.dir_name, register.file_name());
+        match std::fs::read(&file_path) {
+            Ok(content) => return Ok(Some(content)),
+            // replaced by a newer version right now. Try again with that one
+            Err(e) if e.kind() == ErrorKind::NotFound => {}
+            Err(e) => return Err(e).with_context(|| format!("could not read file {}", file_path)),
+        }
+    }
+
+    bail!(
+        "register {} kept disappearing while reading it, {} tries",
+        name,
+        MAX_READ_ATTEMPTS
+    );
+}
+
+/// writes a new version of the register
+pub fn set(config: &Config, name: &str, content: &[u8]) -> Result<()> {
+    check_config(config)?;
+
+    if !is_valid_name(name) {
+        bail!(
+            "register names may only contain letters, digits, - and _: {}",
+            name
+        );
+    }
+
+    let staging = RegisterFile::new(
+        name,
+        &config.local_name,
+        get_timestamp(),
+        EntryKind::Staging,
+    );
+    let published = staging.published();
+
+    let staging_path = format!("{}/{}", config.dir_name, staging.file_name());
+    let file_path = format!("{}/{}", config.dir_name, published.file_name());
+
+    // same as the entries: never let anyone read a half written file
+    std::fs::write(&staging_path, content)
+        .with_context(|| format!("could not write to file {}", staging_path))?;
+    std::fs::rename(&staging_path, &file_path)
+        .with_context(|| format!("could not publish file {}", file_path))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::test_dir;
+
+    fn config(dir: &str, lines: &str) -> Config {
+        Config::parse(&format!(
+            "local_name=a\nremote_names=b\ndir_name={}\n{}",
+            dir, lines
+        ))
+        .unwrap()
+    }
+
+    fn write(dir: &str, name: &str, origin: &str, timestamp: u64, content: &str) {
+        let file = RegisterFile::new(name, origin, timestamp, EntryKind::Published);
+        std::fs::write(format!("{}/{}", dir, file.file_name()), content).unwrap();
+    }
+
+    #[test]
+    fn a_set_register_can_be_read_again() {
+        let dir = test_dir("registers-set");
+        let config = config(&dir, "");
+
+        assert_eq!(get(&config, "r").unwrap(), None);
+        set(&config, "r", b"value").unwrap();
+        assert_eq!(get(&config, "r").unwrap().as_deref(), Some(&b"value"[..]));
+        // reading doesn't consume it
+        assert_eq!(get(&config, "r").unwrap().as_deref(), Some(&b"value"[..]));
+
+        assert!(set(&config, "no/name", b"value").is_err());
+    }
+
+    #[test]
+    fn the_newest_version_of_the_group_wins() {
+        let dir = test_dir("registers-newest");
+        let config = config(&dir, "");
+
+        write(&dir, "r", "a", 100, "old");
+        write(&dir, "r", "b", 200, "newest");
+        // not part of this group
+        write(&dir, "r", "z", 300, "foreign");
+        write(&dir, "s", "z", 300, "foreign");
+
+        assert_eq!(get(&config, "r").unwrap().as_deref(), Some(&b"newest"[..]));
+        assert_eq!(get(&config, "s").unwrap(), None);
+
+        let listed: Vec<_> = list(&config)
+            .unwrap()
+            .into_iter()
+            .map(|r| (r.name, r.origin, r.timestamp))
+            .collect();
+        assert_eq!(listed, vec![("r".to_string(), "b".to_string(), 200)]);
+    }
+
+    #[test]
+    fn registers_need_the_directory_transport() {
+        let dir = test_dir("registers-tcp");
+        let config = config(&dir, "transport=tcp\npeers=b@127.0.0.1:4717\ntls=off\n");
+
+        assert!(get(&config, "r").is_err());
+        assert!(set(&config, "r", b"value").is_err());
+    }
+}
@@ -0,0 +1,306 @@
+// Entry rules written in Rhai, see https://rhai.rs. Lighter than a hook, the script runs inside
+// this process.
+//
+// script=rules.rhai
+//
+// The script runs once for every entry, on send after the filters and the send transforms and on
+// receive after the receive transforms. It sees these variables:
+//
+// event        "send" or "receive"
+// text         the content as text
+// origin       the machine which sent the entry
+// local_name   this machine
+// channel      the named channel, "" for the default one
+// size         the content length in bytes
+// encoding     "utf-8", "utf-16le", "utf-16be" or "binary", what the content originally was
+// line_ending  "lf" or "crlf"
+// sensitive    true for sensitive entries
+// timestamp    when it was sent, seconds since the epoch
+//
+// Its value decides what happens with the entry:
+//
+// ()            nothing, the entry goes on as it is
+// true / false  keep or drop the entry
+// a string      the new text of the entry
+// a map         any of #{ drop: true, text: "...", channel: "..." }. channel publishes the entry
+//               on that named channel instead, it only applies on send
+//
+// The scripts can't touch anything outside of themselves, and are stopped once they exceed the
+// limits below. A failing script is logged and the entry goes on as if there was no script.
+
+use crate::events::{self, ErrorKind};
+use crate::hooks::HookContext;
+use crate::logfile::log;
+use anyhow::{anyhow, bail, Context, Result};
+use rhai::{Dynamic, Engine, Map, Scope, AST};
+
+const MAX_OPERATIONS: u64 = 100_000;
+const MAX_CALL_LEVELS: usize = 32;
+const MAX_EXPR_DEPTH: usize = 64;
+const MAX_FUNCTION_EXPR_DEPTH: usize = 32;
+// the content itself is a string, so this has to fit the largest entry
+const MAX_STRING_SIZE: usize = 64 * 1024 * 1024;
+const MAX_ARRAY_SIZE: usize = 10_000;
+const MAX_MAP_SIZE: usize = 1_000;
+
+/// a compiled script
+#[derive(Clone)]
+pub struct Script {
+    pub path: String,
+    ast: AST,
+}
+
+impl Script {
+    /// reads and compiles the script, so syntax errors show up when the config is loaded
+    pub fn load(path: &str) -> Result<Self> {
+        let source = std::fs::read_to_string(path)
+            .with_context(|| format!("could not read script {}", path))?;
+        let ast = engine()
+            .compile(source)
+            .map_err(|e| anyhow!("could not compile script {}: {}", path, e))?;
+
+        Ok(Self {
+            path: path.to_string(),
+            ast,
+        })
+    }
+}
+
+/// what the script decided
+#[derive(Debug, Default)]
+pub struct Decision {
+    pub drop: bool,
+    /// the new text of the entry
+    pub text: Option<String>,
+    /// publish on this channel instead
+    pub channel: Option<String>,
+}
+
+/// runs scripts. The engine is built once, it is not cheap
+pub struct ScriptRunner {
+    engine: Engine,
+    script: Script,
+}
+
+impl ScriptRunner {
+    pub fn new(script: Script) -> Self {
+        Self {
+            engine: engine(),
+            script,
+        }
+    }
+
+    /// runs the script on the entry. Failures are logged and decide nothing
+    pub fn decide(&self, context: &HookContext, local_name: &str, content: &[u8]) -> Decision {
+        match self.run(context, local_name, content) {
+            Ok(decision) => decision,
+            Err(e) => {
+                events::report_error(
+                    ErrorKind::Script,
+                    format!("script {} failed: {:#}", self.script.path, e),
+                );
+                Decision::default()
+            }
+        }
+    }
+
+    fn run(&self, context: &HookContext, local_name: &str, content: &[u8]) -> Result<Decision> {
+        let mut scope = Scope::new();
+        scope.push("event", context.event.name());
+        scope.push("text", String::from_utf8_lossy(content).into_owned());
+        scope.push("origin", context.origin.to_string());
+        scope.push("local_name", local_name.to_string());
+        scope.push("channel", context.channel.unwrap_or_default().to_string());
+        scope.push("size", content.len() as i64);
+        scope.push("encoding", context.format.encoding.name());
+        scope.push("line_ending", context.format.line_ending.name());
+        scope.push("sensitive", context.sensitive);
+        scope.push("timestamp", context.timestamp as i64);
+
+        let value = self
+            .engine
+            .eval_ast_with_scope::<Dynamic>(&mut scope, &self.script.ast)
+            .map_err(|e| anyhow!("{}", e))?;
+
+        to_decision(value)
+    }
+}
+
+fn to_decision(value: Dynamic) -> Result<Decision> {
+    if value.is_unit() {
+        return Ok(Decision::default());
+    }
+    if let Ok(keep) = value.as_bool() {
+        return Ok(Decision {
+            drop: !keep,
+            ..Decision::default()
+        });
+    }
+    if value.is_string() {
+        return Ok(Decision {
+            text: Some(value.into_string().unwrap()),
+            ..Decision::default()
+        });
+    }
+
+    let type_name = value.type_name();
+    let Some(map) = value.try_cast::<Map>() else {
+        bail!(
+            "the script returned a {}, expected (), a bool, a string or a map",
+            type_name
+        );
+    };
+
+    let mut decision = Decision::default();
+    for (key, value) in map {
+        let type_name = value.type_name();
+        match key.as_str() {
+            "drop" => {
+                decision.drop = value
+                    .as_bool()
+                    .map_err(|_| anyhow!("drop has to be a bool, not a {}", type_name))?;
+            }
+            "text" => {
+                decision.text = Some(
+                    value
+                        .into_string()
+                        .map_err(|_| anyhow!("text has to be a string, not a {}", type_name))?,
+                );
+            }
+            "channel" => {
+                decision.channel = Some(
+                    value
+                        .into_string()
+                        .map_err(|_| anyhow!("channel has to be a string, not a {}", type_name))?,
+                );
+            }
+            _ => bail!("unknown key {} in the returned map", key),
+        }
+    }
+
+    Ok(decision)
+}
+
+/// an engine which can't reach the outside and stops runaway scripts
+fn engine() -> Engine {
+    let mut engine = Engine::new();
+
+    engine
+        .set_max_operations(MAX_OPERATIONS)
+        .set_max_call_levels(MAX_CALL_LEVELS)
+        .set_max_expr_depths(MAX_EXPR_DEPTH, MAX_FUNCTION_EXPR_DEPTH)
+        .set_max_string_size(MAX_STRING_SIZE)
+        .set_max_array_size(MAX_ARRAY_SIZE)
+        .set_max_map_size(MAX_MAP_SIZE)
+        // import would read other files
+        .set_max_modules(0)
+        .disable_symbol("eval");
+
+    // the output of print and debug ends up in the log instead of stdout, which recv writes to
+    engine.on_print(|text| log(&format!("script: {}\n", text)));
+    engine.on_debug(|text, _, _| log(&format!("script: {}\n", text)));
+
+    engine
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hooks::HookEvent;
+    use crate::text_format::TextFormat;
+    use crate::utils::test_dir;
+    use std::time::{Duration, Instant};
+
+    fn runner(name: &str, source: &str) -> ScriptRunner {
+        let path = format!("{}/rules.rhai", test_dir(name));
+        std::fs::write(&path, source).unwrap();
+        ScriptRunner::new(Script::load(&path).unwrap())
+    }
+
+    fn decide(runner: &ScriptRunner, text: &str) -> Decision {
+        let context = HookContext {
+            event: HookEvent::Send,
+            origin: "a",
+            timestamp: 100,
+            channel: None,
+            sensitive: false,
+            format: TextFormat::default(),
+        };
+        runner.decide(&context, "a", text.as_bytes())
+    }
+
+    fn unchanged(decision: &Decision) -> bool {
+        !decision.drop && decision.text.is_none() && decision.channel.is_none()
+    }
+
+    #[test]
+    fn the_value_decides() {
+        let runner = runner(
+            "script-decides",
+            r#"
+                if text.contains("drop") { return false; }
+                if text.contains("upper") { return text.to_upper(); }
+                if event == "send" && size > 10 { return #{ channel: "long" }; }
+            "#,
+        );
+
+        assert!(decide(&runner, "drop me").drop);
+        assert_eq!(decide(&runner, "upper").text.as_deref(), Some("UPPER"));
+        assert_eq!(
+            decide(&runner, "a longer text").channel.as_deref(),
+            Some("long")
+        );
+        assert!(unchanged(&decide(&runner, "short")));
+    }
+
+    #[test]
+    fn runaway_scripts_are_stopped() {
+        let scripts = [
+            ("script-loop", "loop { } false"),
+            ("script-recursion", "fn f(x) { f(x + 1) } f(0); false"),
+            (
+                "script-array",
+                "let a = []; for i in 0..100000 { a.push(i); } false",
+            ),
+            ("script-string", "let s = text; loop { s += s; } false"),
+        ];
+
+        for (name, source) in scripts {
+            let runner = runner(name, source);
+            let started = Instant::now();
+            // false at the end would drop the entry, had the script got there
+            assert!(unchanged(&decide(&runner, "text")), "{} went on", name);
+            assert!(
+                started.elapsed() < Duration::from_secs(10),
+                "{} ran long",
+                name
+            );
+        }
+    }
+
+    #[test]
+    fn a_failing_script_leaves_the_entry_unchanged() {
+        let scripts = [
+            ("script-error", "text.no_such_function(); false"),
+            ("script-throw", r#"throw "nope""#),
+            ("script-type", "42"),
+            ("script-key", "#{ dropp: true }"),
+            ("script-value", r#"#{ drop: "yes" }"#),
+        ];
+
+        for (name, source) in scripts {
+            assert!(
+                unchanged(&decide(&runner(name, source), "text")),
+                "{}",
+                name
+            );
+        }
+    }
+
+    #[test]
+    fn syntax_errors_show_up_when_loading() {
+        let path = format!("{}/rules.rhai", test_dir("script-syntax"));
+        std::fs::write(&path, "if {").unwrap();
+        assert!(Script::load(&path).is_err());
+    }
+}
@@ -0,0 +1,256 @@
+// Clean shutdown of the running instance. Ctrl+C, SIGTERM and SIGHUP request it, on windows
+// ctrl+c, closing the console, logging off and WM_QUIT. So does a hotkey backend which stopped
+// listening by itself. The instance then
+//
+// 1. stops accepting actions: key events and control requests are refused, the socket is removed
+// 2. lets every file handler finish the actions queued before and close its transport. That
+//    removes the own entry as well if remove_entry_on_exit is on
+// 3. removes its heartbeat, so the remotes don't have to wait for it to go stale
+// 4. unhooks the keyboard
+//
+// and exits with 0, or 1 if the hotkeys failed or a step didn't complete. Another ctrl+c while
+// shutting down exits right away.
+
+use crate::config::Config;
+use crate::control;
+use crate::file_handlers::ClipboardAction;
+use crate::global_hotkeys::HotkeyListener;
+use crate::logfile::log;
+use crate::presence::HeartbeatThread;
+use anyhow::{Context, Result};
+use std::fmt;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, RecvTimeoutError, Sender};
+use std::sync::{Condvar, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+// how long the file handlers get to finish their queued actions. Writing to a slow share may take
+// a few seconds
+const FLUSH_TIMEOUT: Duration = Duration::from_secs(10);
+// the hotkey backends stop within a timer tick
+const UNHOOK_TIMEOUT: Duration = Duration::from_secs(1);
+const FAILED_EXIT_CODE: i32 = 1;
+// like a shell reports a process ended by ctrl+c
+const FORCED_EXIT_CODE: i32 = 130;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Reason {
+    /// ctrl+c
+    Interrupted,
+    /// SIGTERM or SIGHUP. On windows the console was closed or the session ends
+    Terminated,
+    /// the hotkey thread got WM_QUIT
+    Quit,
+    /// the hotkey backend stopped listening by itself
+    HotkeysFailed(String),
+}
+
+impl Reason {
+    fn exit_code(&self) -> i32 {
+        match self {
+            Reason::Interrupted | Reason::Terminated | Reason::Quit => 0,
+            Reason::HotkeysFailed(_) => FAILED_EXIT_CODE,
+        }
+    }
+}
+
+impl fmt::Display for Reason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Reason::Interrupted => write!(f, "interrupted"),
+            Reason::Terminated => write!(f, "terminated"),
+            Reason::Quit => write!(f, "got WM_QUIT"),
+            Reason::HotkeysFailed(e) => write!(f, "stopped listening for hotkeys: {}", e),
+        }
+    }
+}
+
+static STOPPING: AtomicBool = AtomicBool::new(false);
+// the first reason a shutdown was requested for
+static REQUESTED: Mutex<Option<Reason>> = Mutex::new(None);
+static REQUESTED_CHANGED: Condvar = Condvar::new();
+
+/// true once a shutdown was requested. From then on no new actions are accepted
+pub fn is_stopping() -> bool {
+    STOPPING.load(Ordering::SeqCst)
+}
+
+/// requests the shutdown. Only the first reason counts
+pub fn request(reason: Reason) {
+    let mut requested = REQUESTED.lock().unwrap_or_else(|e| e.into_inner());
+    if requested.is_none() {
+        STOPPING.store(true, Ordering::SeqCst);
+        *requested = Some(reason);
+        REQUESTED_CHANGED.notify_all();
+    }
+}
+
+/// blocks until a shutdown is requested
+pub fn wait() -> Reason {
+    let requested = REQUESTED.lock().unwrap_or_else(|e| e.into_inner());
+    let requested = REQUESTED_CHANGED
+        .wait_while(requested, |requested| requested.is_none())
+        .unwrap_or_else(|e| e.into_inner());
+
+    requested.clone().unwrap()
+}
+
+/// a signal asks for the shutdown. If one is already going on, it is cut short
+fn on_signal(reason: Reason) {
+    if is_stopping() {
+        log("stopping right away\n");
+        std::process::exit(FORCED_EXIT_CODE);
+    }
+    request(reason);
+}
+
+/// requests the shutdown on ctrl+c, SIGTERM and SIGHUP
+#[cfg(unix)]
+pub fn handle_signals() -> Result<()> {
+    use signal_hook::consts::{SIGHUP, SIGINT, SIGTERM};
+    use signal_hook::iterator::Signals;
+
+    let mut signals =
+        Signals::new([SIGINT, SIGTERM, SIGHUP]).with_context(|| "could not handle signals")?;
+
+    thread::spawn(move || {
+        for signal in signals.forever() {
+            on_signal(match signal {
+                SIGINT => Reason::Interrupted,
+                _ => Reason::Terminated,
+            });
+        }
+    });
+
+    Ok(())
+}
+
+/// requests the shutdown on ctrl+c and when the console is closed or the session ends
+#[cfg(windows)]
+pub fn handle_signals() -> Result<()> {
+    use windows::Win32::Foundation::TRUE;
+    use windows::Win32::System::Console::SetConsoleCtrlHandler;
+
+    unsafe { SetConsoleCtrlHandler(Some(console_handler), TRUE) }
+        .with_context(|| "could not handle console events")
+}
+
+#[cfg(windows)]
+unsafe extern "system" fn console_handler(ctrl_type: u32) -> windows::Win32::Foundation::BOOL {
+    use windows::Win32::Foundation::{FALSE, TRUE};
+    use windows::Win32::System::Console::{
+        CTRL_BREAK_EVENT, CTRL_CLOSE_EVENT, CTRL_C_EVENT, CTRL_LOGOFF_EVENT, CTRL_SHUTDOWN_EVENT,
+    };
+
+    match ctrl_type {
+        CTRL_C_EVENT | CTRL_BREAK_EVENT => on_signal(Reason::Interrupted),
+        CTRL_CLOSE_EVENT | CTRL_LOGOFF_EVENT | CTRL_SHUTDOWN_EVENT => {
+            on_signal(Reason::Terminated);
+            // windows ends the process as soon as this returns. The shutdown exits by itself
+            loop {
+                thread::park();
+            }
+        }
+        _ => return FALSE,
+    }
+
+    TRUE
+}
+
+/// the parts of the running instance a shutdown stops
+pub struct Instance {
+    pub config: Config,
+    /// the file handlers of all channels
+    pub channels: Vec<Sender<ClipboardAction>>,
+    pub heartbeat: HeartbeatThread,
+    pub hotkeys: HotkeyListener,
+}
+
+/// stops the instance step by step and returns the exit code. A failing step doesn't keep the
+/// others from running
+pub fn shut_down(instance: Instance, reason: Reason) -> i32 {
+    log(&format!("shutting down, {}\n", reason));
+    let mut complete = true;
+
+    if instance.config.control {
+        if let Err(e) = control::close(&instance.config) {
+            log(&format!("could not remove the control socket: {:#}\n", e));
+            complete = false;
+        }
+    }
+
+    // the actions queued before the stop are still run, in order
+    let stops: Vec<_> = instance
+        .channels
+        .iter()
+        .map(|sender| {
+            let (done, stopped) = mpsc::channel();
+            let _ = sender.send(ClipboardAction::Stop { done });
+            stopped
+        })
+        .collect();
+
+    let deadline = Instant::now() + FLUSH_TIMEOUT;
+    for stopped in stops {
+        match stopped.recv_timeout(deadline.saturating_duration_since(Instant::now())) {
+            Ok(Ok(())) => {}
+            Ok(Err(e)) => {
+                log(&format!("could not close a channel: {:#}\n", e));
+                complete = false;
+            }
+            Err(RecvTimeoutError::Timeout) => {
+                log("a file handler didn't finish its actions in time\n");
+                complete = false;
+            }
+            // it stopped before, there is nothing left to finish
+            Err(RecvTimeoutError::Disconnected) => {
+                log("a file handler was already gone\n");
+                complete = false;
+            }
+        }
+    }
+
+    if let Err(e) = instance.heartbeat.stop() {
+        log(&format!("could not remove the heartbeat: {:#}\n", e));
+        complete = false;
+    }
+
+    if !instance.hotkeys.stop(UNHOOK_TIMEOUT) {
+        log("the hotkeys didn't stop in time\n");
+        complete = false;
+    }
+
+    log("stopped\n");
+    if complete {
+        reason.exit_code()
+    } else {
+        FAILED_EXIT_CODE
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_failed_hotkeys_fail_the_exit_code() {
+        assert_eq!(Reason::Interrupted.exit_code(), 0);
+        assert_eq!(Reason::Terminated.exit_code(), 0);
+        assert_eq!(Reason::Quit.exit_code(), 0);
+        assert_eq!(
+            Reason::HotkeysFailed("gone".to_string()).exit_code(),
+            FAILED_EXIT_CODE
+        );
+    }
+
+    #[test]
+    fn the_reason_is_logged_readably() {
+        let reason = Reason::HotkeysFailed("all keyboards are gone".to_string());
+        assert_eq!(
+            reason.to_string(),
+            "stopped listening for hotkeys: all keyboards are gone"
+        );
+        assert_eq!(Reason::Terminated.to_string(), "terminated");
+    }
+}
@@ -0,0 +1,274 @@
+// Pinned snippets: strings which are pasted again and again, like server names or boilerplate
+// commands.
+//
+// They are kept in the subdirectory snippets of config.dir_name, so every machine sharing the
+// directory sees them and the garbage collector never touches them. Every change writes a new
+// revision. A change has to name the revision it is based on, so a change made in the meantime
+// on another machine is not overwritten silently. If two machines write the same revision at
+// once, the snippet is in conflict until it is edited or deleted again.
+
+use crate::config::{Config, TransportKind};
+use crate::entry::{is_valid_name, EntryKind, SnippetFile};
+use crate::utils::read_file_names;
+use anyhow::{bail, Context, Result};
+use std::collections::BTreeMap;
+use std::io::ErrorKind;
+
+const SNIPPET_DIR: &str = "snippets";
+
+pub struct Snippet {
+    pub id: String,
+    pub revision: u64,
+    pub origin: String,
+    pub content: Vec<u8>,
+    /// the other machines which wrote the same revision
+    pub conflicts: Vec<String>,
+}
+
+fn snippet_dir(config: &Config) -> Result<String> {
+    // snippets live in the shared directory, which tcp doesn't have
+    if config.transport != TransportKind::Directory {
+        bail!("snippets need transport=directory");
+    }
+    Ok(format!("{}/{}", config.dir_name, SNIPPET_DIR))
+}
+
+/// all published revisions of this machine and its remotes, grouped by id
+fn read_files(config: &Config) -> Result<BTreeMap<String, Vec<SnippetFile>>> {
+    let dir = snippet_dir(config)?;
+
+    let file_names = match read_file_names(&dir) {
+        Ok(file_names) => file_names,
+        // nothing was pinned yet
+        Err(_) if !std::path::Path::new(&dir).exists() => Vec::new(),
+        Err(e) => return Err(e),
+    };
+
+    let mut files: BTreeMap<String, Vec<SnippetFile>> = BTreeMap::new();
+    for file in file_names.iter().filter_map(|f| SnippetFile::parse(f)) {
+        if file.kind == EntryKind::Published
+            && (file.origin == config.local_name || config.knows(&file.origin))
+        {
+            files.entry(file.id.clone()).or_default().push(file);
+        }
+    }
+
+    // newest revision first, ties in a stable order
+    for revisions in files.values_mut() {
+        revisions.sort_by(|a, b| b.revision.cmp(&a.revision).then(a.origin.cmp(&b.origin)));
+    }
+
+    Ok(files)
+}
+
+/// all snippets, sorted by id. The position in this list is the index used by get and the
+/// hotkeys, starting at 1.
+pub fn list(config: &Config) -> Result<Vec<Snippet>> {
+    let dir = snippet_dir(config)?;
+    let mut snippets = Vec::new();
+
+    for (id, revisions) in read_files(config)? {
+        let newest = &revisions[0];
+        let file_path = format!("{}/{}", dir, newest.file_name());
+        let content = match std::fs::read(&file_path) {
+            Ok(content) => content,
+            // changed right now. It shows up again with the next listing
+            Err(e) if e.kind() == ErrorKind::NotFound => continue,
+            Err(e) => return Err(e).with_context(|| format!("could not read {}", file_path)),
+        };
+
+        snippets.push(Snippet {
+            id,
+            revision: newest.revision,
+            origin: newest.origin.clone(),
+            content,
+            conflicts: revisions[1..]
+                .iter()
+                .take_while(|r| r.revision == newest.revision)
+                .map(|r| r.origin.clone())
+                .collect(),
+        });
+    }
+
+    Ok(snippets)
+}
+
+/// the snippet with this id, or at this index if it is a number. An id wins over an index, ids
+/// pinned before numbers were refused may still be numbers
+pub fn get(config: &Config, id_or_index: &str) -> Result<Option<Snippet>> {
+    let mut snippets = list(config)?;
+
+    if let Some(i) = snippets.iter().position(|s| s.id == id_or_index) {
+        return Ok(Some(snippets.swap_remove(i)));
+    }
+
+    Ok(match id_or_index.parse::<usize>() {
+        Ok(index) => nth(snippets, index),
+        Err(_) => None,
+    })
+}
+
+/// the snippet at this index, counted from 1 like list shows them
+pub fn at(config: &Config, index: usize) -> Result<Option<Snippet>> {
+    Ok(nth(list(config)?, index))
+}
+
+fn nth(snippets: Vec<Snippet>, index: usize) -> Option<Snippet> {
+    index
+        .checked_sub(1)
+        .and_then(|i| snippets.into_iter().nth(i))
+}
+
+/// pins a new snippet. Fails if the id is taken already
+pub fn add(config: &Config, id: &str, content: &[u8]) -> Result<()> {
+    if !is_valid_name(id) {
+        bail!(
+            "snippet ids may only contain letters, digits, - and _: {}",
+            id
+        );
+    }
+    // get would take it for an index
+    if id.chars().all(|c| c.is_ascii_digit()) {
+        bail!(
+            "snippet ids can't be numbers, those are the indices: {}",
+            id
+        );
+    }
+
+    if read_files(config)?.contains_key(id) {
+        bail!("snippet {} exists already, use edit to change it", id);
+    }
+
+    let dir = snippet_dir(config)?;
+    std::fs::create_dir_all(&dir).with_context(|| format!("could not create {}", dir))?;
+
+    write_revision(config, id, 1, content)
+}
+
+/// replaces the content of the snippet. base is the revision the change is based on
+pub fn edit(config: &Config, id: &str, base: u64, content: &[u8]) -> Result<u64> {
+    let revisions = check_base(config, id, base)?;
+    let revision = base + 1;
+
+    write_revision(config, id, revision, content)?;
+    remove_files(config, &revisions)?;
+
+    Ok(revision)
+}
+
+/// unpins the snippet. base is the revision the deletion is based on
+pub fn delete(config: &Config, id: &str, base: u64) -> Result<()> {
+    let revisions = check_base(config, id, base)?;
+    remove_files(config, &revisions)
+}
+
+/// makes sure nobody changed the snippet since revision base. Returns all its files
+fn check_base(config: &Config, id: &str, base: u64) -> Result<Vec<SnippetFile>> {
+    let Some(revisions) = read_files(config)?.remove(id) else {
+        bail!("there is no snippet {}", id);
+    };
+
+    let newest = &revisions[0];
+    if newest.revision != base {
+        bail!(
+            "conflict: snippet {} was changed by {} to revision {} in the meantime. Check it with `clipboard snippets get {}`",
+            id,
+            newest.origin,
+            newest.revision,
+            id
+        );
+    }
+
+    Ok(revisions)
+}
+
+fn write_revision(config: &Config, id: &str, revision: u64, content: &[u8]) -> Result<()> {
+    let dir = snippet_dir(config)?;
+    let staging = SnippetFile::new(id, &config.local_name, revision, EntryKind::Staging);
+
+    let staging_path = format!("{}/{}", dir, staging.file_name());
+    let file_path = format!("{}/{}", dir, staging.published().file_name());
+
+    // same as the entries: never let anyone read a half written file
+    std::fs::write(&staging_path, content)
+        .with_context(|| format!("could not write to file {}", staging_path))?;
+    std::fs::rename(&staging_path, &file_path)
+        .with_context(|| format!("could not publish file {}", file_path))
+}
+
+fn remove_files(config: &Config, files: &[SnippetFile]) -> Result<()> {
+    let dir = snippet_dir(config)?;
+
+    for file in files {
+        let file_path = format!("{}/{}", dir, file.file_name());
+        match std::fs::remove_file(&file_path) {
+            Ok(()) => {}
+            Err(e) if e.kind() == ErrorKind::NotFound => {}
+            Err(e) => return Err(e).with_context(|| format!("could not delete {}", file_path)),
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::test_dir;
+
+    fn config(local: &str, remote: &str, dir: &str) -> Config {
+        Config::parse(&format!(
+            "local_name={}\nremote_names={}\ndir_name={}\n",
+            local, remote, dir
+        ))
+        .unwrap()
+    }
+
+    fn text(snippet: &Snippet) -> &str {
+        std::str::from_utf8(&snippet.content).unwrap()
+    }
+
+    #[test]
+    fn a_change_on_an_old_revision_is_refused() {
+        let dir = test_dir("snippets-stale");
+        let (a, b) = (config("a", "b", &dir), config("b", "a", &dir));
+
+        add(&a, "srv", b"host1").unwrap();
+        assert_eq!(edit(&a, "srv", 1, b"host2").unwrap(), 2);
+
+        // b still saw revision 1
+        let error = edit(&b, "srv", 1, b"host3").unwrap_err();
+        assert!(error.to_string().starts_with("conflict"));
+        assert!(delete(&b, "srv", 1).is_err());
+
+        let snippet = get(&b, "srv").unwrap().unwrap();
+        assert_eq!((snippet.revision, text(&snippet)), (2, "host2"));
+        assert!(snippet.conflicts.is_empty());
+    }
+
+    #[test]
+    fn the_same_revision_written_twice_is_a_conflict() {
+        let dir = test_dir("snippets-conflict");
+        let (a, b) = (config("a", "b", &dir), config("b", "a", &dir));
+
+        add(&a, "srv", b"host1").unwrap();
+        // both based their change on revision 1 and wrote before seeing the other one
+        write_revision(&a, "srv", 2, b"from a").unwrap();
+        write_revision(&b, "srv", 2, b"from b").unwrap();
+
+        let snippet = get(&b, "srv").unwrap().unwrap();
+        assert_eq!(snippet.revision, 2);
+        assert_eq!(snippet.origin, "a");
+        assert_eq!(snippet.conflicts, vec!["b".to_string()]);
+
+        // editing the shown revision resolves it and removes both
+        assert_eq!(edit(&b, "srv", 2, b"merged").unwrap(), 3);
+        let snippet = get(&a, "srv").unwrap().unwrap();
+        assert_eq!((snippet.revision, text(&snippet)), (3, "merged"));
+        assert!(snippet.conflicts.is_empty());
+        assert_eq!(read_files(&a).unwrap()["srv"].len(), 1);
+
+        delete(&a, "srv", 3).unwrap();
+        assert!(get(&b, "srv").unwrap().is_none());
+    }
+}
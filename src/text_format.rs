@@ -0,0 +1,380 @@
+// Line endings and encodings of the entries.
+//
+// Windows and Linux don't agree on line endings, and text piped into send on Windows may be
+// utf-16. The sender decodes utf-16 to utf-8, so the filters and transforms always see utf-8, and
+// records what the content originally was. In the file name it is a suffix of the stamp:
+//
+// clipboard2-<origin>-<timestamp>+crlf.tmp           utf-8 with windows line endings
+// clipboard2-<origin>-<timestamp>+utf16le+crlf.tmp   was utf-16 with windows line endings
+// clipboard2-<origin>-<timestamp>+binary.tmp         no text at all, passed through untouched
+//
+// utf-8 with unix line endings (or none) has no suffix, that is what older versions write. They
+// can't parse a suffix, so those entries get the prefix clipboard2 which they skip, see entry.rs
+//
+// With text_format=native (the default) a receiver converts the line endings to those of its own
+// platform. With text_format=original the line endings stay as they were, and recv writes the
+// content in its original encoding.
+
+use crate::transforms::Transform;
+use anyhow::{bail, Result};
+
+const FORMAT_SEPARATOR: char = '+';
+const UTF16LE_BOM: &[u8] = b"\xff\xfe";
+const UTF16BE_BOM: &[u8] = b"\xfe\xff";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Encoding {
+    #[default]
+    Utf8,
+    /// utf-16 with a byte order mark, as windows tools like to write it
+    Utf16Le,
+    Utf16Be,
+    /// anything which isn't text, or text in an encoding which can't be told apart
+    Binary,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum LineEnding {
+    /// also used for text without any line breaks
+    #[default]
+    Lf,
+    Crlf,
+}
+
+impl Encoding {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Encoding::Utf8 => "utf-8",
+            Encoding::Utf16Le => "utf-16le",
+            Encoding::Utf16Be => "utf-16be",
+            Encoding::Binary => "binary",
+        }
+    }
+}
+
+impl LineEnding {
+    pub fn name(&self) -> &'static str {
+        match self {
+            LineEnding::Lf => "lf",
+            LineEnding::Crlf => "crlf",
+        }
+    }
+
+    /// the line ending of this platform
+    pub fn native() -> Self {
+        if cfg!(windows) {
+            LineEnding::Crlf
+        } else {
+            LineEnding::Lf
+        }
+    }
+
+    /// crlf if any line ends with \r\n, lf otherwise
+    pub fn detect(text: &[u8]) -> Self {
+        if text.windows(2).any(|w| w == b"\r\n") {
+            LineEnding::Crlf
+        } else {
+            LineEnding::Lf
+        }
+    }
+}
+
+/// what the content of an entry originally was
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct TextFormat {
+    pub encoding: Encoding,
+    pub line_ending: LineEnding,
+}
+
+impl TextFormat {
+    pub const BINARY: TextFormat = TextFormat {
+        encoding: Encoding::Binary,
+        line_ending: LineEnding::Lf,
+    };
+
+    /// the suffix of the stamp in the file name. Empty for utf-8 with unix line endings
+    pub fn suffix(&self) -> String {
+        let encoding = match self.encoding {
+            Encoding::Utf8 => None,
+            Encoding::Utf16Le => Some("utf16le"),
+            Encoding::Utf16Be => Some("utf16be"),
+            // line endings mean nothing without text
+            Encoding::Binary => return format!("{}binary", FORMAT_SEPARATOR),
+        };
+        let line_ending = match self.line_ending {
+            LineEnding::Lf => None,
+            LineEnding::Crlf => Some("crlf"),
+        };
+
+        [encoding, line_ending]
+            .into_iter()
+            .flatten()
+            .map(|part| format!("{}{}", FORMAT_SEPARATOR, part))
+            .collect()
+    }
+
+    /// splits the suffix written by TextFormat::suffix from the stamp. Returns None for unknown
+    /// suffixes, those entries were written by a newer version.
+    pub fn split_suffix(stamp: &str) -> Option<(&str, Self)> {
+        let Some((stamp, suffix)) = stamp.split_once(FORMAT_SEPARATOR) else {
+            return Some((stamp, Self::default()));
+        };
+
+        let mut format = Self::default();
+        for part in suffix.split(FORMAT_SEPARATOR) {
+            match part {
+                "utf16le" => format.encoding = Encoding::Utf16Le,
+                "utf16be" => format.encoding = Encoding::Utf16Be,
+                "binary" => format.encoding = Encoding::Binary,
+                "crlf" => format.line_ending = LineEnding::Crlf,
+                _ => return None,
+            }
+        }
+
+        Some((stamp, format))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TextFormatMode {
+    /// utf-8 with the line endings of this platform
+    Native,
+    /// the line endings and the encoding of the sender
+    Original,
+}
+
+impl TextFormatMode {
+    pub fn parse(value: &str) -> Result<Self> {
+        match value {
+            "native" => Ok(TextFormatMode::Native),
+            "original" => Ok(TextFormatMode::Original),
+            _ => bail!("text_format is either native or original: {}", value),
+        }
+    }
+}
+
+/// turns the content into utf-8 and tells what it was before. Content which is no text is
+/// returned as it is
+pub fn decode(content: Vec<u8>) -> (Vec<u8>, TextFormat) {
+    let (encoding, text) = if let Some(utf16) = content.strip_prefix(UTF16LE_BOM) {
+        (Encoding::Utf16Le, decode_utf16(utf16, u16::from_le_bytes))
+    } else if let Some(utf16) = content.strip_prefix(UTF16BE_BOM) {
+        (Encoding::Utf16Be, decode_utf16(utf16, u16::from_be_bytes))
+    } else {
+        (Encoding::Utf8, String::from_utf8(content.clone()).ok())
+    };
+
+    match text {
+        Some(text) => {
+            let text = text.into_bytes();
+            let line_ending = LineEnding::detect(&text);
+            (
+                text,
+                TextFormat {
+                    encoding,
+                    line_ending,
+                },
+            )
+        }
+        None => (content, TextFormat::BINARY),
+    }
+}
+
+fn decode_utf16(content: &[u8], from_bytes: fn([u8; 2]) -> u16) -> Option<String> {
+    if !content.len().is_multiple_of(2) {
+        return None;
+    }
+
+    let units: Vec<u16> = content
+        .chunks_exact(2)
+        .map(|pair| from_bytes([pair[0], pair[1]]))
+        .collect();
+    String::from_utf16(&units).ok()
+}
+
+/// converts the line endings of a received entry according to the mode. The content stays utf-8,
+/// see encode for the encoding
+pub fn convert_line_endings(content: Vec<u8>, format: TextFormat, mode: TextFormatMode) -> Vec<u8> {
+    if format.encoding == Encoding::Binary || mode == TextFormatMode::Original {
+        return content;
+    }
+
+    let transform = match LineEnding::native() {
+        LineEnding::Lf if format.line_ending == LineEnding::Crlf => Transform::CrlfToLf,
+        LineEnding::Crlf => Transform::LfToCrlf,
+        LineEnding::Lf => return content,
+    };
+
+    match String::from_utf8(content) {
+        Ok(text) => transform.apply(&text).into_bytes(),
+        Err(e) => e.into_bytes(),
+    }
+}
+
+/// the received utf-8 content in the encoding the mode asks for. Only the pipe mode writes
+/// anything but utf-8, the clipboard takes text anyway
+pub fn encode(content: Vec<u8>, format: TextFormat, mode: TextFormatMode) -> Vec<u8> {
+    if mode == TextFormatMode::Native {
+        return content;
+    }
+
+    let text = match String::from_utf8(content) {
+        Ok(text) => text,
+        // binary content is passed through untouched
+        Err(e) => return e.into_bytes(),
+    };
+
+    let mut encoded = Vec::with_capacity(text.len() * 2 + 2);
+    match format.encoding {
+        Encoding::Utf16Le => {
+            encoded.extend_from_slice(UTF16LE_BOM);
+            encoded.extend(text.encode_utf16().flat_map(u16::to_le_bytes));
+        }
+        Encoding::Utf16Be => {
+            encoded.extend_from_slice(UTF16BE_BOM);
+            encoded.extend(text.encode_utf16().flat_map(u16::to_be_bytes));
+        }
+        Encoding::Utf8 | Encoding::Binary => return text.into_bytes(),
+    }
+    encoded
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn utf16le(text: &str) -> Vec<u8> {
+        let mut bytes = UTF16LE_BOM.to_vec();
+        bytes.extend(text.encode_utf16().flat_map(u16::to_le_bytes));
+        bytes
+    }
+
+    fn utf16be(text: &str) -> Vec<u8> {
+        let mut bytes = UTF16BE_BOM.to_vec();
+        bytes.extend(text.encode_utf16().flat_map(u16::to_be_bytes));
+        bytes
+    }
+
+    fn format(encoding: Encoding, line_ending: LineEnding) -> TextFormat {
+        TextFormat {
+            encoding,
+            line_ending,
+        }
+    }
+
+    #[test]
+    fn decode_tells_what_the_content_was() {
+        assert_eq!(
+            decode(b"a\nb".to_vec()),
+            (b"a\nb".to_vec(), format(Encoding::Utf8, LineEnding::Lf))
+        );
+        assert_eq!(
+            decode(b"a\r\nb".to_vec()),
+            (b"a\r\nb".to_vec(), format(Encoding::Utf8, LineEnding::Crlf))
+        );
+        assert_eq!(
+            decode(utf16le("grüße\r\n")),
+            (
+                "grüße\r\n".as_bytes().to_vec(),
+                format(Encoding::Utf16Le, LineEnding::Crlf)
+            )
+        );
+        assert_eq!(
+            decode(utf16be("€ 1")),
+            (
+                "€ 1".as_bytes().to_vec(),
+                format(Encoding::Utf16Be, LineEnding::Lf)
+            )
+        );
+    }
+
+    #[test]
+    fn what_is_no_text_stays_as_it_is() {
+        let binary: &[&[u8]] = &[
+            b"\x89PNG\r\n\x1a\n\x00",
+            // a byte order mark and half a code unit
+            b"\xff\xfea\x00b",
+            // an unpaired surrogate
+            b"\xff\xfe\x00\xd8",
+        ];
+
+        for content in binary {
+            assert_eq!(
+                decode(content.to_vec()),
+                (content.to_vec(), TextFormat::BINARY)
+            );
+        }
+    }
+
+    #[test]
+    fn utf16_survives_the_round_trip() {
+        for original in [utf16le("line 1\r\nline 2 ✓"), utf16be("line 1\nline 2 ✓")] {
+            let (content, format) = decode(original.clone());
+            assert_eq!(
+                encode(content.clone(), format, TextFormatMode::Original),
+                original
+            );
+            // the clipboard gets utf-8
+            assert_eq!(
+                encode(content.clone(), format, TextFormatMode::Native),
+                content
+            );
+        }
+
+        let binary = b"\x00\xff".to_vec();
+        assert_eq!(
+            encode(binary.clone(), TextFormat::BINARY, TextFormatMode::Original),
+            binary
+        );
+    }
+
+    #[test]
+    fn native_converts_the_line_endings_to_this_platform() {
+        let crlf = format(Encoding::Utf8, LineEnding::Crlf);
+        let lf = format(Encoding::Utf8, LineEnding::Lf);
+        let native = match LineEnding::native() {
+            LineEnding::Lf => b"a\nb".to_vec(),
+            LineEnding::Crlf => b"a\r\nb".to_vec(),
+        };
+
+        assert_eq!(
+            convert_line_endings(b"a\r\nb".to_vec(), crlf, TextFormatMode::Native),
+            native
+        );
+        assert_eq!(
+            convert_line_endings(b"a\nb".to_vec(), lf, TextFormatMode::Native),
+            native
+        );
+        assert_eq!(
+            convert_line_endings(b"a\r\nb".to_vec(), crlf, TextFormatMode::Original),
+            b"a\r\nb"
+        );
+        assert_eq!(
+            convert_line_endings(
+                b"a\r\n\xff".to_vec(),
+                TextFormat::BINARY,
+                TextFormatMode::Native
+            ),
+            b"a\r\n\xff"
+        );
+    }
+
+    #[test]
+    fn the_suffix_survives_the_file_name() {
+        let formats = [
+            TextFormat::default(),
+            format(Encoding::Utf8, LineEnding::Crlf),
+            format(Encoding::Utf16Le, LineEnding::Crlf),
+            format(Encoding::Utf16Be, LineEnding::Lf),
+            TextFormat::BINARY,
+        ];
+
+        for format in formats {
+            let stamp = format!("100{}", format.suffix());
+            assert_eq!(TextFormat::split_suffix(&stamp), Some(("100", format)));
+        }
+        assert_eq!(TextFormat::default().suffix(), "");
+        // written by a newer version
+        assert_eq!(TextFormat::split_suffix("100+utf32"), None);
+    }
+}
@@ -0,0 +1,202 @@
+// Small text transforms applied to the entries on their way between the machines.
+//
+// send_transforms are applied before an entry is published, receive_transforms before a remote
+// entry is pasted. receive_transforms.<peer> only apply to the entries of that remote, after the
+// general ones. transform_hotkeys lists the transforms ctrl+shift+alt+1 to 9 apply to the local
+// clipboard right away.
+//
+// Every transform is a pure function from text to text. Content which isn't utf-8 is passed
+// through untouched.
+
+use anyhow::{bail, Result};
+use regex::Regex;
+use std::sync::LazyLock;
+use unicode_normalization::UnicodeNormalization;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Transform {
+    /// windows line endings to unix ones
+    CrlfToLf,
+    /// unix line endings to windows ones
+    LfToCrlf,
+    /// removes the whitespace at the end of every line and of the text
+    TrimTrailing,
+    /// removes the color and cursor codes a terminal copy may contain
+    StripAnsi,
+    /// unicode normalization form C, so the same text is always the same bytes
+    Nfc,
+    /// turns C:\dir\file into C:/dir/file and \\server\share into //server/share
+    UnixPaths,
+}
+
+pub const TRANSFORMS: [Transform; 6] = [
+    Transform::CrlfToLf,
+    Transform::LfToCrlf,
+    Transform::TrimTrailing,
+    Transform::StripAnsi,
+    Transform::Nfc,
+    Transform::UnixPaths,
+];
+
+// CSI sequences like colors, and OSC sequences like window titles or links
+static ANSI_ESCAPE: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(r"\x1b\[[0-?]*[ -/]*[@-~]|\x1b\][^\x07\x1b]*(?:\x07|\x1b\\)|\x1b[@-Z\\-_]").unwrap()
+});
+// a drive letter or a UNC share, followed by everything up to the next whitespace
+static WINDOWS_PATH: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(r#"\b[A-Za-z]:\\[^\s"'<>|]*|\\\\[^\s"'<>|\\]+\\[^\s"'<>|]*"#).unwrap()
+});
+
+impl Transform {
+    pub fn parse(value: &str) -> Result<Self> {
+        match TRANSFORMS.iter().find(|t| t.name() == value) {
+            Some(transform) => Ok(*transform),
+            None => bail!(
+                "unknown transform {}, the transforms are {}",
+                value,
+                TRANSFORMS.map(|t| t.name()).join(", ")
+            ),
+        }
+    }
+
+    /// a comma separated list of transforms
+    pub fn parse_list(value: &str) -> Result<Vec<Self>> {
+        value.split(",").map(|t| Self::parse(t.trim())).collect()
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Transform::CrlfToLf => "crlf-to-lf",
+            Transform::LfToCrlf => "lf-to-crlf",
+            Transform::TrimTrailing => "trim-trailing",
+            Transform::StripAnsi => "strip-ansi",
+            Transform::Nfc => "nfc",
+            Transform::UnixPaths => "unix-paths",
+        }
+    }
+
+    pub fn apply(&self, text: &str) -> String {
+        match self {
+            Transform::CrlfToLf => crlf_to_lf(text),
+            Transform::LfToCrlf => lf_to_crlf(text),
+            Transform::TrimTrailing => trim_trailing(text),
+            Transform::StripAnsi => strip_ansi(text),
+            Transform::Nfc => text.nfc().collect(),
+            Transform::UnixPaths => unix_paths(text),
+        }
+    }
+}
+
+fn crlf_to_lf(text: &str) -> String {
+    text.replace("\r\n", "\n")
+}
+
+fn lf_to_crlf(text: &str) -> String {
+    // lines which end with \r\n already must not get a second \r
+    crlf_to_lf(text).replace("\n", "\r\n")
+}
+
+fn trim_trailing(text: &str) -> String {
+    let lines: Vec<_> = text
+        .split('\n')
+        .map(|line| match line.strip_suffix('\r') {
+            // keep windows line endings
+            Some(line) => format!("{}\r", line.trim_end()),
+            None => line.trim_end().to_string(),
+        })
+        .collect();
+
+    lines.join("\n").trim_end().to_string()
+}
+
+fn strip_ansi(text: &str) -> String {
+    ANSI_ESCAPE.replace_all(text, "").into_owned()
+}
+
+fn unix_paths(text: &str) -> String {
+    WINDOWS_PATH
+        .replace_all(text, |caps: &regex::Captures| caps[0].replace('\\', "/"))
+        .into_owned()
+}
+
+/// applies the transforms in order. Content which isn't utf-8 is returned as it is
+pub fn apply_all(transforms: &[Transform], content: Vec<u8>) -> Vec<u8> {
+    if transforms.is_empty() {
+        return content;
+    }
+
+    match String::from_utf8(content) {
+        Ok(text) => transforms
+            .iter()
+            .fold(text, |text, transform| transform.apply(&text))
+            .into_bytes(),
+        Err(e) => e.into_bytes(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn crlf_to_lf_leaves_lone_cr() {
+        assert_eq!(Transform::CrlfToLf.apply("a\r\nb\rc\n"), "a\nb\rc\n");
+    }
+
+    #[test]
+    fn lf_to_crlf_doesnt_double_cr() {
+        assert_eq!(Transform::LfToCrlf.apply("a\nb\r\nc"), "a\r\nb\r\nc");
+    }
+
+    #[test]
+    fn trim_trailing_keeps_line_endings() {
+        assert_eq!(
+            Transform::TrimTrailing.apply("a  \nb\t\r\nc \n\n"),
+            "a\nb\r\nc"
+        );
+        assert_eq!(Transform::TrimTrailing.apply("  indented"), "  indented");
+    }
+
+    #[test]
+    fn strip_ansi_removes_colors_and_titles() {
+        let copied = "\x1b[1;31merror\x1b[0m: \x1b]0;title\x07done\x1b]8;;http://x\x1b\\link";
+        assert_eq!(Transform::StripAnsi.apply(copied), "error: donelink");
+    }
+
+    #[test]
+    fn nfc_composes() {
+        assert_eq!(Transform::Nfc.apply("e\u{301}"), "\u{e9}");
+    }
+
+    #[test]
+    fn unix_paths_converts_drives_and_shares_only() {
+        assert_eq!(
+            Transform::UnixPaths.apply(r"see C:\dir\file.txt and \\server\share\x, not a\b"),
+            r"see C:/dir/file.txt and //server/share/x, not a\b"
+        );
+    }
+
+    #[test]
+    fn parse_list_by_name() {
+        assert_eq!(
+            Transform::parse_list("crlf-to-lf, nfc").unwrap(),
+            vec![Transform::CrlfToLf, Transform::Nfc]
+        );
+        assert!(Transform::parse("upper").is_err());
+        for transform in TRANSFORMS {
+            assert_eq!(Transform::parse(transform.name()).unwrap(), transform);
+        }
+    }
+
+    #[test]
+    fn apply_all_in_order_and_skips_binary() {
+        let transforms = [Transform::StripAnsi, Transform::TrimTrailing];
+        assert_eq!(
+            apply_all(&transforms, b"\x1b[0mtext \x1b[0m".to_vec()),
+            b"text".to_vec()
+        );
+
+        let binary = vec![0xff, b' ', b'\r', b'\n'];
+        assert_eq!(apply_all(&transforms, binary.clone()), binary);
+    }
+}
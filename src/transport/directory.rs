@@ -0,0 +1,349 @@
+use super::index::EntryIndex;
+use super::watcher::spawn_watcher;
+use super::{Entry, Transport};
+use crate::config::{Config, PasteMode};
+use crate::entry::{EntryFile, EntryKind};
+use crate::logfile::log;
+use crate::utils::get_timestamp;
+use anyhow::{Context, Result};
+use std::io::ErrorKind;
+use std::sync::{Arc, Mutex};
+
+// (file name, content) of the newest remote entry, read before it was requested
+type Prefetched = Arc<Mutex<Option<(String, Vec<u8>)>>>;
+
+/// exchanges entries as files in the shared directory config.dir_name
+pub struct DirectoryTransport {
+    config: Config,
+    index: Arc<EntryIndex>,
+    prefetched: Prefetched,
+}
+
+impl DirectoryTransport {
+    /// scans config.dir_name and starts watching it for changes
+    pub fn open(config: &Config) -> Result<Self> {
+        let index = Arc::new(EntryIndex::new(config));
+        index.rescan(&config.dir_name)?;
+
+        let prefetched: Prefetched = Arc::new(Mutex::new(None));
+
+        // read the newest remote entry as soon as it shows up so the paste is instant
+        let dir_name = config.dir_name.clone();
+        let cache = prefetched.clone();
+        spawn_watcher(config.clone(), index.clone(), move |entry| {
+            let file_name = entry.file_name();
+            match read_file(&dir_name, &file_name) {
+                Ok(content) => *cache.lock().unwrap() = Some((file_name, content)),
+                Err(e) => {
+                    // not fatal. The file is read again on the actual load
+                    log(&format!("could not prefetch: {}\n", e));
+                }
+            }
+        })?;
+
+        Ok(Self {
+            config: config.clone(),
+            index,
+            prefetched,
+        })
+    }
+
+    /// a transport which only publishes. It doesn't watch config.dir_name, so it never reports
+    /// the arrivals of the channel a second time
+    pub fn publisher(config: &Config) -> Result<Self> {
+        let index = Arc::new(EntryIndex::new(config));
+        index.rescan(&config.dir_name)?;
+
+        Ok(Self {
+            config: config.clone(),
+            index,
+            prefetched: Prefetched::default(),
+        })
+    }
+
+    fn get_file_to_load(&self) -> Option<EntryFile> {
+        self.index.latest_remote()
+    }
+
+    /// returns the content of the file. Uses the prefetched content if there is any. None if the
+    /// file is gone, then it is dropped from the index
+    fn load_file(&self, file_name: &str) -> Result<Option<Vec<u8>>> {
+        if let Some((prefetched_name, content)) = self.prefetched.lock().unwrap().take() {
+            if prefetched_name == file_name {
+                return Ok(Some(content));
+            }
+        }
+
+        match std::fs::read(format!("{}/{}", self.config.dir_name, file_name)) {
+            Ok(content) => Ok(Some(content)),
+            // another instance took it before the watcher told us, the index is behind
+            Err(e) if e.kind() == ErrorKind::NotFound => {
+                log(&format!("{} is gone already\n", file_name));
+                self.index.remove(file_name);
+                Ok(None)
+            }
+            Err(e) => Err(e).with_context(|| format!("could not read file {}", file_name)),
+        }
+    }
+
+    fn generate_file(&self, entry: &Entry) -> Result<()> {
+        let queue = self.config.paste_mode == PasteMode::Queue;
+
+        // queued entries must not replace each other, even if they are from the same second
+        let newest_own = self.index.own_entries().last().map(|e| e.timestamp);
+        let timestamp = match newest_own {
+            Some(newest) if queue && newest >= entry.timestamp => newest + 1,
+            _ => entry.timestamp,
+        };
+
+        let staging = EntryFile::new(
+            self.config.channel.as_deref(),
+            &entry.origin,
+            timestamp,
+            EntryKind::Staging,
+        )
+        .with_expiry(entry.expires)
+        .with_format(entry.format);
+        let published = staging.published();
+
+        let staging_path = format!("{}/{}", self.config.dir_name, staging.file_name());
+        let file_path = format!("{}/{}", self.config.dir_name, published.file_name());
+
+        // check if there is already a file created from this instance. If so, delete it.
+        // In queue mode it is still waiting to be pasted
+        if !queue {
+            self.try_delete_own_file()?;
+        }
+
+        // write under a staging name first so the remotes never load a half written file
+        std::fs::write(&staging_path, &entry.content)
+            .with_context(|| format!("could not write to file {}", staging_path))?;
+
+        std::fs::rename(&staging_path, &file_path)
+            .with_context(|| format!("could not publish file {}", file_path))?;
+
+        // don't wait for the watcher. The next load has to know about this file already
+        self.index.insert(&published.file_name());
+        Ok(())
+    }
+
+    fn try_delete_own_file(&self) -> Result<()> {
+        for entry in self.index.own_entries() {
+            let file_name = entry.file_name();
+            let file_path = format!("{}/{}", self.config.dir_name, file_name);
+
+            match std::fs::remove_file(&file_path) {
+                Ok(()) => {}
+                Err(e) if e.kind() == ErrorKind::NotFound => {}
+                Err(e) => return Err(e.into()),
+            }
+            self.index.remove(&file_name);
+        }
+        Ok(())
+    }
+
+    fn try_delete_file(&self, file_name: &str) -> Result<()> {
+        let file_path = format!("{}/{}", self.config.dir_name, file_name);
+        std::fs::remove_file(&file_path)?;
+        self.index.remove(file_name);
+        Ok(())
+    }
+}
+
+impl Transport for DirectoryTransport {
+    fn publish(&mut self, entry: &Entry) -> Result<()> {
+        self.generate_file(entry)
+            .with_context(|| "could not generate file")
+    }
+
+    fn take_latest(&mut self) -> Result<Option<Entry>> {
+        let (entry_file, content) = loop {
+            let Some(entry_file) = self.get_file_to_load() else {
+                return Ok(None);
+            };
+            // a stale file is out of the index now, the next one is tried
+            if let Some(content) = self.load_file(&entry_file.file_name())? {
+                break (entry_file, content);
+            }
+        };
+
+        let file_name = entry_file.file_name();
+
+        self.try_delete_own_file()
+            .with_context(|| "could not delete own file")?;
+
+        if let Err(e) = self.try_delete_file(&file_name) {
+            log(&format!("could not delete file: {}\nThis is ignored since the program will run fine. But it will leave useless .tmp files behind.", e));
+        }
+
+        Ok(Some(Entry {
+            origin: entry_file.origin,
+            timestamp: entry_file.timestamp,
+            expires: entry_file.expires,
+            content,
+            format: entry_file.format,
+        }))
+    }
+
+    fn peek_oldest(&mut self) -> Result<Option<Entry>> {
+        let (entry_file, content) = loop {
+            let Some(entry_file) = self.index.oldest_remote() else {
+                return Ok(None);
+            };
+            if let Some(content) = self.load_file(&entry_file.file_name())? {
+                break (entry_file, content);
+            }
+        };
+
+        Ok(Some(Entry {
+            origin: entry_file.origin,
+            timestamp: entry_file.timestamp,
+            expires: entry_file.expires,
+            content,
+            format: entry_file.format,
+        }))
+    }
+
+    fn remove(&mut self, entry: &Entry) -> Result<()> {
+        let file_name = EntryFile::new(
+            self.config.channel.as_deref(),
+            &entry.origin,
+            entry.timestamp,
+            EntryKind::Published,
+        )
+        .with_expiry(entry.expires)
+        .with_format(entry.format)
+        .file_name();
+
+        let file_path = format!("{}/{}", self.config.dir_name, file_name);
+
+        match std::fs::remove_file(&file_path) {
+            Ok(()) => {}
+            // another instance was faster. It is gone either way
+            Err(e) if e.kind() == ErrorKind::NotFound => {}
+            Err(e) => {
+                return Err(e).with_context(|| format!("could not delete file {}", file_path))
+            }
+        }
+
+        self.index.remove(&file_name);
+        Ok(())
+    }
+
+    fn remove_expired(&mut self) -> Result<()> {
+        for entry in self.index.expired(get_timestamp()) {
+            let file_name = entry.file_name();
+            let file_path = format!("{}/{}", self.config.dir_name, file_name);
+
+            match std::fs::remove_file(&file_path) {
+                Ok(()) => log(&format!("removed expired entry {}\n", file_name)),
+                // the other side was faster
+                Err(e) if e.kind() == ErrorKind::NotFound => {}
+                Err(e) => {
+                    return Err(e).with_context(|| format!("could not delete file {}", file_path))
+                }
+            }
+            self.index.remove(&file_name);
+        }
+
+        // a prefetched copy must not outlive the file
+        let mut prefetched = self.prefetched.lock().unwrap();
+        let expired = prefetched.as_ref().is_some_and(|(file_name, _)| {
+            EntryFile::parse(file_name).is_some_and(|e| e.is_expired(get_timestamp()))
+        });
+        if expired {
+            *prefetched = None;
+        }
+
+        Ok(())
+    }
+
+    fn close(&mut self, remove_own_entry: bool) -> Result<()> {
+        // in queue mode that is every own entry which wasn't pasted yet
+        if remove_own_entry {
+            self.try_delete_own_file()
+                .with_context(|| "could not delete own file")?;
+        }
+        Ok(())
+    }
+}
+
+fn read_file(dir_name: &str, file_name: &str) -> Result<Vec<u8>> {
+    let file_path = format!("{}/{}", dir_name, file_name);
+    std::fs::read(&file_path).with_context(|| format!("could not read file {}", file_path))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::text_format::TextFormat;
+    use crate::utils::test_dir;
+
+    fn config(local: &str, remote: &str, dir: &str, paste_mode: &str) -> Config {
+        Config::parse(&format!(
+            "local_name={}\nremote_names={}\ndir_name={}\npaste_mode={}\n",
+            local, remote, dir, paste_mode
+        ))
+        .unwrap()
+    }
+
+    fn entry(origin: &str, timestamp: u64, text: &str) -> Entry {
+        Entry {
+            origin: origin.to_string(),
+            timestamp,
+            expires: None,
+            content: text.as_bytes().to_vec(),
+            format: TextFormat::default(),
+        }
+    }
+
+    fn text(entry: &Entry) -> &str {
+        std::str::from_utf8(&entry.content).unwrap()
+    }
+
+    #[test]
+    fn queued_entries_of_the_same_second_are_pasted_in_order() {
+        let dir = test_dir("directory-queue");
+        let mut a = DirectoryTransport::publisher(&config("a", "b", &dir, "queue")).unwrap();
+
+        let now = get_timestamp();
+        for text in ["first", "second", "third"] {
+            a.publish(&entry("a", now, text)).unwrap();
+        }
+        // a copy stamped before the newest one still goes to the end of the queue
+        a.publish(&entry("a", now - 5, "fourth")).unwrap();
+
+        let mut b = DirectoryTransport::publisher(&config("b", "a", &dir, "queue")).unwrap();
+        let mut pasted = Vec::new();
+        while let Some(oldest) = b.peek_oldest().unwrap() {
+            pasted.push((oldest.timestamp - now, text(&oldest).to_string()));
+            b.remove(&oldest).unwrap();
+        }
+
+        assert_eq!(
+            pasted,
+            vec![
+                (0, "first".to_string()),
+                (1, "second".to_string()),
+                (2, "third".to_string()),
+                (3, "fourth".to_string()),
+            ]
+        );
+        assert!(std::fs::read_dir(&dir).unwrap().next().is_none());
+    }
+
+    #[test]
+    fn without_queue_a_new_entry_replaces_the_own_one() {
+        let dir = test_dir("directory-latest");
+        let mut a = DirectoryTransport::publisher(&config("a", "b", &dir, "latest")).unwrap();
+
+        let now = get_timestamp();
+        a.publish(&entry("a", now, "first")).unwrap();
+        a.publish(&entry("a", now, "second")).unwrap();
+        assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 1);
+
+        let mut b = DirectoryTransport::publisher(&config("b", "a", &dir, "latest")).unwrap();
+        assert_eq!(text(&b.take_latest().unwrap().unwrap()), "second");
+        assert!(b.take_latest().unwrap().is_none());
+    }
+}
@@ -0,0 +1,148 @@
+use crate::config::Config;
+use crate::entry::{EntryFile, EntryKind};
+use crate::metrics::{self, Histogram};
+use crate::utils::{get_timestamp, read_file_names};
+use anyhow::Result;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Mutex;
+use std::time::Instant;
+
+/// in-memory view of the published entries in config.dir_name. It is kept up to date by the
+/// watcher so that the file handler never has to scan the whole directory on a keypress.
+/// Only the entries of config.channel are part of it.
+pub struct EntryIndex {
+    channel: Option<String>,
+    local_name: String,
+    remote_names: Vec<String>,
+    // origin -> all published entries of this origin by their timestamp
+    entries: Mutex<HashMap<String, BTreeMap<u64, EntryFile>>>,
+}
+
+impl EntryIndex {
+    pub fn new(config: &Config) -> Self {
+        Self {
+            channel: config.channel.clone(),
+            local_name: config.local_name.clone(),
+            remote_names: config.remote_names.clone(),
+            entries: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// throws away the current view and reads the directory again
+    pub fn rescan(&self, dir_name: &str) -> Result<()> {
+        let mut entries: HashMap<String, BTreeMap<u64, EntryFile>> = HashMap::new();
+
+        let start = Instant::now();
+        let file_names = read_file_names(dir_name)?;
+        metrics::observe(Histogram::ScanSeconds, start.elapsed());
+
+        for file_name in file_names {
+            if let Some(entry) = self.parse_published(&file_name) {
+                entries
+                    .entry(entry.origin.clone())
+                    .or_default()
+                    .insert(entry.timestamp, entry);
+            }
+        }
+
+        *self.entries.lock().unwrap() = entries;
+        Ok(())
+    }
+
+    /// adds the entry. Returns it if it wasn't known yet
+    pub fn insert(&self, file_name: &str) -> Option<EntryFile> {
+        let entry = self.parse_published(file_name)?;
+        self.entries
+            .lock()
+            .unwrap()
+            .entry(entry.origin.clone())
+            .or_default()
+            .insert(entry.timestamp, entry.clone())
+            .is_none()
+            .then_some(entry)
+    }
+
+    pub fn remove(&self, file_name: &str) {
+        if let Some(entry) = self.parse_published(file_name) {
+            let mut entries = self.entries.lock().unwrap();
+            if let Some(timestamps) = entries.get_mut(&entry.origin) {
+                timestamps.remove(&entry.timestamp);
+                if timestamps.is_empty() {
+                    entries.remove(&entry.origin);
+                }
+            }
+        }
+    }
+
+    /// the newest entry of the configured remotes. None if there is none or if this instance has
+    /// published something newer in the meantime. Expired entries are skipped.
+    pub fn latest_remote(&self) -> Option<EntryFile> {
+        let entries = self.entries.lock().unwrap();
+        let now = get_timestamp();
+
+        let newest = |origin: &str| {
+            entries.get(origin).and_then(|timestamps| {
+                timestamps
+                    .values()
+                    .rev()
+                    .find(|entry| !entry.is_expired(now))
+            })
+        };
+
+        let own = newest(&self.local_name);
+        let remote = self
+            .remote_names
+            .iter()
+            .filter_map(|origin| newest(origin))
+            .max_by_key(|entry| (entry.timestamp, &entry.origin));
+
+        match (own, remote) {
+            (Some(own), Some(remote)) if own.timestamp >= remote.timestamp => None,
+            (_, remote) => remote.cloned(),
+        }
+    }
+
+    /// the oldest entry of the configured remotes, no matter what this instance published.
+    /// Expired entries are skipped.
+    pub fn oldest_remote(&self) -> Option<EntryFile> {
+        let entries = self.entries.lock().unwrap();
+        let now = get_timestamp();
+
+        self.remote_names
+            .iter()
+            .filter_map(|origin| {
+                entries
+                    .get(origin)
+                    .and_then(|timestamps| timestamps.values().find(|entry| !entry.is_expired(now)))
+            })
+            .min_by_key(|entry| (entry.timestamp, &entry.origin))
+            .cloned()
+    }
+
+    /// all entries published by this instance
+    pub fn own_entries(&self) -> Vec<EntryFile> {
+        self.entries
+            .lock()
+            .unwrap()
+            .get(&self.local_name)
+            .map(|timestamps| timestamps.values().cloned().collect())
+            .unwrap_or_default()
+    }
+
+    /// the sensitive entries of any origin whose time is up
+    pub fn expired(&self, now: u64) -> Vec<EntryFile> {
+        self.entries
+            .lock()
+            .unwrap()
+            .values()
+            .flat_map(|timestamps| timestamps.values())
+            .filter(|entry| entry.is_expired(now))
+            .cloned()
+            .collect()
+    }
+
+    fn parse_published(&self, file_name: &str) -> Option<EntryFile> {
+        EntryFile::parse(file_name)
+            .filter(|entry| entry.kind == EntryKind::Published && entry.channel == self.channel)
+    }
+}
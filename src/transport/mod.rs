@@ -0,0 +1,60 @@
+// A transport moves clipboard entries between this instance and its remotes.
+//
+// directory: the original implementation. Entries are files in a shared directory
+// tcp:       entries are pushed directly to the configured peers
+
+mod directory;
+mod index;
+mod tcp;
+mod tls;
+mod watcher;
+
+use crate::config::{Config, TransportKind};
+use crate::text_format::TextFormat;
+use anyhow::Result;
+
+pub use directory::DirectoryTransport;
+pub use tcp::TcpTransport;
+pub use tls::{generate_certificate, own_fingerprint};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Entry {
+    pub origin: String,
+    pub timestamp: u64,
+    /// when a sensitive entry has to be gone everywhere. None for all others
+    pub expires: Option<u64>,
+    /// raw bytes. Text is always utf-8 here, pipe mode may send anything else as binary
+    pub content: Vec<u8>,
+    /// the line endings and encoding the content had at the sender
+    pub format: TextFormat,
+}
+
+pub trait Transport: Send {
+    /// makes the entry available to the remotes. It replaces the previous entry of this instance.
+    fn publish(&mut self, entry: &Entry) -> Result<()>;
+
+    /// takes the newest remote entry, unless this instance published something newer since.
+    /// A taken entry is consumed and will not be returned again.
+    fn take_latest(&mut self) -> Result<Option<Entry>>;
+
+    /// the oldest remote entry. It is not consumed until it is removed. Used by the queue mode
+    fn peek_oldest(&mut self) -> Result<Option<Entry>>;
+
+    /// consumes an entry returned by peek_oldest
+    fn remove(&mut self, entry: &Entry) -> Result<()>;
+
+    /// drops the sensitive entries whose time is up, the own ones and the received ones
+    fn remove_expired(&mut self) -> Result<()>;
+
+    /// ends the transport, the instance shuts down. With remove_own_entry the entry of this
+    /// instance is taken back where the transport keeps it
+    fn close(&mut self, remove_own_entry: bool) -> Result<()>;
+}
+
+/// opens the transport selected in the config
+pub fn open(config: &Config) -> Result<Box<dyn Transport>> {
+    Ok(match config.transport {
+        TransportKind::Directory => Box::new(DirectoryTransport::open(config)?),
+        TransportKind::Tcp => Box::new(TcpTransport::start(config)?),
+    })
+}
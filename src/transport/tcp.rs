@@ -0,0 +1,1049 @@
+use super::tls::TlsContext;
+use super::{Entry, Transport};
+use crate::config::{Config, PasteMode, Peer};
+use crate::entry::is_expired;
+use crate::events::{self, ErrorKind, Event};
+use crate::logfile::log;
+use crate::metrics::{self, Counter};
+use crate::text_format::{Encoding, LineEnding, TextFormat};
+use crate::utils::{get_timestamp, join_until};
+use anyhow::{bail, Context, Result};
+use std::collections::VecDeque;
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::net::{TcpListener, TcpStream, ToSocketAddrs};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+// every connection starts with this, so a stray connection is rejected right away. Version 2
+// added the expiry of sensitive entries, version 3 the text format, version 4 the acks
+const MAGIC: &[u8; 4] = b"CLP4";
+// the receiver answers every entry with one of these. Only then the sender forgets it
+const ACK_TAKEN: u8 = 0;
+// the entry was over MAX_CONTENT_LEN. Sending it again wouldn't help
+const ACK_REJECTED: u8 = 1;
+// how long the sender waits for the ack once the entry is written
+const ACK_TIMEOUT: Duration = Duration::from_secs(30);
+// only the newest entries matter. Older ones are dropped while a peer is offline
+const MAX_QUEUED: usize = 16;
+// in queue mode every entry stays until it is pasted. A peer must not fill the memory with them
+const MAX_INBOX: usize = 64;
+const MAX_CONTENT_LEN: u32 = 64 * 1024 * 1024;
+const MAX_ORIGIN_LEN: u32 = 256;
+const RECONNECT_DELAY: Duration = Duration::from_secs(5);
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+// how long closing waits for the pending entries to be delivered. Long enough for a connect
+const CLOSE_TIMEOUT: Duration = Duration::from_secs(6);
+// how long an accepted connection may take for the tls handshake and the magic. A peer which
+// connects and stays silent must not keep its thread forever
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+// every peer needs one connection. The rest are refused, so nobody can start threads without end
+const MAX_CONNECTIONS: usize = 32;
+
+// the received entries. Only the newest one of every origin, unless in queue mode
+type Inbox = Arc<Mutex<Vec<Entry>>>;
+
+// a plain or a tls connection. Entries go one way, the acks the other
+trait Stream: Read + Write + Send {}
+impl<T: Read + Write + Send> Stream for T {}
+
+/// pushes entries directly to the configured peers and listens for theirs
+pub struct TcpTransport {
+    inbox: Inbox,
+    queues: Vec<Sender<Entry>>,
+    workers: Vec<JoinHandle<()>>,
+    last_published: Option<u64>,
+}
+
+impl TcpTransport {
+    /// binds config.listen and starts a worker for every peer in config.peers
+    pub fn start(config: &Config) -> Result<Self> {
+        let listener = TcpListener::bind(&config.listen)
+            .with_context(|| format!("could not listen on {}", config.listen))?;
+        log(&format!("listening on {}\n", listener.local_addr()?));
+
+        let tls = if config.tls {
+            Some(Arc::new(TlsContext::load(config)?))
+        } else {
+            log("tls is disabled, entries are sent unencrypted\n");
+            None
+        };
+
+        let inbox: Inbox = Arc::new(Mutex::new(Vec::new()));
+
+        let accepting_inbox = inbox.clone();
+        let accepting_tls = tls.clone();
+        let remote_names = config.remote_names.clone();
+        let queue = config.paste_mode == PasteMode::Queue;
+        thread::spawn(move || {
+            accept_loop(
+                listener,
+                remote_names,
+                accepting_tls,
+                queue,
+                accepting_inbox,
+            )
+        });
+
+        let (queues, workers) = config
+            .peers
+            .iter()
+            .map(|peer| {
+                let (sender, receiver) = mpsc::channel();
+                let peer = peer.clone();
+                let tls = tls.clone();
+                (
+                    sender,
+                    thread::spawn(move || peer_worker(peer, tls, receiver)),
+                )
+            })
+            .unzip();
+
+        Ok(Self {
+            inbox,
+            queues,
+            workers,
+            last_published: None,
+        })
+    }
+}
+
+impl Transport for TcpTransport {
+    fn publish(&mut self, entry: &Entry) -> Result<()> {
+        // the peers would refuse it anyway
+        if u32::try_from(entry.content.len()).map_or(true, |len| len > MAX_CONTENT_LEN) {
+            bail!(
+                "the entry has {} bytes, the tcp transport takes at most {}",
+                entry.content.len(),
+                MAX_CONTENT_LEN
+            );
+        }
+
+        self.last_published = Some(entry.timestamp);
+
+        for queue in &self.queues {
+            // a closed queue means the worker died. The others are still worth a try
+            if queue.send(entry.clone()).is_err() {
+                log("a peer worker is gone, entry not queued for it\n");
+            }
+        }
+
+        Ok(())
+    }
+
+    fn take_latest(&mut self) -> Result<Option<Entry>> {
+        let mut inbox = self.inbox.lock().unwrap();
+
+        let Some((i, newest)) = inbox
+            .iter()
+            .enumerate()
+            .max_by_key(|(_, entry)| entry.timestamp)
+        else {
+            return Ok(None);
+        };
+
+        if self.last_published >= Some(newest.timestamp) {
+            // this instance published something newer in the meantime
+            return Ok(None);
+        }
+
+        self.last_published = None;
+        Ok(Some(inbox.remove(i)))
+    }
+
+    fn peek_oldest(&mut self) -> Result<Option<Entry>> {
+        let inbox = self.inbox.lock().unwrap();
+        Ok(inbox.iter().min_by_key(|entry| entry.timestamp).cloned())
+    }
+
+    fn remove(&mut self, entry: &Entry) -> Result<()> {
+        self.inbox.lock().unwrap().retain(|e| e != entry);
+        Ok(())
+    }
+
+    fn remove_expired(&mut self) -> Result<()> {
+        // the entries queued for offline peers are dropped by their workers
+        let now = get_timestamp();
+        self.inbox
+            .lock()
+            .unwrap()
+            .retain(|e| !is_expired(e.expires, now));
+        Ok(())
+    }
+
+    fn close(&mut self, _remove_own_entry: bool) -> Result<()> {
+        // the entries live at the peers, there is nothing to take back. Without their queues the
+        // workers deliver what is pending to the reachable peers and end
+        self.queues.clear();
+
+        let deadline = Instant::now() + CLOSE_TIMEOUT;
+        let unfinished = self
+            .workers
+            .drain(..)
+            .map(|worker| join_until(worker, deadline))
+            .filter(|finished| !finished)
+            .count();
+
+        if unfinished > 0 {
+            bail!("{} peer(s) didn't take their entries in time", unfinished);
+        }
+        Ok(())
+    }
+}
+
+fn accept_loop(
+    listener: TcpListener,
+    remote_names: Vec<String>,
+    tls: Option<Arc<TlsContext>>,
+    queue: bool,
+    inbox: Inbox,
+) {
+    let connections = Arc::new(AtomicUsize::new(0));
+
+    for stream in listener.incoming() {
+        match stream {
+            Ok(stream) => {
+                let peer_addr = stream.peer_addr().ok();
+                if connections.fetch_add(1, Ordering::SeqCst) >= MAX_CONNECTIONS {
+                    connections.fetch_sub(1, Ordering::SeqCst);
+                    events::report_error(
+                        ErrorKind::Transport,
+                        format!(
+                            "refused connection from {:?}, {} are open already",
+                            peer_addr, MAX_CONNECTIONS
+                        ),
+                    );
+                    continue;
+                }
+
+                let remote_names = remote_names.clone();
+                let tls = tls.clone();
+                let inbox = inbox.clone();
+                let connections = connections.clone();
+                thread::spawn(move || {
+                    let result = receive_entries(stream, remote_names, tls, queue, &inbox);
+                    connections.fetch_sub(1, Ordering::SeqCst);
+                    if let Err(e) = result {
+                        events::report_error(
+                            ErrorKind::Transport,
+                            format!("connection from {:?} closed: {:#}", peer_addr, e),
+                        );
+                    }
+                });
+            }
+            Err(e) => events::report_error(
+                ErrorKind::Transport,
+                format!("could not accept connection: {}", e),
+            ),
+        }
+    }
+}
+
+/// reads entries from the stream until the peer closes it
+fn receive_entries(
+    stream: TcpStream,
+    remote_names: Vec<String>,
+    tls: Option<Arc<TlsContext>>,
+    queue: bool,
+    inbox: &Inbox,
+) -> Result<()> {
+    // the options are shared with the clone, which stays reachable behind the tls stream
+    let socket = stream.try_clone()?;
+    socket.set_read_timeout(Some(HANDSHAKE_TIMEOUT))?;
+
+    // with tls the certificate tells who is on the other end. That peer may only send its own
+    // entries
+    let (allowed_origins, stream): (Vec<String>, Box<dyn Stream>) = match tls {
+        Some(tls) => {
+            let (name, stream) = tls.accept(stream)?;
+            (vec![name], Box::new(stream))
+        }
+        None => (remote_names, Box::new(stream)),
+    };
+
+    let mut reader = BufReader::new(stream);
+    read_magic(&mut reader)?;
+    // the connection stays open between entries, however long that takes
+    socket.set_read_timeout(None)?;
+
+    while let Some(frame) = read_entry(&mut reader)? {
+        let ack = match frame {
+            Frame::Entry(entry) => {
+                store_entry(entry, &allowed_origins, queue, inbox);
+                ACK_TAKEN
+            }
+            Frame::Oversize { origin, len } => {
+                events::report_error(
+                    ErrorKind::Transport,
+                    format!(
+                        "rejected an entry of {} bytes from {}, the limit is {}",
+                        len, origin, MAX_CONTENT_LEN
+                    ),
+                );
+                ACK_REJECTED
+            }
+        };
+
+        let stream = reader.get_mut();
+        stream.write_all(&[ack])?;
+        stream.flush()?;
+    }
+
+    Ok(())
+}
+
+/// puts a received entry into the inbox, unless it has to be dropped
+fn store_entry(entry: Entry, allowed_origins: &[String], queue: bool, inbox: &Inbox) {
+    if !allowed_origins.contains(&entry.origin) {
+        log(&format!(
+            "dropping entry from {} which is not allowed on this connection\n",
+            entry.origin
+        ));
+        return;
+    }
+
+    // it was queued while this instance was offline
+    if is_expired(entry.expires, get_timestamp()) {
+        return;
+    }
+
+    let mut inbox = inbox.lock().unwrap();
+    // the ack got lost and the peer sent it again
+    if inbox
+        .iter()
+        .any(|known| known.origin == entry.origin && known.timestamp == entry.timestamp)
+    {
+        return;
+    }
+
+    if queue {
+        if inbox.len() >= MAX_INBOX {
+            log(&format!(
+                "the inbox is full, dropping the oldest of {} entries\n",
+                inbox.len()
+            ));
+            let oldest = inbox
+                .iter()
+                .enumerate()
+                .min_by_key(|(_, known)| known.timestamp)
+                .map(|(i, _)| i);
+            if let Some(i) = oldest {
+                inbox.remove(i);
+            }
+        }
+    } else {
+        // only the newest entry of every origin matters
+        let older = inbox
+            .iter()
+            .any(|known| known.origin == entry.origin && known.timestamp > entry.timestamp);
+        if older {
+            return;
+        }
+        inbox.retain(|known| known.origin != entry.origin);
+    }
+
+    events::publish(Event::EntryReceived {
+        origin: entry.origin.clone(),
+        timestamp: entry.timestamp,
+        channel: None,
+        size: entry.content.len(),
+        sensitive: entry.expires.is_some(),
+    });
+    inbox.push(entry);
+}
+
+/// delivers the queued entries to a single peer. Reconnects whenever the connection breaks and
+/// keeps the entries queued while the peer is offline.
+fn peer_worker(peer: Peer, tls: Option<Arc<TlsContext>>, queue: Receiver<Entry>) {
+    let mut pending = VecDeque::new();
+    let mut connection: Option<BufWriter<Box<dyn Stream>>> = None;
+    let mut online = true;
+
+    loop {
+        // wait for work. While the peer is offline, wake up regularly to reconnect
+        let received = if pending.is_empty() {
+            queue.recv().map_err(|_| RecvTimeoutError::Disconnected)
+        } else if connection.is_none() {
+            queue.recv_timeout(RECONNECT_DELAY)
+        } else {
+            Err(RecvTimeoutError::Timeout)
+        };
+
+        match received {
+            Ok(entry) => pending.push_back(entry),
+            Err(RecvTimeoutError::Timeout) => {}
+            Err(RecvTimeoutError::Disconnected) => {
+                // the instance shuts down while the peer is offline
+                if !pending.is_empty() {
+                    log(&format!(
+                        "dropping {} entries queued for {}\n",
+                        pending.len(),
+                        peer.name
+                    ));
+                }
+                return;
+            }
+        }
+        pending.extend(queue.try_iter());
+
+        while pending.len() > MAX_QUEUED {
+            pending.pop_front();
+        }
+
+        // a sensitive entry is not delivered after its time is up
+        let now = get_timestamp();
+        pending.retain(|entry| !is_expired(entry.expires, now));
+        if pending.is_empty() {
+            continue;
+        }
+
+        if connection.is_none() {
+            match connect(&peer, tls.as_deref()) {
+                Ok(stream) => {
+                    if !online {
+                        log(&format!("peer {} is reachable again\n", peer.name));
+                        events::publish(Event::PeerOnline {
+                            name: peer.name.clone(),
+                            version: None,
+                        });
+                    }
+                    online = true;
+                    connection = Some(stream);
+                }
+                Err(e) => {
+                    if online {
+                        log(&format!(
+                            "peer {} is unreachable, queueing entries: {:#}\n",
+                            peer.name, e
+                        ));
+                        events::publish(Event::PeerOffline {
+                            name: peer.name.clone(),
+                        });
+                    }
+                    online = false;
+                    continue;
+                }
+            }
+        }
+
+        let Some(writer) = connection.as_mut() else {
+            continue;
+        };
+
+        // an entry is only forgotten once the peer acked it. If the connection breaks before, it
+        // is sent again
+        while let Some(entry) = pending.front() {
+            match deliver(writer, entry) {
+                Ok(ACK_TAKEN) => {
+                    metrics::add(Counter::EntriesDelivered, &peer.name, 1);
+                    metrics::add(
+                        Counter::DeliveredBytes,
+                        &peer.name,
+                        entry.content.len() as u64,
+                    );
+                }
+                Ok(_) => {
+                    events::report_error(
+                        ErrorKind::Transport,
+                        format!("peer {} rejected an entry as too large", peer.name),
+                    );
+                }
+                Err(e) => {
+                    log(&format!("lost connection to {}: {:#}\n", peer.name, e));
+                    events::publish(Event::PeerOffline {
+                        name: peer.name.clone(),
+                    });
+                    connection = None;
+                    online = false;
+                    break;
+                }
+            }
+            pending.pop_front();
+        }
+    }
+}
+
+/// writes the entry and waits for the ack of the peer
+fn deliver(writer: &mut BufWriter<Box<dyn Stream>>, entry: &Entry) -> Result<u8> {
+    write_entry(writer, entry)?;
+    writer.flush()?;
+
+    let mut ack = [0u8; 1];
+    writer
+        .get_mut()
+        .read_exact(&mut ack)
+        .with_context(|| "no ack for the entry")?;
+    Ok(ack[0])
+}
+
+fn connect(peer: &Peer, tls: Option<&TlsContext>) -> Result<BufWriter<Box<dyn Stream>>> {
+    let stream = connect_tcp(&peer.address)?;
+    // a peer which takes the entry but never acks it must not block the worker
+    stream.set_read_timeout(Some(ACK_TIMEOUT))?;
+
+    let stream: Box<dyn Stream> = match tls {
+        Some(tls) => Box::new(tls.connect(peer, stream)?),
+        None => Box::new(stream),
+    };
+
+    let mut writer = BufWriter::new(stream);
+    writer.write_all(MAGIC)?;
+    Ok(writer)
+}
+
+fn connect_tcp(address: &str) -> Result<TcpStream> {
+    let mut last_error = None;
+
+    for addr in address
+        .to_socket_addrs()
+        .with_context(|| format!("could not resolve {}", address))?
+    {
+        match TcpStream::connect_timeout(&addr, CONNECT_TIMEOUT) {
+            Ok(stream) => return Ok(stream),
+            Err(e) => last_error = Some(e),
+        }
+    }
+
+    match last_error {
+        Some(e) => Err(e).with_context(|| format!("could not connect to {}", address)),
+        None => bail!("{} did not resolve to any address", address),
+    }
+}
+
+// wire format of an entry, all integers big endian:
+// u32 origin length, origin, u64 timestamp, u64 expires (0 if never), u8 encoding,
+// u8 line ending, u32 content length, content
+// the receiver answers with a u8 ack
+
+/// what the receiver got
+enum Frame {
+    Entry(Entry),
+    /// the content was over MAX_CONTENT_LEN. It was skipped, the connection is still usable
+    Oversize {
+        origin: String,
+        len: u32,
+    },
+}
+
+fn write_entry(writer: &mut impl Write, entry: &Entry) -> Result<()> {
+    let origin_len = u32::try_from(entry.origin.len())
+        .ok()
+        .filter(|len| *len <= MAX_ORIGIN_LEN)
+        .with_context(|| format!("origin {} is too long", entry.origin))?;
+    let content_len = u32::try_from(entry.content.len())
+        .ok()
+        .filter(|len| *len <= MAX_CONTENT_LEN)
+        .with_context(|| format!("the entry has {} bytes", entry.content.len()))?;
+
+    writer.write_all(&origin_len.to_be_bytes())?;
+    writer.write_all(entry.origin.as_bytes())?;
+    writer.write_all(&entry.timestamp.to_be_bytes())?;
+    writer.write_all(&entry.expires.unwrap_or(0).to_be_bytes())?;
+    writer.write_all(&format_to_bytes(entry.format))?;
+    writer.write_all(&content_len.to_be_bytes())?;
+    writer.write_all(&entry.content)?;
+    Ok(())
+}
+
+/// reads the next entry. Returns None if the stream ended cleanly between two entries.
+fn read_entry(reader: &mut impl Read) -> Result<Option<Frame>> {
+    let mut len = [0u8; 4];
+    match reader.read_exact(&mut len) {
+        Ok(()) => {}
+        Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e.into()),
+    }
+
+    let origin = read_string(reader, u32::from_be_bytes(len), MAX_ORIGIN_LEN)?;
+
+    let mut timestamp = [0u8; 8];
+    reader.read_exact(&mut timestamp)?;
+
+    let mut expires = [0u8; 8];
+    reader.read_exact(&mut expires)?;
+
+    let mut format = [0u8; 2];
+    reader.read_exact(&mut format)?;
+
+    reader.read_exact(&mut len)?;
+    let len = u32::from_be_bytes(len);
+    if len > MAX_CONTENT_LEN {
+        // skipped, so the next entry can still be read
+        let skipped = std::io::copy(&mut reader.take(len.into()), &mut std::io::sink())?;
+        if skipped < len.into() {
+            bail!("the connection ended within an entry");
+        }
+        return Ok(Some(Frame::Oversize { origin, len }));
+    }
+    let content = read_bytes(reader, len, MAX_CONTENT_LEN)?;
+
+    Ok(Some(Frame::Entry(Entry {
+        origin,
+        timestamp: u64::from_be_bytes(timestamp),
+        expires: match u64::from_be_bytes(expires) {
+            0 => None,
+            expires => Some(expires),
+        },
+        content,
+        format: format_from_bytes(format)?,
+    })))
+}
+
+fn format_to_bytes(format: TextFormat) -> [u8; 2] {
+    let encoding = match format.encoding {
+        Encoding::Utf8 => 0,
+        Encoding::Utf16Le => 1,
+        Encoding::Utf16Be => 2,
+        Encoding::Binary => 3,
+    };
+    let line_ending = match format.line_ending {
+        LineEnding::Lf => 0,
+        LineEnding::Crlf => 1,
+    };
+    [encoding, line_ending]
+}
+
+fn format_from_bytes(bytes: [u8; 2]) -> Result<TextFormat> {
+    let encoding = match bytes[0] {
+        0 => Encoding::Utf8,
+        1 => Encoding::Utf16Le,
+        2 => Encoding::Utf16Be,
+        3 => Encoding::Binary,
+        other => bail!("unknown encoding {}", other),
+    };
+    let line_ending = match bytes[1] {
+        0 => LineEnding::Lf,
+        1 => LineEnding::Crlf,
+        other => bail!("unknown line ending {}", other),
+    };
+
+    Ok(TextFormat {
+        encoding,
+        line_ending,
+    })
+}
+
+fn read_magic(reader: &mut impl Read) -> Result<()> {
+    let mut magic = [0u8; 4];
+    reader.read_exact(&mut magic)?;
+    if &magic != MAGIC {
+        bail!("peer does not speak the clipboard protocol");
+    }
+    Ok(())
+}
+
+fn read_string(reader: &mut impl Read, len: u32, max_len: u32) -> Result<String> {
+    let buf = read_bytes(reader, len, max_len)?;
+    String::from_utf8(buf).with_context(|| "received text is not valid utf-8")
+}
+
+fn read_bytes(reader: &mut impl Read, len: u32, max_len: u32) -> Result<Vec<u8>> {
+    if len > max_len {
+        bail!("announced length {} exceeds the limit of {}", len, max_len);
+    }
+
+    let mut buf = vec![0u8; len as usize];
+    reader.read_exact(&mut buf)?;
+    Ok(buf)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::test_dir;
+    use std::io::Cursor;
+
+    const NAMES: [&str; 3] = ["a", "b", "c"];
+
+    fn free_port() -> u16 {
+        TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port()
+    }
+
+    /// an instance of a cluster of a, b and c on loopback, pushing to the given peers
+    fn config(name: &str, port: u16, peers: &[(&str, u16)], extra: &str) -> Config {
+        let remote_names: Vec<_> = NAMES.iter().filter(|n| **n != name).copied().collect();
+        let peers: Vec<_> = peers
+            .iter()
+            .map(|(peer, port)| format!("{}@127.0.0.1:{}", peer, port))
+            .collect();
+        Config::parse(&format!(
+            "local_name={}\nremote_names={}\ndir_name={}\ntransport=tcp\nlisten=127.0.0.1:{}\n\
+             peers={}\ntls=off\n{}",
+            name,
+            remote_names.join(","),
+            test_dir(&format!("tcp-{}-{}", name, port)),
+            port,
+            peers.join(","),
+            extra
+        ))
+        .unwrap()
+    }
+
+    fn entry(origin: &str, timestamp: u64, text: &str) -> Entry {
+        Entry {
+            origin: origin.to_string(),
+            timestamp,
+            expires: None,
+            content: text.as_bytes().to_vec(),
+            format: TextFormat::default(),
+        }
+    }
+
+    fn text(entry: &Entry) -> &str {
+        std::str::from_utf8(&entry.content).unwrap()
+    }
+
+    /// polls until the transport has something, the peers deliver on their own threads
+    fn wait_for(
+        transport: &mut TcpTransport,
+        timeout: Duration,
+        mut f: impl FnMut(&mut TcpTransport) -> Option<Entry>,
+    ) -> Entry {
+        let deadline = Instant::now() + timeout;
+        loop {
+            if let Some(entry) = f(transport) {
+                return entry;
+            }
+            assert!(Instant::now() < deadline, "nothing arrived in time");
+            thread::sleep(Duration::from_millis(20));
+        }
+    }
+
+    fn latest(transport: &mut TcpTransport) -> Entry {
+        wait_for(transport, Duration::from_secs(5), |t| {
+            t.take_latest().unwrap()
+        })
+    }
+
+    fn encoded(entry: &Entry) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        write_entry(&mut bytes, entry).unwrap();
+        bytes
+    }
+
+    // the header of an entry announcing content_len bytes, up to the content
+    fn header(origin: &str, content_len: u32) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        bytes.extend((origin.len() as u32).to_be_bytes());
+        bytes.extend(origin.as_bytes());
+        bytes.extend(100u64.to_be_bytes());
+        bytes.extend(0u64.to_be_bytes());
+        bytes.extend([0, 0]);
+        bytes.extend(content_len.to_be_bytes());
+        bytes
+    }
+
+    fn frame_entry(frame: Option<Frame>) -> Entry {
+        match frame {
+            Some(Frame::Entry(entry)) => entry,
+            Some(Frame::Oversize { .. }) => panic!("the entry was skipped"),
+            None => panic!("the stream ended"),
+        }
+    }
+
+    #[test]
+    fn entries_survive_the_wire_format() {
+        let mut sent = entry("a", 100, "line\r\n");
+        sent.expires = Some(200);
+        sent.format = TextFormat {
+            encoding: Encoding::Utf16Be,
+            line_ending: LineEnding::Crlf,
+        };
+
+        let mut reader = Cursor::new(encoded(&sent));
+        assert_eq!(frame_entry(read_entry(&mut reader).unwrap()), sent);
+        assert!(read_entry(&mut reader).unwrap().is_none());
+    }
+
+    #[test]
+    fn oversize_content_is_skipped_and_the_next_entry_read() {
+        let next = entry("a", 200, "next");
+        let mut reader = Cursor::new(header("a", MAX_CONTENT_LEN + 1))
+            .chain(std::io::repeat(0).take(u64::from(MAX_CONTENT_LEN) + 1))
+            .chain(Cursor::new(encoded(&next)));
+
+        match read_entry(&mut reader).unwrap() {
+            Some(Frame::Oversize { origin, len }) => {
+                assert_eq!(origin, "a");
+                assert_eq!(len, MAX_CONTENT_LEN + 1);
+            }
+            _ => panic!("the entry was not skipped"),
+        }
+        assert_eq!(frame_entry(read_entry(&mut reader).unwrap()), next);
+    }
+
+    #[test]
+    fn a_cut_off_entry_is_an_error() {
+        let bytes = encoded(&entry("a", 100, "content"));
+        let mut reader = Cursor::new(&bytes[..bytes.len() - 1]);
+        assert!(read_entry(&mut reader).is_err());
+
+        let mut reader = Cursor::new(header("a", MAX_CONTENT_LEN + 1));
+        assert!(read_entry(&mut reader).is_err());
+    }
+
+    #[test]
+    fn long_origins_are_refused() {
+        let origin = "a".repeat(MAX_ORIGIN_LEN as usize + 1);
+        assert!(write_entry(&mut Vec::new(), &entry(&origin, 100, "")).is_err());
+
+        let mut reader = Cursor::new(header(&origin, 0));
+        assert!(read_entry(&mut reader).is_err());
+    }
+
+    #[test]
+    fn the_inbox_keeps_the_newest_entry_of_every_origin() {
+        let inbox = Inbox::default();
+        let allowed = vec!["b".to_string(), "c".to_string()];
+
+        store_entry(entry("b", 200, "new"), &allowed, false, &inbox);
+        store_entry(entry("b", 100, "old"), &allowed, false, &inbox);
+        store_entry(entry("c", 150, "other"), &allowed, false, &inbox);
+        store_entry(entry("d", 300, "stranger"), &allowed, false, &inbox);
+        store_entry(entry("b", 200, "sent again"), &allowed, false, &inbox);
+
+        let inbox = inbox.lock().unwrap();
+        let texts: Vec<_> = inbox.iter().map(text).collect();
+        assert_eq!(texts, ["new", "other"]);
+    }
+
+    #[test]
+    fn the_queue_inbox_drops_the_oldest_entries_when_full() {
+        let inbox = Inbox::default();
+        let allowed = vec!["b".to_string()];
+
+        for timestamp in 0..MAX_INBOX as u64 + 2 {
+            store_entry(entry("b", timestamp, ""), &allowed, true, &inbox);
+        }
+        // an entry sent again is not queued twice
+        store_entry(entry("b", 10, ""), &allowed, true, &inbox);
+
+        let inbox = inbox.lock().unwrap();
+        assert_eq!(inbox.len(), MAX_INBOX);
+        assert_eq!(inbox.iter().map(|e| e.timestamp).min(), Some(2));
+    }
+
+    #[test]
+    fn expired_entries_are_not_stored() {
+        let inbox = Inbox::default();
+        let mut expired = entry("b", 100, "secret");
+        expired.expires = Some(1);
+
+        store_entry(expired, &["b".to_string()], false, &inbox);
+        assert!(inbox.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn publish_refuses_entries_over_the_limit() {
+        let port = free_port();
+        let mut a = TcpTransport::start(&config("a", port, &[("b", free_port())], "")).unwrap();
+
+        let mut oversize = entry("a", 100, "");
+        oversize.content = vec![0; MAX_CONTENT_LEN as usize + 1];
+        assert!(a.publish(&oversize).is_err());
+        a.close(false).unwrap();
+    }
+
+    #[test]
+    fn entries_reach_every_peer() {
+        let ports = [free_port(), free_port(), free_port()];
+        let mut transports: Vec<_> = NAMES
+            .iter()
+            .zip(ports)
+            .map(|(name, port)| {
+                let peers: Vec<_> = NAMES
+                    .iter()
+                    .zip(ports)
+                    .filter(|(peer, _)| peer != &name)
+                    .map(|(peer, port)| (*peer, port))
+                    .collect();
+                TcpTransport::start(&config(name, port, &peers, "")).unwrap()
+            })
+            .collect();
+
+        transports[0].publish(&entry("a", 100, "from a")).unwrap();
+        assert_eq!(text(&latest(&mut transports[1])), "from a");
+        assert_eq!(text(&latest(&mut transports[2])), "from a");
+
+        transports[1].publish(&entry("b", 200, "from b")).unwrap();
+        assert_eq!(text(&latest(&mut transports[0])), "from b");
+        assert_eq!(text(&latest(&mut transports[2])), "from b");
+
+        // taken entries are gone
+        assert!(transports[2].take_latest().unwrap().is_none());
+
+        for transport in &mut transports {
+            transport.close(false).unwrap();
+        }
+    }
+
+    #[test]
+    fn a_newer_own_entry_hides_the_received_one() {
+        let (a_port, b_port) = (free_port(), free_port());
+        let mut a = TcpTransport::start(&config("a", a_port, &[("b", b_port)], "")).unwrap();
+        let mut b = TcpTransport::start(&config("b", b_port, &[("a", a_port)], "")).unwrap();
+
+        a.publish(&entry("a", 100, "from a")).unwrap();
+        wait_for(&mut b, Duration::from_secs(5), |b| b.peek_oldest().unwrap());
+        b.publish(&entry("b", 200, "from b")).unwrap();
+        assert!(b.take_latest().unwrap().is_none());
+
+        a.close(false).unwrap();
+        b.close(false).unwrap();
+    }
+
+    #[test]
+    fn queue_mode_pastes_in_order() {
+        let (a_port, b_port) = (free_port(), free_port());
+        let mut a = TcpTransport::start(&config("a", a_port, &[("b", b_port)], "")).unwrap();
+        let mut b =
+            TcpTransport::start(&config("b", b_port, &[("a", a_port)], "paste_mode=queue\n"))
+                .unwrap();
+
+        for (timestamp, text) in [(100, "first"), (200, "second"), (300, "third")] {
+            a.publish(&entry("a", timestamp, text)).unwrap();
+        }
+        // the worker delivers in order, so the last one arriving means all did
+        wait_for(&mut b, Duration::from_secs(5), |b| {
+            let inbox = b.inbox.lock().unwrap();
+            inbox.iter().find(|e| e.timestamp == 300).cloned()
+        });
+
+        let mut pasted = Vec::new();
+        while let Some(oldest) = b.peek_oldest().unwrap() {
+            b.remove(&oldest).unwrap();
+            pasted.push(text(&oldest).to_string());
+        }
+        assert_eq!(pasted, ["first", "second", "third"]);
+
+        a.close(false).unwrap();
+        b.close(false).unwrap();
+    }
+
+    #[test]
+    fn entries_wait_for_an_offline_peer() {
+        let (a_port, b_port) = (free_port(), free_port());
+        let mut a = TcpTransport::start(&config("a", a_port, &[("b", b_port)], "")).unwrap();
+
+        a.publish(&entry("a", 100, "queued")).unwrap();
+        thread::sleep(Duration::from_millis(200));
+
+        let mut b = TcpTransport::start(&config("b", b_port, &[("a", a_port)], "")).unwrap();
+        let received = wait_for(&mut b, RECONNECT_DELAY * 3, |b| b.take_latest().unwrap());
+        assert_eq!(text(&received), "queued");
+
+        a.close(false).unwrap();
+        b.close(false).unwrap();
+    }
+
+    #[test]
+    fn the_receiver_acks_every_entry() {
+        let b_port = free_port();
+        let mut b = TcpTransport::start(&config("b", b_port, &[("a", free_port())], "")).unwrap();
+
+        let mut stream = TcpStream::connect(("127.0.0.1", b_port)).unwrap();
+        stream.set_read_timeout(Some(ACK_TIMEOUT)).unwrap();
+        let mut ack = [0u8; 1];
+
+        stream.write_all(MAGIC).unwrap();
+        stream.write_all(&header("a", MAX_CONTENT_LEN + 1)).unwrap();
+        std::io::copy(
+            &mut std::io::repeat(0).take(u64::from(MAX_CONTENT_LEN) + 1),
+            &mut stream,
+        )
+        .unwrap();
+        stream.read_exact(&mut ack).unwrap();
+        assert_eq!(ack[0], ACK_REJECTED);
+
+        // the connection is still usable after a rejected entry
+        stream
+            .write_all(&encoded(&entry("a", 100, "fits")))
+            .unwrap();
+        stream.read_exact(&mut ack).unwrap();
+        assert_eq!(ack[0], ACK_TAKEN);
+        assert_eq!(text(&latest(&mut b)), "fits");
+
+        b.close(false).unwrap();
+    }
+
+    #[test]
+    fn stray_connections_are_refused() {
+        let b_port = free_port();
+        let mut b = TcpTransport::start(&config("b", b_port, &[("a", free_port())], "")).unwrap();
+
+        let mut stream = TcpStream::connect(("127.0.0.1", b_port)).unwrap();
+        stream.write_all(b"GET / HTTP/1.1\r\n\r\n").unwrap();
+        stream
+            .write_all(&encoded(&entry("a", 100, "sneaky")))
+            .unwrap();
+        stream
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        // closed without an ack
+        assert_eq!(stream.read(&mut [0u8; 1]).unwrap(), 0);
+        assert!(b.take_latest().unwrap().is_none());
+
+        b.close(false).unwrap();
+    }
+
+    #[test]
+    fn silent_connections_are_closed() {
+        let b_port = free_port();
+        let mut b = TcpTransport::start(&config("b", b_port, &[("a", free_port())], "")).unwrap();
+
+        let mut stream = TcpStream::connect(("127.0.0.1", b_port)).unwrap();
+        stream
+            .set_read_timeout(Some(HANDSHAKE_TIMEOUT * 2))
+            .unwrap();
+        let started = Instant::now();
+        assert_eq!(stream.read(&mut [0u8; 1]).unwrap(), 0);
+        assert!(started.elapsed() >= HANDSHAKE_TIMEOUT - Duration::from_secs(1));
+
+        b.close(false).unwrap();
+    }
+
+    #[test]
+    fn connections_over_the_limit_are_refused() {
+        let b_port = free_port();
+        let mut b = TcpTransport::start(&config("b", b_port, &[("a", free_port())], "")).unwrap();
+
+        let open: Vec<_> = (0..MAX_CONNECTIONS)
+            .map(|_| TcpStream::connect(("127.0.0.1", b_port)).unwrap())
+            .collect();
+
+        let mut refused = TcpStream::connect(("127.0.0.1", b_port)).unwrap();
+        refused
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        // closed right away, long before the handshake timeout
+        assert_eq!(refused.read(&mut [0u8; 1]).unwrap(), 0);
+
+        // a connection which ends frees its slot
+        drop(open);
+        let deadline = Instant::now() + Duration::from_secs(5);
+        let stream = loop {
+            let mut stream = TcpStream::connect(("127.0.0.1", b_port)).unwrap();
+            stream.set_read_timeout(Some(ACK_TIMEOUT)).unwrap();
+            stream.write_all(MAGIC).unwrap();
+            if stream.write_all(&encoded(&entry("a", 100, "room"))).is_ok() {
+                let mut ack = [0u8; 1];
+                if stream.read_exact(&mut ack).is_ok() {
+                    assert_eq!(ack[0], ACK_TAKEN);
+                    break stream;
+                }
+            }
+            assert!(Instant::now() < deadline, "no slot was freed");
+            thread::sleep(Duration::from_millis(50));
+        };
+        assert_eq!(text(&latest(&mut b)), "room");
+        stream.shutdown(std::net::Shutdown::Both).unwrap();
+
+        b.close(false).unwrap();
+    }
+}
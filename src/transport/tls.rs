@@ -0,0 +1,549 @@
+// TLS for the tcp transport.
+//
+// There is no certificate authority. Every instance has a self-signed certificate (see gen-cert)
+// and every peer entry in the config pins the SHA-256 fingerprint of the remotes certificate.
+// Both sides present their certificate, so a connection is only established between two
+// instances which know each other.
+
+use crate::config::{Config, Peer};
+use anyhow::{anyhow, bail, Context, Result};
+use rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+use rustls::crypto::{verify_tls12_signature, verify_tls13_signature, CryptoProvider};
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName, UnixTime};
+use rustls::server::danger::{ClientCertVerified, ClientCertVerifier};
+use rustls::{
+    ClientConfig, ClientConnection, DigitallySignedStruct, DistinguishedName, ServerConfig,
+    ServerConnection, SignatureScheme, StreamOwned,
+};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::io::Write;
+use std::net::TcpStream;
+use std::path::Path;
+use std::sync::Arc;
+
+pub struct TlsContext {
+    // every peer is pinned to its own fingerprint, so every peer has its own client config
+    clients: HashMap<String, Arc<ClientConfig>>,
+    server: Arc<ServerConfig>,
+    // fingerprint -> peer name
+    peer_names: HashMap<String, String>,
+}
+
+impl TlsContext {
+    /// loads the certificate of this instance and pins the fingerprints of config.peers
+    pub fn load(config: &Config) -> Result<Self> {
+        let certs = CertificateDer::pem_file_iter(&config.tls_cert)
+            .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+            .with_context(|| format!("could not read certificate {}", config.tls_cert))?;
+        let key = PrivateKeyDer::from_pem_file(&config.tls_key)
+            .with_context(|| format!("could not read private key {}", config.tls_key))?;
+
+        let provider = Arc::new(rustls::crypto::ring::default_provider());
+
+        let mut peer_names = HashMap::new();
+        let mut clients = HashMap::new();
+
+        for peer in &config.peers {
+            let fingerprint = pinned_fingerprint(peer)?;
+            peer_names.insert(fingerprint.clone(), peer.name.clone());
+
+            let verifier = PinnedServer {
+                peer_name: peer.name.clone(),
+                fingerprint,
+                provider: provider.clone(),
+            };
+
+            let client = ClientConfig::builder_with_provider(provider.clone())
+                .with_safe_default_protocol_versions()?
+                .dangerous()
+                .with_custom_certificate_verifier(Arc::new(verifier))
+                .with_client_auth_cert(certs.clone(), key.clone_key())
+                .with_context(|| "could not use the certificate as client certificate")?;
+
+            clients.insert(peer.name.clone(), Arc::new(client));
+        }
+
+        let verifier = PinnedClients {
+            fingerprints: peer_names.keys().cloned().collect(),
+            provider: provider.clone(),
+        };
+
+        let server = ServerConfig::builder_with_provider(provider)
+            .with_safe_default_protocol_versions()?
+            .with_client_cert_verifier(Arc::new(verifier))
+            .with_single_cert(certs, key)
+            .with_context(|| "could not use the certificate as server certificate")?;
+
+        Ok(Self {
+            clients,
+            server: Arc::new(server),
+            peer_names,
+        })
+    }
+
+    /// performs the handshake with a peer we connected to
+    pub fn connect(
+        &self,
+        peer: &Peer,
+        mut stream: TcpStream,
+    ) -> Result<StreamOwned<ClientConnection, TcpStream>> {
+        let client = self
+            .clients
+            .get(&peer.name)
+            .ok_or_else(|| anyhow!("no tls config for peer {}", peer.name))?;
+
+        // the name is not verified, the pinned fingerprint is. But rustls needs one anyway
+        let server_name = ServerName::try_from(peer.name.clone())
+            .with_context(|| format!("{} can not be used as tls server name", peer.name))?;
+
+        let mut connection = ClientConnection::new(client.clone(), server_name)?;
+        while connection.is_handshaking() {
+            connection
+                .complete_io(&mut stream)
+                .with_context(|| format!("tls handshake with {} failed", peer.name))?;
+        }
+
+        Ok(StreamOwned::new(connection, stream))
+    }
+
+    /// performs the handshake with a peer which connected to us. Returns the name of the peer the
+    /// presented certificate belongs to.
+    pub fn accept(
+        &self,
+        mut stream: TcpStream,
+    ) -> Result<(String, StreamOwned<ServerConnection, TcpStream>)> {
+        let mut connection = ServerConnection::new(self.server.clone())?;
+        while connection.is_handshaking() {
+            connection
+                .complete_io(&mut stream)
+                .with_context(|| "tls handshake failed")?;
+        }
+
+        let fingerprint = connection
+            .peer_certificates()
+            .and_then(|certs| certs.first())
+            .map(fingerprint)
+            .ok_or_else(|| anyhow!("peer did not present a certificate"))?;
+
+        let name = self
+            .peer_names
+            .get(&fingerprint)
+            .cloned()
+            .ok_or_else(|| anyhow!("certificate {} is not pinned for any peer", fingerprint))?;
+
+        Ok((name, StreamOwned::new(connection, stream)))
+    }
+}
+
+/// SHA-256 of the DER encoded certificate as lowercase hex
+pub fn fingerprint(cert: &CertificateDer) -> String {
+    Sha256::digest(cert.as_ref())
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+/// fingerprint of the certificate of this instance at config.tls_cert
+pub fn own_fingerprint(config: &Config) -> Result<String> {
+    let cert = CertificateDer::from_pem_file(&config.tls_cert)
+        .with_context(|| format!("could not read certificate {}", config.tls_cert))?;
+
+    Ok(fingerprint(&cert))
+}
+
+/// creates a self-signed certificate for config.local_name at config.tls_cert and config.tls_key.
+/// Returns its fingerprint.
+pub fn generate_certificate(config: &Config, overwrite: bool) -> Result<String> {
+    for path in [&config.tls_cert, &config.tls_key] {
+        if !overwrite && Path::new(path).exists() {
+            bail!("{} already exists. Use --force to replace it", path);
+        }
+    }
+
+    let certified = rcgen::generate_simple_self_signed(vec![config.local_name.clone()])
+        .with_context(|| "could not generate certificate")?;
+
+    std::fs::write(&config.tls_cert, certified.cert.pem())
+        .with_context(|| format!("could not write {}", config.tls_cert))?;
+    write_private(
+        &config.tls_key,
+        certified.signing_key.serialize_pem().as_bytes(),
+    )
+    .with_context(|| format!("could not write {}", config.tls_key))?;
+
+    Ok(fingerprint(certified.cert.der()))
+}
+
+/// writes a file only this user can read, from the moment it exists
+fn write_private(path: &str, content: &[u8]) -> Result<()> {
+    // an existing file would keep its permissions, so it is replaced instead of overwritten
+    match std::fs::remove_file(path) {
+        Ok(()) => {}
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+        Err(e) => return Err(e.into()),
+    }
+
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+
+    options.open(path)?.write_all(content)?;
+    Ok(())
+}
+
+fn pinned_fingerprint(peer: &Peer) -> Result<String> {
+    peer.fingerprint
+        .clone()
+        .ok_or_else(|| {
+            anyhow!(
+                "tls is on but peer {} has no pinned fingerprint (name@host:port#fingerprint). Pin the one gen-cert printed on it, or set tls=off to send unencrypted",
+                peer.name
+            )
+        })
+}
+
+/// accepts exactly the one certificate pinned for the peer we connect to
+#[derive(Debug)]
+struct PinnedServer {
+    peer_name: String,
+    fingerprint: String,
+    provider: Arc<CryptoProvider>,
+}
+
+impl ServerCertVerifier for PinnedServer {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        let actual = fingerprint(end_entity);
+        if actual != self.fingerprint {
+            return Err(rustls::Error::General(format!(
+                "certificate fingerprint mismatch for peer {}: pinned {} but got {}",
+                self.peer_name, self.fingerprint, actual
+            )));
+        }
+
+        Ok(ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls12_signature(
+            message,
+            cert,
+            dss,
+            &self.provider.signature_verification_algorithms,
+        )
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls13_signature(
+            message,
+            cert,
+            dss,
+            &self.provider.signature_verification_algorithms,
+        )
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.provider
+            .signature_verification_algorithms
+            .supported_schemes()
+    }
+}
+
+/// accepts the certificates pinned for any of the configured peers
+#[derive(Debug)]
+struct PinnedClients {
+    fingerprints: Vec<String>,
+    provider: Arc<CryptoProvider>,
+}
+
+impl ClientCertVerifier for PinnedClients {
+    fn root_hint_subjects(&self) -> &[DistinguishedName] {
+        &[]
+    }
+
+    fn verify_client_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _now: UnixTime,
+    ) -> Result<ClientCertVerified, rustls::Error> {
+        let actual = fingerprint(end_entity);
+        if !self.fingerprints.contains(&actual) {
+            return Err(rustls::Error::General(format!(
+                "client certificate {} is not pinned for any peer",
+                actual
+            )));
+        }
+
+        Ok(ClientCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls12_signature(
+            message,
+            cert,
+            dss,
+            &self.provider.signature_verification_algorithms,
+        )
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls13_signature(
+            message,
+            cert,
+            dss,
+            &self.provider.signature_verification_algorithms,
+        )
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.provider
+            .signature_verification_algorithms
+            .supported_schemes()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transport::{Entry, TcpTransport, Transport};
+    use crate::utils::test_dir;
+    use std::io::Read;
+    use std::net::TcpListener;
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    fn free_port() -> u16 {
+        TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port()
+    }
+
+    /// an instance with a fresh certificate in its own directory, without peers yet
+    fn instance(dir: &str, name: &str, port: u16) -> (Config, String) {
+        let remote_names: Vec<_> = ["a", "b", "c"].into_iter().filter(|n| *n != name).collect();
+        let config = Config::parse(&format!(
+            "local_name={name}\nremote_names={remotes}\ndir_name={dir}\nlisten=127.0.0.1:{port}\n\
+             tls=on\ntls_cert={dir}/{name}.cert.pem\ntls_key={dir}/{name}.key.pem\n",
+            name = name,
+            remotes = remote_names.join(","),
+            dir = dir,
+            port = port
+        ))
+        .unwrap();
+        let fingerprint = generate_certificate(&config, false).unwrap();
+        (config, fingerprint)
+    }
+
+    fn peer(name: &str, port: u16, fingerprint: &str) -> Peer {
+        Peer {
+            name: name.to_string(),
+            address: format!("127.0.0.1:{}", port),
+            fingerprint: Some(fingerprint.to_string()),
+        }
+    }
+
+    /// connects a to b over loopback. Returns what the client and the server side got
+    fn handshake(a: &TlsContext, b: &TlsContext, b_peer: &Peer) -> (Result<()>, Result<String>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        thread::scope(|scope| {
+            let server = scope.spawn(|| {
+                let (stream, _) = listener.accept().unwrap();
+                let (name, mut stream) = b.accept(stream)?;
+                let mut greeting = [0u8; 5];
+                stream.read_exact(&mut greeting)?;
+                assert_eq!(&greeting, b"hello");
+                Ok(name)
+            });
+
+            let stream = TcpStream::connect(address).unwrap();
+            let client = a.connect(b_peer, stream).and_then(|mut stream| {
+                stream.write_all(b"hello")?;
+                stream.flush()?;
+                Ok(())
+            });
+            (client, server.join().unwrap())
+        })
+    }
+
+    #[test]
+    fn generated_certificates_are_pinned_by_their_fingerprint() {
+        let dir = test_dir("tls-generate");
+        let (config, fingerprint) = instance(&dir, "a", 0);
+
+        assert_eq!(own_fingerprint(&config).unwrap(), fingerprint);
+        assert_eq!(
+            crate::config::parse_fingerprint(&fingerprint).unwrap(),
+            fingerprint
+        );
+    }
+
+    #[test]
+    fn generating_does_not_replace_a_certificate_unless_forced() {
+        let dir = test_dir("tls-force");
+        let (config, fingerprint) = instance(&dir, "a", 0);
+
+        assert!(generate_certificate(&config, false).is_err());
+        assert_eq!(own_fingerprint(&config).unwrap(), fingerprint);
+
+        let replaced = generate_certificate(&config, true).unwrap();
+        assert_ne!(replaced, fingerprint);
+        assert_eq!(own_fingerprint(&config).unwrap(), replaced);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn only_the_owner_can_read_the_key() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = test_dir("tls-key-mode");
+        let (config, _) = instance(&dir, "a", 0);
+
+        let mode = |path: &str| std::fs::metadata(path).unwrap().permissions().mode() & 0o777;
+        assert_eq!(mode(&config.tls_key), 0o600);
+
+        // replacing a key somebody made readable takes that back
+        std::fs::set_permissions(&config.tls_key, std::fs::Permissions::from_mode(0o644)).unwrap();
+        generate_certificate(&config, true).unwrap();
+        assert_eq!(mode(&config.tls_key), 0o600);
+    }
+
+    #[test]
+    fn peers_need_a_pinned_fingerprint() {
+        let dir = test_dir("tls-unpinned");
+        let (mut config, _) = instance(&dir, "a", 0);
+        config.peers = vec![Peer {
+            fingerprint: None,
+            ..peer("b", 0, "")
+        }];
+
+        assert!(TlsContext::load(&config).is_err());
+    }
+
+    #[test]
+    fn pinned_peers_connect_and_know_each_other() {
+        let dir = test_dir("tls-pinned");
+        let (mut a, a_fingerprint) = instance(&dir, "a", 0);
+        let (mut b, b_fingerprint) = instance(&dir, "b", 0);
+        let b_peer = peer("b", 0, &b_fingerprint);
+        a.peers = vec![b_peer.clone()];
+        b.peers = vec![peer("a", 0, &a_fingerprint)];
+
+        let (client, server) = handshake(
+            &TlsContext::load(&a).unwrap(),
+            &TlsContext::load(&b).unwrap(),
+            &b_peer,
+        );
+        client.unwrap();
+        assert_eq!(server.unwrap(), "a");
+    }
+
+    #[test]
+    fn a_server_with_another_certificate_is_refused() {
+        let dir = test_dir("tls-wrong-server");
+        let (mut a, a_fingerprint) = instance(&dir, "a", 0);
+        let (mut b, _) = instance(&dir, "b", 0);
+        let (_, c_fingerprint) = instance(&dir, "c", 0);
+        // a expects c's certificate where b answers
+        let b_peer = peer("b", 0, &c_fingerprint);
+        a.peers = vec![b_peer.clone()];
+        b.peers = vec![peer("a", 0, &a_fingerprint)];
+
+        let (client, server) = handshake(
+            &TlsContext::load(&a).unwrap(),
+            &TlsContext::load(&b).unwrap(),
+            &b_peer,
+        );
+        assert!(client.is_err());
+        assert!(server.is_err());
+    }
+
+    #[test]
+    fn a_client_with_an_unknown_certificate_is_refused() {
+        let dir = test_dir("tls-wrong-client");
+        let (mut a, _) = instance(&dir, "a", 0);
+        let (mut b, b_fingerprint) = instance(&dir, "b", 0);
+        let (_, c_fingerprint) = instance(&dir, "c", 0);
+        let b_peer = peer("b", 0, &b_fingerprint);
+        a.peers = vec![b_peer.clone()];
+        // b only lets c in
+        b.peers = vec![peer("c", 0, &c_fingerprint)];
+
+        let (_, server) = handshake(
+            &TlsContext::load(&a).unwrap(),
+            &TlsContext::load(&b).unwrap(),
+            &b_peer,
+        );
+        assert!(server.is_err());
+    }
+
+    #[test]
+    fn the_tcp_transport_delivers_over_tls() {
+        let dir = test_dir("tls-transport");
+        let (a_port, b_port) = (free_port(), free_port());
+        let (mut a, a_fingerprint) = instance(&dir, "a", a_port);
+        let (mut b, b_fingerprint) = instance(&dir, "b", b_port);
+        a.peers = vec![peer("b", b_port, &b_fingerprint)];
+        b.peers = vec![peer("a", a_port, &a_fingerprint)];
+
+        let mut a = TcpTransport::start(&a).unwrap();
+        let mut b = TcpTransport::start(&b).unwrap();
+
+        let sent = Entry {
+            origin: "a".to_string(),
+            timestamp: 100,
+            expires: None,
+            content: b"encrypted".to_vec(),
+            format: Default::default(),
+        };
+        a.publish(&sent).unwrap();
+
+        let deadline = Instant::now() + Duration::from_secs(5);
+        let received = loop {
+            if let Some(entry) = b.take_latest().unwrap() {
+                break entry;
+            }
+            assert!(Instant::now() < deadline, "nothing arrived in time");
+            thread::sleep(Duration::from_millis(20));
+        };
+        assert_eq!(received, sent);
+
+        a.close(false).unwrap();
+        b.close(false).unwrap();
+    }
+}
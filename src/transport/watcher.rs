@@ -0,0 +1,170 @@
+use super::index::EntryIndex;
+use crate::config::{Config, WatchMode};
+use crate::entry::EntryFile;
+use crate::events::{self, ErrorKind, Event};
+use crate::logfile::log;
+use anyhow::{Context, Result};
+use notify::{Event as NotifyEvent, PollWatcher, RecursiveMode, Watcher};
+use std::path::Path;
+use std::sync::mpsc::{self, Sender};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+/// starts a thread which keeps the index in sync with config.dir_name. Whenever the newest remote
+/// entry changes, on_change is called with it.
+pub fn spawn_watcher<F>(config: Config, index: Arc<EntryIndex>, on_change: F) -> Result<()>
+where
+    F: Fn(EntryFile) + Send + 'static,
+{
+    let (event_sender, event_receiver) = mpsc::channel();
+    let dir = Path::new(&config.dir_name);
+
+    let mut watcher: Box<dyn Watcher + Send> = match config.watch_mode {
+        WatchMode::Events => match notify::recommended_watcher(event_sender.clone()) {
+            Ok(watcher) => Box::new(watcher),
+            Err(e) => {
+                log(&format!(
+                    "could not watch for filesystem events, falling back to polling: {}\n",
+                    e
+                ));
+                Box::new(poll_watcher(&config, event_sender)?)
+            }
+        },
+        WatchMode::Poll => Box::new(poll_watcher(&config, event_sender)?),
+    };
+
+    watcher
+        .watch(dir, RecursiveMode::NonRecursive)
+        .with_context(|| format!("could not watch {}", config.dir_name))?;
+
+    thread::spawn(move || {
+        // the watcher stops as soon as it is dropped. So it has to live as long as this thread
+        let _watcher = watcher;
+        let mut latest = index.latest_remote();
+
+        for event in event_receiver {
+            apply_event(&config, &index, event);
+
+            let current = index.latest_remote();
+            if current != latest {
+                latest = current;
+                if let Some(entry) = &latest {
+                    on_change(entry.clone());
+                }
+            }
+        }
+    });
+
+    Ok(())
+}
+
+fn poll_watcher(
+    config: &Config,
+    event_sender: Sender<notify::Result<NotifyEvent>>,
+) -> Result<PollWatcher> {
+    let poll_config = notify::Config::default()
+        .with_poll_interval(Duration::from_secs(config.poll_interval_secs));
+
+    PollWatcher::new(event_sender, poll_config).with_context(|| "could not start polling watcher")
+}
+
+fn apply_event(config: &Config, index: &EntryIndex, event: notify::Result<NotifyEvent>) {
+    let event = match event {
+        Ok(event) => event,
+        Err(e) => {
+            events::report_error(
+                ErrorKind::Watcher,
+                format!("watcher error, rescanning {}: {}", config.dir_name, e),
+            );
+            rescan(config, index);
+            return;
+        }
+    };
+
+    if event.need_rescan() {
+        rescan(config, index);
+        return;
+    }
+
+    // renames and removals are reported differently on every platform. Checking whether the file
+    // is still there covers all of them.
+    for path in &event.paths {
+        let Some(file_name) = path.file_name().map(|f| f.to_string_lossy()) else {
+            continue;
+        };
+
+        if path.exists() {
+            if let Some(entry) = index.insert(&file_name) {
+                announce(config, &entry, path);
+            }
+        } else {
+            index.remove(&file_name);
+        }
+    }
+}
+
+/// publishes the arrival of a remote entry. Files of other machines sharing the directory are no
+/// news for this one, just like they are never pasted
+fn announce(config: &Config, entry: &EntryFile, path: &Path) {
+    if !config.remote_names.contains(&entry.origin) {
+        return;
+    }
+
+    events::publish(Event::EntryReceived {
+        origin: entry.origin.clone(),
+        timestamp: entry.timestamp,
+        channel: entry.channel.clone(),
+        size: path
+            .metadata()
+            .map(|m| m.len() as usize)
+            .unwrap_or_default(),
+        sensitive: entry.expires.is_some(),
+    });
+}
+
+fn rescan(config: &Config, index: &EntryIndex) {
+    if let Err(e) = index.rescan(&config.dir_name) {
+        events::report_error(
+            ErrorKind::Watcher,
+            format!("could not rescan {}: {}", config.dir_name, e),
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::entry::EntryKind;
+    use crate::utils::test_dir;
+
+    #[test]
+    fn only_entries_of_the_remotes_are_announced() {
+        let dir = test_dir("watcher-announce");
+        let config = Config::parse(&format!(
+            "local_name=watcher_a\nremote_names=watcher_b\ndir_name={}\n",
+            dir
+        ))
+        .unwrap();
+        let subscription = events::subscribe();
+
+        for origin in ["watcher_a", "watcher_c", "watcher_b"] {
+            let entry = EntryFile::new(None, origin, 100, EntryKind::Published);
+            let path = Path::new(&dir).join(entry.file_name());
+            std::fs::write(&path, "text").unwrap();
+            announce(&config, &entry, &path);
+        }
+
+        // the events of other tests pass by as well
+        let announced: Vec<_> = subscription
+            .try_iter()
+            .filter_map(|event| match event {
+                Event::EntryReceived { origin, size, .. } if origin.starts_with("watcher_") => {
+                    Some((origin, size))
+                }
+                _ => None,
+            })
+            .collect();
+        assert_eq!(announced, vec![("watcher_b".to_string(), 4)]);
+    }
+}
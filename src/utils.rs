@@ -1,3 +1,5 @@
+use anyhow::{Context, Result};
+
 pub fn get_timestamp() -> u64 {
     use std::time::{SystemTime, UNIX_EPOCH};
 
@@ -6,3 +8,49 @@ pub fn get_timestamp() -> u64 {
         .expect("time went backwards")
         .as_secs()
 }
+
+/// reads all filenames of the files in the given directory. Here every osfile is included.
+pub fn read_file_names(dir_name: &str) -> Result<Vec<String>> {
+    std::fs::read_dir(dir_name)
+        .with_context(|| format!("tried to read {}", dir_name))?
+        .map(|entry| {
+            Ok(entry
+                .with_context(|| {
+                    "could not read a file name. Something went wrong with the filesystem"
+                })?
+                .file_name()
+                .to_string_lossy()
+                .to_string())
+        })
+        .collect()
+}
+
+/// waits until the thread finished, but no longer than until the deadline. Returns false if it
+/// is still running then
+pub fn join_until(handle: std::thread::JoinHandle<()>, deadline: std::time::Instant) -> bool {
+    use std::time::{Duration, Instant};
+
+    while !handle.is_finished() {
+        if Instant::now() >= deadline {
+            return false;
+        }
+        std::thread::sleep(Duration::from_millis(20));
+    }
+
+    let _ = handle.join();
+    true
+}
+
+/// an empty directory of its own for a test
+#[cfg(test)]
+pub fn test_dir(name: &str) -> String {
+    let root = std::env::temp_dir().join(format!("clipboard-test-{}", std::process::id()));
+    std::fs::create_dir_all(&root).unwrap();
+    // keep the logfile out of the working copy
+    crate::logfile::set_log_dir(&root);
+
+    let dir = root.join(name);
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    dir.to_string_lossy().into_owned()
+}
@@ -0,0 +1,51 @@
+// Helpers shared by the integration tests. Every test binary compiles its own copy, not all of
+// them use everything
+#![allow(dead_code)]
+
+use clipboard::config::Config;
+use clipboard::control::{self, Client};
+use clipboard::file_handlers::{self, FileHandler};
+use clipboard::transport;
+use std::collections::HashMap;
+use std::sync::Once;
+
+static IN_TEMP_DIR: Once = Once::new();
+
+/// an empty directory for the test. The instance logs to the working directory, so that is
+/// moved out of the working copy as well
+pub fn test_dir(name: &str) -> String {
+    let root = std::env::temp_dir().join(format!("clipboard-it-{}", std::process::id()));
+    IN_TEMP_DIR.call_once(|| {
+        std::fs::create_dir_all(&root).unwrap();
+        std::env::set_current_dir(&root).unwrap();
+    });
+
+    let dir = root.join(name);
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    dir.to_string_lossy().into_owned()
+}
+
+/// the config of an instance, the lines are added to local_name, remote_names and dir_name
+pub fn config(local_name: &str, remote_names: &str, dir: &str, lines: &str) -> Config {
+    Config::parse(&format!(
+        "local_name={}\nremote_names={}\ndir_name={}\n{}",
+        local_name, remote_names, dir, lines
+    ))
+    .unwrap()
+}
+
+/// writes the config as config.ini of dir, where the binary started in dir looks for it
+pub fn config_file(dir: &str, content: &str) -> Config {
+    std::fs::write(format!("{}/config.ini", dir), content).unwrap();
+    Config::parse(content).unwrap()
+}
+
+/// runs the transport, the file handler and the control socket of an instance in this process,
+/// without hotkeys. Returns a client of its control socket
+pub fn start(config: &Config) -> Client {
+    let handler = FileHandler::new(config, transport::open(config).unwrap());
+    let (sender, _) = file_handlers::provide_file_handler(handler, None);
+    control::spawn_server(config, HashMap::from([(None, sender)])).unwrap();
+    control::connect(config).unwrap().unwrap()
+}
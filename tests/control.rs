@@ -0,0 +1,151 @@
+// The clients of the control socket: status, send and recv of the binary talk to instances
+// running in this process. Each binary finds the config.ini of its instance in its working
+// directory.
+
+mod common;
+
+use clipboard::config::Config;
+use clipboard::Event;
+use std::io::Write;
+use std::process::{Command, Output, Stdio};
+
+/// the config.ini of name in a directory of its own, sharing dir_name with the others of the test
+fn instance(test: &str, name: &str, remote: &str, shared: &str) -> (String, Config) {
+    let dir = common::test_dir(&format!("{}-{}", test, name));
+    let config = common::config_file(
+        &dir,
+        &format!(
+            "local_name={}\nremote_names={}\ndir_name={}\ncontrol_path={}/control.sock\n",
+            name, remote, shared, dir
+        ),
+    );
+    (dir, config)
+}
+
+/// runs the binary in dir with the input on stdin
+fn clipboard(dir: &str, args: &[&str], input: &[u8]) -> Output {
+    let mut child = Command::new(env!("CARGO_BIN_EXE_clipboard"))
+        .args(args)
+        .current_dir(dir)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    child.stdin.take().unwrap().write_all(input).unwrap();
+    child.wait_with_output().unwrap()
+}
+
+fn stdout(output: &Output) -> String {
+    String::from_utf8_lossy(&output.stdout).into_owned()
+}
+
+#[test]
+fn status_tells_when_nothing_is_running() {
+    let shared = common::test_dir("not-running");
+    let (dir, _) = instance("not-running", "a", "b", &shared);
+
+    let output = clipboard(&dir, &["status"], b"");
+    assert_eq!(output.status.code(), Some(4));
+    assert_eq!(stdout(&output), "not running\n");
+
+    let output = clipboard(&dir, &["status", "--json"], b"");
+    assert_eq!(output.status.code(), Some(4));
+    assert_eq!(stdout(&output), "{\"running\":false}\n");
+}
+
+#[test]
+fn status_describes_the_running_instance() {
+    let shared = common::test_dir("status");
+    let (dir, config) = instance("status", "a", "b", &shared);
+    common::start(&config);
+
+    let output = clipboard(&dir, &["status"], b"");
+    assert_eq!(output.status.code(), Some(0));
+    let status = stdout(&output);
+    assert!(
+        status.starts_with(&format!(
+            "a: running (version {}, pid {},",
+            env!("CARGO_PKG_VERSION"),
+            std::process::id()
+        )),
+        "{}",
+        status
+    );
+    assert!(status.contains("transport directory, paste_mode latest"));
+
+    let output = clipboard(&dir, &["status", "--json"], b"");
+    let status: serde_json::Value = serde_json::from_slice(&output.stdout).unwrap();
+    assert_eq!(status["local_name"], "a");
+}
+
+#[test]
+fn send_and_recv_go_through_the_running_instances() {
+    let shared = common::test_dir("send-recv");
+    let (a_dir, a) = instance("send-recv", "a", "b", &shared);
+    let (b_dir, b) = instance("send-recv", "b", "a", &shared);
+    common::start(&a);
+    common::start(&b);
+    let events = clipboard::subscribe();
+
+    let output = clipboard(&a_dir, &["send"], b"hello b");
+    assert_eq!(output.status.code(), Some(0), "{:?}", output);
+
+    // published by the instance in this process, not by the binary itself
+    let published = events
+        .iter()
+        .find(|event| matches!(event, Event::EntryPublished { .. }));
+    assert!(
+        matches!(published, Some(Event::EntryPublished { origin, size: 7, .. }) if origin == "a")
+    );
+
+    let output = clipboard(&b_dir, &["recv"], b"");
+    assert_eq!(output.status.code(), Some(0), "{:?}", output);
+    assert_eq!(output.stdout, b"hello b");
+
+    // taken already
+    let output = clipboard(&b_dir, &["recv"], b"");
+    assert_eq!(output.status.code(), Some(3));
+    assert!(output.stdout.is_empty());
+}
+
+#[test]
+fn send_refuses_empty_input() {
+    let shared = common::test_dir("send-empty");
+    let (dir, config) = instance("send-empty", "a", "b", &shared);
+    common::start(&config);
+
+    let output = clipboard(&dir, &["send"], b"");
+    assert_eq!(output.status.code(), Some(1));
+    assert!(String::from_utf8_lossy(&output.stderr).contains("stdin was empty"));
+}
+
+#[cfg(unix)]
+#[test]
+fn only_this_user_can_connect() {
+    use std::os::unix::fs::PermissionsExt;
+
+    let shared = common::test_dir("mode");
+    let (_, config) = instance("mode", "a", "b", &shared);
+    common::start(&config);
+
+    let mode = std::fs::metadata(&config.control_path)
+        .unwrap()
+        .permissions()
+        .mode();
+    assert_eq!(mode & 0o777, 0o600);
+    // nothing is left of the staging
+    let leftovers: Vec<_> =
+        std::fs::read_dir(std::path::Path::new(&config.control_path).parent().unwrap())
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name())
+            .filter(|name| name.to_string_lossy().ends_with(".tmp"))
+            .collect();
+    assert!(leftovers.is_empty(), "{:?}", leftovers);
+
+    // a second instance doesn't take the socket over
+    let error = clipboard::control::spawn_server(&config, Default::default())
+        .err()
+        .unwrap();
+    assert!(error.to_string().contains("already listening"));
+}
@@ -0,0 +1,82 @@
+// The metrics endpoint of an instance whose tcp transport delivers to two peers over loopback
+
+mod common;
+
+use clipboard::metrics;
+use std::io::{Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::thread;
+use std::time::{Duration, Instant};
+
+fn free_address() -> String {
+    TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .to_string()
+}
+
+fn scrape(address: &str) -> String {
+    let mut stream = TcpStream::connect(address).unwrap();
+    write!(stream, "GET /metrics HTTP/1.0\r\nHost: {}\r\n\r\n", address).unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).unwrap();
+    response
+}
+
+/// an instance of a, b or c. All of them live in this process and share the metrics
+fn config(
+    name: &str,
+    remotes: &str,
+    listen: &str,
+    peers: &[(&str, &str)],
+) -> clipboard::config::Config {
+    let dir = common::test_dir(name);
+    let peers: Vec<_> = peers
+        .iter()
+        .map(|(name, address)| format!("{}@{}", name, address))
+        .collect();
+    common::config(
+        name,
+        remotes,
+        &dir,
+        &format!(
+            "transport=tcp\nlisten={}\npeers={}\ntls=off\ncontrol_path={}/control.sock\n",
+            listen,
+            peers.join(","),
+            dir
+        ),
+    )
+}
+
+#[test]
+fn deliveries_are_counted_per_peer() {
+    let [a, b, c] = [free_address(), free_address(), free_address()];
+    let mut sender = common::start(&config("a", "b,c", &a, &[("b", &b), ("c", &c)]));
+    common::start(&config("b", "a", &b, &[("a", &a)]));
+    common::start(&config("c", "a", &c, &[("a", &a)]));
+
+    let endpoint = free_address();
+    metrics::spawn_endpoint(&endpoint).unwrap();
+
+    assert!(sender.push(b"12345", None, false).unwrap());
+
+    // delivered once both peers acked it
+    let expected = [
+        "clipboard_entries_delivered_total{peer=\"b\"} 1\n",
+        "clipboard_entries_delivered_total{peer=\"c\"} 1\n",
+        "clipboard_delivered_bytes_total{peer=\"b\"} 5\n",
+        "clipboard_delivered_bytes_total{peer=\"c\"} 5\n",
+        "clipboard_entries_received_total{origin=\"a\"} 2\n",
+        "clipboard_entries_published_total{channel=\"default\"} 1\n",
+    ];
+    let deadline = Instant::now() + Duration::from_secs(5);
+    loop {
+        let text = scrape(&endpoint);
+        if expected.iter().all(|line| text.contains(line)) {
+            break;
+        }
+        assert!(Instant::now() < deadline, "missing metrics in\n{}", text);
+        thread::sleep(Duration::from_millis(20));
+    }
+}
@@ -0,0 +1,220 @@
+// The shutdown of a running instance on unix: a signal requests it, then every part is stopped
+// in order. Only the first request ever counts, so every test here runs after the same SIGTERM.
+#![cfg(unix)]
+
+mod common;
+
+use clipboard::config::Config;
+use clipboard::control;
+use clipboard::file_handlers::ClipboardAction;
+use clipboard::global_hotkeys::{self, HotkeyBackend, Stopper};
+use clipboard::presence;
+use clipboard::shutdown::{self, Instance, Reason};
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Arc, Mutex, Once};
+use std::thread;
+use std::time::{Duration, Instant};
+
+static TERMINATED: Once = Once::new();
+
+/// the instance got SIGTERM
+fn terminated() {
+    TERMINATED.call_once(|| {
+        shutdown::handle_signals().unwrap();
+        signal_hook::low_level::raise(signal_hook::consts::SIGTERM).unwrap();
+        assert_eq!(shutdown::wait(), Reason::Terminated);
+    });
+}
+
+/// stands in for the keyboard backends of linux: listens until its stopper is called, then
+/// releases the keyboard. One which ignores the stopper never does
+struct FakeHotkeys {
+    stop: Sender<()>,
+    stopped: Receiver<()>,
+    released: Arc<AtomicBool>,
+    stoppable: bool,
+}
+
+impl FakeHotkeys {
+    fn new(stoppable: bool) -> (Self, Arc<AtomicBool>) {
+        let (stop, stopped) = mpsc::channel();
+        let released = Arc::new(AtomicBool::new(false));
+        let hotkeys = Self {
+            stop,
+            stopped,
+            released: released.clone(),
+            stoppable,
+        };
+        (hotkeys, released)
+    }
+}
+
+impl HotkeyBackend for FakeHotkeys {
+    fn listen(&mut self) -> anyhow::Result<()> {
+        let _ = self.stopped.recv();
+        self.released.store(true, Ordering::SeqCst);
+        Ok(())
+    }
+
+    fn stopper(&self) -> Stopper {
+        let stop = self.stop.clone();
+        let stoppable = self.stoppable;
+        Box::new(move || {
+            if stoppable {
+                let _ = stop.send(());
+            }
+        })
+    }
+}
+
+/// stands in for the file handler of a channel and notes the actions it got, in order
+fn handler(finished: Arc<Mutex<Vec<&'static str>>>) -> Sender<ClipboardAction> {
+    let (sender, actions) = mpsc::channel();
+    thread::spawn(move || {
+        for action in actions {
+            match action {
+                ClipboardAction::Push { reply, .. } => {
+                    // a slow share
+                    thread::sleep(Duration::from_millis(200));
+                    finished.lock().unwrap().push("push");
+                    let _ = reply.send(Ok(true));
+                }
+                ClipboardAction::Stop { done } => {
+                    finished.lock().unwrap().push("stop");
+                    let _ = done.send(Ok(()));
+                    return;
+                }
+                _ => {}
+            }
+        }
+    });
+    sender
+}
+
+fn config(name: &str) -> Config {
+    let dir = common::test_dir(name);
+    common::config(
+        "a",
+        "b",
+        &dir,
+        &format!("control=on\ncontrol_path={}/control.sock\n", dir),
+    )
+}
+
+fn heartbeat_path(config: &Config) -> String {
+    format!("{}/heartbeat-{}.tmp", config.dir_name, config.local_name)
+}
+
+fn wait_until(condition: impl Fn() -> bool) {
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while !condition() {
+        assert!(Instant::now() < deadline, "timed out");
+        thread::sleep(Duration::from_millis(10));
+    }
+}
+
+/// an instance whose hotkeys stop when asked. Returns whether they released the keyboard
+fn instance(
+    config: &Config,
+    channels: Vec<Sender<ClipboardAction>>,
+) -> (Instance, Arc<AtomicBool>) {
+    let heartbeat = presence::spawn_heartbeat(config.clone());
+    wait_until(|| Path::new(&heartbeat_path(config)).exists());
+
+    let (hotkeys, released) = FakeHotkeys::new(true);
+    let instance = Instance {
+        config: config.clone(),
+        channels,
+        heartbeat,
+        hotkeys: global_hotkeys::spawn_listener(Box::new(hotkeys)),
+    };
+    (instance, released)
+}
+
+#[test]
+fn the_first_reason_counts() {
+    terminated();
+
+    assert!(shutdown::is_stopping());
+    shutdown::request(Reason::Interrupted);
+    assert_eq!(shutdown::wait(), Reason::Terminated);
+}
+
+#[test]
+fn every_part_is_stopped_in_order() {
+    terminated();
+    let config = config("in-order");
+    let finished = Arc::new(Mutex::new(Vec::new()));
+    let sender = handler(finished.clone());
+
+    control::spawn_server(&config, HashMap::from([(None, sender.clone())])).unwrap();
+    let mut client = control::connect(&config).unwrap().unwrap();
+    // nothing new is accepted once the shutdown was requested
+    let refused = client.status().err().unwrap().to_string();
+    assert!(refused.contains("shutting down"), "{}", refused);
+
+    // queued before the stop, so it is finished first
+    let (reply, published) = mpsc::channel();
+    sender
+        .send(ClipboardAction::Push {
+            content: b"last words".to_vec(),
+            sensitive: false,
+            reply,
+        })
+        .unwrap();
+
+    let (instance, released) = instance(&config, vec![sender]);
+    assert_eq!(shutdown::shut_down(instance, Reason::Terminated), 0);
+
+    assert!(published.recv().unwrap().unwrap());
+    assert_eq!(*finished.lock().unwrap(), ["push", "stop"]);
+    assert!(!Path::new(&config.control_path).exists());
+    assert!(control::connect(&config).unwrap().is_none());
+    assert!(!Path::new(&heartbeat_path(&config)).exists());
+    assert!(released.load(Ordering::SeqCst));
+}
+
+#[test]
+fn failed_hotkeys_exit_with_an_error() {
+    terminated();
+    let config = config("hotkeys-failed");
+    let sender = handler(Arc::default());
+
+    let (instance, _) = instance(&config, vec![sender]);
+    let reason = Reason::HotkeysFailed("all keyboards are gone".to_string());
+    assert_eq!(shutdown::shut_down(instance, reason), 1);
+}
+
+#[test]
+fn a_handler_which_is_gone_fails_the_shutdown() {
+    terminated();
+    let config = config("handler-gone");
+    let (gone, _) = mpsc::channel();
+
+    let (instance, released) = instance(&config, vec![gone, handler(Arc::default())]);
+    assert_eq!(shutdown::shut_down(instance, Reason::Terminated), 1);
+
+    // the other steps still ran
+    assert!(!Path::new(&heartbeat_path(&config)).exists());
+    assert!(released.load(Ordering::SeqCst));
+}
+
+#[test]
+fn hotkeys_which_dont_stop_fail_the_shutdown() {
+    terminated();
+    let config = config("hotkeys-stuck");
+    let heartbeat = presence::spawn_heartbeat(config.clone());
+    let (hotkeys, released) = FakeHotkeys::new(false);
+
+    let instance = Instance {
+        config: config.clone(),
+        channels: vec![handler(Arc::default())],
+        heartbeat,
+        hotkeys: global_hotkeys::spawn_listener(Box::new(hotkeys)),
+    };
+    assert_eq!(shutdown::shut_down(instance, Reason::Terminated), 1);
+    assert!(!released.load(Ordering::SeqCst));
+}
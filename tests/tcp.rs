@@ -0,0 +1,130 @@
+// Several instances with the tcp transport in one process, talking over loopback. Their control
+// sockets drive them like send and recv would.
+
+mod common;
+
+use clipboard::control::{Client, PulledEntry};
+use std::net::TcpListener;
+use std::thread;
+use std::time::{Duration, Instant};
+
+const NAMES: [&str; 3] = ["a", "b", "c"];
+
+fn free_port() -> u16 {
+    TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port()
+}
+
+/// starts a, b and c, each a peer of the others. The lines are added to the config of each
+fn cluster(test: &str, lines: [&str; 3]) -> Vec<Client> {
+    let ports = NAMES.map(|_| free_port());
+
+    NAMES
+        .iter()
+        .zip(ports)
+        .zip(lines)
+        .map(|((name, port), lines)| {
+            let dir = common::test_dir(&format!("{}-{}", test, name));
+            let others: Vec<_> = NAMES
+                .iter()
+                .zip(ports)
+                .filter(|(n, _)| n != &name)
+                .collect();
+            let remote_names: Vec<_> = others.iter().map(|(n, _)| **n).collect();
+            let peers: Vec<_> = others
+                .iter()
+                .map(|(n, port)| format!("{}@127.0.0.1:{}", n, port))
+                .collect();
+
+            let config = common::config(
+                name,
+                &remote_names.join(","),
+                &dir,
+                &format!(
+                    "transport=tcp\nlisten=127.0.0.1:{}\npeers={}\ntls=off\n\
+                     control_path={}/control.sock\n{}",
+                    port,
+                    peers.join(","),
+                    dir,
+                    lines
+                ),
+            );
+            common::start(&config)
+        })
+        .collect()
+}
+
+/// pulls until an entry arrives, the peers deliver in the background
+fn pull(client: &mut Client) -> PulledEntry {
+    let deadline = Instant::now() + Duration::from_secs(5);
+    loop {
+        if let Some(entry) = client.pull(None).unwrap() {
+            return entry;
+        }
+        assert!(Instant::now() < deadline, "nothing arrived in time");
+        thread::sleep(Duration::from_millis(20));
+    }
+}
+
+#[test]
+fn a_push_reaches_every_peer() {
+    let mut clients = cluster("push", ["", "", ""]);
+
+    assert!(clients[0].push(b"from a", None, false).unwrap());
+    for client in &mut clients[1..] {
+        let entry = pull(client);
+        assert_eq!(entry.origin, "a");
+        assert_eq!(entry.text, "from a");
+        assert_eq!(entry.expires, None);
+    }
+
+    // the entry was taken, and a doesn't receive its own
+    assert!(clients[1].pull(None).unwrap().is_none());
+    assert!(clients[0].pull(None).unwrap().is_none());
+}
+
+#[test]
+fn the_line_endings_and_the_expiry_travel_along() {
+    let mut clients = cluster(
+        "format",
+        ["sensitive_ttl=60\n", "text_format=original\n", ""],
+    );
+
+    assert!(clients[0].push(b"one\r\ntwo", None, true).unwrap());
+
+    // b asked for the original format, c for the native one of its platform
+    let original = pull(&mut clients[1]);
+    assert_eq!(original.line_ending, "crlf");
+    assert_eq!(original.data().unwrap(), b"one\r\ntwo");
+    let expires = original.expires.unwrap();
+    assert!(expires > original.timestamp && expires <= original.timestamp + 60);
+
+    let native = pull(&mut clients[2]);
+    let expected: &[u8] = if cfg!(windows) {
+        b"one\r\ntwo"
+    } else {
+        b"one\ntwo"
+    };
+    assert_eq!(native.data().unwrap(), expected);
+}
+
+#[test]
+fn queue_mode_receives_every_entry_in_order() {
+    let mut clients = cluster("queue", ["", "", "paste_mode=queue\n"]);
+
+    for text in ["first", "second", "third"] {
+        assert!(clients[0].push(text.as_bytes(), None, false).unwrap());
+        // the entries are told apart by their timestamp in seconds
+        thread::sleep(Duration::from_millis(1100));
+    }
+
+    let received: Vec<_> = (0..3).map(|_| pull(&mut clients[2]).text).collect();
+    assert_eq!(received, ["first", "second", "third"]);
+
+    // latest mode only keeps the newest one
+    assert_eq!(pull(&mut clients[1]).text, "third");
+    assert!(clients[1].pull(None).unwrap().is_none());
+}